@@ -0,0 +1,254 @@
+// accumulator.rs - Merkle accumulator over an epoch's tags.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Research deployments sometimes need to show an auditor that a given
+//! packet was recorded by a mix, without handing over every tag of the
+//! epoch. With the `accumulator` feature each key keeps a Merkle tree
+//! over its tags in the order they were stored, hashed as in RFC 6962
+//! with blake2b. The mix publishes `MixKey::accumulator_root` and
+//! answers an auditor with `MixKey::inclusion_proof`, which the auditor
+//! checks against the published root with `InclusionProof::verify`.
+//!
+//! The tree only grows: tags removed with `MixKey::remove_tag` stay in
+//! it. It is held in memory, at about three hashes per tag, and is
+//! rebuilt from the store in the store's order when the key is opened,
+//! so roots published before a restart do not match those after it.
+//!
+
+use std::collections::HashMap;
+
+use blake2::blake2b;
+
+use errors::MixKeyError;
+use store::ReplayStore;
+use super::Tag;
+
+
+/// The size of the accumulator's hashes.
+pub const HASH_SIZE: usize = 32;
+
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+fn hash(prefix: u8, parts: &[&[u8]]) -> [u8; HASH_SIZE] {
+    let mut data = vec![prefix];
+    for part in parts {
+        data.extend_from_slice(part);
+    }
+    let mut out = [0u8; HASH_SIZE];
+    out.copy_from_slice(&blake2b(HASH_SIZE, &data));
+    out
+}
+
+fn leaf_hash(tag: &Tag) -> [u8; HASH_SIZE] {
+    hash(LEAF_PREFIX, &[&tag.0])
+}
+
+fn node_hash(left: &[u8; HASH_SIZE], right: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    hash(NODE_PREFIX, &[left, right])
+}
+
+/// Returns the largest power of two below `size`, which must be at
+/// least two.
+fn split(size: u64) -> u64 {
+    1 << (63 - (size - 1).leading_zeros())
+}
+
+/// AccumulatorRoot is the root of the tree over the first `size` tags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccumulatorRoot {
+    pub size: u64,
+    pub hash: [u8; HASH_SIZE],
+}
+
+/// InclusionProof shows that a tag is the `index`th leaf of the tree
+/// over the first `size` tags.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub index: u64,
+    pub size: u64,
+    pub path: Vec<[u8; HASH_SIZE]>,
+}
+
+impl InclusionProof {
+    /// Returns true if the proof shows the tag is under the root, which
+    /// must be of the same size as the proof.
+    pub fn verify(&self, tag: &Tag, root: &AccumulatorRoot) -> bool {
+        if self.size != root.size || self.index >= self.size {
+            return false
+        }
+        // RFC 9162 section 2.1.3.2.
+        let (mut node, mut last) = (self.index, self.size - 1);
+        let mut hash = leaf_hash(tag);
+        for sibling in &self.path {
+            if last == 0 {
+                return false
+            }
+            if node & 1 == 1 || node == last {
+                hash = node_hash(sibling, &hash);
+                while node & 1 == 0 && node != 0 {
+                    node >>= 1;
+                    last >>= 1;
+                }
+            } else {
+                hash = node_hash(&hash, sibling);
+            }
+            node >>= 1;
+            last >>= 1;
+        }
+        last == 0 && hash == root.hash
+    }
+}
+
+/// Accumulator holds the hashes of every complete subtree of the tree,
+/// by level, so that roots and proofs take a logarithmic number of
+/// hashes.
+#[derive(Default)]
+pub(crate) struct Accumulator {
+    levels: Vec<Vec<[u8; HASH_SIZE]>>,
+    index: HashMap<Tag, u64>,
+}
+
+impl Accumulator {
+    /// Append every tag of the store.
+    pub(crate) fn extend(&mut self, store: &mut dyn ReplayStore) -> Result<(), MixKeyError> {
+        for raw in store.tags() {
+            self.push(&Tag(raw?));
+        }
+        Ok(())
+    }
+
+    /// Append the tag as the next leaf.
+    pub(crate) fn push(&mut self, tag: &Tag) {
+        let mut node = leaf_hash(tag);
+        self.index.insert(tag.clone(), self.size());
+        let mut level = 0;
+        loop {
+            if self.levels.len() == level {
+                self.levels.push(vec![]);
+            }
+            self.levels[level].push(node);
+            let len = self.levels[level].len();
+            if len % 2 == 1 {
+                return
+            }
+            node = node_hash(&self.levels[level][len - 2], &self.levels[level][len - 1]);
+            level += 1;
+        }
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.levels.first().map_or(0, |leaves| leaves.len() as u64)
+    }
+
+    pub(crate) fn root(&self) -> AccumulatorRoot {
+        let size = self.size();
+        AccumulatorRoot{
+            size: size,
+            hash: match size {
+                0 => {
+                    let mut empty = [0u8; HASH_SIZE];
+                    empty.copy_from_slice(&blake2b(HASH_SIZE, &[]));
+                    empty
+                },
+                _ => self.subtree(0, size),
+            },
+        }
+    }
+
+    /// Returns the proof of the tag against the current root, or None
+    /// if the tag was never stored.
+    pub(crate) fn proof(&self, tag: &Tag) -> Option<InclusionProof> {
+        let index = *self.index.get(tag)?;
+        let size = self.size();
+        let mut path = vec![];
+        self.path(index, 0, size, &mut path);
+        Some(InclusionProof{
+            index: index,
+            size: size,
+            path: path,
+        })
+    }
+
+    /// Returns the hash of the `size` leaves from `start`, which is a
+    /// multiple of the largest power of two not above `size`.
+    fn subtree(&self, start: u64, size: u64) -> [u8; HASH_SIZE] {
+        if size.is_power_of_two() {
+            let level = size.trailing_zeros();
+            return self.levels[level as usize][(start >> level) as usize]
+        }
+        let k = split(size);
+        node_hash(&self.subtree(start, k), &self.subtree(start + k, size - k))
+    }
+
+    fn path(&self, index: u64, start: u64, size: u64, path: &mut Vec<[u8; HASH_SIZE]>) {
+        if size == 1 {
+            return
+        }
+        let k = split(size);
+        if index < start + k {
+            self.path(index, start, k, path);
+            path.push(self.subtree(start + k, size - k));
+        } else {
+            self.path(index, start + k, size - k, path);
+            path.push(self.subtree(start, k));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use super::*;
+
+
+    /// Returns the RFC 6962 root computed directly from the leaves.
+    fn naive_root(leaves: &[[u8; HASH_SIZE]]) -> [u8; HASH_SIZE] {
+        if leaves.len() == 1 {
+            return leaves[0]
+        }
+        let k = split(leaves.len() as u64) as usize;
+        node_hash(&naive_root(&leaves[..k]), &naive_root(&leaves[k..]))
+    }
+
+    #[test]
+    fn accumulator_test() {
+        let tags: Vec<Tag> = (0..23u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        let mut accumulator = Accumulator::default();
+        let empty = accumulator.root();
+        assert_eq!(empty.size, 0);
+        for (n, tag) in tags.iter().enumerate() {
+            accumulator.push(tag);
+            let leaves: Vec<_> = tags[..n + 1].iter().map(leaf_hash).collect();
+            let root = accumulator.root();
+            assert_eq!(root, AccumulatorRoot{ size: n as u64 + 1, hash: naive_root(&leaves) });
+            for tag in &tags[..n + 1] {
+                assert!(accumulator.proof(tag).unwrap().verify(tag, &root));
+            }
+        }
+
+        let root = accumulator.root();
+        let mut proof = accumulator.proof(&tags[5]).unwrap();
+        assert!(!proof.verify(&tags[6], &root));
+        assert!(!proof.verify(&tags[5], &empty));
+        proof.path[0][0] ^= 1;
+        assert!(!proof.verify(&tags[5], &root));
+        assert!(accumulator.proof(&Tag([99; SPHINX_REPLAY_TAG_SIZE])).is_none());
+    }
+}
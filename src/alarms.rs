@@ -0,0 +1,232 @@
+// alarms.rs - Capacity alarms.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Tag counts and disk usage say how full an epoch is, not whether it
+//! will last until its end. `MixKeys::check_capacity` samples the
+//! statistics of every key, estimates each epoch's tag rate since the
+//! previous sample, and forecasts at that rate:
+//!
+//!  * when the epoch's filter will hold the tags it was sized for, past
+//!    which its false positive rate exceeds the configured target, and
+//!  * whether the disk holding the caches fills up before the epoch
+//!    ends, going by the disk space each stored tag has taken so far.
+//!
+//! A forecast that falls before the end of the epoch raises a
+//! `CapacityAlarm`. Each alarm is delivered once to the `AlertSink` when
+//! it is raised, and again only after it has cleared; by default they
+//! are logged. `MixKeys::flush_due` checks capacity every
+//! `MIX_KEY_CAPACITY_CHECK_INTERVAL` seconds, and with the `metrics`
+//! feature the alarms in force are rendered as gauges.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+
+use stats::KeyStats;
+
+
+/// CapacityAlarm forecasts that an epoch runs out of a resource before
+/// it ends, in `seconds` at the current tag rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CapacityAlarm {
+    /// The filter will hold the tags it was sized for.
+    FilterCapacity {
+        epoch: u64,
+        seconds: u64,
+    },
+    /// The disk holding the caches will be full.
+    DiskFull {
+        epoch: u64,
+        seconds: u64,
+    },
+}
+
+impl CapacityAlarm {
+    pub fn epoch(&self) -> u64 {
+        match *self {
+            CapacityAlarm::FilterCapacity{epoch, ..} => epoch,
+            CapacityAlarm::DiskFull{epoch, ..} => epoch,
+        }
+    }
+
+    pub fn seconds(&self) -> u64 {
+        match *self {
+            CapacityAlarm::FilterCapacity{seconds, ..} => seconds,
+            CapacityAlarm::DiskFull{seconds, ..} => seconds,
+        }
+    }
+
+    /// Returns the name of the resource, as used in metric labels.
+    pub fn resource(&self) -> &'static str {
+        match *self {
+            CapacityAlarm::FilterCapacity{..} => "filter",
+            CapacityAlarm::DiskFull{..} => "disk",
+        }
+    }
+}
+
+impl fmt::Display for CapacityAlarm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CapacityAlarm::FilterCapacity{epoch, seconds} => write!(f, "the filter of epoch {} will reach its target false positive rate in ~{} minutes at the current rate",
+                                                                    epoch, seconds / 60),
+            CapacityAlarm::DiskFull{epoch, seconds} => write!(f, "the disk will fill in ~{} minutes, before epoch {} ends", seconds / 60, epoch),
+        }
+    }
+}
+
+/// AlertSink receives capacity alarms. This is an extension point for
+/// paging and chat integrations.
+pub trait AlertSink: Send + Sync {
+    fn alert(&self, alarm: &CapacityAlarm);
+}
+
+/// LogAlertSink logs every alarm as a warning.
+#[derive(Clone, Debug, Default)]
+pub struct LogAlertSink;
+
+impl AlertSink for LogAlertSink {
+    fn alert(&self, alarm: &CapacityAlarm) {
+        warn!("capacity alarm: {}", alarm);
+    }
+}
+
+/// CapacityForecaster keeps the previous sample of every epoch, to
+/// estimate tag rates, and the alarms in force.
+#[derive(Debug, Default)]
+pub(crate) struct CapacityForecaster {
+    /// The time, tag count and tag rate of each epoch's last sample.
+    samples: HashMap<u64, (Duration, u64, f64)>,
+    active: HashSet<(u64, &'static str)>,
+    last_check: Option<Duration>,
+}
+
+impl CapacityForecaster {
+    /// Returns true if no check ran within the interval before `now`.
+    pub fn is_due(&self, now: Duration, interval: Duration) -> bool {
+        match self.last_check {
+            Some(at) => now >= at + interval,
+            None => true,
+        }
+    }
+
+    /// Forecast each key with the tags its filter is sized for and the
+    /// seconds until its epoch ends, given the free disk space if the
+    /// caches are on disk. Returns every alarm in force along with the
+    /// number of them newly raised, which come first.
+    pub fn check(&mut self, now: Duration, keys: &[(KeyStats, u64, u64)], free_disk: Option<u64>) -> (Vec<CapacityAlarm>, usize) {
+        self.last_check = Some(now);
+        self.samples.retain(|epoch, _| keys.iter().any(|(stats, _, _)| stats.epoch == *epoch));
+        let mut alarms = vec![];
+        for &(ref stats, capacity, remaining) in keys {
+            let rate = match self.samples.get(&stats.epoch) {
+                Some(&(at, _, rate)) if at >= now => rate,
+                Some(&(at, tags, _)) => tags_per_second(at, tags, now, stats.tags),
+                None => 0.0,
+            };
+            self.samples.insert(stats.epoch, (now, stats.tags, rate));
+            alarms.extend(forecast(stats, rate, capacity, remaining, free_disk));
+        }
+        let active: HashSet<(u64, &'static str)> = alarms.iter().map(|alarm| (alarm.epoch(), alarm.resource())).collect();
+        alarms.sort_by_key(|alarm| self.active.contains(&(alarm.epoch(), alarm.resource())));
+        let raised = active.difference(&self.active).count();
+        self.active = active;
+        (alarms, raised)
+    }
+}
+
+fn tags_per_second(then: Duration, then_tags: u64, now: Duration, tags: u64) -> f64 {
+    tags.saturating_sub(then_tags) as f64 / (now - then).as_secs_f64()
+}
+
+/// Forecast one epoch at the given tags per second.
+fn forecast(stats: &KeyStats, rate: f64, capacity: u64, remaining: u64, free_disk: Option<u64>) -> Vec<CapacityAlarm> {
+    let mut alarms = vec![];
+    if stats.tags >= capacity {
+        alarms.push(CapacityAlarm::FilterCapacity{
+            epoch: stats.epoch,
+            seconds: 0,
+        });
+    } else if rate > 0.0 {
+        let seconds = (capacity - stats.tags) as f64 / rate;
+        if seconds < remaining as f64 {
+            alarms.push(CapacityAlarm::FilterCapacity{
+                epoch: stats.epoch,
+                seconds: seconds as u64,
+            });
+        }
+    }
+    if let Some(free) = free_disk {
+        if rate > 0.0 && stats.tags > 0 && stats.disk_bytes > 0 {
+            let bytes_per_second = rate * stats.disk_bytes as f64 / stats.tags as f64;
+            let seconds = free as f64 / bytes_per_second;
+            if seconds < remaining as f64 {
+                alarms.push(CapacityAlarm::DiskFull{
+                    epoch: stats.epoch,
+                    seconds: seconds as u64,
+                });
+            }
+        }
+    }
+    alarms
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    fn stats(epoch: u64, tags: u64, disk_bytes: u64) -> KeyStats {
+        KeyStats{
+            epoch: epoch,
+            tags: tags,
+            fill_ratio: None,
+            false_positive_rate: None,
+            disk_bytes: disk_bytes,
+            replays: 0,
+        }
+    }
+
+    #[test]
+    fn capacity_forecaster_test() {
+        let mut forecaster = CapacityForecaster::default();
+        let interval = Duration::from_secs(60);
+        assert!(forecaster.is_due(Duration::from_secs(0), interval));
+        assert_eq!(forecaster.check(Duration::from_secs(0), &[(stats(1, 0, 0), 10000, 3600)], Some(1 << 30)), (vec![], 0));
+        assert!(!forecaster.is_due(Duration::from_secs(30), interval));
+        assert!(forecaster.is_due(Duration::from_secs(60), interval));
+
+        // 100 tags a second fills 10000 tags in 100 seconds, and at 1000
+        // bytes a tag the 5MB free in 50 seconds, both within the hour.
+        let (alarms, raised) = forecaster.check(Duration::from_secs(60), &[(stats(1, 6000, 6000000), 10000, 3600)], Some(5000000));
+        assert_eq!(alarms, vec![
+            CapacityAlarm::FilterCapacity{ epoch: 1, seconds: 40 },
+            CapacityAlarm::DiskFull{ epoch: 1, seconds: 50 },
+        ]);
+        assert_eq!(raised, 2);
+        assert_eq!(alarms[0].to_string(), "the filter of epoch 1 will reach its target false positive rate in ~0 minutes at the current rate");
+
+        // At one tag a second both forecasts fall after the epoch ends,
+        // clearing the alarms, so that the full filter raises one again.
+        assert_eq!(forecaster.check(Duration::from_secs(120), &[(stats(1, 6060, 6060000), 10000, 3600)], Some(5000000)), (vec![], 0));
+        let (alarms, raised) = forecaster.check(Duration::from_secs(180), &[(stats(1, 10000, 6060000), 10000, 3600)], None);
+        assert_eq!(alarms, vec![CapacityAlarm::FilterCapacity{ epoch: 1, seconds: 0 }]);
+        assert_eq!(raised, 1);
+    }
+}
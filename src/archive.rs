@@ -0,0 +1,333 @@
+// archive.rs - Compressed archives of expired epoch caches.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Deployments that must retain replay tags for auditing can archive
+//! the caches of pruned epochs instead of only deleting them. An archive
+//! is a single file holding the epoch's tags in sorted order, split into
+//! zstd compressed blocks, followed by an index of the first tag of
+//! every block. Looking up a tag only decompresses the one block that
+//! may hold it. Every block and the index carry a BLAKE2b checksum.
+//!
+//! The layout is:
+//!
+//! * header: magic, version, epoch, tag count
+//! * blocks: zstd compressed runs of sorted tags
+//! * index: per block the first tag, offset, length, tag count and checksum
+//! * footer: index offset, index checksum, magic
+//!
+//! This module is only available with the `archive` feature.
+//!
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use blake2::blake2b;
+use zstd;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use fsutil;
+use super::Tag;
+
+
+const ARCHIVE_MAGIC: &[u8; 8] = b"SRCARCH\0";
+pub(crate) const ARCHIVE_VERSION: u8 = 0;
+const ARCHIVE_EXTENSION: &str = "archive";
+const CHECKSUM_SIZE: usize = 32;
+const HEADER_SIZE: usize = 8 + 1 + 8 + 8;
+const INDEX_ENTRY_SIZE: usize = SPHINX_REPLAY_TAG_SIZE + 8 + 4 + 4 + CHECKSUM_SIZE;
+const FOOTER_SIZE: usize = 8 + CHECKSUM_SIZE + 8;
+const TAGS_PER_BLOCK: usize = 4096;
+const COMPRESSION_LEVEL: i32 = 3;
+
+
+/// Returns the path of the archive for the given epoch.
+pub fn archive_path(archive_dir: &Path, epoch: u64) -> PathBuf {
+    let mut path = fsutil::epoch_dir(archive_dir, epoch).into_os_string();
+    path.push(".");
+    path.push(ARCHIVE_EXTENSION);
+    PathBuf::from(path)
+}
+
+fn checksum(data: &[u8]) -> Vec<u8> {
+    blake2b(CHECKSUM_SIZE, data)
+}
+
+struct BlockIndex {
+    first_tag: [u8; SPHINX_REPLAY_TAG_SIZE],
+    offset: u64,
+    length: u32,
+    tags: u32,
+    checksum: Vec<u8>,
+}
+
+impl BlockIndex {
+    fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![0u8; INDEX_ENTRY_SIZE];
+        out[..SPHINX_REPLAY_TAG_SIZE].copy_from_slice(&self.first_tag);
+        let mut i = SPHINX_REPLAY_TAG_SIZE;
+        LittleEndian::write_u64(&mut out[i..i+8], self.offset);
+        i += 8;
+        LittleEndian::write_u32(&mut out[i..i+4], self.length);
+        i += 4;
+        LittleEndian::write_u32(&mut out[i..i+4], self.tags);
+        i += 4;
+        out[i..].copy_from_slice(&self.checksum);
+        out
+    }
+
+    fn from_bytes(b: &[u8]) -> BlockIndex {
+        let mut first_tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        first_tag.copy_from_slice(&b[..SPHINX_REPLAY_TAG_SIZE]);
+        let i = SPHINX_REPLAY_TAG_SIZE;
+        BlockIndex{
+            first_tag: first_tag,
+            offset: LittleEndian::read_u64(&b[i..i+8]),
+            length: LittleEndian::read_u32(&b[i+8..i+12]),
+            tags: LittleEndian::read_u32(&b[i+12..i+16]),
+            checksum: b[i+16..i+16+CHECKSUM_SIZE].to_vec(),
+        }
+    }
+}
+
+/// Write an archive of the given tags, which must be sorted, to `path`.
+/// The first error yielded by `tags` aborts the archive.
+/// The archive is written to a temporary file first and renamed into
+/// place once it is complete and synced.
+pub fn write_archive<I>(path: &Path, epoch: u64, tags: I) -> Result<(), MixKeyError>
+    where I: IntoIterator<Item=Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>>
+{
+    let tmp_path = path.with_extension("tmp");
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path)?;
+    file.write_all(&[0u8; HEADER_SIZE])?;
+
+    let mut offset = HEADER_SIZE as u64;
+    let mut index = vec![];
+    let mut count = 0u64;
+    let mut block: Vec<u8> = Vec::with_capacity(TAGS_PER_BLOCK * SPHINX_REPLAY_TAG_SIZE);
+    let mut tags = tags.into_iter().peekable();
+    while tags.peek().is_some() {
+        block.clear();
+        let mut first_tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        let mut block_tags = 0;
+        while block_tags < TAGS_PER_BLOCK {
+            match tags.next() {
+                Some(tag) => {
+                    let tag = tag?;
+                    if block_tags == 0 {
+                        first_tag = tag;
+                    }
+                    block.extend_from_slice(&tag);
+                    block_tags += 1;
+                },
+                None => break,
+            }
+        }
+        let compressed = zstd::encode_all(&block[..], COMPRESSION_LEVEL)?;
+        file.write_all(&compressed)?;
+        index.push(BlockIndex{
+            first_tag: first_tag,
+            offset: offset,
+            length: compressed.len() as u32,
+            tags: block_tags as u32,
+            checksum: checksum(&compressed),
+        });
+        offset += compressed.len() as u64;
+        count += block_tags as u64;
+    }
+
+    let mut raw_index = vec![];
+    for entry in index.iter() {
+        raw_index.extend_from_slice(&entry.to_vec());
+    }
+    file.write_all(&raw_index)?;
+    let mut footer = vec![0u8; 8];
+    LittleEndian::write_u64(&mut footer, offset);
+    footer.extend_from_slice(&checksum(&raw_index));
+    footer.extend_from_slice(ARCHIVE_MAGIC);
+    file.write_all(&footer)?;
+
+    let mut header = ARCHIVE_MAGIC.to_vec();
+    header.push(ARCHIVE_VERSION);
+    let mut raw = [0u8; 16];
+    LittleEndian::write_u64(&mut raw[..8], epoch);
+    LittleEndian::write_u64(&mut raw[8..], count);
+    header.extend_from_slice(&raw);
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&header)?;
+
+    fsutil::sync_file(&file)?;
+    drop(file);
+    fsutil::atomic_rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Archive is a read only handle to an archived epoch.
+pub struct Archive {
+    file: File,
+    epoch: u64,
+    tag_count: u64,
+    index: Vec<BlockIndex>,
+}
+
+impl Archive {
+    /// Open an archive, verifying its header and index.
+    pub fn open_read_only(path: &Path) -> Result<Archive, MixKeyError> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < (HEADER_SIZE + FOOTER_SIZE) as u64 {
+            return Err(MixKeyError::InvalidArchive)
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        if &header[..8] != ARCHIVE_MAGIC || header[8] != ARCHIVE_VERSION {
+            return Err(MixKeyError::InvalidArchive)
+        }
+        let epoch = LittleEndian::read_u64(&header[9..17]);
+        let tag_count = LittleEndian::read_u64(&header[17..25]);
+
+        let mut footer = [0u8; FOOTER_SIZE];
+        file.seek(SeekFrom::Start(size - FOOTER_SIZE as u64))?;
+        file.read_exact(&mut footer)?;
+        if &footer[8 + CHECKSUM_SIZE..] != ARCHIVE_MAGIC {
+            return Err(MixKeyError::InvalidArchive)
+        }
+        let index_offset = LittleEndian::read_u64(&footer[..8]);
+        if index_offset < HEADER_SIZE as u64 || index_offset > size - FOOTER_SIZE as u64 {
+            return Err(MixKeyError::InvalidArchive)
+        }
+        let index_size = (size - FOOTER_SIZE as u64 - index_offset) as usize;
+        if index_size % INDEX_ENTRY_SIZE != 0 {
+            return Err(MixKeyError::InvalidArchive)
+        }
+        let mut raw_index = vec![0u8; index_size];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut raw_index)?;
+        if checksum(&raw_index) != &footer[8..8 + CHECKSUM_SIZE] {
+            return Err(MixKeyError::InvalidArchive)
+        }
+        let index = raw_index.chunks(INDEX_ENTRY_SIZE).map(BlockIndex::from_bytes).collect();
+
+        Ok(Archive{
+            file: file,
+            epoch: epoch,
+            tag_count: tag_count,
+            index: index,
+        })
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the number of archived tags.
+    pub fn len(&self) -> u64 {
+        self.tag_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tag_count == 0
+    }
+
+    /// Returns true if the tag was recorded during the archived epoch.
+    pub fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let block = match self.index.iter().rposition(|b| b.first_tag <= tag.0) {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        let tags = self.read_block(block)?;
+        Ok(tags.chunks(SPHINX_REPLAY_TAG_SIZE).collect::<Vec<_>>().binary_search(&&tag.0[..]).is_ok())
+    }
+
+    fn read_block(&mut self, block: usize) -> Result<Vec<u8>, MixKeyError> {
+        let entry = &self.index[block];
+        let mut compressed = vec![0u8; entry.length as usize];
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.read_exact(&mut compressed)?;
+        if checksum(&compressed) != entry.checksum {
+            return Err(MixKeyError::InvalidArchive)
+        }
+        let tags = zstd::decode_all(&compressed[..])?;
+        if tags.len() != entry.tags as usize * SPHINX_REPLAY_TAG_SIZE {
+            return Err(MixKeyError::InvalidArchive)
+        }
+        Ok(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use byteorder::BigEndian;
+    use super::*;
+
+
+    fn tag(i: u32) -> [u8; SPHINX_REPLAY_TAG_SIZE] {
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        BigEndian::write_u32(&mut raw[..4], i);
+        raw
+    }
+
+    #[test]
+    fn archive_test() {
+        let dir = TempDir::new().unwrap();
+        let path = archive_path(dir.path(), 9);
+        let count = TAGS_PER_BLOCK as u32 * 2 + 10;
+        write_archive(&path, 9, (0..count).map(|i| Ok(tag(i * 2)))).unwrap();
+        assert!(path.ends_with("mix_key.9.archive"));
+
+        let mut archive = Archive::open_read_only(&path).unwrap();
+        assert_eq!(archive.epoch(), 9);
+        assert_eq!(archive.len(), count as u64);
+        assert_eq!(archive.index.len(), 3);
+        for i in &[0, 1, 2, TAGS_PER_BLOCK as u32 * 2, count * 2 - 2, count * 2] {
+            assert_eq!(archive.contains(&Tag::new(tag(*i))).unwrap(), i % 2 == 0 && *i < count * 2);
+        }
+    }
+
+    #[test]
+    fn corrupt_archive_test() {
+        let dir = TempDir::new().unwrap();
+        let path = archive_path(dir.path(), 1);
+        write_archive(&path, 1, (0..100).map(|i| Ok(tag(i)))).unwrap();
+
+        let mut raw = vec![];
+        File::open(&path).unwrap().read_to_end(&mut raw).unwrap();
+        raw[HEADER_SIZE + 1] ^= 0xff;
+        File::create(&path).unwrap().write_all(&raw).unwrap();
+        let mut archive = Archive::open_read_only(&path).unwrap();
+        match archive.contains(&Tag::new(tag(5))) {
+            Err(MixKeyError::InvalidArchive) => {},
+            _ => panic!("read a corrupt block"),
+        }
+
+        let len = raw.len();
+        raw[len - FOOTER_SIZE - 1] ^= 0xff;
+        File::create(&path).unwrap().write_all(&raw).unwrap();
+        match Archive::open_read_only(&path) {
+            Err(MixKeyError::InvalidArchive) => {},
+            _ => panic!("opened an archive with a corrupt index"),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+// asynchronous.rs - Asynchronous mix key API.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Futures based wrappers around `MixKeys` and `MixKey` for tokio based
+//! mix servers. Every operation that may touch the disk is run on the
+//! tokio blocking thread pool. This module is only available with the
+//! `async` feature and must be used from within a tokio runtime.
+//!
+
+use std::future::Future;
+use std::panic;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use ecdh_wrapper::PublicKey;
+use epoch::Clock;
+
+use errors::MixKeyError;
+use super::{MixKeys, MixKey, Tag};
+
+
+/// Blocking is a future resolving to the result of a disk bound
+/// operation. The operation is started on the blocking thread pool
+/// when the future is first polled.
+pub struct Blocking<T> {
+    task: Option<Box<dyn FnOnce() -> Result<T, MixKeyError> + Send + 'static>>,
+    handle: Option<JoinHandle<Result<T, MixKeyError>>>,
+}
+
+impl<T> Blocking<T> where T: Send + 'static {
+    fn spawn<F>(f: F) -> Blocking<T> where F: FnOnce() -> Result<T, MixKeyError> + Send + 'static {
+        Blocking{
+            task: Some(Box::new(f)),
+            handle: None,
+        }
+    }
+}
+
+impl<T> Future for Blocking<T> where T: Send + 'static {
+    type Output = Result<T, MixKeyError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(task) = self.task.take() {
+            self.handle = Some(spawn_blocking(task));
+        }
+        match Pin::new(self.handle.as_mut().unwrap()).poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(x)) => Poll::Ready(x),
+            Poll::Ready(Err(e)) => panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+/// AsyncMixKeys wraps `MixKeys`, running key generation on the
+/// blocking thread pool.
+#[derive(Clone)]
+pub struct AsyncMixKeys {
+    inner: MixKeys,
+}
+
+impl AsyncMixKeys {
+    pub fn new(clock: Clock, num_mix_keys: u8, base_dir: String, line_rate: u64) -> Blocking<AsyncMixKeys> {
+        Blocking::spawn(move || {
+            let inner = MixKeys::new(clock, num_mix_keys, base_dir, line_rate)?;
+            Ok(AsyncMixKeys{
+                inner: inner,
+            })
+        })
+    }
+
+    pub fn generate(&self, base_epoch: u64) -> Blocking<bool> {
+        let mut inner = self.inner.clone();
+        Blocking::spawn(move || inner.generate(base_epoch))
+    }
+
+    pub fn flush(&self) -> Blocking<()> {
+        let inner = self.inner.clone();
+        Blocking::spawn(move || {
+            for (_epoch, mut key) in inner.snapshot_keys() {
+                key.flush()?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn public_key(&self, epoch: u64) -> Option<PublicKey> {
+        self.inner.public_key(epoch)
+    }
+
+    /// Returns the key packets of the given epoch are checked with,
+    /// failing as `MixKeys::key_for_packet` does for keys that expired,
+    /// were revoked or are not yet valid.
+    pub fn key(&self, epoch: u64) -> Result<AsyncMixKey, MixKeyError> {
+        self.inner.key_for_packet(epoch).map(|key| AsyncMixKey{
+            inner: key,
+        })
+    }
+
+    /// Check the tag of a packet made for the given epoch, as
+    /// `MixKeys::is_replay` does.
+    pub fn is_replay(&self, epoch: u64, tag: Tag) -> Blocking<bool> {
+        let inner = self.inner.clone();
+        Blocking::spawn(move || inner.is_replay(epoch, &tag))
+    }
+
+    /// Returns the wrapped synchronous `MixKeys`.
+    pub fn get_ref(&self) -> &MixKeys {
+        &self.inner
+    }
+}
+
+/// AsyncMixKey wraps `MixKey`, running replay checks and flushes on
+/// the blocking thread pool.
+#[derive(Clone)]
+pub struct AsyncMixKey {
+    inner: MixKey,
+}
+
+impl AsyncMixKey {
+    pub fn public_key(&self) -> PublicKey {
+        self.inner.public_key()
+    }
+
+    pub fn is_replay(&self, tag: Tag) -> Blocking<bool> {
+        let inner = self.inner.clone();
+        Blocking::spawn(move || inner.is_replay(&tag))
+    }
+
+    pub fn flush(&self) -> Blocking<()> {
+        let mut inner = self.inner.clone();
+        Blocking::spawn(move || inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+    extern crate rand;
+
+    use tokio::runtime::Builder;
+    use self::rand::Rng;
+    use self::rand::os::OsRng;
+    use self::tempfile::TempDir;
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+    use super::*;
+
+
+    #[test]
+    fn async_is_replay_test() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mix_keys = runtime.block_on(AsyncMixKeys::new(clock, 2, base_dir, 128974848)).unwrap();
+        let key = mix_keys.key(epoch).unwrap();
+        assert_eq!(Some(key.public_key()), mix_keys.public_key(epoch));
+
+        let mut rng = OsRng::new().unwrap();
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        rng.fill_bytes(&mut raw);
+        assert_eq!(runtime.block_on(key.is_replay(Tag::new(raw))).unwrap(), false);
+        assert_eq!(runtime.block_on(key.is_replay(Tag::new(raw))).unwrap(), true);
+        assert_eq!(runtime.block_on(mix_keys.is_replay(epoch, Tag::new(raw))).unwrap(), true);
+        rng.fill_bytes(&mut raw);
+        assert_eq!(runtime.block_on(mix_keys.is_replay(epoch, Tag::new(raw))).unwrap(), false);
+        assert!(mix_keys.key(epoch + 5).is_err());
+        runtime.block_on(mix_keys.flush()).unwrap();
+        assert_eq!(runtime.block_on(mix_keys.generate(epoch)).unwrap(), false);
+    }
+}
@@ -0,0 +1,314 @@
+// backup.rs - Backups of live keys and their tags.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Copying sled directories under a live writer does not produce a
+//! cache sled can open. `MixKeys::backup` instead writes every live key
+//! through the same interfaces the replay checks use, while packets
+//! keep being processed:
+//!
+//!    <dest_dir>/mix_key.<epoch>/metadata    the key and its metadata
+//!    <dest_dir>/mix_key.<epoch>/tags        the tags, in the dump format
+//!    <dest_dir>/manifest
+//!
+//! Each key is flushed before its tags are written. Tags are never
+//! updated, so the backup holds every tag stored before it reached the
+//! key, and may hold some stored while it was written.
+//!
+//! The manifest is written last, so a backup without one is incomplete:
+//!
+//!    magic (8) || version (1) || count (4, LE) || entries || checksum (32)
+//!    entry: epoch (8, LE) || tags (8, LE) || metadata checksum (32)
+//!
+//! The checksums are BLAKE2b; the dump carries its own per chunk. The
+//! metadata holds the private key as the key provider stores it, so the
+//! backup directory is only readable by its owner.
+//!
+//! `MixKeys::restore` verifies the whole backup before it writes
+//! anything: the manifest and metadata checksums, that the key provider
+//! opens every key and that it matches the stored public key, and every
+//! chunk and the tag count of every dump. A backup restored with
+//! another key provider than it was made with is refused.
+//!
+
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use blake2::blake2b;
+
+use dump::DumpReader;
+use errors::MixKeyError;
+use fsutil;
+use keyprovider::KeyProvider;
+use metafile::MetadataFile;
+use super::{MIX_CACHE_KEY, PUBLIC_KEY_KEY};
+
+
+const MANIFEST_FILE_NAME: &str = "manifest";
+const TAGS_FILE_NAME: &str = "tags";
+const MANIFEST_MAGIC: &[u8; 8] = b"SRCBACKP";
+const BACKUP_VERSION: u8 = 0;
+const HEADER_SIZE: usize = 8 + 1 + 4;
+const CHECKSUM_SIZE: usize = 32;
+const ENTRY_SIZE: usize = 8 + 8 + CHECKSUM_SIZE;
+
+
+/// BackupEntry describes the backup of one key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupEntry {
+    pub epoch: u64,
+    /// Tags written to the dump.
+    pub tags: u64,
+    pub(crate) metadata_checksum: Vec<u8>,
+}
+
+impl BackupEntry {
+    /// Returns the directory holding the key's backup.
+    pub fn dir(&self, backup_dir: &Path) -> PathBuf {
+        fsutil::epoch_dir(backup_dir, self.epoch)
+    }
+
+    /// Returns the path of the key's tag dump.
+    pub fn tags_path(&self, backup_dir: &Path) -> PathBuf {
+        self.dir(backup_dir).join(TAGS_FILE_NAME)
+    }
+}
+
+/// BackupManifest lists the keys a backup holds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupManifest {
+    pub entries: Vec<BackupEntry>,
+}
+
+impl BackupManifest {
+    /// Read and verify the manifest of the backup in `backup_dir`.
+    pub fn read(backup_dir: &Path) -> Result<BackupManifest, MixKeyError> {
+        let mut raw = vec![];
+        File::open(backup_dir.join(MANIFEST_FILE_NAME))?.read_to_end(&mut raw)?;
+        if raw.len() < HEADER_SIZE + CHECKSUM_SIZE || &raw[..8] != MANIFEST_MAGIC || raw[8] != BACKUP_VERSION {
+            return Err(MixKeyError::InvalidBackup)
+        }
+        let (body, checksum) = raw.split_at(raw.len() - CHECKSUM_SIZE);
+        if checksum != &blake2b(CHECKSUM_SIZE, body)[..] {
+            return Err(MixKeyError::InvalidBackup)
+        }
+        let count = LittleEndian::read_u32(&body[9..HEADER_SIZE]) as usize;
+        if body.len() != HEADER_SIZE + count * ENTRY_SIZE {
+            return Err(MixKeyError::InvalidBackup)
+        }
+        let entries = body[HEADER_SIZE..].chunks(ENTRY_SIZE).map(|raw| BackupEntry{
+            epoch: LittleEndian::read_u64(&raw[..8]),
+            tags: LittleEndian::read_u64(&raw[8..16]),
+            metadata_checksum: raw[16..].to_vec(),
+        }).collect();
+        Ok(BackupManifest{
+            entries: entries,
+        })
+    }
+
+    /// Write the manifest, completing the backup in `backup_dir`.
+    pub(crate) fn write(&self, backup_dir: &Path) -> Result<(), MixKeyError> {
+        let mut raw = Vec::with_capacity(HEADER_SIZE + self.entries.len() * ENTRY_SIZE + CHECKSUM_SIZE);
+        raw.extend_from_slice(MANIFEST_MAGIC);
+        raw.push(BACKUP_VERSION);
+        let mut field = [0u8; 8];
+        LittleEndian::write_u32(&mut field, self.entries.len() as u32);
+        raw.extend_from_slice(&field[..4]);
+        for entry in &self.entries {
+            LittleEndian::write_u64(&mut field, entry.epoch);
+            raw.extend_from_slice(&field);
+            LittleEndian::write_u64(&mut field, entry.tags);
+            raw.extend_from_slice(&field);
+            raw.extend_from_slice(&entry.metadata_checksum);
+        }
+        let checksum = blake2b(CHECKSUM_SIZE, &raw);
+        raw.extend_from_slice(&checksum);
+        let mut file = File::create(backup_dir.join(MANIFEST_FILE_NAME))?;
+        file.write_all(&raw)?;
+        fsutil::sync_file(&file)?;
+        fsutil::sync_dir(backup_dir)?;
+        Ok(())
+    }
+}
+
+/// Create the backup directory, which must not exist yet, readable
+/// only by its owner.
+pub(crate) fn create_backup_dir(backup_dir: &Path) -> Result<(), MixKeyError> {
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(backup_dir)?;
+    Ok(())
+}
+
+/// Back up one key: its metadata, and the tags `export` writes.
+pub(crate) fn write_entry<F>(backup_dir: &Path, epoch: u64, metadata: &HashMap<String, Vec<u8>>, export: F) -> Result<BackupEntry, MixKeyError>
+    where F: FnOnce(&mut BufWriter<File>) -> Result<u64, MixKeyError>
+{
+    let dir = fsutil::epoch_dir(backup_dir, epoch);
+    fs::create_dir(&dir)?;
+    let metadata_file = MetadataFile::new(&dir);
+    metadata_file.store(metadata)?;
+    let metadata_checksum = blake2b(CHECKSUM_SIZE, &fs::read(metadata_file.path())?);
+    let mut writer = BufWriter::new(File::create(dir.join(TAGS_FILE_NAME))?);
+    let tags = export(&mut writer)?;
+    writer.flush()?;
+    fsutil::sync_file(writer.get_ref())?;
+    fsutil::sync_dir(&dir)?;
+    Ok(BackupEntry{
+        epoch: epoch,
+        tags: tags,
+        metadata_checksum: metadata_checksum,
+    })
+}
+
+/// Verify the backup of one key against its manifest entry, returning
+/// the key's metadata.
+pub(crate) fn verify_entry(backup_dir: &Path, entry: &BackupEntry, provider: &dyn KeyProvider) -> Result<HashMap<String, Vec<u8>>, MixKeyError> {
+    let metadata_file = MetadataFile::new(&entry.dir(backup_dir));
+    if blake2b(CHECKSUM_SIZE, &fs::read(metadata_file.path())?) != entry.metadata_checksum {
+        return Err(MixKeyError::InvalidBackup)
+    }
+    let metadata = metadata_file.load()?;
+    let key = match metadata.get(MIX_CACHE_KEY) {
+        Some(key_id) => provider.open(entry.epoch, key_id)?,
+        None => return Err(MixKeyError::InvalidBackup),
+    };
+    if metadata.get(PUBLIC_KEY_KEY).map_or(false, |public_key| *public_key != key.public_key().to_vec()) {
+        return Err(MixKeyError::InvalidBackup)
+    }
+    let mut dump = read_tags(backup_dir, entry)?;
+    let mut tags = 0;
+    while let Some(chunk) = dump.next_chunk()? {
+        tags += chunk.verify()?.len() as u64;
+    }
+    if tags != entry.tags {
+        return Err(MixKeyError::InvalidBackup)
+    }
+    Ok(metadata)
+}
+
+/// Open the dump of a key's tags.
+pub(crate) fn read_tags(backup_dir: &Path, entry: &BackupEntry) -> Result<DumpReader<BufReader<File>>, MixKeyError> {
+    let dump = DumpReader::new(BufReader::new(File::open(entry.tags_path(backup_dir))?))?;
+    if dump.epoch() != entry.epoch {
+        return Err(MixKeyError::InvalidBackup)
+    }
+    Ok(dump)
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom};
+
+    use self::tempfile::TempDir;
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+    use epoch::Clock;
+    use builder::MixKeysBuilder;
+    use keyprovider::LocalKeyProvider;
+    use store::CacheBackend;
+    use testing::TestClock;
+    use super::super::{MixKey, MixKeys, Tag};
+    use super::*;
+
+
+    #[test]
+    fn backup_test() {
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeysBuilder::new(clock).backend(CacheBackend::Memory).num_mix_keys(2).build().unwrap();
+        let key = mix_keys.key(epoch).unwrap();
+        for i in 0..100u8 {
+            assert_eq!(key.is_replay(&Tag([i; SPHINX_REPLAY_TAG_SIZE])).unwrap(), false);
+        }
+
+        let dir = TempDir::new().unwrap();
+        let backup_dir = dir.path().join("backup");
+        let manifest = mix_keys.backup(&backup_dir).unwrap();
+        assert_eq!(BackupManifest::read(&backup_dir).unwrap(), manifest);
+        assert!(manifest.entries.len() >= 2);
+        let entry = manifest.entries.iter().find(|entry| entry.epoch == epoch).unwrap();
+        assert_eq!(entry.tags, 100);
+        let metadata = MetadataFile::new(&entry.dir(&backup_dir)).load().unwrap();
+        assert!(metadata.contains_key("private_key"));
+        let mut dump = DumpReader::new(File::open(entry.tags_path(&backup_dir)).unwrap()).unwrap();
+        assert_eq!(dump.epoch(), epoch);
+        let mut tags = 0;
+        while let Some(chunk) = dump.next_chunk().unwrap() {
+            tags += chunk.verify().unwrap().len();
+        }
+        assert_eq!(tags, 100);
+
+        // A backup never overwrites another.
+        assert!(mix_keys.backup(&backup_dir).is_err());
+    }
+
+    #[test]
+    fn restore_test() {
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().join("base");
+        let mix_keys = MixKeysBuilder::new(clock.clone()).base_dir(base_dir.to_str().unwrap().to_string()).build().unwrap();
+        let tags: Vec<Tag> = (0..100u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        let key = mix_keys.key(epoch).unwrap();
+        for tag in &tags {
+            assert_eq!(key.is_replay(tag).unwrap(), false);
+        }
+        let public_key = key.public_key();
+        let backup_dir = dir.path().join("backup");
+        let manifest = mix_keys.backup(&backup_dir).unwrap();
+        drop(key);
+        drop(mix_keys);
+
+        let restored_dir = dir.path().join("restored");
+        let restored = MixKeys::restore(&LocalKeyProvider, &clock, &backup_dir, &restored_dir, false).unwrap();
+        assert_eq!(restored, manifest.entries.iter().map(|entry| entry.epoch).collect::<Vec<_>>());
+        let mix_key = MixKey::new(1024 * 1024, epoch, 60, &restored_dir.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(mix_key.public_key(), public_key);
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+        drop(mix_key);
+
+        match MixKeys::restore(&LocalKeyProvider, &clock, &backup_dir, &restored_dir, false) {
+            Err(MixKeyError::RestoreConflict(_)) => {},
+            x => panic!("unexpected restore result: {:?}", x),
+        }
+        MixKeys::restore(&LocalKeyProvider, &TestClock::at(5, 60), &backup_dir, &restored_dir, true).unwrap();
+        assert!(fsutil::quarantine_dir(&restored_dir, epoch, 300).exists());
+
+        // A damaged dump is refused before anything is written.
+        let entry = manifest.entries.iter().find(|entry| entry.epoch == epoch).unwrap();
+        let mut file = OpenOptions::new().write(true).open(entry.tags_path(&backup_dir)).unwrap();
+        file.seek(SeekFrom::Start(40)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+        let other_dir = dir.path().join("other");
+        assert!(MixKeys::restore(&LocalKeyProvider, &clock, &backup_dir, &other_dir, false).is_err());
+        assert!(!fsutil::epoch_dir(&other_dir, epoch).exists());
+    }
+}
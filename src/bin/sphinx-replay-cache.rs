@@ -0,0 +1,103 @@
+// sphinx-replay-cache.rs - Replay cache inspection tool.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Reports the contents of a `mix_key.<epoch>` cache directory:
+//!
+//!    sphinx-replay-cache <mix_key directory> [--tag <hex tag>]
+//!
+
+extern crate sphinxcrypto;
+extern crate sphinx_replay_cache;
+
+use std::env;
+use std::path::Path;
+use std::process;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+use sphinx_replay_cache::Tag;
+use sphinx_replay_cache::inspect::CacheInspector;
+
+
+const USAGE: &str = "usage: sphinx-replay-cache <mix_key directory> [--tag <hex tag>]";
+
+
+fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn parse_tag(s: &str) -> Option<Tag> {
+    if s.len() != SPHINX_REPLAY_TAG_SIZE * 2 || !s.is_ascii() {
+        return None
+    }
+    let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+    for (i, byte) in raw.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(Tag::new(raw))
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (path, tag) = match args.len() {
+        1 => (&args[0], None),
+        3 if args[1] == "--tag" => match parse_tag(&args[2]) {
+            Some(tag) => (&args[0], Some((&args[2], tag))),
+            None => fail(&format!("tag must be {} hex encoded bytes", SPHINX_REPLAY_TAG_SIZE)),
+        },
+        _ => fail(USAGE),
+    };
+
+    let inspector = match CacheInspector::open_read_only(Path::new(path)) {
+        Ok(x) => x,
+        Err(e) => fail(&e.to_string()),
+    };
+    let info = match inspector.info() {
+        Ok(x) => x,
+        Err(e) => fail(&e.to_string()),
+    };
+    println!("path: {}", path);
+    match info.epoch {
+        Some(epoch) => println!("epoch: {}", epoch),
+        None => println!("epoch: unknown"),
+    }
+    match info.public_key {
+        Some(public_key) => println!("public key: {}", to_hex(&public_key.to_vec())),
+        None => println!("public key: unknown"),
+    }
+    match info.format_version {
+        Some(format) => println!("format: {}", format),
+        None => println!("format: 0 (unrecorded)"),
+    }
+    match info.writer_version {
+        Some(writer) => println!("written by: {}", writer),
+        None => println!("written by: unknown"),
+    }
+    println!("tags: {}", info.tag_count);
+    println!("disk bytes: {}", info.disk_bytes);
+    if let Some((hex, tag)) = tag {
+        match inspector.contains(&tag) {
+            Ok(true) => println!("tag {}: present", hex),
+            Ok(false) => println!("tag {}: absent", hex),
+            Err(e) => fail(&e.to_string()),
+        }
+    }
+}
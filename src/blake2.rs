@@ -0,0 +1,49 @@
+// blake2.rs - BLAKE2b digests and MACs.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Variable length BLAKE2b, used for the checksums of dumps, backups
+//! and archives, and keyed for the MACs of secrets, identity bundles
+//! and replication messages.
+//!
+
+use blake2b_simd::Params;
+
+
+/// The `size` byte BLAKE2b digest of `data`.
+pub fn blake2b(size: usize, data: &[u8]) -> Vec<u8> {
+    Params::new().hash_length(size).hash(data).as_bytes().to_vec()
+}
+
+/// The `size` byte BLAKE2b MAC of `data` under `key`, which may be at
+/// most 64 bytes long.
+pub fn blake2b_keyed(size: usize, key: &[u8], data: &[u8]) -> Vec<u8> {
+    Params::new().hash_length(size).key(key).hash(data).as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn blake2b_test() {
+        assert_eq!(blake2b(16, b"abc").len(), 16);
+        assert_ne!(blake2b(32, b"abc"), blake2b_keyed(32, b"key", b"abc"));
+        assert_ne!(blake2b_keyed(32, b"key", b"abc"), blake2b_keyed(32, b"other key", b"abc"));
+    }
+}
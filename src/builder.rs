@@ -0,0 +1,748 @@
+// builder.rs - MixKeys configuration builder.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! `MixKeysBuilder` configures a `MixKeys`, including the cache tuning
+//! that is otherwise fixed by the constants in `constants`:
+//!
+//! ```rust,ignore
+//! let mix_keys = MixKeysBuilder::new(clock)
+//!     .base_dir("/var/lib/mix/replay".to_string())
+//!     .line_rate(10 * 1024 * 1024)
+//!     .false_positive_rate(0.001)
+//!     .flush_interval(Duration::from_secs(30))
+//!     .build()?;
+//! ```
+//!
+//! The `MixKeys` constructors taking positional arguments are shorthand
+//! for the builder with the default tuning.
+//!
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use sphinxcrypto::constants::{PACKET_SIZE, SPHINX_REPLAY_TAG_SIZE};
+
+use constants::{MIX_KEY_DEFAULT_LINE_RATE, MIX_KEY_DEFAULT_NUM_KEYS, MIX_KEY_FALSE_POSITIVE_RATE,
+                MIX_KEY_FLUSH_FREQUENCY, MIX_KEY_GRACE_PERIOD, MIX_KEY_MIN_TAG_SIZE, MIX_KEY_SNAPSHOT_AFTER_OPS};
+use countdown::EarlyTagPolicy;
+use durability::DurabilityPolicy;
+use errors::MixKeyError;
+use flushcontrol::FlushBounds;
+use keyprovider::{KeyProvider, LocalKeyProvider};
+use preflight;
+use store::{CacheBackend, ReplayStoreFactory};
+use storm::ReplayStormConfig;
+use timesource::{ClockSource, MonotonicClock, SystemMonotonicClock};
+use writeback::WriteBatch;
+use super::MixKeys;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+
+/// OverflowBehavior decides what happens to fresh tags once an epoch
+/// holds its maximum number of tags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum OverflowBehavior {
+    /// Refuse the tag with `TagLimitReached`, so the packet is dropped.
+    Reject,
+    /// Warn, then keep further tags in memory only. They are lost if the
+    /// process restarts.
+    MemoryOnly,
+    /// Warn, then keep further tags in a separate sled tree in the
+    /// epoch's cache directory. Only sled backed keys support this.
+    OverflowTree,
+    /// Warn, then keep further tags in a second filter in memory, sized
+    /// for another `max_tags` at the key's false positive rate, so that
+    /// the flood does not wear down the first filter's guarantee. They
+    /// are lost if the process restarts, and a fresh tag mistaken for
+    /// one of them is dropped.
+    SecondaryFilter,
+    /// Warn, then store further tags as if there were no limit.
+    LogAndContinue,
+}
+
+/// FutureCachePolicy decides what happens at startup to cache
+/// directories in `base_dir` for epochs beyond those the `MixKeys`
+/// keeps, such as those left behind by a clock that was set back or
+/// restored from an old backup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum FutureCachePolicy {
+    /// Move them to `base_dir/quarantine` for an operator to inspect.
+    Quarantine,
+    /// Leave them in place, to be loaded once their epochs are reached.
+    Adopt,
+    /// Wipe them.
+    Delete,
+}
+
+impl Default for FutureCachePolicy {
+    fn default() -> Self {
+        FutureCachePolicy::Quarantine
+    }
+}
+
+/// ClockRollbackPolicy decides whether a `MixKeys` opened while its
+/// clock reports an earlier epoch than it saw before starts at all. See
+/// the `highwater` module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum ClockRollbackPolicy {
+    /// Fail to open with `ClockRollback`.
+    Refuse,
+    /// Warn, and open with only the keys whose caches still exist.
+    Gate,
+}
+
+impl Default for ClockRollbackPolicy {
+    fn default() -> Self {
+        ClockRollbackPolicy::Gate
+    }
+}
+
+/// CacheConfig tunes the bloom filter and sled cache of every key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CacheConfig {
+    pub false_positive_rate: f32,
+    /// Tags each epoch's filter is sized for, or None to size it for a
+    /// whole epoch at the line rate.
+    pub expected_tags: Option<u32>,
+    /// Bytes of sled page cache, or None to hold half of an epoch's
+    /// tags at the line rate.
+    pub cache_capacity: Option<usize>,
+    pub snapshot_after_ops: usize,
+    /// Milliseconds between sled's own background flushes.
+    pub flush_every_ms: u64,
+    /// When each key flushes the tags it stores. See the `durability`
+    /// module.
+    pub durability: DurabilityPolicy,
+    /// Only takes effect if sled is built with its `zstd` feature.
+    pub use_compression: bool,
+    /// Tags stored per epoch before `overflow` applies, or None for no
+    /// limit.
+    pub max_tags: Option<u64>,
+    pub overflow: OverflowBehavior,
+    /// Use counting filters, which take four times the memory and do
+    /// not grow past `expected_tags`, so that `MixKey::remove_tag` can
+    /// remove tags.
+    pub counting_filter: bool,
+    /// Warm up each key as it is opened, with `MixKey::warm_up`.
+    pub warm_up: bool,
+    /// Commit the fresh tags of sled backed keys in batches from a
+    /// writer thread, or None to insert each from the replay check.
+    /// See the `writeback` module.
+    pub write_batch: Option<WriteBatch>,
+    /// Freeze the caches of sled backed keys into sorted, immutable
+    /// files as their epochs end. See the `frozen` module.
+    pub freeze: bool,
+    /// Bytes each epoch's sled cache directory may take, or None for no
+    /// quota. The directory is measured every `MIX_KEY_QUOTA_CHECK_TAGS`
+    /// fresh tags and every flush; once it is over, fresh tags fail with
+    /// `DiskQuotaExceeded`.
+    pub disk_quota: Option<u64>,
+    /// Keep fresh tags arriving past the disk quota in memory only,
+    /// after a warning, rather than refusing them. They are lost if the
+    /// process restarts.
+    pub quota_fallback: bool,
+    /// Bytes of each tag a sled backed key stores, for Sphinx variants
+    /// whose tags are shorter than `SPHINX_REPLAY_TAG_SIZE`. Such tags
+    /// are checked zero padded, as made by `Tag::padded`, and only
+    /// their first `tag_size` bytes are written to the sled tree. Other
+    /// stores, frozen caches and dumps keep full size tags.
+    pub tag_size: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig{
+            false_positive_rate: MIX_KEY_FALSE_POSITIVE_RATE,
+            expected_tags: None,
+            cache_capacity: None,
+            snapshot_after_ops: MIX_KEY_SNAPSHOT_AFTER_OPS,
+            flush_every_ms: MIX_KEY_FLUSH_FREQUENCY,
+            durability: DurabilityPolicy::default(),
+            use_compression: false,
+            max_tags: None,
+            overflow: OverflowBehavior::Reject,
+            counting_filter: false,
+            warm_up: false,
+            write_batch: None,
+            freeze: false,
+            disk_quota: None,
+            quota_fallback: false,
+            tag_size: SPHINX_REPLAY_TAG_SIZE,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Returns the number of tags an epoch's filter is sized for.
+    pub fn expected_tags_per_epoch(&self, line_rate: u64, epoch_duration: u64) -> u32 {
+        self.expected_tags.unwrap_or((line_rate as f64 / PACKET_SIZE as f64) as u32 * epoch_duration as u32)
+    }
+
+    /// Returns the bytes of sled page cache each key is given.
+    pub fn cache_capacity_per_epoch(&self, line_rate: u64, epoch_duration: u64) -> usize {
+        self.cache_capacity.unwrap_or((((epoch_duration * line_rate) / PACKET_SIZE as u64) as usize * SPHINX_REPLAY_TAG_SIZE) / 2)
+    }
+
+    /// Returns the configuration with the filter and, if `sled`, the
+    /// sled cache shrunk in proportion so that together they take no
+    /// more than `budget` bytes.
+    pub fn within_budget(&self, budget: u64, sled: bool, line_rate: u64, epoch_duration: u64) -> CacheConfig {
+        let mut config = *self;
+        let expected = self.expected_tags_per_epoch(line_rate, epoch_duration);
+        let filter = preflight::filter_bytes(expected as u64, self.false_positive_rate);
+        let cache = if sled { self.cache_capacity_per_epoch(line_rate, epoch_duration) as u64 } else { 0 };
+        if filter.saturating_add(cache) <= budget {
+            return config
+        }
+        let scale = budget as f64 / (filter + cache) as f64;
+        config.expected_tags = Some(((expected as f64 * scale) as u32).max(1));
+        if sled {
+            config.cache_capacity = Some((cache as f64 * scale) as usize);
+        }
+        config
+    }
+}
+
+/// MixKeysConfig is the configuration of a `MixKeys` as plain data, for
+/// mix servers that read it from their own configuration file. With the
+/// `serde` feature it can be deserialized, and fields left out take
+/// their defaults.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct MixKeysConfig {
+    pub base_dir: String,
+    pub num_mix_keys: u8,
+    /// Epochs beyond the current one to generate keys for, so that they
+    /// can be published before their epochs start.
+    pub publish_ahead: u8,
+    /// Bytes per second the filters and caches are sized for.
+    pub line_rate: u64,
+    /// `sled` or `memory`.
+    pub backend: CacheBackend,
+    pub false_positive_rate: f32,
+    pub expected_tags: Option<u32>,
+    pub cache_capacity: Option<usize>,
+    pub flush_interval_ms: u64,
+    /// `every_write`, `manual` or `{"interval_ms": ...}`. An interval
+    /// here is overridden by `flush_interval_ms`.
+    pub durability: DurabilityPolicy,
+    /// Seconds the previous epoch's key is kept after each boundary.
+    pub grace_period: u64,
+    pub max_tags: Option<u64>,
+    pub overflow: OverflowBehavior,
+    pub counting_filter: bool,
+    pub warm_up: bool,
+    pub freeze: bool,
+    pub future_caches: FutureCachePolicy,
+    pub clock_rollback: ClockRollbackPolicy,
+    /// Bytes the filters and sled caches of every live key share, or
+    /// None to size each key for the line rate.
+    pub memory_budget: Option<u64>,
+    /// Bytes each epoch's cache directory may take on disk.
+    pub disk_quota: Option<u64>,
+    /// Bytes the cache directories of every live key share on disk.
+    pub base_dir_quota: Option<u64>,
+    pub quota_fallback: bool,
+    pub tag_size: usize,
+}
+
+impl Default for MixKeysConfig {
+    fn default() -> Self {
+        let cache = CacheConfig::default();
+        MixKeysConfig{
+            base_dir: String::new(),
+            num_mix_keys: MIX_KEY_DEFAULT_NUM_KEYS,
+            publish_ahead: 0,
+            line_rate: MIX_KEY_DEFAULT_LINE_RATE,
+            backend: CacheBackend::Sled,
+            false_positive_rate: cache.false_positive_rate,
+            expected_tags: cache.expected_tags,
+            cache_capacity: cache.cache_capacity,
+            flush_interval_ms: cache.flush_every_ms,
+            durability: cache.durability,
+            grace_period: MIX_KEY_GRACE_PERIOD as u64,
+            max_tags: cache.max_tags,
+            overflow: cache.overflow,
+            counting_filter: cache.counting_filter,
+            warm_up: cache.warm_up,
+            freeze: cache.freeze,
+            future_caches: FutureCachePolicy::default(),
+            clock_rollback: ClockRollbackPolicy::default(),
+            memory_budget: None,
+            disk_quota: cache.disk_quota,
+            base_dir_quota: None,
+            quota_fallback: cache.quota_fallback,
+            tag_size: cache.tag_size,
+        }
+    }
+}
+
+impl MixKeysConfig {
+    /// Returns a builder set up with this configuration, for the
+    /// settings it does not cover, such as the key provider.
+    pub fn builder<C: ClockSource + 'static>(&self, clock: C) -> MixKeysBuilder {
+        let mut builder = MixKeysBuilder::new(clock)
+            .base_dir(self.base_dir.clone())
+            .num_mix_keys(self.num_mix_keys)
+            .publish_ahead(self.publish_ahead)
+            .line_rate(self.line_rate)
+            .backend(self.backend)
+            .false_positive_rate(self.false_positive_rate)
+            .durability_policy(self.durability)
+            .flush_interval(Duration::from_millis(self.flush_interval_ms))
+            .grace_period(self.grace_period)
+            .counting_filter(self.counting_filter)
+            .warm_up(self.warm_up)
+            .freeze(self.freeze)
+            .quota_fallback(self.quota_fallback)
+            .tag_size(self.tag_size)
+            .future_cache_policy(self.future_caches)
+            .clock_rollback_policy(self.clock_rollback);
+        builder.cache.expected_tags = self.expected_tags;
+        builder.cache.cache_capacity = self.cache_capacity;
+        builder.cache.max_tags = self.max_tags;
+        builder.cache.overflow = self.overflow;
+        builder.memory_budget = self.memory_budget;
+        builder.cache.disk_quota = self.disk_quota;
+        builder.base_dir_quota = self.base_dir_quota;
+        builder
+    }
+}
+
+/// MixKeysBuilder builds a `MixKeys`.
+pub struct MixKeysBuilder {
+    pub(crate) clock: Arc<dyn ClockSource>,
+    pub(crate) timer: Arc<dyn MonotonicClock>,
+    pub(crate) num_mix_keys: u8,
+    pub(crate) publish_ahead: u8,
+    pub(crate) base_dir: String,
+    pub(crate) line_rate: u64,
+    pub(crate) provider: Arc<dyn KeyProvider>,
+    pub(crate) backend: CacheBackend,
+    pub(crate) stores: Option<Arc<dyn ReplayStoreFactory>>,
+    pub(crate) cache: CacheConfig,
+    pub(crate) grace_period: u64,
+    pub(crate) memory_budget: Option<u64>,
+    pub(crate) base_dir_quota: Option<u64>,
+    pub(crate) flush_bounds: FlushBounds,
+    pub(crate) early_tags: EarlyTagPolicy,
+    pub(crate) replay_storm: Option<ReplayStormConfig>,
+    pub(crate) future_caches: FutureCachePolicy,
+    pub(crate) clock_rollback: ClockRollbackPolicy,
+    #[cfg(feature = "metrics")]
+    pub(crate) node_id: String,
+}
+
+impl MixKeysBuilder {
+    /// Returns a builder for sled backed keys with the default
+    /// configuration. A base directory must be set unless another
+    /// backend is chosen.
+    pub fn new<C: ClockSource + 'static>(clock: C) -> MixKeysBuilder {
+        MixKeysBuilder{
+            clock: Arc::new(clock),
+            timer: Arc::new(SystemMonotonicClock::new()),
+            num_mix_keys: MIX_KEY_DEFAULT_NUM_KEYS,
+            publish_ahead: 0,
+            base_dir: String::new(),
+            line_rate: MIX_KEY_DEFAULT_LINE_RATE,
+            provider: Arc::new(LocalKeyProvider),
+            backend: CacheBackend::Sled,
+            stores: None,
+            cache: CacheConfig::default(),
+            grace_period: MIX_KEY_GRACE_PERIOD as u64,
+            memory_budget: None,
+            base_dir_quota: None,
+            flush_bounds: FlushBounds::default(),
+            early_tags: EarlyTagPolicy::default(),
+            replay_storm: None,
+            future_caches: FutureCachePolicy::default(),
+            clock_rollback: ClockRollbackPolicy::default(),
+            #[cfg(feature = "metrics")]
+            node_id: String::new(),
+        }
+    }
+
+    /// Keep keys for this many epochs, starting with the current one.
+    pub fn num_mix_keys(mut self, num_mix_keys: u8) -> Self {
+        self.num_mix_keys = num_mix_keys;
+        self
+    }
+
+    /// Generate keys for at least this many epochs beyond the current
+    /// one, so that the PKI can publish them before their epochs start.
+    /// Keys are kept for `num_mix_keys` epochs if that covers more.
+    pub fn publish_ahead(mut self, epochs: u8) -> Self {
+        self.publish_ahead = epochs;
+        self
+    }
+
+    pub fn base_dir(mut self, base_dir: String) -> Self {
+        self.base_dir = base_dir;
+        self
+    }
+
+    /// Size the filters and caches for this many bytes per second.
+    pub fn line_rate(mut self, line_rate: u64) -> Self {
+        self.line_rate = line_rate;
+        self
+    }
+
+    pub fn key_provider(mut self, provider: Arc<dyn KeyProvider>) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    /// Use the given monotonic clock for idle tracking and flush
+    /// scheduling, as `MixKeys::set_monotonic_clock` does.
+    pub fn monotonic_clock(mut self, timer: Arc<dyn MonotonicClock>) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Keep the tags in the given backend. `Custom` requires
+    /// `store_factory` instead.
+    pub fn backend(mut self, backend: CacheBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Keep the tags in the stores the factory opens.
+    pub fn store_factory(mut self, stores: Arc<dyn ReplayStoreFactory>) -> Self {
+        self.backend = CacheBackend::Custom;
+        self.stores = Some(stores);
+        self
+    }
+
+    pub fn cache_config(mut self, cache: CacheConfig) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn false_positive_rate(mut self, false_positive_rate: f32) -> Self {
+        self.cache.false_positive_rate = false_positive_rate;
+        self
+    }
+
+    /// Size each epoch's filter for this many tags instead of a whole
+    /// epoch at the line rate.
+    pub fn expected_tags(mut self, expected_tags: u32) -> Self {
+        self.cache.expected_tags = Some(expected_tags);
+        self
+    }
+
+    pub fn cache_capacity(mut self, cache_capacity: usize) -> Self {
+        self.cache.cache_capacity = Some(cache_capacity);
+        self
+    }
+
+    pub fn snapshot_after_ops(mut self, snapshot_after_ops: usize) -> Self {
+        self.cache.snapshot_after_ops = snapshot_after_ops;
+        self
+    }
+
+    pub fn use_compression(mut self, use_compression: bool) -> Self {
+        self.cache.use_compression = use_compression;
+        self
+    }
+
+    /// Store at most `max_tags` tags per epoch, handling any more as
+    /// `overflow` says. Derive the limit from capacity planning, well
+    /// above the tags an epoch at the line rate should ever see.
+    pub fn max_tags(mut self, max_tags: u64, overflow: OverflowBehavior) -> Self {
+        self.cache.max_tags = Some(max_tags);
+        self.cache.overflow = overflow;
+        self
+    }
+
+    /// Decide whether `MixKeys::key_for_packet` hands out keys whose
+    /// epoch has not started yet.
+    pub fn early_tag_policy(mut self, early_tags: EarlyTagPolicy) -> Self {
+        self.early_tags = early_tags;
+        self
+    }
+
+    /// Raise a `ReplayStorm` whenever the replays per second over the
+    /// sliding window exceed the threshold. See the `storm` module.
+    pub fn replay_storm_alarm(mut self, window: Duration, threshold: f64) -> Self {
+        self.replay_storm = Some(ReplayStormConfig{
+            window: window,
+            threshold: threshold,
+        });
+        self
+    }
+
+    /// Decide what to do at startup with caches in `base_dir` for epochs
+    /// beyond those the keys are kept for.
+    pub fn future_cache_policy(mut self, future_caches: FutureCachePolicy) -> Self {
+        self.future_caches = future_caches;
+        self
+    }
+
+    /// Decide whether to open at all while the clock reports an earlier
+    /// epoch than was seen before.
+    pub fn clock_rollback_policy(mut self, clock_rollback: ClockRollbackPolicy) -> Self {
+        self.clock_rollback = clock_rollback;
+        self
+    }
+
+    /// Use counting filters, so that tags can be removed with
+    /// `MixKey::remove_tag`.
+    pub fn counting_filter(mut self, counting_filter: bool) -> Self {
+        self.cache.counting_filter = counting_filter;
+        self
+    }
+
+    /// Warm up every key as it is opened or generated, so that the
+    /// builder, and each rollover, only return once the keys are ready
+    /// for their first packets. See `MixKey::warm_up`.
+    pub fn warm_up(mut self, warm_up: bool) -> Self {
+        self.cache.warm_up = warm_up;
+        self
+    }
+
+    /// Freeze each key's sled cache into a compact, sorted file once its
+    /// epoch ends, for the grace period's lookups.
+    pub fn freeze(mut self, freeze: bool) -> Self {
+        self.cache.freeze = freeze;
+        self
+    }
+
+    /// Commit the fresh tags of every key to sled in batches from a
+    /// writer thread rather than one at a time from the replay check.
+    pub fn write_batch(mut self, batch: WriteBatch) -> Self {
+        self.cache.write_batch = Some(batch);
+        self
+    }
+
+    /// Flush the caches this often, both from `MixKeys::flush_due` and
+    /// from sled's background flusher. A stalled flush may still back
+    /// off to the maximum flush interval.
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.cache.flush_every_ms = interval.as_millis() as u64;
+        self.flush_bounds.min_interval = interval;
+        self.flush_bounds.max_interval = self.flush_bounds.max_interval.max(interval);
+        self
+    }
+
+    /// Flush every key as the policy says. An `IntervalMs` policy sets
+    /// the flush interval as `flush_interval` does, and stalled flushes
+    /// may still back it off.
+    pub fn durability_policy(mut self, durability: DurabilityPolicy) -> Self {
+        if let DurabilityPolicy::IntervalMs(ms) = durability {
+            self = self.flush_interval(Duration::from_millis(ms));
+        }
+        self.cache.durability = durability;
+        self
+    }
+
+    pub fn flush_bounds(mut self, bounds: FlushBounds) -> Self {
+        self.cache.flush_every_ms = bounds.min_interval.as_millis() as u64;
+        self.flush_bounds = bounds;
+        self
+    }
+
+    /// Keep the previous epoch's key for this many seconds after each
+    /// epoch boundary.
+    pub fn grace_period(mut self, grace_period: u64) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Divide this many bytes between the filters and sled caches of
+    /// the live keys, the `num_mix_keys` current and upcoming ones and
+    /// the previous epoch's during its grace period. Keys whose line
+    /// rate sizing does not fit their share have their filter and cache
+    /// shrunk in proportion. A filter that then fills up grows, or loses
+    /// precision, as it would at a line rate above the configured one.
+    pub fn memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Refuse fresh tags for an epoch once its cache directory takes
+    /// more than this many bytes on disk, rather than letting sled fill
+    /// the partition.
+    pub fn disk_quota(mut self, bytes: u64) -> Self {
+        self.cache.disk_quota = Some(bytes);
+        self
+    }
+
+    /// Divide this many bytes of disk between the cache directories of
+    /// the live keys, as `memory_budget` divides memory. Each key's
+    /// share caps its disk quota.
+    pub fn base_dir_quota(mut self, bytes: u64) -> Self {
+        self.base_dir_quota = Some(bytes);
+        self
+    }
+
+    /// Keep the fresh tags of an epoch over its disk quota in memory
+    /// only, rather than refusing them.
+    pub fn quota_fallback(mut self, fallback: bool) -> Self {
+        self.cache.quota_fallback = fallback;
+        self
+    }
+
+    /// Store only the first `bytes` bytes of every tag, for Sphinx
+    /// variants with tags shorter than `SPHINX_REPLAY_TAG_SIZE`. Tags
+    /// must then be passed zero padded; see `Tag::padded`. A cache keeps
+    /// the tag size it was created with. Only sled trees shrink; other
+    /// stores, frozen caches and dumps keep full size tags.
+    pub fn tag_size(mut self, bytes: usize) -> Self {
+        self.cache.tag_size = bytes;
+        self
+    }
+
+    /// Label every exported metric with this node id.
+    #[cfg(feature = "metrics")]
+    pub fn node_id(mut self, node_id: &str) -> Self {
+        self.node_id = node_id.to_string();
+        self
+    }
+
+    fn validate(&self) -> Result<(), MixKeyError> {
+        let invalid = |reason: &str| Err(MixKeyError::InvalidConfig(reason.to_string()));
+        if self.num_mix_keys == 0 {
+            return invalid("at least one mix key is required")
+        }
+        if self.backend == CacheBackend::Sled && self.base_dir.is_empty() {
+            return invalid("sled backed keys need a base directory")
+        }
+        if self.backend == CacheBackend::Custom && self.stores.is_none() {
+            return invalid("custom backed keys need a store factory")
+        }
+        if !(self.cache.false_positive_rate > 0.0 && self.cache.false_positive_rate < 1.0) {
+            return invalid("the false positive rate must be between 0 and 1")
+        }
+        if self.cache.expected_tags == Some(0) {
+            return invalid("the filters must expect at least one tag")
+        }
+        if self.cache.max_tags == Some(0) {
+            return invalid("the tag limit must allow at least one tag")
+        }
+        if self.cache.max_tags.is_some() && self.cache.overflow == OverflowBehavior::OverflowTree && self.backend != CacheBackend::Sled {
+            return invalid("only sled backed keys can overflow to a separate tree")
+        }
+        if self.cache.write_batch.is_some() && self.backend != CacheBackend::Sled {
+            return invalid("only sled backed keys can batch their writes")
+        }
+        if self.memory_budget == Some(0) {
+            return invalid("the memory budget must be above zero")
+        }
+        if self.cache.disk_quota == Some(0) || self.base_dir_quota == Some(0) {
+            return invalid("a disk quota must be above zero")
+        }
+        if (self.cache.disk_quota.is_some() || self.base_dir_quota.is_some()) && self.backend != CacheBackend::Sled {
+            return invalid("only sled backed keys take disk quotas")
+        }
+        if self.cache.tag_size < MIX_KEY_MIN_TAG_SIZE || self.cache.tag_size > SPHINX_REPLAY_TAG_SIZE {
+            return invalid("the tag size must be between 16 and 32 bytes")
+        }
+        if self.cache.freeze && self.backend != CacheBackend::Sled {
+            return invalid("only sled backed keys can be frozen")
+        }
+        if self.cache.write_batch.map_or(false, |batch| batch.max_tags == 0) {
+            return invalid("a write batch must hold at least one tag")
+        }
+        if self.flush_bounds.min_interval.as_millis() == 0 || self.flush_bounds.min_interval > self.flush_bounds.max_interval {
+            return invalid("the flush interval must be positive and within its bounds")
+        }
+        if self.replay_storm.map_or(false, |storm| storm.window.as_millis() == 0 || !(storm.threshold >= 0.0)) {
+            return invalid("the replay storm window must be positive and its threshold not negative")
+        }
+        Ok(())
+    }
+
+    pub fn build(self) -> Result<MixKeys, MixKeyError> {
+        self.validate()?;
+        MixKeys::open(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use epoch::Clock;
+    use constants::MIX_KEY_MAX_FLUSH_INTERVAL;
+    use super::*;
+
+
+    #[test]
+    fn mix_keys_builder_test() {
+        let base_dir = TempDir::new().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        match MixKeysBuilder::new(clock.clone()).build() {
+            Err(MixKeyError::InvalidConfig(_)) => {},
+            _ => panic!("built sled backed keys without a base directory"),
+        }
+        assert!(MixKeysBuilder::new(clock.clone()).backend(CacheBackend::Memory).false_positive_rate(1.5).build().is_err());
+
+        let mix_keys = MixKeysBuilder::new(clock)
+            .base_dir(base_dir.path().to_str().unwrap().to_string())
+            .num_mix_keys(2)
+            .line_rate(1024 * 1024)
+            .false_positive_rate(0.001)
+            .expected_tags(1000)
+            .cache_capacity(1024 * 1024)
+            .snapshot_after_ops(1000)
+            .flush_interval(Duration::from_secs(30))
+            .grace_period(60)
+            .build()
+            .unwrap();
+        assert_eq!(mix_keys.grace_period(), 60);
+        assert_eq!(mix_keys.flush_interval(), Duration::from_secs(30));
+        assert_eq!(mix_keys.flush_bounds().max_interval, Duration::from_millis(MIX_KEY_MAX_FLUSH_INTERVAL));
+        let key = mix_keys.key(epoch + 1).unwrap();
+        assert_eq!(key.false_positive_rate, 0.001);
+        assert_eq!(key.expected_num_items, 1000);
+        assert!(mix_keys.key(epoch + 2).is_none());
+
+        match MixKeysBuilder::new(Clock::new_katzenpost()).backend(CacheBackend::Memory).max_tags(10, OverflowBehavior::OverflowTree).build() {
+            Err(MixKeyError::InvalidConfig(_)) => {},
+            _ => panic!("built memory backed keys overflowing to a sled tree"),
+        }
+        match MixKeysBuilder::new(Clock::new_katzenpost()).backend(CacheBackend::Memory).base_dir_quota(1 << 30).build() {
+            Err(MixKeyError::InvalidConfig(_)) => {},
+            _ => panic!("built memory backed keys with a disk quota"),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn mix_keys_config_test() {
+        extern crate serde_json;
+
+        let config: MixKeysConfig = serde_json::from_str(r#"{"backend": "memory", "num_mix_keys": 2, "max_tags": 100, "overflow": "memory_only"}"#).unwrap();
+        assert_eq!(config.line_rate, MIX_KEY_DEFAULT_LINE_RATE);
+        assert_eq!(config.overflow, OverflowBehavior::MemoryOnly);
+        let round_trip: MixKeysConfig = serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(round_trip, config);
+
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = config.builder(clock).build().unwrap();
+        assert!(mix_keys.key(epoch + 1).is_some());
+        assert!(serde_json::from_str::<MixKeysConfig>(r#"{"backend": "tape"}"#).is_err());
+    }
+}
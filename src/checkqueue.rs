@@ -0,0 +1,233 @@
+// checkqueue.rs - Prioritized replay check queue.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A mix that checks replays in the order packets arrive lets a flood
+//! of bulk traffic delay the checks of its own cover and loop traffic
+//! and of control messages. A `ReplayCheckQueue` instead keeps a high
+//! and a low priority queue in front of a pool of worker threads.
+//!
+//! Workers take high priority checks first, but after
+//! `MIX_KEY_HIGH_PRIORITY_BURST` of them in a row they take a waiting
+//! low priority check, so that neither queue starves the other. Each
+//! queue holds at most `capacity` checks; once the low priority queue
+//! is full a flood is refused with `QueueFull` rather than delaying
+//! everything behind it.
+//!
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use constants::MIX_KEY_HIGH_PRIORITY_BURST;
+use errors::MixKeyError;
+use super::{MixKeys, Tag};
+
+
+/// Priority selects the queue a replay check waits in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Cover, loop and control traffic.
+    High,
+    /// Bulk traffic.
+    Low,
+}
+
+struct Check {
+    epoch: u64,
+    tag: Tag,
+    reply: Sender<Result<bool, MixKeyError>>,
+}
+
+struct Queues {
+    high: VecDeque<Check>,
+    low: VecDeque<Check>,
+    high_streak: usize,
+    halted: bool,
+}
+
+impl Queues {
+    fn queue(&mut self, priority: Priority) -> &mut VecDeque<Check> {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Low => &mut self.low,
+        }
+    }
+
+    /// Returns the next check to run, high priority first unless a low
+    /// priority check has waited through a whole burst.
+    fn next(&mut self) -> Option<Check> {
+        let take_low = !self.low.is_empty() && (self.high.is_empty() || self.high_streak >= MIX_KEY_HIGH_PRIORITY_BURST);
+        if take_low {
+            self.high_streak = 0;
+            return self.low.pop_front()
+        }
+        let check = self.high.pop_front();
+        if check.is_some() {
+            self.high_streak += 1;
+        }
+        check
+    }
+}
+
+struct Shared {
+    queues: Mutex<Queues>,
+    ready: Condvar,
+}
+
+/// ReplayCheckQueue runs the replay checks submitted to it on a pool
+/// of worker threads, high priority checks first.
+pub struct ReplayCheckQueue {
+    shared: Arc<Shared>,
+    capacity: usize,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ReplayCheckQueue {
+    /// Start `workers` threads checking tags against the given keys,
+    /// with room for `capacity` waiting checks of each priority.
+    pub fn new(mix_keys: MixKeys, workers: usize, capacity: usize) -> ReplayCheckQueue {
+        let shared = Arc::new(Shared{
+            queues: Mutex::new(Queues{
+                high: VecDeque::new(),
+                low: VecDeque::new(),
+                high_streak: 0,
+                halted: false,
+            }),
+            ready: Condvar::new(),
+        });
+        let workers = (0..workers).map(|_| {
+            let shared = shared.clone();
+            let mix_keys = mix_keys.clone();
+            thread::spawn(move || work(&shared, &mix_keys))
+        }).collect();
+        ReplayCheckQueue{
+            shared: shared,
+            capacity: capacity,
+            workers: workers,
+        }
+    }
+
+    /// Queue a check of the tag for the given epoch, returning a
+    /// receiver of its result, which is like that of `MixKey::is_replay`
+    /// on the key `MixKeys::key_for_packet` returns. Fails with
+    /// `QueueFull` if the priority's queue is full.
+    pub fn submit(&self, priority: Priority, epoch: u64, tag: Tag) -> Result<Receiver<Result<bool, MixKeyError>>, MixKeyError> {
+        let (reply, result) = channel();
+        {
+            let mut queues = self.shared.queues.lock().unwrap();
+            let queue = queues.queue(priority);
+            if queue.len() >= self.capacity {
+                return Err(MixKeyError::QueueFull)
+            }
+            queue.push_back(Check{
+                epoch: epoch,
+                tag: tag,
+                reply: reply,
+            });
+        }
+        self.shared.ready.notify_one();
+        Ok(result)
+    }
+
+    /// Returns the number of checks waiting in the priority's queue.
+    pub fn len(&self, priority: Priority) -> usize {
+        self.shared.queues.lock().unwrap().queue(priority).len()
+    }
+
+    /// Stop the workers once the queued checks are done, and wait for
+    /// them to exit.
+    pub fn halt(&mut self) {
+        self.shared.queues.lock().unwrap().halted = true;
+        self.shared.ready.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for ReplayCheckQueue {
+    fn drop(&mut self) {
+        self.halt();
+    }
+}
+
+fn work(shared: &Shared, mix_keys: &MixKeys) {
+    loop {
+        let check = {
+            let mut queues = shared.queues.lock().unwrap();
+            loop {
+                if let Some(check) = queues.next() {
+                    break check
+                }
+                if queues.halted {
+                    return
+                }
+                queues = shared.ready.wait(queues).unwrap();
+            }
+        };
+        let result = mix_keys.key_for_packet(check.epoch).and_then(|key| key.is_replay(&check.tag));
+        let _ = check.reply.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use super::*;
+
+
+    #[test]
+    fn replay_check_queue_test() {
+        let clock = ::epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+
+        let idle = ReplayCheckQueue::new(mix_keys.clone(), 0, 2);
+        for i in 0..2 {
+            idle.submit(Priority::Low, epoch, Tag([i; SPHINX_REPLAY_TAG_SIZE])).unwrap();
+        }
+        match idle.submit(Priority::Low, epoch, Tag([2; SPHINX_REPLAY_TAG_SIZE])) {
+            Err(MixKeyError::QueueFull) => {},
+            _ => panic!("queued past the low priority capacity"),
+        }
+        for i in 0..2 {
+            idle.submit(Priority::High, epoch, Tag([10 + i; SPHINX_REPLAY_TAG_SIZE])).unwrap();
+        }
+        assert_eq!((idle.len(Priority::High), idle.len(Priority::Low)), (2, 2));
+
+        {
+            let mut queues = idle.shared.queues.lock().unwrap();
+            for _ in 0..MIX_KEY_HIGH_PRIORITY_BURST {
+                let (reply, _result) = channel();
+                queues.high.push_back(Check{ epoch: epoch, tag: Tag([20; SPHINX_REPLAY_TAG_SIZE]), reply: reply });
+            }
+            let order: Vec<u8> = (0..4 + MIX_KEY_HIGH_PRIORITY_BURST).map(|_| queues.next().unwrap().tag.0[0]).collect();
+            assert_eq!(order[MIX_KEY_HIGH_PRIORITY_BURST], 0);
+            assert!(order[..MIX_KEY_HIGH_PRIORITY_BURST].iter().all(|x| *x >= 10));
+            assert_eq!(order.last(), Some(&1));
+        }
+
+        let queue = ReplayCheckQueue::new(mix_keys, 2, 16);
+        let tag = Tag([7; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(queue.submit(Priority::High, epoch, tag.clone()).unwrap().recv().unwrap().unwrap(), false);
+        assert_eq!(queue.submit(Priority::Low, epoch, tag).unwrap().recv().unwrap().unwrap(), true);
+        assert!(queue.submit(Priority::Low, epoch + 100, Tag([8; SPHINX_REPLAY_TAG_SIZE])).unwrap().recv().unwrap().is_err());
+    }
+}
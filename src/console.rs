@@ -0,0 +1,240 @@
+// console.rs - Diagnostic console.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Serves a line based command interface onto a running `MixKeys` over
+//! a Unix domain socket, so that an operator can look into a production
+//! mix with `socat` or `nc -U` without restarting it or attaching a
+//! debugger. This module is only available on unix with the `console`
+//! feature. Commands are not authenticated, and anyone who can connect
+//! can flush and prune the keys, so the socket is made accessible to
+//! its owner alone as soon as it is bound.
+//!
+//! Each command is a line. Its output is zero or more lines followed by
+//! a line of `ok`, or a line of `error: ` and the error. The commands
+//! are:
+//!
+//!    help                 list the commands
+//!    epochs               list the epochs with keys, marking the active one
+//!    stats                print `MixKeys::stats`
+//!    check <epoch> <tag>  look up a hex tag without storing it
+//!    flush                flush every key
+//!    prune                remove the keys that are no longer live
+//!
+
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use errors::MixKeyError;
+use super::{MixKeys, Tag};
+
+
+const HELP: &str = "\
+help                 list the commands
+epochs               list the epochs with keys, marking the active one
+stats                print the statistics of every key
+check <epoch> <tag>  look up a hex tag without storing it
+flush                flush every key
+prune                remove the keys that are no longer live";
+
+
+/// DiagnosticConsole serves the console of one `MixKeys`.
+pub struct DiagnosticConsole {
+    listener: UnixListener,
+    path: PathBuf,
+    mix_keys: MixKeys,
+}
+
+impl DiagnosticConsole {
+    /// Listen on the socket at `path`, which only its owner may connect
+    /// to. A socket left behind by a console that is no longer running
+    /// is replaced.
+    pub fn bind<P: AsRef<Path>>(path: P, mix_keys: MixKeys) -> Result<DiagnosticConsole, MixKeyError> {
+        let path = path.as_ref();
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+                return Err(MixKeyError::IoError(ErrorKind::AddrInUse.into()))
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(DiagnosticConsole{
+            listener: listener,
+            path: path.to_path_buf(),
+            mix_keys: mix_keys,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accept connections until accepting fails, serving each one on a
+    /// thread of its own.
+    pub fn serve(&self) -> Result<(), MixKeyError> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let mix_keys = self.mix_keys.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(stream, mix_keys) {
+                    warn!("diagnostic console connection failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiagnosticConsole {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve_connection(stream: UnixStream, mut mix_keys: MixKeys) -> Result<(), MixKeyError> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        match run(&mut mix_keys, &line) {
+            Ok(output) => {
+                for line in output {
+                    writeln!(writer, "{}", line)?;
+                }
+                writeln!(writer, "ok")?;
+            },
+            Err(e) => writeln!(writer, "error: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+/// Run one command, returning its output lines.
+fn run(mix_keys: &mut MixKeys, line: &str) -> Result<Vec<String>, MixKeyError> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let usage = || MixKeyError::InvalidConfig(format!("unknown command {:?}, try help", line.trim()));
+    match words.as_slice() {
+        ["help"] => Ok(HELP.lines().map(str::to_string).collect()),
+        ["epochs"] => {
+            let active = mix_keys.active_epoch();
+            let mut epochs: Vec<u64> = mix_keys.snapshot_keys().iter().map(|&(epoch, _)| epoch).collect();
+            epochs.sort();
+            Ok(epochs.into_iter().map(|epoch| match Some(epoch) == active {
+                true => format!("{} active", epoch),
+                false => epoch.to_string(),
+            }).collect())
+        },
+        ["stats"] => {
+            let stats = mix_keys.stats()?;
+            let mut output: Vec<String> = stats.epochs.iter().map(|key| {
+                format!("epoch {} tags {} fill {} false_positive_rate {} disk_bytes {} replays {}",
+                        key.epoch, key.tags, estimate(key.fill_ratio), estimate(key.false_positive_rate), key.disk_bytes, key.replays)
+            }).collect();
+            output.push(format!("total tags {} disk_bytes {} replays {}", stats.tags, stats.disk_bytes, stats.replays));
+            Ok(output)
+        },
+        ["check", epoch, tag] => {
+            let epoch = epoch.parse::<u64>().map_err(|_| usage())?;
+            let tag = Tag::from_hex(tag)?;
+            let key = mix_keys.key(epoch).ok_or(MixKeyError::UnknownEpoch(epoch))?;
+            Ok(vec![match key.contains(&tag)? {
+                true => "present".to_string(),
+                false => "absent".to_string(),
+            }])
+        },
+        ["flush"] => {
+            let mut flushed = vec![];
+            for (epoch, mut key) in mix_keys.snapshot_keys() {
+                key.flush()?;
+                flushed.push(epoch);
+            }
+            flushed.sort();
+            Ok(flushed.into_iter().map(|epoch| format!("flushed {}", epoch)).collect())
+        },
+        ["prune"] => Ok(mix_keys.prune().into_iter().map(|epoch| format!("pruned {}", epoch)).collect()),
+        _ => Err(usage()),
+    }
+}
+
+fn estimate(value: Option<f64>) -> String {
+    value.map_or("shed".to_string(), |x| format!("{:.6}", x))
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use std::sync::Arc;
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use self::tempfile::TempDir;
+    use epoch::Clock;
+    use store::CacheBackend;
+    use super::*;
+
+
+    /// Send a command and return its output up to and including the
+    /// final status line.
+    fn command(reader: &mut BufReader<UnixStream>, command: &str) -> Vec<String> {
+        writeln!(reader.get_mut(), "{}", command).unwrap();
+        let mut output = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            let done = line == "ok" || line.starts_with("error: ");
+            output.push(line);
+            if done {
+                return output
+            }
+        }
+    }
+
+    #[test]
+    fn diagnostic_console_test() {
+        let dir = TempDir::new().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::builder(clock).num_mix_keys(2).backend(CacheBackend::Memory).build().unwrap();
+        let tag = Tag([3u8; SPHINX_REPLAY_TAG_SIZE]);
+        mix_keys.key(epoch).unwrap().is_replay(&tag).unwrap();
+        let console = Arc::new(DiagnosticConsole::bind(dir.path().join("console.sock"), mix_keys).unwrap());
+        assert_eq!(fs::metadata(console.path()).unwrap().permissions().mode() & 0o777, 0o600);
+        let serving = console.clone();
+        thread::spawn(move || serving.serve());
+
+        let mut reader = BufReader::new(UnixStream::connect(console.path()).unwrap());
+        assert_eq!(command(&mut reader, "epochs"), vec![epoch.to_string(), (epoch + 1).to_string(), "ok".to_string()]);
+        assert_eq!(command(&mut reader, &format!("check {} {}", epoch, tag)), vec!["present", "ok"]);
+        assert_eq!(command(&mut reader, &format!("check {} {}", epoch + 1, tag)), vec!["absent", "ok"]);
+        let stats = command(&mut reader, "stats");
+        assert!(stats[0].starts_with(&format!("epoch {} tags 1 ", epoch)));
+        assert_eq!(stats.last().unwrap(), "ok");
+        assert_eq!(command(&mut reader, "flush"), vec![format!("flushed {}", epoch), format!("flushed {}", epoch + 1), "ok".to_string()]);
+        assert_eq!(command(&mut reader, "prune"), vec!["ok"]);
+        assert!(command(&mut reader, "reboot")[0].starts_with("error: "));
+        assert!(command(&mut reader, "check x y")[0].starts_with("error: "));
+    }
+}
@@ -18,5 +18,93 @@
 /// Flush mix key writeback cache every 10 seconds.
 pub const MIX_KEY_FLUSH_FREQUENCY: u64 = 10000;
 
+/// Back off to flushing at most every 80 seconds when flushes stall.
+pub const MIX_KEY_MAX_FLUSH_INTERVAL: u64 = 80000;
+
+/// Health checks fail once `MixKeys::flush_due` has not run for this
+/// many maximum flush intervals.
+pub const MIX_KEY_HEALTH_FLUSH_INTERVALS: u32 = 2;
+
+/// `MixKeys::flush_due` forecasts capacity at most once a minute.
+pub const MIX_KEY_CAPACITY_CHECK_INTERVAL: u64 = 60;
+
+/// Generate the upcoming mix keys 5 minutes before each epoch boundary.
+pub const MIX_KEY_GENERATE_AHEAD: u64 = 5 * 60;
+
 /// Allow a mix expiration grace period of 2 minutes.
 pub const MIX_KEY_GRACE_PERIOD: u16 = 2 * 60;
+
+/// Shed the resources of mix keys idle for 15 minutes.
+pub const MIX_KEY_IDLE_PERIOD: u64 = 15 * 60;
+
+/// Sync per-worker filter replicas every 100 milliseconds.
+pub const MIX_KEY_REPLICA_SYNC_FREQUENCY: u64 = 100;
+
+/// Keep the 65536 most recently inserted tags for replicas to sync from.
+pub const MIX_KEY_REPLICA_DELTA_CAPACITY: usize = 1 << 16;
+
+/// Size each epoch's bloom filter for a 1% false positive rate.
+pub const MIX_KEY_FALSE_POSITIVE_RATE: f32 = 0.01;
+
+/// Keep keys for 3 epochs, the current one and the next two.
+pub const MIX_KEY_DEFAULT_NUM_KEYS: u8 = 3;
+
+/// Assume a line rate of 123 MiB/s unless configured otherwise.
+pub const MIX_KEY_DEFAULT_LINE_RATE: u64 = 123 * 1024 * 1024;
+
+/// Snapshot each sled cache after 100000 operations.
+pub const MIX_KEY_SNAPSHOT_AFTER_OPS: usize = 100_000;
+
+/// Answer repeated replays of the 1024 most recently replayed tags per
+/// mix key without touching the filter or cache.
+pub const MIX_KEY_REPLAY_CACHE_CAPACITY: usize = 1024;
+
+/// Split each mix key's filter and cache handles into 16 shards by tag
+/// prefix, so that up to 16 threads can check tags at once.
+pub const MIX_KEY_SHARDS: usize = 16;
+
+/// Import tag dumps with 4 threads.
+pub const MIX_KEY_IMPORT_THREADS: usize = 4;
+
+/// Flush and report the progress of an import every 65536 tags.
+pub const MIX_KEY_IMPORT_CHECKPOINT: u64 = 1 << 16;
+
+/// Take a waiting low priority replay check after at most 8 high
+/// priority checks in a row.
+pub const MIX_KEY_HIGH_PRIORITY_BURST: usize = 8;
+
+/// Keep up to 4194304 of an epoch's tags in the hot tier of a
+/// tiered store.
+pub const MIX_KEY_TIER_HOT_TAGS: u64 = 1 << 22;
+
+/// Move tags to the cold tier of a tiered store 65536 at a time.
+pub const MIX_KEY_TIER_BATCH: u64 = 1 << 16;
+
+/// Accept packets for a key up to 30 seconds before its epoch starts,
+/// to tolerate clock skew between mixes.
+pub const MIX_KEY_CLOCK_SKEW: u64 = 30;
+
+/// Commit a key's write buffer to sled once 4096 tags are waiting.
+pub const MIX_KEY_WRITE_BATCH_TAGS: usize = 4096;
+
+/// Commit a key's write buffer to sled at most 5 milliseconds after the
+/// first waiting tag arrived.
+pub const MIX_KEY_WRITE_BATCH_DELAY: u64 = 5;
+
+/// Grow an epoch's tag log by 65536 tags, 2 MiB, at a time.
+pub const MIX_KEY_TAG_LOG_CHUNK: u64 = 1 << 16;
+
+/// Measure a key's cache directory against its disk quota every 1024
+/// fresh tags, besides every flush.
+pub const MIX_KEY_QUOTA_CHECK_TAGS: u64 = 1 << 10;
+
+/// Store tags of at least 16 bytes, so that a stored tag can never be
+/// mistaken for a cache's metadata.
+pub const MIX_KEY_MIN_TAG_SIZE: usize = 16;
+
+/// Send at most 4096 replicated tags, 128 KiB, in one frame.
+pub const MIX_KEY_REPLICATION_BATCH_TAGS: usize = 1 << 12;
+
+/// Push the tags stored since the last push to a replication peer every
+/// 10 milliseconds.
+pub const MIX_KEY_REPLICATION_BATCH_DELAY: u64 = 10;
@@ -0,0 +1,156 @@
+// countdown.rs - Key lifecycle countdowns.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Every key passes through the same lifecycle: it is generated ahead
+//! of its epoch, becomes active when its epoch starts, expires when its
+//! epoch ends and is destroyed once the grace period after that is
+//! over. `MixKeys::countdowns` reports how far away each of those
+//! moments is, so that dashboards can draw key timelines without
+//! repeating the epoch arithmetic.
+//!
+//! A packet made for a key that is not active yet comes from a mix
+//! whose clock runs ahead of ours, or from an attacker probing keys
+//! before their epoch. `EarlyTagPolicy` decides how early such packets
+//! may be processed; `MixKeys::key_for_packet` applies it.
+//!
+//! `MixKeys::key_info` summarizes each live key as an `EpochKeyInfo`,
+//! which with the `serde` feature serializes with its public key in
+//! base64, for mix servers publishing their keys over a JSON API.
+//!
+
+use ecdh_wrapper::PublicKey;
+use epoch::Time;
+
+use constants::MIX_KEY_CLOCK_SKEW;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+
+/// KeyCountdown is the number of seconds until each stage of a key's
+/// lifecycle. Stages already reached count as zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyCountdown {
+    pub epoch: u64,
+    pub until_activation: u64,
+    pub until_expiry: u64,
+    pub until_destruction: u64,
+}
+
+impl KeyCountdown {
+    /// Returns the countdown of the given epoch's key at time `now`,
+    /// for epochs of `period` seconds followed by a grace period of
+    /// `grace_period` seconds.
+    pub fn new(epoch: u64, now: &Time, period: u64, grace_period: u64) -> KeyCountdown {
+        let start = (epoch as i64 - now.epoch as i64) * period as i64 - now.elapsed as i64;
+        let until = |offset: i64| (start + offset).max(0) as u64;
+        KeyCountdown{
+            epoch: epoch,
+            until_activation: until(0),
+            until_expiry: until(period as i64),
+            until_destruction: until(period as i64 + grace_period as i64),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.until_activation == 0 && self.until_expiry > 0
+    }
+}
+
+/// EpochKeyInfo is the public metadata of an epoch's key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EpochKeyInfo {
+    pub epoch: u64,
+    #[cfg_attr(feature = "serde", serde(with = "public_key_base64"))]
+    pub public_key: PublicKey,
+    /// The Unix time at which the epoch ends.
+    pub expiry: u64,
+}
+
+#[cfg(feature = "serde")]
+mod public_key_base64 {
+    use ecdh_wrapper::PublicKey;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(public_key: &PublicKey, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&public_key.to_base64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PublicKey, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        PublicKey::from_base64(encoded).map_err(|_| de::Error::custom("invalid base64 public key"))
+    }
+}
+
+/// EarlyTagPolicy decides whether packets for a key whose epoch has not
+/// started yet are processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EarlyTagPolicy {
+    /// Reject them with `EpochNotYetValid`.
+    Reject,
+    /// Accept them if the epoch starts within this many seconds.
+    AcceptWithin(u64),
+}
+
+impl Default for EarlyTagPolicy {
+    fn default() -> Self {
+        EarlyTagPolicy::AcceptWithin(MIX_KEY_CLOCK_SKEW)
+    }
+}
+
+impl EarlyTagPolicy {
+    /// Returns true if packets for the key may be processed now.
+    pub fn admits(&self, countdown: &KeyCountdown) -> bool {
+        match *self {
+            EarlyTagPolicy::Reject => countdown.until_activation == 0,
+            EarlyTagPolicy::AcceptWithin(skew) => countdown.until_activation <= skew,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn key_countdown_test() {
+        let now = Time{
+            epoch: 10,
+            elapsed: 100,
+            till: 1100,
+        };
+        let previous = KeyCountdown::new(9, &now, 1200, 120);
+        assert_eq!((previous.until_activation, previous.until_expiry, previous.until_destruction), (0, 0, 20));
+        assert!(!previous.is_active());
+
+        let current = KeyCountdown::new(10, &now, 1200, 120);
+        assert_eq!((current.until_activation, current.until_expiry, current.until_destruction), (0, 1100, 1220));
+        assert!(current.is_active());
+
+        assert!(EarlyTagPolicy::Reject.admits(&current));
+        let upcoming = KeyCountdown::new(11, &now, 1200, 120);
+        assert!(!EarlyTagPolicy::Reject.admits(&upcoming));
+        assert!(EarlyTagPolicy::AcceptWithin(1100).admits(&upcoming));
+        assert!(!EarlyTagPolicy::AcceptWithin(1099).admits(&upcoming));
+
+        let next = KeyCountdown::new(12, &now, 1200, 120);
+        assert_eq!((next.until_activation, next.until_expiry, next.until_destruction), (2300, 3500, 3620));
+        assert!(!next.is_active());
+    }
+}
@@ -0,0 +1,160 @@
+// decisioncache.rs - Cache of recently replayed tags.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! An attacker replaying the same packet millions of times would
+//! otherwise pay for a filter lookup and a cache lookup on every copy.
+//! A `ReplayDecisionCache` is a small least recently used set of tags
+//! already found to be replays, checked before the filter, which answers
+//! repeated duplicates from a single hash map lookup.
+//!
+//! Only replays are cached: a tag is never answered as fresh from here,
+//! so the cache can not cause a replay to be accepted. Every cached tag
+//! counts its hits, which a flood detector can read with `top`.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+
+use super::Tag;
+
+
+/// ReplayHit is a cached replayed tag and the number of times it was
+/// replayed since it was cached.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ReplayHit {
+    pub tag: Tag,
+    pub hits: u64,
+}
+
+/// ReplayDecisionCache holds the most recently replayed tags.
+pub struct ReplayDecisionCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<Tag, (u64, u64)>,
+    recency: BTreeMap<u64, Tag>,
+    hits: u64,
+}
+
+impl ReplayDecisionCache {
+    pub fn new(capacity: usize) -> ReplayDecisionCache {
+        ReplayDecisionCache{
+            capacity: capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+        }
+    }
+
+    /// Returns true, counting a hit, if the tag is a cached replay.
+    pub fn lookup(&mut self, tag: &Tag) -> bool {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(tag) {
+            Some(entry) => {
+                self.recency.remove(&entry.1);
+                self.recency.insert(tick, tag.clone());
+                entry.0 += 1;
+                entry.1 = tick;
+                self.hits += 1;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Cache a tag found to be a replay, evicting the least recently
+    /// replayed tag if the cache is full.
+    pub fn record(&mut self, tag: &Tag) {
+        if self.capacity == 0 || self.lookup(tag) {
+            return
+        }
+        if self.entries.len() == self.capacity {
+            let oldest = *self.recency.keys().next().unwrap();
+            let evicted = self.recency.remove(&oldest).unwrap();
+            self.entries.remove(&evicted);
+        }
+        self.recency.insert(self.tick, tag.clone());
+        self.entries.insert(tag.clone(), (0, self.tick));
+    }
+
+    /// Drop the tag from the cache, once it is no longer a replay.
+    pub fn forget(&mut self, tag: &Tag) {
+        if let Some(entry) = self.entries.remove(tag) {
+            self.recency.remove(&entry.1);
+        }
+    }
+
+    /// Returns the number of times the tag was answered from the cache.
+    pub fn hits(&self, tag: &Tag) -> Option<u64> {
+        self.entries.get(tag).map(|entry| entry.0)
+    }
+
+    /// Returns the number of replays answered from the cache.
+    pub fn total_hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the `n` cached tags with the most hits, most hits first.
+    pub fn top(&self, n: usize) -> Vec<ReplayHit> {
+        let mut hits: Vec<ReplayHit> = self.entries.iter().map(|(tag, entry)| ReplayHit{
+            tag: tag.clone(),
+            hits: entry.0,
+        }).collect();
+        hits.sort_by(|a, b| b.hits.cmp(&a.hits));
+        hits.truncate(n);
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use super::*;
+
+
+    #[test]
+    fn replay_decision_cache_test() {
+        let tags: Vec<Tag> = (0..3u8).map(|i| Tag::new([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        let mut cache = ReplayDecisionCache::new(2);
+        assert!(!cache.lookup(&tags[0]));
+        cache.record(&tags[0]);
+        cache.record(&tags[1]);
+        assert!(cache.lookup(&tags[0]));
+        assert!(cache.lookup(&tags[0]));
+        assert_eq!(cache.hits(&tags[0]), Some(2));
+
+        cache.record(&tags[2]);
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.lookup(&tags[1]));
+        assert!(cache.lookup(&tags[2]));
+        assert_eq!(cache.total_hits(), 3);
+        let top = cache.top(1);
+        assert_eq!(top.len(), 1);
+        assert!(top[0].tag == tags[0]);
+        assert_eq!(top[0].hits, 2);
+    }
+}
@@ -0,0 +1,190 @@
+// dedup.rs - De-duplication of fixed size digests.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Mix servers de-duplicate more than packets: PKI document digests,
+//! consensus hashes and command identifiers should each be acted on
+//! once. A `DedupSet<N>` does for digests of `N` bytes what a `MixKey`
+//! does for replay tags: a filter answers fresh digests from memory, and
+//! every digest is kept in a sled tree, so it is remembered across
+//! restarts once flushed. The digests are kept under keys prefixed with
+//! `<namespace>/dedup/`, so a set can share the tree a `MixKeys` or the
+//! application already uses, as with `MixKeys::with_sled_tree`.
+//!
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sled::Tree;
+
+use constants::MIX_KEY_FALSE_POSITIVE_RATE;
+use errors::MixKeyError;
+use super::TagFilter;
+
+
+enum Backend<const N: usize> {
+    Sled {
+        tree: Tree,
+        prefix: Vec<u8>,
+    },
+    Memory(HashSet<[u8; N]>),
+}
+
+impl<const N: usize> Backend<N> {
+    fn key(prefix: &[u8], digest: &[u8; N]) -> Vec<u8> {
+        let mut key = Vec::with_capacity(prefix.len() + N);
+        key.extend_from_slice(prefix);
+        key.extend_from_slice(digest);
+        key
+    }
+
+    fn contains(&self, digest: &[u8; N]) -> Result<bool, MixKeyError> {
+        match *self {
+            Backend::Sled{ref tree, ref prefix} => Ok(tree.get(&Backend::key(prefix, digest))?.is_some()),
+            Backend::Memory(ref digests) => Ok(digests.contains(digest)),
+        }
+    }
+
+    /// Store the digest, returning true if it was already stored.
+    fn insert(&mut self, digest: &[u8; N]) -> Result<bool, MixKeyError> {
+        match *self {
+            Backend::Sled{ref tree, ref prefix} => Ok(tree.insert(Backend::key(prefix, digest), vec![])?.is_some()),
+            Backend::Memory(ref mut digests) => Ok(!digests.insert(*digest)),
+        }
+    }
+}
+
+/// DedupSet remembers digests of `N` bytes.
+pub struct DedupSet<const N: usize> {
+    backend: Mutex<Backend<N>>,
+    filter: Mutex<TagFilter>,
+    len: AtomicU64,
+}
+
+impl<const N: usize> DedupSet<N> {
+    /// Open the set kept in the tree under the namespace, loading the
+    /// digests already stored. The filter is sized for `expected` digests.
+    pub fn open(tree: Tree, namespace: &str, expected: u32) -> Result<DedupSet<N>, MixKeyError> {
+        let prefix = format!("{}/dedup/", namespace).into_bytes();
+        let mut filter = TagFilter::new(MIX_KEY_FALSE_POSITIVE_RATE, expected, false);
+        let mut len = 0;
+        for item in tree.scan_prefix(&prefix) {
+            let (key, _) = item?;
+            if key.len() == prefix.len() + N {
+                let mut digest = [0u8; N];
+                digest.copy_from_slice(&key[prefix.len()..]);
+                filter.insert(&digest);
+                len += 1;
+            }
+        }
+        Ok(DedupSet{
+            backend: Mutex::new(Backend::Sled{
+                tree: tree,
+                prefix: prefix,
+            }),
+            filter: Mutex::new(filter),
+            len: AtomicU64::new(len),
+        })
+    }
+
+    /// Returns a set kept in memory only.
+    pub fn in_memory(expected: u32) -> DedupSet<N> {
+        DedupSet{
+            backend: Mutex::new(Backend::Memory(HashSet::new())),
+            filter: Mutex::new(TagFilter::new(MIX_KEY_FALSE_POSITIVE_RATE, expected, false)),
+            len: AtomicU64::new(0),
+        }
+    }
+
+    /// Record the digest, returning true if it was seen before.
+    pub fn is_duplicate(&self, digest: &[u8; N]) -> Result<bool, MixKeyError> {
+        let mut backend = self.backend.lock().unwrap();
+        let mut filter = self.filter.lock().unwrap();
+        if filter.contains(digest) && backend.contains(digest)? {
+            return Ok(true)
+        }
+        let present = backend.insert(digest)?;
+        filter.insert(digest);
+        if !present {
+            self.len.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(present)
+    }
+
+    /// Returns true if the digest was seen, without recording it.
+    pub fn contains(&self, digest: &[u8; N]) -> Result<bool, MixKeyError> {
+        if !self.filter.lock().unwrap().contains(digest) {
+            return Ok(false)
+        }
+        self.backend.lock().unwrap().contains(digest)
+    }
+
+    /// Returns the number of digests seen.
+    pub fn len(&self) -> u64 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Make every recorded digest durable.
+    pub fn flush(&self) -> Result<(), MixKeyError> {
+        if let Backend::Sled{ref tree, ..} = *self.backend.lock().unwrap() {
+            tree.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use store::open_tree;
+    use super::*;
+
+
+    #[test]
+    fn dedup_set_test() {
+        let dir = TempDir::new().unwrap();
+        let config = sled::Config::new().path(dir.path());
+        {
+            let tree = open_tree(&config).unwrap();
+            tree.insert(b"pki/dedup/short".to_vec(), vec![]).unwrap();
+            let set: DedupSet<16> = DedupSet::open(tree.clone(), "pki", 100).unwrap();
+            assert_eq!(set.len(), 0);
+            assert!(!set.is_duplicate(&[1u8; 16]).unwrap());
+            assert!(set.is_duplicate(&[1u8; 16]).unwrap());
+            assert!(!set.contains(&[2u8; 16]).unwrap());
+            let other: DedupSet<16> = DedupSet::open(tree, "commands", 100).unwrap();
+            assert!(!other.is_duplicate(&[1u8; 16]).unwrap());
+            set.flush().unwrap();
+        }
+        let set: DedupSet<16> = DedupSet::open(open_tree(&config).unwrap(), "pki", 100).unwrap();
+        assert_eq!(set.len(), 1);
+        assert!(set.contains(&[1u8; 16]).unwrap());
+        assert!(set.is_duplicate(&[1u8; 16]).unwrap());
+
+        let digests: DedupSet<64> = DedupSet::in_memory(10);
+        assert!(!digests.is_duplicate(&[7u8; 64]).unwrap());
+        assert!(digests.is_duplicate(&[7u8; 64]).unwrap());
+        assert_eq!(digests.len(), 1);
+    }
+}
@@ -0,0 +1,201 @@
+// descriptor.rs - Public key bundles for PKI descriptors.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A mix uploads the public keys of its live epochs to the PKI in its
+//! descriptor. `MixKeys::descriptor_bundle` collects them into a
+//! `DescriptorBundle`, optionally signed with the mix's identity key by
+//! a `DescriptorSigner`, so that every mix server need not encode them
+//! itself. `DescriptorBundle::mix_keys` returns them as the map of epoch
+//! to public key the Katzenpost descriptor's `MixKeys` field holds.
+//!
+//! This crate has no signature scheme of its own; the signer is
+//! whatever the mix signs its descriptor with, and any function from a
+//! message to a signature is a `DescriptorSigner`. The signature covers
+//! `DescriptorBundle::message`, which is the encoded entries prefixed
+//! with a domain separation string, so that it can not be mistaken for
+//! a signature over anything else. A bundle is encoded as:
+//!
+//!    version (1) || count (4, LE) || count * (epoch (8, LE) || public key (32))
+//!    || signature length (4, LE) || signature
+//!
+//! with a signature length of zero for an unsigned bundle.
+//!
+
+use std::collections::BTreeMap;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use ecdh_wrapper::{PublicKey, KEY_SIZE};
+
+use errors::MixKeyError;
+
+
+const DESCRIPTOR_BUNDLE_VERSION: u8 = 0;
+const DESCRIPTOR_SIGNATURE_CONTEXT: &str = "sphinx-replay-cache-descriptor-bundle-v0";
+
+
+/// DescriptorSigner signs descriptor bundles with the mix's identity key.
+pub trait DescriptorSigner {
+    /// Returns the signature over the message.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, MixKeyError>;
+}
+
+impl<F: Fn(&[u8]) -> Result<Vec<u8>, MixKeyError>> DescriptorSigner for F {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        self(message)
+    }
+}
+
+/// DescriptorEntry is the public key of one live epoch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescriptorEntry {
+    pub epoch: u64,
+    pub public_key: PublicKey,
+}
+
+/// DescriptorBundle holds the public keys of every live epoch, ordered
+/// by epoch, and the signature over them if it was signed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescriptorBundle {
+    pub entries: Vec<DescriptorEntry>,
+    pub signature: Option<Vec<u8>>,
+}
+
+impl DescriptorBundle {
+    /// Returns a bundle of the entries, signed by the signer if one is
+    /// given.
+    pub fn new(entries: Vec<DescriptorEntry>, signer: Option<&dyn DescriptorSigner>) -> Result<DescriptorBundle, MixKeyError> {
+        let mut bundle = DescriptorBundle{
+            entries: entries,
+            signature: None,
+        };
+        if let Some(signer) = signer {
+            bundle.signature = Some(signer.sign(&bundle.message())?);
+        }
+        Ok(bundle)
+    }
+
+    /// Returns the message the signature is over.
+    pub fn message(&self) -> Vec<u8> {
+        let mut out = DESCRIPTOR_SIGNATURE_CONTEXT.as_bytes().to_vec();
+        out.extend(self.encode_entries());
+        out
+    }
+
+    /// Returns the public keys by epoch, as the Katzenpost descriptor
+    /// holds them.
+    pub fn mix_keys(&self) -> BTreeMap<u64, Vec<u8>> {
+        self.entries.iter().map(|entry| (entry.epoch, entry.public_key.to_vec())).collect()
+    }
+
+    fn encode_entries(&self) -> Vec<u8> {
+        let mut out = vec![DESCRIPTOR_BUNDLE_VERSION];
+        let mut raw_u32 = [0u8; 4];
+        LittleEndian::write_u32(&mut raw_u32, self.entries.len() as u32);
+        out.extend_from_slice(&raw_u32);
+        for entry in self.entries.iter() {
+            let mut raw_epoch = [0u8; 8];
+            LittleEndian::write_u64(&mut raw_epoch, entry.epoch);
+            out.extend_from_slice(&raw_epoch);
+            out.extend(entry.public_key.to_vec());
+        }
+        out
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = self.encode_entries();
+        let signature = self.signature.as_ref().map_or(&[][..], |signature| &signature[..]);
+        let mut raw_u32 = [0u8; 4];
+        LittleEndian::write_u32(&mut raw_u32, signature.len() as u32);
+        out.extend_from_slice(&raw_u32);
+        out.extend_from_slice(signature);
+        out
+    }
+
+    /// Deserialize a bundle produced by `to_vec`. The signature is not
+    /// checked; that is up to the PKI, which knows the mix's identity key.
+    pub fn from_bytes(b: &[u8]) -> Result<DescriptorBundle, MixKeyError> {
+        let mut offset = 0;
+        if read_bytes(b, &mut offset, 1)?[0] != DESCRIPTOR_BUNDLE_VERSION {
+            return Err(MixKeyError::InvalidDescriptorBundle);
+        }
+        let count = LittleEndian::read_u32(read_bytes(b, &mut offset, 4)?);
+        let mut entries = vec![];
+        for _ in 0..count {
+            let epoch = LittleEndian::read_u64(read_bytes(b, &mut offset, 8)?);
+            let mut public_key = PublicKey::default();
+            public_key.from_bytes(read_bytes(b, &mut offset, KEY_SIZE)?)?;
+            entries.push(DescriptorEntry{
+                epoch: epoch,
+                public_key: public_key,
+            });
+        }
+        let signature_len = LittleEndian::read_u32(read_bytes(b, &mut offset, 4)?) as usize;
+        let signature = read_bytes(b, &mut offset, signature_len)?.to_vec();
+        if offset != b.len() {
+            return Err(MixKeyError::InvalidDescriptorBundle);
+        }
+        Ok(DescriptorBundle{
+            entries: entries,
+            signature: match signature.is_empty() {
+                true => None,
+                false => Some(signature),
+            },
+        })
+    }
+}
+
+fn read_bytes<'a>(b: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8], MixKeyError> {
+    if b.len() < *offset + len {
+        return Err(MixKeyError::InvalidDescriptorBundle);
+    }
+    let out = &b[*offset..*offset + len];
+    *offset += len;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate rand;
+
+    use blake2::blake2b_keyed;
+    use ecdh_wrapper::PrivateKey;
+
+    use self::rand::os::OsRng;
+    use super::*;
+
+
+    #[test]
+    fn descriptor_bundle_test() {
+        let mut rng = OsRng::new().unwrap();
+        let entries = vec![
+            DescriptorEntry{ epoch: 7, public_key: PrivateKey::generate(&mut rng).unwrap().public_key() },
+            DescriptorEntry{ epoch: 8, public_key: PrivateKey::generate(&mut rng).unwrap().public_key() },
+        ];
+        let unsigned = DescriptorBundle::new(entries.clone(), None).unwrap();
+        assert_eq!(DescriptorBundle::from_bytes(&unsigned.to_vec()).unwrap(), unsigned);
+        assert_eq!(unsigned.mix_keys().keys().cloned().collect::<Vec<u64>>(), vec![7, 8]);
+
+        let signer = |message: &[u8]| Ok(blake2b_keyed(32, b"identity key", message));
+        let signed = DescriptorBundle::new(entries, Some(&signer)).unwrap();
+        assert_eq!(signed.signature, Some(blake2b_keyed(32, b"identity key", &unsigned.message())));
+        let encoded = signed.to_vec();
+        assert_eq!(DescriptorBundle::from_bytes(&encoded).unwrap(), signed);
+        assert!(DescriptorBundle::from_bytes(&encoded[..encoded.len() - 1]).is_err());
+    }
+}
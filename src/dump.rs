@@ -0,0 +1,478 @@
+// dump.rs - Replay tag dumps.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! `MixKey::export_tags` writes an epoch's tags as a dump which
+//! `MixKey::import_tags` loads on another node, so that a mix can move
+//! to new hardware part way through an epoch without forgetting which
+//! packets it has already seen.
+//!
+//! The layout is:
+//!
+//!    magic (8) || version (1) || epoch (8, LE)
+//!    count (4, LE) || count tags || checksum (32), for every chunk
+//!    count (4, LE) of zero
+//!
+//! A chunk holds at most `DUMP_CHUNK_TAGS` tags, and carries a BLAKE2b
+//! checksum of the epoch, the chunk's position and its tags, so that
+//! chunks can be verified independently, by several threads, and a
+//! corrupt, reordered or repeated chunk is rejected. The zero count
+//! marks the end of the dump, so that a truncated dump is rejected
+//! rather than silently imported in part.
+//!
+//! `write_dump` fills every chunk but the last. `write_sorted_dump`
+//! instead writes the tags in sorted order, and ends a chunk before
+//! every tag whose last two bytes are a multiple of 1024, or once it is
+//! full. Two nodes holding the same tags write identical dumps, and a
+//! tag one of them lacks only changes the chunk it falls in, so their
+//! dumps diff well with rsync. Each chunk's `ChunkDigest`, a BLAKE2b
+//! digest of its tags alone, addresses it by content: comparing two
+//! manifests with `diff_manifests` finds the chunks where the nodes
+//! diverge.
+//!
+//! Version 0 dumps, without chunks or checksums, hold a length (2, LE)
+//! before every tag and a length of zero at the end. They can still be
+//! read.
+//!
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use byteorder::{ByteOrder, LittleEndian};
+use blake2::blake2b;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+
+
+const DUMP_MAGIC: &[u8; 8] = b"SRCDUMP\0";
+pub(crate) const DUMP_VERSION: u8 = 1;
+const HEADER_SIZE: usize = 8 + 1 + 8;
+const CHECKSUM_SIZE: usize = 32;
+
+/// Write the tags in chunks of 4096.
+pub const DUMP_CHUNK_TAGS: usize = 4096;
+
+/// End a chunk of a sorted dump before a tag whose last two bytes, read
+/// as little endian, have none of these bits set.
+const SORTED_BOUNDARY_MASK: u16 = 0x3ff;
+
+
+fn checksum(epoch: u64, first: u64, tags: &[[u8; SPHINX_REPLAY_TAG_SIZE]]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(16 + tags.len() * SPHINX_REPLAY_TAG_SIZE);
+    let mut position = [0u8; 16];
+    LittleEndian::write_u64(&mut position[..8], epoch);
+    LittleEndian::write_u64(&mut position[8..], first);
+    data.extend_from_slice(&position);
+    for tag in tags {
+        data.extend_from_slice(tag);
+    }
+    blake2b(CHECKSUM_SIZE, &data)
+}
+
+fn write_chunk<W: Write>(writer: &mut W, epoch: u64, first: u64, tags: &[[u8; SPHINX_REPLAY_TAG_SIZE]]) -> Result<(), MixKeyError> {
+    let mut count = [0u8; 4];
+    LittleEndian::write_u32(&mut count, tags.len() as u32);
+    writer.write_all(&count)?;
+    for tag in tags {
+        writer.write_all(tag)?;
+    }
+    writer.write_all(&checksum(epoch, first, tags))?;
+    Ok(())
+}
+
+
+fn write_header<W: Write>(writer: &mut W, epoch: u64) -> Result<(), MixKeyError> {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..8].copy_from_slice(DUMP_MAGIC);
+    header[8] = DUMP_VERSION;
+    LittleEndian::write_u64(&mut header[9..], epoch);
+    writer.write_all(&header)?;
+    Ok(())
+}
+
+/// Write a dump of the given tags to `writer`, returning the number of
+/// tags written. The first error yielded by `tags` aborts the dump.
+pub fn write_dump<W, I>(mut writer: W, epoch: u64, tags: I) -> Result<u64, MixKeyError>
+    where W: Write, I: IntoIterator<Item=Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>>
+{
+    write_header(&mut writer, epoch)?;
+
+    let mut chunk = Vec::with_capacity(DUMP_CHUNK_TAGS);
+    let mut count = 0;
+    for tag in tags {
+        chunk.push(tag?);
+        if chunk.len() == DUMP_CHUNK_TAGS {
+            write_chunk(&mut writer, epoch, count, &chunk)?;
+            count += chunk.len() as u64;
+            chunk.clear();
+        }
+    }
+    if !chunk.is_empty() {
+        write_chunk(&mut writer, epoch, count, &chunk)?;
+        count += chunk.len() as u64;
+    }
+    writer.write_all(&[0u8; 4])?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// ChunkDigest addresses a chunk of a sorted dump by its content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkDigest {
+    /// The chunk's first, and smallest, tag.
+    pub first_tag: [u8; SPHINX_REPLAY_TAG_SIZE],
+    pub tags: u32,
+    pub digest: [u8; CHECKSUM_SIZE],
+}
+
+impl ChunkDigest {
+    pub(crate) fn new(tags: &[[u8; SPHINX_REPLAY_TAG_SIZE]]) -> ChunkDigest {
+        let mut digest = [0u8; CHECKSUM_SIZE];
+        digest.copy_from_slice(&blake2b(CHECKSUM_SIZE, &tags.concat()));
+        ChunkDigest{
+            first_tag: tags[0],
+            tags: tags.len() as u32,
+            digest: digest,
+        }
+    }
+}
+
+fn is_boundary(tag: &[u8; SPHINX_REPLAY_TAG_SIZE]) -> bool {
+    LittleEndian::read_u16(&tag[SPHINX_REPLAY_TAG_SIZE - 2..]) & SORTED_BOUNDARY_MASK == 0
+}
+
+/// Sort the tags, dropping repeated ones, and split them into the chunks
+/// of a sorted dump.
+pub(crate) fn sorted_chunks(tags: &mut Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>) -> Vec<Range<usize>> {
+    tags.sort();
+    tags.dedup();
+    let mut chunks = vec![];
+    let mut first = 0;
+    while first < tags.len() {
+        let mut end = first + 1;
+        while end < tags.len() && end - first < DUMP_CHUNK_TAGS && !is_boundary(&tags[end]) {
+            end += 1;
+        }
+        chunks.push(first..end);
+        first = end;
+    }
+    chunks
+}
+
+/// Write a dump of the given tags in canonical order to `writer`,
+/// returning the digest of every chunk. Repeated tags are written once.
+pub fn write_sorted_dump<W: Write>(mut writer: W, epoch: u64, mut tags: Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>) -> Result<Vec<ChunkDigest>, MixKeyError> {
+    let chunks = sorted_chunks(&mut tags);
+    write_header(&mut writer, epoch)?;
+    let mut manifest = vec![];
+    for range in chunks {
+        let chunk = &tags[range.clone()];
+        write_chunk(&mut writer, epoch, range.start as u64, chunk)?;
+        manifest.push(ChunkDigest::new(chunk));
+    }
+    writer.write_all(&[0u8; 4])?;
+    writer.flush()?;
+    Ok(manifest)
+}
+
+/// Returns the chunks of `ours` that `theirs` does not hold.
+pub fn diff_manifests(ours: &[ChunkDigest], theirs: &[ChunkDigest]) -> Vec<ChunkDigest> {
+    let theirs: HashSet<&[u8; CHECKSUM_SIZE]> = theirs.iter().map(|chunk| &chunk.digest).collect();
+    ours.iter().filter(|chunk| !theirs.contains(&chunk.digest)).cloned().collect()
+}
+
+/// DumpChunk is a run of consecutive tags of a dump, read but not yet
+/// verified.
+pub struct DumpChunk {
+    epoch: u64,
+    first: u64,
+    tags: Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>,
+    checksum: Option<Vec<u8>>,
+}
+
+impl DumpChunk {
+    /// Returns the position of the chunk's first tag in the dump.
+    pub fn first(&self) -> u64 {
+        self.first
+    }
+
+    /// Returns the position following the chunk's last tag.
+    pub fn end(&self) -> u64 {
+        self.first + self.tags.len() as u64
+    }
+
+    /// Check the chunk's checksum, returning its tags. Chunks of version
+    /// 0 dumps have no checksum to check.
+    pub fn verify(self) -> Result<Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>, MixKeyError> {
+        if let Some(ref expected) = self.checksum {
+            if checksum(self.epoch, self.first, &self.tags) != *expected {
+                return Err(MixKeyError::InvalidDump)
+            }
+        }
+        Ok(self.tags)
+    }
+}
+
+/// DumpReader yields the tags of a dump.
+pub struct DumpReader<R> {
+    reader: R,
+    epoch: u64,
+    version: u8,
+    position: u64,
+    pending: ::std::vec::IntoIter<[u8; SPHINX_REPLAY_TAG_SIZE]>,
+    done: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Read the dump's header.
+    pub fn new(mut reader: R) -> Result<DumpReader<R>, MixKeyError> {
+        let mut header = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut header).map_err(|_| MixKeyError::InvalidDump)?;
+        if &header[..8] != DUMP_MAGIC || header[8] > DUMP_VERSION {
+            return Err(MixKeyError::InvalidDump)
+        }
+        Ok(DumpReader{
+            reader: reader,
+            epoch: LittleEndian::read_u64(&header[9..]),
+            version: header[8],
+            position: 0,
+            pending: vec![].into_iter(),
+            done: false,
+        })
+    }
+
+    /// Returns the epoch the dumped tags belong to.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Read the next chunk without verifying it, returning None at the
+    /// end of the dump. Tags already taken through the iterator are not
+    /// returned again.
+    pub fn next_chunk(&mut self) -> Result<Option<DumpChunk>, MixKeyError> {
+        if !self.pending.as_slice().is_empty() {
+            let tags: Vec<_> = self.pending.by_ref().collect();
+            return Ok(Some(DumpChunk{
+                epoch: self.epoch,
+                first: self.position - tags.len() as u64,
+                tags: tags,
+                checksum: None,
+            }))
+        }
+        if self.done {
+            return Ok(None)
+        }
+        let chunk = match self.version {
+            0 => self.read_records(),
+            _ => self.read_chunk(),
+        };
+        match chunk {
+            Ok(Some(chunk)) => {
+                self.position = chunk.end();
+                Ok(Some(chunk))
+            },
+            Ok(None) => {
+                self.done = true;
+                Ok(None)
+            },
+            Err(e) => {
+                self.done = true;
+                Err(e)
+            },
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<DumpChunk>, MixKeyError> {
+        let mut count = [0u8; 4];
+        self.reader.read_exact(&mut count).map_err(|_| MixKeyError::InvalidDump)?;
+        let count = LittleEndian::read_u32(&count) as usize;
+        if count == 0 {
+            return Ok(None)
+        }
+        if count > DUMP_CHUNK_TAGS {
+            return Err(MixKeyError::InvalidDump)
+        }
+        let mut tags = vec![[0u8; SPHINX_REPLAY_TAG_SIZE]; count];
+        for tag in tags.iter_mut() {
+            self.reader.read_exact(tag).map_err(|_| MixKeyError::InvalidDump)?;
+        }
+        let mut checksum = vec![0u8; CHECKSUM_SIZE];
+        self.reader.read_exact(&mut checksum).map_err(|_| MixKeyError::InvalidDump)?;
+        Ok(Some(DumpChunk{
+            epoch: self.epoch,
+            first: self.position,
+            tags: tags,
+            checksum: Some(checksum),
+        }))
+    }
+
+    /// Read up to a chunk's worth of version 0 records.
+    fn read_records(&mut self) -> Result<Option<DumpChunk>, MixKeyError> {
+        let mut tags = vec![];
+        while tags.len() < DUMP_CHUNK_TAGS {
+            match self.read_tag()? {
+                Some(tag) => tags.push(tag),
+                None => {
+                    self.done = true;
+                    break
+                },
+            }
+        }
+        if tags.is_empty() {
+            return Ok(None)
+        }
+        Ok(Some(DumpChunk{
+            epoch: self.epoch,
+            first: self.position,
+            tags: tags,
+            checksum: None,
+        }))
+    }
+
+    fn read_tag(&mut self) -> Result<Option<[u8; SPHINX_REPLAY_TAG_SIZE]>, MixKeyError> {
+        let mut length = [0u8; 2];
+        self.reader.read_exact(&mut length).map_err(|_| MixKeyError::InvalidDump)?;
+        match LittleEndian::read_u16(&length) as usize {
+            0 => Ok(None),
+            SPHINX_REPLAY_TAG_SIZE => {
+                let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+                self.reader.read_exact(&mut tag).map_err(|_| MixKeyError::InvalidDump)?;
+                Ok(Some(tag))
+            },
+            _ => Err(MixKeyError::InvalidDump),
+        }
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(tag) = self.pending.next() {
+            return Some(Ok(tag))
+        }
+        match self.next_chunk().and_then(|chunk| chunk.map(|chunk| chunk.verify()).transpose()) {
+            Ok(Some(tags)) => {
+                self.pending = tags.into_iter();
+                self.pending.next().map(Ok)
+            },
+            Ok(None) => None,
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn dump_test() {
+        let tags = vec![[1u8; SPHINX_REPLAY_TAG_SIZE], [2u8; SPHINX_REPLAY_TAG_SIZE]];
+        let mut raw = vec![];
+        assert_eq!(write_dump(&mut raw, 42, tags.iter().map(|tag| Ok(*tag))).unwrap(), 2);
+        assert_eq!(raw.len(), HEADER_SIZE + 4 + 2 * SPHINX_REPLAY_TAG_SIZE + CHECKSUM_SIZE + 4);
+
+        let reader = DumpReader::new(&raw[..]).unwrap();
+        assert_eq!(reader.epoch(), 42);
+        assert_eq!(reader.collect::<Result<Vec<_>, _>>().unwrap(), tags);
+
+        let truncated = DumpReader::new(&raw[..raw.len() - 2]).unwrap();
+        match truncated.collect::<Result<Vec<_>, _>>() {
+            Err(MixKeyError::InvalidDump) => {},
+            x => panic!("unexpected read result: {:?}", x),
+        }
+        match DumpReader::new(&b"SRCARCH\0"[..]) {
+            Err(MixKeyError::InvalidDump) => {},
+            _ => panic!("read a dump with the wrong magic"),
+        }
+
+        let mut corrupt = raw.clone();
+        corrupt[HEADER_SIZE + 4] ^= 1;
+        assert!(DumpReader::new(&corrupt[..]).unwrap().collect::<Result<Vec<_>, _>>().is_err());
+        let mut other_epoch = raw.clone();
+        other_epoch[9] = 43;
+        assert!(DumpReader::new(&other_epoch[..]).unwrap().collect::<Result<Vec<_>, _>>().is_err());
+
+        let mut version_0 = raw[..HEADER_SIZE].to_vec();
+        version_0[8] = 0;
+        for tag in &tags {
+            version_0.extend_from_slice(&[SPHINX_REPLAY_TAG_SIZE as u8, 0]);
+            version_0.extend_from_slice(tag);
+        }
+        version_0.extend_from_slice(&[0, 0]);
+        assert_eq!(DumpReader::new(&version_0[..]).unwrap().collect::<Result<Vec<_>, _>>().unwrap(), tags);
+    }
+
+    #[test]
+    fn dump_chunks_test() {
+        let tags: Vec<_> = (0..DUMP_CHUNK_TAGS as u64 + 10).map(|i| {
+            let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            LittleEndian::write_u64(&mut tag, i);
+            tag
+        }).collect();
+        let mut raw = vec![];
+        write_dump(&mut raw, 42, tags.iter().map(|tag| Ok(*tag))).unwrap();
+
+        let mut reader = DumpReader::new(&raw[..]).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), tags[0]);
+        let rest = reader.next_chunk().unwrap().unwrap();
+        assert_eq!((rest.first(), rest.end()), (1, DUMP_CHUNK_TAGS as u64));
+        let last = reader.next_chunk().unwrap().unwrap();
+        assert_eq!((last.first(), last.end()), (DUMP_CHUNK_TAGS as u64, tags.len() as u64));
+        assert_eq!(last.verify().unwrap(), &tags[DUMP_CHUNK_TAGS..]);
+        assert!(reader.next_chunk().unwrap().is_none());
+    }
+
+    #[test]
+    fn sorted_dump_test() {
+        let tags: Vec<_> = (0..20000u64).map(|i| {
+            let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            tag.copy_from_slice(&blake2b(SPHINX_REPLAY_TAG_SIZE, &i.to_le_bytes()));
+            tag
+        }).collect();
+        let mut ours = vec![];
+        let our_manifest = write_sorted_dump(&mut ours, 42, tags.clone()).unwrap();
+        let mut reversed = vec![];
+        let mut shuffled = tags.clone();
+        shuffled.reverse();
+        shuffled.push(tags[7]);
+        assert_eq!(write_sorted_dump(&mut reversed, 42, shuffled).unwrap(), our_manifest);
+        assert_eq!(reversed, ours);
+
+        let read = DumpReader::new(&ours[..]).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        let mut sorted = tags.clone();
+        sorted.sort();
+        assert_eq!(read, sorted);
+        assert!(our_manifest.len() > 1);
+        assert_eq!(our_manifest.iter().map(|chunk| chunk.tags as usize).sum::<usize>(), tags.len());
+
+        let mut theirs = vec![];
+        let their_manifest = write_sorted_dump(&mut theirs, 42, tags[1..].to_vec()).unwrap();
+        let diverged = diff_manifests(&our_manifest, &their_manifest);
+        assert_eq!(diverged.len(), 1);
+        assert!(diverged[0].first_tag <= tags[0]);
+        assert_eq!(diff_manifests(&their_manifest, &our_manifest).len(), 1);
+    }
+}
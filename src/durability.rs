@@ -0,0 +1,101 @@
+// durability.rs - Replay tag durability policy.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Tags inserted into the replay cache are only safe from a crash once
+//! they have been flushed to disk. Any tag inserted after the last flush
+//! is forgotten by a crash, and the packet it belongs to can then be
+//! replayed. The durability policy determines how large that window is.
+//!
+//! Each key takes its policy from `CacheConfig::durability`, set for all
+//! keys with `MixKeysBuilder::durability_policy`. `EveryWrite` closes the
+//! window at the cost of a flush per fresh tag, while `Manual` leaves
+//! flushing entirely to the application, which also stops sled's own
+//! background flushes.
+//!
+
+use sphinxcrypto::constants::PACKET_SIZE;
+
+use constants::MIX_KEY_FLUSH_FREQUENCY;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+
+/// DurabilityPolicy determines when inserted tags are flushed to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum DurabilityPolicy {
+    /// Flush before every replay check that stores a fresh tag returns.
+    EveryWrite,
+    /// Flush every given number of milliseconds.
+    IntervalMs(u64),
+    /// Only flush when explicitly asked to.
+    Manual,
+}
+
+impl Default for DurabilityPolicy {
+    fn default() -> Self {
+        DurabilityPolicy::IntervalMs(MIX_KEY_FLUSH_FREQUENCY)
+    }
+}
+
+/// ReplayWindow is the worst case amount of traffic whose tags a crash
+/// can lose.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayWindow {
+    pub packets: u64,
+    pub seconds: f64,
+}
+
+impl DurabilityPolicy {
+    /// Returns the maximum number of packets, and seconds of traffic,
+    /// that could be replayed after a crash at the given line rate in
+    /// bytes per second. Returns None if the window is unbounded, as is
+    /// the case for `Manual`.
+    pub fn worst_case_replay_window(&self, line_rate: u64) -> Option<ReplayWindow> {
+        let seconds = match *self {
+            DurabilityPolicy::EveryWrite => 0.0,
+            DurabilityPolicy::IntervalMs(ms) => ms as f64 / 1000.0,
+            DurabilityPolicy::Manual => return None,
+        };
+        Some(ReplayWindow{
+            packets: (line_rate as f64 * seconds / PACKET_SIZE as f64).ceil() as u64,
+            seconds: seconds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn worst_case_replay_window_test() {
+        let line_rate = 100 * PACKET_SIZE as u64;
+        let window = DurabilityPolicy::IntervalMs(10000).worst_case_replay_window(line_rate).unwrap();
+        assert_eq!(window.packets, 1000);
+        assert_eq!(window.seconds, 10.0);
+
+        let window = DurabilityPolicy::EveryWrite.worst_case_replay_window(line_rate).unwrap();
+        assert_eq!(window.packets, 0);
+        assert_eq!(window.seconds, 0.0);
+
+        assert_eq!(DurabilityPolicy::Manual.worst_case_replay_window(line_rate), None);
+        assert_eq!(DurabilityPolicy::default(), DurabilityPolicy::IntervalMs(MIX_KEY_FLUSH_FREQUENCY));
+    }
+}
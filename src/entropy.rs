@@ -0,0 +1,194 @@
+// entropy.rs - OS entropy with retries and health status.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! The OS random number generator can be briefly unavailable, for
+//! instance while a container is starting. Keys and secrets are only
+//! ever generated from the OS generator, never from a weaker fallback,
+//! so `os_rng` retries opening it with exponential backoff before
+//! giving up with `MixKeyError::EntropyUnavailable`.
+//!
+//! Every attempt is recorded in a process wide status, which
+//! `MixKeys::entropy_status` reports so that operators can tell an
+//! epoch left without a key by missing entropy from other failures.
+//!
+
+use std::io::Error as IoError;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use rand::os::OsRng;
+
+use errors::MixKeyError;
+
+
+static HEALTH: EntropyHealth = EntropyHealth::new();
+static RETRY: Mutex<EntropyRetry> = Mutex::new(EntropyRetry::DEFAULT);
+
+
+/// EntropyRetry is how persistently `os_rng` retries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EntropyRetry {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl EntropyRetry {
+    /// Try 6 times over about three seconds.
+    const DEFAULT: EntropyRetry = EntropyRetry{
+        attempts: 6,
+        initial_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(1600),
+    };
+}
+
+impl Default for EntropyRetry {
+    fn default() -> Self {
+        EntropyRetry::DEFAULT
+    }
+}
+
+/// EntropyStatus is the outcome of the latest attempt to open the OS
+/// random number generator.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntropyStatus {
+    Available,
+    /// The generator could not be opened the last `failures` times.
+    Unavailable {
+        failures: u64,
+        error: String,
+    },
+}
+
+struct EntropyHealth {
+    failures: AtomicU64,
+    error: Mutex<Option<String>>,
+}
+
+impl EntropyHealth {
+    const fn new() -> EntropyHealth {
+        EntropyHealth{
+            failures: AtomicU64::new(0),
+            error: Mutex::new(None),
+        }
+    }
+
+    fn status(&self) -> EntropyStatus {
+        match *self.error.lock().unwrap() {
+            Some(ref error) => EntropyStatus::Unavailable{
+                failures: self.failures.load(Ordering::Relaxed),
+                error: error.clone(),
+            },
+            None => EntropyStatus::Available,
+        }
+    }
+
+    fn succeeded(&self) {
+        *self.error.lock().unwrap() = None;
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    fn failed(&self, error: &IoError) {
+        *self.error.lock().unwrap() = Some(error.to_string());
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Set how persistently `os_rng` retries, for the whole process.
+pub fn set_retry(retry: EntropyRetry) {
+    *RETRY.lock().unwrap() = retry;
+}
+
+/// Returns the status of the OS random number generator.
+pub fn status() -> EntropyStatus {
+    HEALTH.status()
+}
+
+/// Open the OS random number generator, retrying with backoff.
+pub fn os_rng() -> Result<OsRng, MixKeyError> {
+    let retry = *RETRY.lock().unwrap();
+    with_retry(&retry, &HEALTH, OsRng::new)
+}
+
+fn with_retry<T, F>(retry: &EntropyRetry, health: &EntropyHealth, mut open: F) -> Result<T, MixKeyError>
+    where F: FnMut() -> Result<T, IoError>
+{
+    let mut backoff = retry.initial_backoff;
+    for attempt in 1..retry.attempts.max(1) + 1 {
+        match open() {
+            Ok(x) => {
+                health.succeeded();
+                return Ok(x)
+            },
+            Err(e) => {
+                health.failed(&e);
+                warn!("OS random number generator unavailable (attempt {} of {}): {}", attempt, retry.attempts, e);
+                if attempt < retry.attempts {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(retry.max_backoff);
+                }
+            },
+        }
+    }
+    Err(MixKeyError::EntropyUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::ErrorKind;
+
+    use super::*;
+
+
+    #[test]
+    fn entropy_retry_test() {
+        let retry = EntropyRetry{
+            attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(2),
+        };
+        let health = EntropyHealth::new();
+        let mut calls = 0;
+        let result: Result<(), MixKeyError> = with_retry(&retry, &health, || {
+            calls += 1;
+            Err(IoError::new(ErrorKind::NotFound, "no getrandom"))
+        });
+        match result {
+            Err(MixKeyError::EntropyUnavailable) => {},
+            x => panic!("unexpected result: {:?}", x),
+        }
+        assert_eq!(calls, 3);
+        assert_eq!(health.status(), EntropyStatus::Unavailable{
+            failures: 3,
+            error: "no getrandom".to_string(),
+        });
+
+        let mut calls = 0;
+        let result = with_retry(&retry, &health, || {
+            calls += 1;
+            if calls < 2 {
+                return Err(IoError::new(ErrorKind::NotFound, "no getrandom"))
+            }
+            Ok(calls)
+        });
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(health.status(), EntropyStatus::Available);
+    }
+}
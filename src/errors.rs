@@ -17,9 +17,79 @@
 use std::fmt;
 use std::io::Error as IoError;
 use std::error::Error;
+use std::path::{Path, PathBuf};
 
 use ecdh_wrapper::errors::KeyError;
+use sled;
+use sled_legacy;
 
+use keyprovider::Kem;
+use lifecycle::KeyState;
+use version::{CACHE_FORMAT_VERSION, CRATE_VERSION};
+
+
+/// Op names the mix key operation an error occurred in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    OpenCache,
+    LoadEpoch,
+    StoreEpoch,
+    LoadKey,
+    GenerateKey,
+    StoreKey,
+    LoadFilter,
+    LookupTag,
+    InsertTag,
+    RemoveTag,
+    RemoveCache,
+    QuarantineCache,
+    ArchiveCache,
+    ExportTags,
+    ImportTags,
+    RestoreCache,
+    Rollover,
+    WarmUp,
+    FlushCache,
+    Transition,
+    FreezeCache,
+    BackupCache,
+    OpenNamespace,
+    RevokeKey,
+    UpgradeCache,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Op::*;
+        match self {
+            OpenCache => write!(f, "opening cache"),
+            LoadEpoch => write!(f, "loading epoch"),
+            StoreEpoch => write!(f, "storing epoch"),
+            LoadKey => write!(f, "loading private key"),
+            GenerateKey => write!(f, "generating private key"),
+            StoreKey => write!(f, "storing private key"),
+            LoadFilter => write!(f, "loading bloom filter"),
+            LookupTag => write!(f, "looking up tag"),
+            InsertTag => write!(f, "inserting tag"),
+            RemoveTag => write!(f, "removing tag"),
+            RemoveCache => write!(f, "removing cache"),
+            QuarantineCache => write!(f, "quarantining cache"),
+            ArchiveCache => write!(f, "archiving cache"),
+            ExportTags => write!(f, "exporting tags"),
+            ImportTags => write!(f, "importing tags"),
+            RestoreCache => write!(f, "restoring cache"),
+            Rollover => write!(f, "rolling over"),
+            WarmUp => write!(f, "warming up"),
+            FlushCache => write!(f, "flushing cache"),
+            Transition => write!(f, "changing key state"),
+            FreezeCache => write!(f, "freezing cache"),
+            BackupCache => write!(f, "backing up cache"),
+            OpenNamespace => write!(f, "opening tag namespace"),
+            RevokeKey => write!(f, "revoking key"),
+            UpgradeCache => write!(f, "upgrading cache"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum MixKeyError {
@@ -27,7 +97,131 @@ pub enum MixKeyError {
     LoadCacheFailed,
     KeyError(KeyError),
     IoError(IoError),
-    SledError,
+    SledError(sled::Error),
+    /// A cache written by sled 0.16 could not be read for its upgrade.
+    LegacySledError(sled_legacy::Error<()>),
+    InvalidBundle,
+    InvalidDescriptorBundle,
+    BaseDirLocked,
+    KeyNotExportable,
+    InvalidArchive,
+    InvalidBackup,
+    InvalidSeed,
+    InvalidJournal,
+    InvalidHighWaterMark,
+    InvalidDump,
+    InvalidKatzenpostKey,
+    InvalidRequest,
+    InvalidReplicationFrame,
+    /// A replication peer does not hold the shared secret, or a frame
+    /// from it was forged or replayed.
+    ReplicationAuthFailed,
+    /// The bytes are not a tag of `SPHINX_REPLAY_TAG_SIZE` bytes.
+    InvalidTag,
+    InvalidConfig(String),
+    EntropyUnavailable,
+    /// The epoch already holds this many tags and rejects fresh ones.
+    TagLimitReached(u64),
+    /// The epoch's cache takes more than its disk quota of this many
+    /// bytes and refuses fresh tags.
+    DiskQuotaExceeded(u64),
+    /// The base directory already holds a cache for this epoch, which
+    /// a restore would overwrite.
+    RestoreConflict(u64),
+    RemovalUnsupported,
+    /// The key's store does not keep tag namespaces.
+    NamespacesUnsupported,
+    /// The key provider would generate the revoked key again.
+    RevocationUnsupported,
+    /// The cache stores tags of `cache` bytes, but keys of `config`
+    /// byte tags were configured.
+    TagSizeMismatch {
+        cache: usize,
+        config: usize,
+    },
+    /// The replay check queue of the packet's priority is full.
+    QueueFull,
+    /// The epoch's key is not active yet, and starts in this many
+    /// seconds.
+    EpochNotYetValid {
+        epoch: u64,
+        starts_in: u64,
+    },
+    UnknownEpoch(u64),
+    /// The epoch is live, but has no key and is too old for one to be
+    /// generated.
+    NoKeyForEpoch(u64),
+    /// The epoch, and its grace period, ended before the current one.
+    EpochTooOld {
+        epoch: u64,
+        current: u64,
+    },
+    /// The epoch is beyond those keys are generated for.
+    EpochTooNew {
+        epoch: u64,
+        current: u64,
+    },
+    /// A key may not move from state `from` to state `to`.
+    InvalidTransition {
+        from: KeyState,
+        to: KeyState,
+    },
+    /// The key's state does not allow it to check tags.
+    KeyUnusable(KeyState),
+    /// The clock reports `epoch`, but `highest` was already seen, so no
+    /// key is created for the epochs in between.
+    ClockRollback {
+        epoch: u64,
+        highest: u64,
+    },
+    /// The cache was made for a key of the KEM `cache`, or of one this
+    /// build does not know, but its key is of the KEM `key`.
+    KemMismatch {
+        cache: Option<Kem>,
+        key: Kem,
+    },
+    /// The cache was written in a format this build does not support.
+    IncompatibleCache {
+        format: u8,
+        writer: Option<String>,
+    },
+    SecretsError(String),
+    StoreError(String),
+    /// An error that occurred while operating on an epoch's cache.
+    Context {
+        epoch: u64,
+        op: Op,
+        path: PathBuf,
+        source: Box<MixKeyError>,
+    },
+}
+
+impl MixKeyError {
+    /// Wrap the error with the epoch, operation and cache path it
+    /// occurred on, unless it already carries them.
+    pub fn context(self, epoch: u64, op: Op, path: &Path) -> MixKeyError {
+        match self {
+            MixKeyError::Context{..} => self,
+            _ => MixKeyError::Context{
+                epoch: epoch,
+                op: op,
+                path: path.to_path_buf(),
+                source: Box::new(self),
+            },
+        }
+    }
+}
+
+/// ResultExt adds error context to results carrying errors convertible
+/// to MixKeyError.
+pub trait ResultExt<T> {
+    fn context(self, epoch: u64, op: Op, path: &Path) -> Result<T, MixKeyError>;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E> where E: Into<MixKeyError> {
+    fn context(self, epoch: u64, op: Op, path: &Path) -> Result<T, MixKeyError> {
+        self.map_err(|e| e.into().context(epoch, op, path))
+    }
 }
 
 impl fmt::Display for MixKeyError {
@@ -38,7 +232,48 @@ impl fmt::Display for MixKeyError {
             LoadCacheFailed => write!(f, "Failed to load cache."),
             KeyError(x) => x.fmt(f),
             IoError(x) => x.fmt(f),
-            SledError => write!(f, "Failed to set page cache key."),
+            SledError(x) => write!(f, "Sled failure: {}", x),
+            LegacySledError(x) => write!(f, "Legacy sled failure: {}", x),
+            InvalidBundle => write!(f, "Invalid identity bundle."),
+            InvalidDescriptorBundle => write!(f, "Invalid descriptor bundle."),
+            BaseDirLocked => write!(f, "Cache base directory is locked by another process."),
+            KeyNotExportable => write!(f, "Private key may not leave its key provider."),
+            InvalidArchive => write!(f, "Invalid or corrupt epoch archive."),
+            InvalidBackup => write!(f, "Invalid or corrupt backup."),
+            InvalidSeed => write!(f, "Master seed is too short or does not match the stored keys."),
+            InvalidJournal => write!(f, "Invalid or corrupt rollover journal."),
+            InvalidHighWaterMark => write!(f, "Invalid or corrupt record of the highest epoch seen."),
+            InvalidDump => write!(f, "Invalid or corrupt tag dump, or a dump of another epoch."),
+            InvalidKatzenpostKey => write!(f, "Invalid or unsupported Katzenpost mix key file."),
+            InvalidRequest => write!(f, "Invalid replay oracle request or response."),
+            InvalidReplicationFrame => write!(f, "Invalid replication handshake or frame."),
+            ReplicationAuthFailed => write!(f, "Replication peer failed to authenticate."),
+            InvalidTag => write!(f, "Invalid tag length or hex encoding."),
+            InvalidConfig(x) => write!(f, "Invalid configuration: {}", x),
+            EntropyUnavailable => write!(f, "The OS random number generator is unavailable."),
+            TagLimitReached(x) => write!(f, "The epoch already holds its limit of {} tags.", x),
+            DiskQuotaExceeded(x) => write!(f, "The epoch's cache exceeds its disk quota of {} bytes.", x),
+            RestoreConflict(x) => write!(f, "The base directory already holds a cache for epoch {}.", x),
+            RemovalUnsupported => write!(f, "Tag removal needs a counting filter and a store that can delete tags."),
+            NamespacesUnsupported => write!(f, "Tag namespaces need a sled or memory backed key."),
+            RevocationUnsupported => write!(f, "The key provider can not replace a revoked key."),
+            TagSizeMismatch{cache, config} => write!(f, "The cache stores tags of {} bytes but tags of {} bytes were configured.", cache, config),
+            QueueFull => write!(f, "The replay check queue is full."),
+            EpochNotYetValid{epoch, starts_in} => write!(f, "The key of epoch {} is not valid for another {} seconds.", epoch, starts_in),
+            UnknownEpoch(x) => write!(f, "There is no live key for epoch {}.", x),
+            NoKeyForEpoch(x) => write!(f, "There is no key for epoch {}.", x),
+            EpochTooOld{epoch, current} => write!(f, "Epoch {} ended before the current epoch {}.", epoch, current),
+            EpochTooNew{epoch, current} => write!(f, "Epoch {} is too far ahead of the current epoch {}.", epoch, current),
+            InvalidTransition{from, to} => write!(f, "A {} key can not become {}.", from, to),
+            KeyUnusable(x) => write!(f, "A {} key does not check tags.", x),
+            ClockRollback{epoch, highest} => write!(f, "The clock reports epoch {} but epoch {} was already seen; no keys are created for past epochs.", epoch, highest),
+            KemMismatch{cache, key} => write!(f, "The cache was made for a key of {} but its key is a {} key.",
+                                              cache.map_or("an unknown KEM".to_string(), |kem| kem.to_string()), key),
+            IncompatibleCache{format, writer} => write!(f, "Cache format {} written by version {} is not supported by version {}, which supports format {}.",
+                                                        format, writer.as_ref().map_or("unknown", |x| x.as_str()), CRATE_VERSION, CACHE_FORMAT_VERSION),
+            SecretsError(x) => write!(f, "Secrets backend failure: {}", x),
+            StoreError(x) => write!(f, "Replay store failure: {}", x),
+            Context{epoch, op, path, source} => write!(f, "Failed {} for epoch {} at {}: {}", op, epoch, path.display(), source),
         }
     }
 }
@@ -48,14 +283,53 @@ impl Error for MixKeyError {
         "I'm a MixKeyError."
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         use self::MixKeyError::*;
         match self {
             CreateCacheFailed => None,
             LoadCacheFailed => None,
-            KeyError(x) => x.cause(),
-            IoError(x) => x.cause(),
-            SledError => None,
+            KeyError(x) => Some(x),
+            IoError(x) => Some(x),
+            SledError(x) => Some(x),
+            LegacySledError(x) => Some(x),
+            InvalidBundle => None,
+            InvalidDescriptorBundle => None,
+            BaseDirLocked => None,
+            KeyNotExportable => None,
+            InvalidArchive => None,
+            InvalidBackup => None,
+            InvalidSeed => None,
+            InvalidJournal => None,
+            InvalidHighWaterMark => None,
+            InvalidDump => None,
+            InvalidKatzenpostKey => None,
+            InvalidRequest => None,
+            InvalidReplicationFrame => None,
+            ReplicationAuthFailed => None,
+            InvalidTag => None,
+            InvalidConfig(_) => None,
+            EntropyUnavailable => None,
+            TagLimitReached(_) => None,
+            DiskQuotaExceeded(_) => None,
+            RestoreConflict(_) => None,
+            RemovalUnsupported => None,
+            NamespacesUnsupported => None,
+            RevocationUnsupported => None,
+            TagSizeMismatch{..} => None,
+            QueueFull => None,
+            EpochNotYetValid{..} => None,
+            UnknownEpoch(_) => None,
+            NoKeyForEpoch(_) => None,
+            EpochTooOld{..} => None,
+            EpochTooNew{..} => None,
+            InvalidTransition{..} => None,
+            KeyUnusable(_) => None,
+            ClockRollback{..} => None,
+            KemMismatch{..} => None,
+            IncompatibleCache{..} => None,
+            SecretsError(_) => None,
+            StoreError(_) => None,
+            Context{source, ..} => Some(source.as_ref()),
         }
     }
 }
@@ -66,8 +340,51 @@ impl From<KeyError> for MixKeyError {
     }
 }
 
+impl From<sled::Error> for MixKeyError {
+    fn from(error: sled::Error) -> Self {
+        MixKeyError::SledError(error)
+    }
+}
+
+impl From<sled_legacy::Error<()>> for MixKeyError {
+    fn from(error: sled_legacy::Error<()>) -> Self {
+        MixKeyError::LegacySledError(error)
+    }
+}
+
 impl From<IoError> for MixKeyError {
     fn from(error: IoError) -> Self {
         MixKeyError::IoError(error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::ErrorKind;
+    use super::*;
+
+
+    #[test]
+    fn context_test() {
+        let result: Result<(), IoError> = Err(IoError::new(ErrorKind::Other, "disk on fire"));
+        let error = result.context(3, Op::InsertTag, Path::new("mix_key.3")).unwrap_err();
+        assert_eq!(error.to_string(), "Failed inserting tag for epoch 3 at mix_key.3: disk on fire");
+        match error.context(4, Op::OpenCache, Path::new("mix_key.4")) {
+            MixKeyError::Context{epoch, op, source, ..} => {
+                assert_eq!(epoch, 3);
+                assert_eq!(op, Op::InsertTag);
+                match *source {
+                    MixKeyError::IoError(_) => {},
+                    e => panic!("unexpected source: {}", e),
+                }
+            },
+            e => panic!("unexpected error: {}", e),
+        }
+
+        let error = MixKeyError::from(sled::Error::Unsupported("no mmap".to_string())).context(5, Op::LookupTag, Path::new("mix_key.5"));
+        assert_eq!(error.to_string(), "Failed looking up tag for epoch 5 at mix_key.5: Sled failure: Unsupported: no mmap");
+        let sled_error = error.source().and_then(|e| e.source()).unwrap();
+        assert_eq!(sled_error.to_string(), "Unsupported: no mmap");
+    }
+}
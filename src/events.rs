@@ -0,0 +1,110 @@
+// events.rs - Key lifecycle events.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Components that publish keys, such as a PKI uploader, need to know
+//! when keys are generated and retired. Rather than polling
+//! `MixKeys::public_key`, they call `MixKeys::subscribe` and receive a
+//! `KeyEvent` for every change, from whichever clone of the `MixKeys`
+//! made it, including a scheduler's.
+//!
+//! Intrusion detection tooling that fingerprints replay attacks calls
+//! `MixKeys::subscribe_replays` instead, and receives a `ReplayEvent`
+//! for every replay `is_replay` detects, as it is detected.
+//!
+//! A subscriber that drops its receiver is forgotten at the next event.
+//!
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::SystemTime;
+
+use ecdh_wrapper::PublicKey;
+
+use super::Tag;
+
+
+/// KeyEvent is a change in the lifecycle of an epoch's key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// A key was generated, or loaded from its cache, for the epoch.
+    KeyGenerated{epoch: u64, public_key: PublicKey},
+    /// The epoch ended at a rollover. Its key remains usable for the
+    /// grace period.
+    KeyExpired{epoch: u64},
+    /// The epoch's key was pruned and no longer processes packets.
+    KeyPruned{epoch: u64},
+    /// The epoch's key was revoked and replaced by a new key with the
+    /// given public key, or None if no replacement could be generated.
+    KeyRevoked{epoch: u64, public_key: Option<PublicKey>},
+}
+
+/// ReplayEvent is a replay detected by an epoch's key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayEvent {
+    pub epoch: u64,
+    pub tag: Tag,
+    /// When the replay was detected.
+    pub timestamp: SystemTime,
+}
+
+/// Subscribers holds the senders of every subscription.
+pub(crate) struct Subscribers<E = KeyEvent> {
+    senders: Vec<Sender<E>>,
+}
+
+impl<E> Default for Subscribers<E> {
+    fn default() -> Self {
+        Subscribers{
+            senders: vec![],
+        }
+    }
+}
+
+impl<E: Clone> Subscribers<E> {
+    pub(crate) fn subscribe(&mut self) -> Receiver<E> {
+        let (tx, rx) = channel();
+        self.senders.push(tx);
+        rx
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    /// Send the event to every subscriber, dropping those that hung up.
+    pub(crate) fn publish(&mut self, event: E) {
+        self.senders.retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn subscribers_test() {
+        let mut subscribers: Subscribers = Subscribers::default();
+        let first = subscribers.subscribe();
+        let second = subscribers.subscribe();
+        subscribers.publish(KeyEvent::KeyExpired{ epoch: 1 });
+        drop(second);
+        subscribers.publish(KeyEvent::KeyPruned{ epoch: 1 });
+        assert_eq!(subscribers.senders.len(), 1);
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), vec![KeyEvent::KeyExpired{ epoch: 1 }, KeyEvent::KeyPruned{ epoch: 1 }]);
+    }
+}
@@ -0,0 +1,195 @@
+// flushcontrol.rs - Flush stall detection and interval adaptation.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A flush that takes longer than the flush interval means the disk is
+//! falling behind: the next flush is already due when the last one
+//! returns, and unflushed tags pile up without bound. This is common on
+//! relays running from SD cards.
+//!
+//! `MixKeys::flush_due` reports every flush duration to a
+//! `FlushController`. When a flush stalls, the controller logs a warning
+//! and doubles the flush interval, up to the configured maximum, so
+//! that each flush writes a larger batch less often. Once flushes take
+//! less than a quarter of the interval again, it halves the interval
+//! back towards the minimum. Every change is kept in a short history.
+//!
+//! A longer interval widens the window of tags a crash can lose, which
+//! `MixKeys::worst_case_replay_window` reflects.
+//!
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use constants::{MIX_KEY_FLUSH_FREQUENCY, MIX_KEY_MAX_FLUSH_INTERVAL};
+
+
+/// Keep the 64 most recent adaptations.
+const HISTORY_CAPACITY: usize = 64;
+
+/// Shrink the interval once flushes take less than a quarter of it.
+const RECOVERY_DIVISOR: u32 = 4;
+
+
+/// FlushBounds limits the flush interval the controller may choose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlushBounds {
+    pub min_interval: Duration,
+    pub max_interval: Duration,
+}
+
+impl Default for FlushBounds {
+    fn default() -> Self {
+        FlushBounds{
+            min_interval: Duration::from_millis(MIX_KEY_FLUSH_FREQUENCY),
+            max_interval: Duration::from_millis(MIX_KEY_MAX_FLUSH_INTERVAL),
+        }
+    }
+}
+
+/// FlushAdaptation records a flush that changed, or stalled at, the
+/// flush interval.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlushAdaptation {
+    /// Monotonic time the flush finished.
+    pub at: Duration,
+    pub epoch: u64,
+    pub flush_duration: Duration,
+    pub old_interval: Duration,
+    pub new_interval: Duration,
+    /// True if the flush took at least the old interval.
+    pub stalled: bool,
+}
+
+/// FlushController chooses the flush interval from observed flush
+/// durations.
+#[derive(Clone, Debug)]
+pub(crate) struct FlushController {
+    bounds: FlushBounds,
+    interval: Duration,
+    stalls: u64,
+    history: VecDeque<FlushAdaptation>,
+}
+
+impl FlushController {
+    pub fn new(bounds: FlushBounds) -> FlushController {
+        FlushController{
+            bounds: bounds,
+            interval: bounds.min_interval,
+            stalls: 0,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn bounds(&self) -> FlushBounds {
+        self.bounds
+    }
+
+    /// Returns the current flush interval.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns the number of stalled flushes observed.
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    /// Returns the recorded adaptations, oldest first.
+    pub fn history(&self) -> Vec<FlushAdaptation> {
+        self.history.iter().cloned().collect()
+    }
+
+    /// Record a flush of the given epoch's key that finished at `at`,
+    /// returning the adaptation it caused, if any.
+    pub fn observe(&mut self, at: Duration, epoch: u64, flush_duration: Duration) -> Option<FlushAdaptation> {
+        let old_interval = self.interval;
+        let stalled = flush_duration >= old_interval;
+        let new_interval = if stalled {
+            (old_interval * 2).min(self.bounds.max_interval)
+        } else if flush_duration * RECOVERY_DIVISOR < old_interval {
+            (old_interval / 2).max(self.bounds.min_interval)
+        } else {
+            old_interval
+        };
+        if !stalled && new_interval == old_interval {
+            return None
+        }
+        if stalled {
+            self.stalls += 1;
+            warn!("flush of epoch {} took {:?}, longer than the {:?} flush interval; flushing every {:?}",
+                  epoch, flush_duration, old_interval, new_interval);
+        } else {
+            info!("flushes have recovered; flushing every {:?}", new_interval);
+        }
+        let adaptation = FlushAdaptation{
+            at: at,
+            epoch: epoch,
+            flush_duration: flush_duration,
+            old_interval: old_interval,
+            new_interval: new_interval,
+            stalled: stalled,
+        };
+        self.interval = new_interval;
+        if self.history.len() == HISTORY_CAPACITY {
+            self.history.pop_front();
+        }
+        self.history.push_back(adaptation);
+        Some(adaptation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn flush_controller_test() {
+        let bounds = FlushBounds{
+            min_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(3),
+        };
+        let mut controller = FlushController::new(bounds);
+        assert_eq!(controller.interval(), Duration::from_secs(1));
+        assert_eq!(controller.observe(Duration::from_secs(1), 7, Duration::from_millis(500)), None);
+
+        let adaptation = controller.observe(Duration::from_secs(2), 7, Duration::from_millis(1500)).unwrap();
+        assert!(adaptation.stalled);
+        assert_eq!(adaptation.new_interval, Duration::from_secs(2));
+        let adaptation = controller.observe(Duration::from_secs(4), 7, Duration::from_secs(5)).unwrap();
+        assert_eq!(adaptation.new_interval, Duration::from_secs(3));
+        let adaptation = controller.observe(Duration::from_secs(9), 7, Duration::from_secs(5)).unwrap();
+        assert_eq!(adaptation.new_interval, Duration::from_secs(3));
+        assert_eq!(controller.stalls(), 3);
+
+        assert_eq!(controller.observe(Duration::from_secs(12), 7, Duration::from_secs(1)), None);
+        let adaptation = controller.observe(Duration::from_secs(15), 7, Duration::from_millis(100)).unwrap();
+        assert!(!adaptation.stalled);
+        assert_eq!(adaptation.new_interval, Duration::from_millis(1500));
+        controller.observe(Duration::from_secs(17), 7, Duration::from_millis(100)).unwrap();
+        assert_eq!(controller.interval(), Duration::from_secs(1));
+        assert_eq!(controller.history().len(), 5);
+
+        for i in 0..HISTORY_CAPACITY as u64 {
+            controller.observe(Duration::from_secs(20 + i), 7, Duration::from_secs(10));
+        }
+        let history = controller.history();
+        assert_eq!(history.len(), HISTORY_CAPACITY);
+        assert_eq!(history[0].at, Duration::from_secs(20));
+    }
+}
@@ -0,0 +1,303 @@
+// frozen.rs - Immutable tag files of finished epochs.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Once an epoch ends its key only sees the stragglers of the grace
+//! period, yet its sled tree keeps the whole epoch's log structured
+//! segments on disk. With `CacheConfig::freeze` set, `MixKeys` freezes
+//! a sled backed key as it enters its grace period: every tag is
+//! written, sorted, to an immutable file that is memory mapped and
+//! searched by bisection, and the sled tree is removed.
+//!
+//! The frozen file of an epoch is `mix_key.<epoch>/frozen`:
+//!
+//!    magic (8) || count (8, LE) || tag (32) || tag (32) || ...
+//!
+//! Tags arriving during the grace period are appended to
+//! `mix_key.<epoch>/late` and kept in memory, and the key's metadata is
+//! kept in a `metafile::MetadataFile`. Lookups allocate nothing.
+//!
+//! The frozen file is renamed into place once it is synced, and only
+//! then is the sled tree removed. A crash in between leaves both; the
+//! next open uses the frozen file and removes what is left of the tree.
+//!
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::Mmap;
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use fsutil;
+use metafile::MetadataFile;
+use sledupgrade;
+use store::ReplayStore;
+use super::Tag;
+
+
+const FROZEN_FILE_NAME: &str = "frozen";
+const FROZEN_TMP_FILE_NAME: &str = "frozen.tmp";
+const LATE_FILE_NAME: &str = "late";
+const FROZEN_MAGIC: &[u8; 8] = b"SRCFROZN";
+const HEADER_SIZE: usize = 16;
+
+
+/// Returns true if the epoch directory holds a frozen cache.
+pub(crate) fn is_frozen(dir: &Path) -> bool {
+    dir.join(FROZEN_FILE_NAME).exists()
+}
+
+/// Write the tags, sorting them, and the metadata into a frozen cache
+/// in `dir`. Once this returns the frozen cache supersedes the cache
+/// the tags came from, which the next `FrozenStore::open` removes.
+pub(crate) fn freeze(dir: &Path, mut tags: Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>, metadata: &HashMap<String, Vec<u8>>) -> Result<(), MixKeyError> {
+    tags.sort();
+    tags.dedup();
+    MetadataFile::new(dir).store(metadata)?;
+    let tmp_path = dir.join(FROZEN_TMP_FILE_NAME);
+    {
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::new(&file);
+        let mut header = [0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(FROZEN_MAGIC);
+        LittleEndian::write_u64(&mut header[8..], tags.len() as u64);
+        writer.write_all(&header)?;
+        for tag in &tags {
+            writer.write_all(tag)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fsutil::sync_file(&file)?;
+    }
+    fs::rename(&tmp_path, dir.join(FROZEN_FILE_NAME))?;
+    fsutil::sync_dir(dir)?;
+    Ok(())
+}
+
+/// FrozenStore holds the tags of a frozen epoch, along with those that
+/// arrived after it was frozen.
+pub(crate) struct FrozenStore {
+    map: Mmap,
+    count: usize,
+    late: HashSet<Tag>,
+    late_file: BufWriter<File>,
+    metadata_file: MetadataFile,
+    metadata: HashMap<String, Vec<u8>>,
+}
+
+impl FrozenStore {
+    /// Open the frozen cache in `dir`, removing what is left of the
+    /// sled tree it replaced.
+    pub(crate) fn open(dir: &Path) -> Result<FrozenStore, MixKeyError> {
+        FrozenStore::remove_thawed(dir)?;
+        let file = File::open(dir.join(FROZEN_FILE_NAME))?;
+        let map = unsafe { Mmap::map(&file)? };
+        if map.len() < HEADER_SIZE || &map[..8] != FROZEN_MAGIC {
+            return Err(MixKeyError::LoadCacheFailed)
+        }
+        let count = LittleEndian::read_u64(&map[8..HEADER_SIZE]) as usize;
+        let size = count.checked_mul(SPHINX_REPLAY_TAG_SIZE).and_then(|size| size.checked_add(HEADER_SIZE));
+        if size != Some(map.len()) {
+            return Err(MixKeyError::LoadCacheFailed)
+        }
+        let late_path = dir.join(LATE_FILE_NAME);
+        let mut late = HashSet::new();
+        let mut raw = vec![];
+        if late_path.exists() {
+            File::open(&late_path)?.read_to_end(&mut raw)?;
+        }
+        // A record torn by a crash is dropped along with its tail.
+        let whole = raw.len() - raw.len() % SPHINX_REPLAY_TAG_SIZE;
+        for record in raw[..whole].chunks(SPHINX_REPLAY_TAG_SIZE) {
+            let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            tag.copy_from_slice(record);
+            late.insert(Tag(tag));
+        }
+        let late_file = OpenOptions::new().append(true).create(true).open(&late_path)?;
+        if whole != raw.len() {
+            late_file.set_len(whole as u64)?;
+            fsutil::sync_file(&late_file)?;
+        }
+        let metadata_file = MetadataFile::new(dir);
+        Ok(FrozenStore{
+            map: map,
+            count: count,
+            late: late,
+            late_file: BufWriter::new(late_file),
+            metadata: metadata_file.load()?,
+            metadata_file: metadata_file,
+        })
+    }
+
+    /// Remove what a crash or the freeze left of the sled tree the
+    /// frozen file replaced, along with an unfinished frozen file.
+    /// Anything else in the directory is left alone.
+    fn remove_thawed(dir: &Path) -> Result<(), MixKeyError> {
+        let mut removed = false;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !sledupgrade::is_sled_file(&name) && name != FROZEN_TMP_FILE_NAME {
+                continue
+            }
+            if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+            removed = true;
+        }
+        if removed {
+            fsutil::sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    fn record(&self, i: usize) -> &[u8] {
+        &self.map[HEADER_SIZE + i * SPHINX_REPLAY_TAG_SIZE..HEADER_SIZE + (i + 1) * SPHINX_REPLAY_TAG_SIZE]
+    }
+
+    fn frozen_contains(&self, tag: &Tag) -> bool {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.record(mid).cmp(&tag.0[..]) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return true,
+            }
+        }
+        false
+    }
+}
+
+impl ReplayStore for FrozenStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(self.frozen_contains(tag) || self.late.contains(tag))
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.contains(tag)? {
+            return Ok(true)
+        }
+        self.late_file.write_all(&tag.0)?;
+        self.late.insert(tag.clone());
+        Ok(false)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        self.late_file.flush()?;
+        fsutil::sync_file(self.late_file.get_ref())?;
+        Ok(())
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        let store = &*self;
+        Box::new((0..store.count).map(move |i| {
+            let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            tag.copy_from_slice(store.record(i));
+            Ok(tag)
+        }).chain(store.late.iter().map(|tag| Ok(tag.0))))
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        Ok(self.metadata.get(name).cloned())
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        if let Some(stored) = self.metadata.get(name) {
+            return Ok(stored.clone())
+        }
+        self.set_metadata(name, value)?;
+        Ok(value.to_vec())
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        self.metadata.insert(name.to_string(), value.to_vec());
+        self.metadata_file.store(&self.metadata)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn frozen_store_test() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("db"), vec![7u8; 1000]).unwrap();
+        fs::write(dir.path().join("conf"), vec![7u8; 10]).unwrap();
+        fs::create_dir(dir.path().join("blobs")).unwrap();
+        fs::create_dir(dir.path().join("overflow")).unwrap();
+        fs::write(dir.path().join("notes"), b"operator notes").unwrap();
+        let tags: Vec<[u8; SPHINX_REPLAY_TAG_SIZE]> = (0..100u8).rev().map(|i| [i * 2; SPHINX_REPLAY_TAG_SIZE]).collect();
+        let mut metadata = HashMap::new();
+        metadata.insert("private_key".to_string(), b"key".to_vec());
+        freeze(dir.path(), tags.clone(), &metadata).unwrap();
+        assert!(is_frozen(dir.path()));
+
+        let mut store = FrozenStore::open(dir.path()).unwrap();
+        assert!(!dir.path().join("db").exists());
+        assert!(!dir.path().join("conf").exists());
+        assert!(!dir.path().join("blobs").exists());
+        assert!(dir.path().join("overflow").exists());
+        assert!(dir.path().join("notes").exists());
+        for tag in &tags {
+            assert_eq!(store.contains(&Tag(*tag)).unwrap(), true);
+        }
+        assert_eq!(store.contains(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), false);
+        assert_eq!(store.insert(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), false);
+        assert_eq!(store.insert(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), true);
+        assert_eq!(store.insert(&Tag([4u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), true);
+        assert_eq!(store.metadata("private_key").unwrap(), Some(b"key".to_vec()));
+        assert_eq!(store.set_metadata("state", &[2]).unwrap(), true);
+        store.flush().unwrap();
+        drop(store);
+
+        // A torn late record is dropped.
+        let mut late = OpenOptions::new().append(true).open(dir.path().join(LATE_FILE_NAME)).unwrap();
+        late.write_all(&[3u8; 5]).unwrap();
+        drop(late);
+
+        let mut store = FrozenStore::open(dir.path()).unwrap();
+        assert_eq!(store.contains(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), true);
+        assert_eq!(store.tags().count(), 101);
+        assert_eq!(store.metadata("state").unwrap(), Some(vec![2]));
+        assert!(store.remove(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])).is_err());
+        drop(store);
+
+        // A count whose size overflows is rejected.
+        let mut header = [0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(FROZEN_MAGIC);
+        LittleEndian::write_u64(&mut header[8..], u64::max_value());
+        fs::write(dir.path().join(FROZEN_FILE_NAME), &header[..]).unwrap();
+        match FrozenStore::open(dir.path()) {
+            Err(MixKeyError::LoadCacheFailed) => {},
+            _ => panic!("expected LoadCacheFailed"),
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// fsutil.rs - Portable filesystem helpers.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Filesystem helpers that hide the differences between Linux, macOS
+//! and Windows:
+//!
+//! * On macOS `fsync` does not flush the drive's write cache, so
+//!   `F_FULLFSYNC` is used instead.
+//! * On Windows directories cannot be opened for syncing; NTFS journals
+//!   directory metadata so syncing a directory is a no-op there.
+//! * On Windows a rename fails if the destination exists, whereas on
+//!   unix it silently replaces it. `atomic_rename` refuses to replace
+//!   an existing destination on every platform.
+//!
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+
+use errors::MixKeyError;
+
+
+const EPOCH_DIR_PREFIX: &str = "mix_key.";
+const LOCK_FILE_NAME: &str = "lock";
+const STAGING_DIR_NAME: &str = "tmp";
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+
+/// Returns the path of the cache directory for the given epoch.
+pub fn epoch_dir(base_dir: &Path, epoch: u64) -> PathBuf {
+    base_dir.join(format!("{}{}", EPOCH_DIR_PREFIX, epoch))
+}
+
+/// Returns the path an epoch's cache directory is created at before it
+/// is renamed into place.
+pub fn staging_dir(base_dir: &Path, epoch: u64) -> PathBuf {
+    epoch_dir(&base_dir.join(STAGING_DIR_NAME), epoch)
+}
+
+/// Returns the path an epoch's cache directory is moved to when it is
+/// quarantined at the given unix time. The time keeps caches
+/// quarantined by different restarts apart.
+pub fn quarantine_dir(base_dir: &Path, epoch: u64, now: u64) -> PathBuf {
+    base_dir.join(QUARANTINE_DIR_NAME).join(format!("{}{}.{}", EPOCH_DIR_PREFIX, epoch, now))
+}
+
+/// Remove whatever a crash left in the staging directory.
+pub fn clear_staging(base_dir: &Path) -> Result<(), IoError> {
+    let staging = base_dir.join(STAGING_DIR_NAME);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    Ok(())
+}
+
+/// Returns the epoch of a cache directory name, if it is one.
+pub fn parse_epoch_dir(name: &str) -> Option<u64> {
+    if !name.starts_with(EPOCH_DIR_PREFIX) {
+        return None
+    }
+    name[EPOCH_DIR_PREFIX.len()..].parse::<u64>().ok()
+}
+
+/// Returns the total size of the files below `path`.
+pub fn disk_usage(path: &Path) -> Result<u64, IoError> {
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += disk_usage(&entry.path())?;
+        } else {
+            total += metadata.len();
+        }
+    }
+    Ok(total)
+}
+
+/// Overwrite every file below `path` with zeros, sync it, and remove
+/// the directory.
+pub fn wipe_dir(path: &Path) -> Result<(), IoError> {
+    fn overwrite(path: &Path) -> Result<(), IoError> {
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                overwrite(&entry.path())?;
+                continue
+            }
+            let mut file = OpenOptions::new().write(true).open(entry.path())?;
+            let zeros = [0u8; 4096];
+            let mut remaining = metadata.len();
+            while remaining > 0 {
+                let n = remaining.min(zeros.len() as u64) as usize;
+                file.write_all(&zeros[..n])?;
+                remaining -= n as u64;
+            }
+            sync_file(&file)?;
+        }
+        Ok(())
+    }
+    overwrite(path)?;
+    fs::remove_dir_all(path)?;
+    if let Some(parent) = path.parent() {
+        sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+/// Flush a file's data and metadata to stable storage.
+#[cfg(target_os = "macos")]
+pub fn sync_file(file: &File) -> Result<(), IoError> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { ::libc::fcntl(file.as_raw_fd(), ::libc::F_FULLFSYNC) } == -1 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+/// Flush a file's data and metadata to stable storage.
+#[cfg(not(target_os = "macos"))]
+pub fn sync_file(file: &File) -> Result<(), IoError> {
+    file.sync_all()
+}
+
+/// Flush a directory's entries to stable storage.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> Result<(), IoError> {
+    sync_file(&File::open(path)?)
+}
+
+/// Flush a directory's entries to stable storage.
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &Path) -> Result<(), IoError> {
+    Ok(())
+}
+
+/// Atomically rename `from` to `to` and make the rename durable. Fails
+/// if `to` already exists.
+pub fn atomic_rename(from: &Path, to: &Path) -> Result<(), IoError> {
+    if to.exists() {
+        return Err(IoError::new(ErrorKind::AlreadyExists, "rename destination exists"));
+    }
+    fs::rename(from, to)?;
+    if let Some(parent) = to.parent() {
+        sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+/// BaseDirLock holds an exclusive lock on a cache base directory for as
+/// long as it is alive, preventing two processes from sharing it.
+#[derive(Debug)]
+pub struct BaseDirLock {
+    file: File,
+}
+
+impl BaseDirLock {
+    /// Create the base directory if needed and lock it.
+    pub fn acquire(base_dir: &Path) -> Result<BaseDirLock, MixKeyError> {
+        fs::create_dir_all(base_dir)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(base_dir.join(LOCK_FILE_NAME))?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(MixKeyError::BaseDirLocked);
+        }
+        Ok(BaseDirLock{
+            file: file,
+        })
+    }
+}
+
+impl Drop for BaseDirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn epoch_dir_test() {
+        let dir = epoch_dir(Path::new("base"), 42);
+        assert_eq!(dir, Path::new("base").join("mix_key.42"));
+        let name = dir.file_name().unwrap().to_str().unwrap();
+        assert_eq!(parse_epoch_dir(name), Some(42));
+        assert_eq!(parse_epoch_dir("mix_key."), None);
+        assert_eq!(parse_epoch_dir("lock"), None);
+        assert_eq!(staging_dir(Path::new("base"), 42), Path::new("base").join("tmp").join("mix_key.42"));
+        assert_eq!(quarantine_dir(Path::new("base"), 42, 7), Path::new("base").join("quarantine").join("mix_key.42.7"));
+    }
+
+    #[test]
+    fn atomic_rename_test() {
+        let base_dir = TempDir::new().unwrap();
+        let from = base_dir.path().join("from");
+        let to = base_dir.path().join("to");
+        fs::create_dir(&from).unwrap();
+        atomic_rename(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert!(to.exists());
+
+        fs::create_dir(&from).unwrap();
+        assert!(atomic_rename(&from, &to).is_err());
+        sync_dir(base_dir.path()).unwrap();
+    }
+
+    #[test]
+    fn wipe_dir_test() {
+        let base_dir = TempDir::new().unwrap();
+        let dir = base_dir.path().join("mix_key.1");
+        fs::create_dir_all(dir.join("blobs")).unwrap();
+        fs::write(dir.join("db"), vec![7u8; 10000]).unwrap();
+        fs::write(dir.join("blobs").join("1"), b"key").unwrap();
+        wipe_dir(&dir).unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn base_dir_lock_test() {
+        let base_dir = TempDir::new().unwrap();
+        let lock = BaseDirLock::acquire(base_dir.path()).unwrap();
+        match BaseDirLock::acquire(base_dir.path()) {
+            Err(MixKeyError::BaseDirLocked) => {},
+            _ => panic!("base directory locked twice"),
+        }
+        drop(lock);
+        BaseDirLock::acquire(base_dir.path()).unwrap();
+    }
+}
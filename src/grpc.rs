@@ -0,0 +1,215 @@
+// grpc.rs - gRPC replay cache service.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Serves one `MixKeys` as the `ReplayCache` gRPC service of
+//! `proto/replay_cache.proto`, so that mix dataplanes written in other
+//! languages, or running in other containers, can use it as their
+//! authoritative replay store. Unlike `server::ReplayServer` it is
+//! reachable over the network, and it also answers for the epochs'
+//! public keys and statistics and flushes the keys on request.
+//!
+//! This module is only available with the `grpc` feature, which builds
+//! the service from the protobuf definition with `protoc`. Serve it with
+//! `tonic`:
+//!
+//! ```ignore
+//! tonic::transport::Server::builder()
+//!     .add_service(ReplayCacheService::new(mix_keys).into_server())
+//!     .serve(addr)
+//!     .await?;
+//! ```
+//!
+//! Every call that may touch the disk runs on the tokio blocking
+//! thread pool. There is no authentication; the service should only be
+//! reachable by the mix's own dataplanes.
+//!
+
+use std::convert::TryFrom;
+
+use tokio::task::spawn_blocking;
+use tonic::{Code, Request, Response, Status};
+
+use errors::MixKeyError;
+use stats::KeyStats;
+use super::{MixKeys, Tag};
+
+
+/// The messages and service stubs generated from the protobuf
+/// definition.
+pub mod proto {
+    tonic::include_proto!("sphinx_replay_cache");
+}
+
+use self::proto::replay_cache_server::{ReplayCache, ReplayCacheServer};
+use self::proto::{CheckReplayRequest, CheckReplayResponse, FlushRequest, FlushResponse,
+                  GetPublicKeyRequest, GetPublicKeyResponse, StatsRequest, StatsResponse};
+
+
+/// Returns the status code a client should see for the error.
+fn code(error: &MixKeyError) -> Code {
+    match *error {
+        MixKeyError::Context{ref source, ..} => code(source),
+        MixKeyError::InvalidTag => Code::InvalidArgument,
+        MixKeyError::UnknownEpoch(_) | MixKeyError::NoKeyForEpoch(_) => Code::NotFound,
+        MixKeyError::EpochTooOld{..} | MixKeyError::EpochTooNew{..} => Code::OutOfRange,
+        MixKeyError::EpochNotYetValid{..} | MixKeyError::KeyUnusable(_) => Code::FailedPrecondition,
+        MixKeyError::QueueFull | MixKeyError::TagLimitReached(_) | MixKeyError::DiskQuotaExceeded(_) => Code::ResourceExhausted,
+        _ => Code::Internal,
+    }
+}
+
+fn status(error: MixKeyError) -> Status {
+    Status::new(code(&error), error.to_string())
+}
+
+/// Run the disk bound call on the blocking thread pool.
+async fn blocking<T, F>(f: F) -> Result<Response<T>, Status>
+    where T: Send + 'static, F: FnOnce() -> Result<T, MixKeyError> + Send + 'static
+{
+    match spawn_blocking(f).await {
+        Ok(Ok(x)) => Ok(Response::new(x)),
+        Ok(Err(e)) => Err(status(e)),
+        Err(e) => Err(Status::internal(e.to_string())),
+    }
+}
+
+fn key_stats(stats: &KeyStats) -> proto::KeyStats {
+    proto::KeyStats{
+        epoch: stats.epoch,
+        tags: stats.tags,
+        fill_ratio: stats.fill_ratio,
+        false_positive_rate: stats.false_positive_rate,
+        disk_bytes: stats.disk_bytes,
+        replays: stats.replays,
+    }
+}
+
+/// ReplayCacheService answers the `ReplayCache` gRPC service from a
+/// `MixKeys`.
+#[derive(Clone)]
+pub struct ReplayCacheService {
+    mix_keys: MixKeys,
+}
+
+impl ReplayCacheService {
+    pub fn new(mix_keys: MixKeys) -> ReplayCacheService {
+        ReplayCacheService{
+            mix_keys: mix_keys,
+        }
+    }
+
+    /// Returns the service for a `tonic::transport::Server`.
+    pub fn into_server(self) -> ReplayCacheServer<ReplayCacheService> {
+        ReplayCacheServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl ReplayCache for ReplayCacheService {
+    async fn check_replay(&self, request: Request<CheckReplayRequest>) -> Result<Response<CheckReplayResponse>, Status> {
+        let request = request.into_inner();
+        let tag = Tag::try_from(&request.tag[..]).map_err(status)?;
+        let mix_keys = self.mix_keys.clone();
+        blocking(move || {
+            let replay = mix_keys.key_for_packet(request.epoch)?.is_replay(&tag)?;
+            Ok(CheckReplayResponse{
+                replay: replay,
+            })
+        }).await
+    }
+
+    async fn get_public_key(&self, request: Request<GetPublicKeyRequest>) -> Result<Response<GetPublicKeyResponse>, Status> {
+        let epoch = request.into_inner().epoch;
+        match self.mix_keys.public_key(epoch) {
+            Some(public_key) => Ok(Response::new(GetPublicKeyResponse{
+                public_key: public_key.to_vec(),
+            })),
+            None => Err(status(MixKeyError::UnknownEpoch(epoch))),
+        }
+    }
+
+    async fn stats(&self, _request: Request<StatsRequest>) -> Result<Response<StatsResponse>, Status> {
+        let mix_keys = self.mix_keys.clone();
+        blocking(move || {
+            let stats = mix_keys.stats()?;
+            Ok(StatsResponse{
+                epochs: stats.epochs.iter().map(key_stats).collect(),
+                tags: stats.tags,
+                disk_bytes: stats.disk_bytes,
+                replays: stats.replays,
+            })
+        }).await
+    }
+
+    async fn flush(&self, _request: Request<FlushRequest>) -> Result<Response<FlushResponse>, Status> {
+        let mix_keys = self.mix_keys.clone();
+        blocking(move || {
+            let mut epochs = vec![];
+            for (epoch, mut key) in mix_keys.snapshot_keys() {
+                key.flush()?;
+                epochs.push(epoch);
+            }
+            epochs.sort();
+            Ok(FlushResponse{
+                epochs: epochs,
+            })
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tokio::runtime::Builder;
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use epoch::Clock;
+    use builder::MixKeysBuilder;
+    use store::CacheBackend;
+    use super::*;
+
+
+    #[test]
+    fn replay_cache_service_test() {
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeysBuilder::new(clock).backend(CacheBackend::Memory).num_mix_keys(2).build().unwrap();
+        let service = ReplayCacheService::new(mix_keys.clone());
+        let runtime = Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let check = |tag: Vec<u8>| service.check_replay(Request::new(CheckReplayRequest{
+                epoch: epoch,
+                tag: tag,
+            }));
+            assert_eq!(check(vec![3u8; SPHINX_REPLAY_TAG_SIZE]).await.unwrap().into_inner().replay, false);
+            assert_eq!(check(vec![3u8; SPHINX_REPLAY_TAG_SIZE]).await.unwrap().into_inner().replay, true);
+            assert_eq!(check(vec![3u8; 5]).await.unwrap_err().code(), Code::InvalidArgument);
+
+            let public_key = service.get_public_key(Request::new(GetPublicKeyRequest{epoch: epoch})).await.unwrap().into_inner().public_key;
+            assert_eq!(public_key, mix_keys.public_key(epoch).unwrap().to_vec());
+            let unknown = service.get_public_key(Request::new(GetPublicKeyRequest{epoch: epoch + 100})).await.unwrap_err();
+            assert_eq!(unknown.code(), Code::NotFound);
+
+            let stats = service.stats(Request::new(StatsRequest{})).await.unwrap().into_inner();
+            assert_eq!(stats.tags, 1);
+            assert_eq!(stats.replays, 1);
+            let flushed = service.flush(Request::new(FlushRequest{})).await.unwrap().into_inner();
+            assert_eq!(flushed.epochs.len(), stats.epochs.len());
+        });
+    }
+}
@@ -0,0 +1,112 @@
+// handle.rs - Cheap handles on the keys for worker threads.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Packet processing threads only need to find an epoch's key, check
+//! tags and read public keys. `MixKeys::handle` returns a
+//! `MixKeysHandle` that does just that. Handles share the keys of the
+//! `MixKeys` they came from, so a worker never holds a stale copy: keys
+//! generated, revoked or pruned by the owner are seen by every handle
+//! at once.
+//!
+//! Rotation, flushing and the rest of the key management stay with the
+//! owner of the `MixKeys`, or its scheduler.
+//!
+
+use ecdh_wrapper::PublicKey;
+
+use errors::MixKeyError;
+use super::{MixKey, MixKeys, Tag};
+
+
+/// MixKeysHandle is a cloneable, read mostly view of a `MixKeys` for
+/// worker threads.
+#[derive(Clone)]
+pub struct MixKeysHandle {
+    mix_keys: MixKeys,
+}
+
+impl MixKeysHandle {
+    pub(crate) fn new(mix_keys: MixKeys) -> MixKeysHandle {
+        MixKeysHandle{
+            mix_keys: mix_keys,
+        }
+    }
+
+    /// Returns the key to process a packet made for the given epoch
+    /// with, as `MixKeys::key_for_packet` does.
+    pub fn key(&self, epoch: u64) -> Result<MixKey, MixKeyError> {
+        self.mix_keys.key_for_packet(epoch)
+    }
+
+    /// Check the tag of a packet made for the given epoch, as
+    /// `MixKeys::is_replay` does.
+    pub fn is_replay(&self, epoch: u64, tag: &Tag) -> Result<bool, MixKeyError> {
+        self.mix_keys.is_replay(epoch, tag)
+    }
+
+    /// Like `is_replay`, but checks the tag where it lies in the packet
+    /// buffer.
+    pub fn is_replay_bytes(&self, epoch: u64, tag: &[u8]) -> Result<bool, MixKeyError> {
+        self.mix_keys.is_replay_bytes(epoch, tag)
+    }
+
+    pub fn public_key(&self, epoch: u64) -> Option<PublicKey> {
+        self.mix_keys.public_key(epoch)
+    }
+
+    /// Returns the epochs of the live keys, in order.
+    pub fn epochs(&self) -> Vec<u64> {
+        self.mix_keys.epochs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::thread;
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use epoch::Clock;
+    use builder::MixKeysBuilder;
+    use store::CacheBackend;
+    use super::*;
+
+
+    #[test]
+    fn mix_keys_handle_test() {
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeysBuilder::new(clock).backend(CacheBackend::Memory).num_mix_keys(2).build().unwrap();
+        let handle = mix_keys.handle();
+        let workers: Vec<_> = (0..4u8).map(|worker| {
+            let handle = handle.clone();
+            thread::spawn(move || {
+                handle.is_replay(epoch, &Tag([worker; SPHINX_REPLAY_TAG_SIZE])).unwrap()
+            })
+        }).collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), false);
+        }
+        assert_eq!(handle.is_replay(epoch, &Tag([2u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), true);
+        assert_eq!(handle.key(epoch).unwrap().tag_count(), 4);
+        assert_eq!(handle.epochs(), vec![epoch, epoch + 1]);
+
+        let public_key = mix_keys.revoke(epoch).unwrap();
+        assert_eq!(handle.public_key(epoch), Some(public_key));
+    }
+}
@@ -0,0 +1,135 @@
+// hashfilter.rs - Hash set tag filter for builds without bloom filters.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Builds without the `bloom` feature, such as `minimal` builds for
+//! single board relays, keep each epoch's filter as a plain hash set of
+//! 64 bit tag hashes instead. It leaves the bloom crate out of the
+//! binary, never saturates, and supports removal, at the cost of about
+//! 16 bytes per tag where a bloom filter needs about 10 bits.
+//!
+//! The hashes are keyed per filter, so an attacker can not choose tags
+//! that collide; otherwise the filter is exact.
+//!
+
+use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+
+/// HashSetFilter holds the hashes of the inserted items. It has the
+/// methods of `ScalableBloomFilter`, which it stands in for.
+pub struct HashSetFilter {
+    hashes: HashSet<u64>,
+    keys: RandomState,
+    counting: bool,
+}
+
+impl HashSetFilter {
+    pub fn with_rate(false_positive_rate: f32, expected_num_items: u32) -> HashSetFilter {
+        HashSetFilter::new(false_positive_rate, expected_num_items, false)
+    }
+
+    pub fn counting(false_positive_rate: f32, expected_num_items: u32) -> HashSetFilter {
+        HashSetFilter::new(false_positive_rate, expected_num_items, true)
+    }
+
+    /// The false positive rate is ignored and the set grows as needed,
+    /// so nothing is allocated up front.
+    pub(crate) fn new(_false_positive_rate: f32, _expected_num_items: u32, counting: bool) -> HashSetFilter {
+        HashSetFilter{
+            hashes: HashSet::new(),
+            keys: RandomState::new(),
+            counting: counting,
+        }
+    }
+
+    fn hash<T: Hash>(&self, item: &T) -> u64 {
+        let mut hasher = self.keys.build_hasher();
+        item.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    pub fn is_counting(&self) -> bool {
+        self.counting
+    }
+
+    /// Insert the item, returning true if it was not already present.
+    pub fn insert<T: Hash>(&mut self, item: &T) -> bool {
+        let hash = self.hash(item);
+        self.hashes.insert(hash)
+    }
+
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.hashes.contains(&self.hash(item))
+    }
+
+    /// Remove the item, returning false if the filter does not hold it
+    /// or was not made with `counting`.
+    pub fn remove<T: Hash>(&mut self, item: &T) -> bool {
+        let hash = self.hash(item);
+        self.counting && self.hashes.remove(&hash)
+    }
+
+    /// The set allocates as it grows, so there is nothing to fault in.
+    pub fn prefault(&mut self) {
+    }
+
+    /// A hash set is a single layer.
+    pub fn layers(&self) -> usize {
+        1
+    }
+
+    pub fn fill_ratio(&self) -> f64 {
+        0.0
+    }
+
+    /// Returns the probability that an item never inserted has the hash
+    /// of one that was.
+    pub fn false_positive_rate(&self) -> f64 {
+        self.hashes.len() as f64 / 2f64.powi(64)
+    }
+
+    /// Returns the memory used by the hashes, in bits.
+    pub fn num_bits(&self) -> usize {
+        self.hashes.capacity() * 64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn hash_set_filter_test() {
+        let mut filter = HashSetFilter::with_rate(0.01, 1000);
+        assert_eq!(filter.num_bits(), 0);
+        for i in 0..1000u32 {
+            assert!(filter.insert(&i));
+        }
+        assert!(!filter.insert(&7u32));
+        assert!((1000..2000u32).all(|i| !filter.contains(&i)));
+        assert!(filter.num_bits() < 1000 * 64 * 2);
+        assert!(!filter.remove(&7u32));
+
+        let mut filter = HashSetFilter::counting(0.01, 1000);
+        filter.insert(&7u32);
+        assert!(filter.remove(&7u32));
+        assert!(!filter.contains(&7u32));
+    }
+}
@@ -0,0 +1,101 @@
+// health.rs - Health reports.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A process can be running while its replay cache is not: a disk gone
+//! read only, a key provider that lost its keys, or a flushing thread
+//! that died. `MixKeys::health` probes each of these and returns a
+//! `HealthReport` an orchestrator's liveness probe can act on.
+//!
+//! Every key's store is checked by writing a probe to its metadata and
+//! reading it back, which leaves its tags alone, and its key by opening the stored key identifier with
+//! the key provider and comparing public keys. Flushing is checked by
+//! how long ago `MixKeys::flush_due` last ran, so it is only checked
+//! once the application has started calling it; sled's own background
+//! flusher cannot be observed.
+//!
+
+use std::fmt;
+
+
+/// Check is the outcome of one health check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Check {
+    Passed,
+    /// The check does not apply, for instance to a shed key.
+    Skipped,
+    Failed(String),
+}
+
+impl Check {
+    pub fn is_failed(&self) -> bool {
+        match *self {
+            Check::Failed(_) => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn from_result<E: fmt::Display>(result: Result<(), E>) -> Check {
+        match result {
+            Ok(()) => Check::Passed,
+            Err(e) => Check::Failed(e.to_string()),
+        }
+    }
+}
+
+/// KeyHealth holds the checks of one epoch's key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyHealth {
+    pub epoch: u64,
+    /// Whether the store can be written to and read from.
+    pub store: Check,
+    /// Whether the stored key identifier opens to the key in use.
+    pub key: Check,
+}
+
+/// HealthReport holds the checks of every key, ordered by epoch, and
+/// of flushing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthReport {
+    pub keys: Vec<KeyHealth>,
+    pub flush: Check,
+}
+
+impl HealthReport {
+    /// Returns true unless any check failed.
+    pub fn is_healthy(&self) -> bool {
+        !self.flush.is_failed() && self.keys.iter().all(|key| !key.store.is_failed() && !key.key.is_failed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn health_report_test() {
+        let mut report = HealthReport{
+            keys: vec![KeyHealth{ epoch: 1, store: Check::Passed, key: Check::Skipped }],
+            flush: Check::Skipped,
+        };
+        assert!(report.is_healthy());
+        report.keys[0].store = Check::from_result(Err("disk on fire"));
+        assert!(!report.is_healthy());
+        assert_eq!(report.keys[0].store, Check::Failed("disk on fire".to_string()));
+    }
+}
@@ -0,0 +1,121 @@
+// highwater.rs - Highest epoch seen.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A clock that is set back, by a misconfigured NTP server or a host
+//! restored from a snapshot, makes a mix believe it is in an epoch it
+//! already left. If that epoch's key was pruned, generating it again
+//! would create a second key for the epoch, and a fresh cache that
+//! accepts every packet replayed from the first time around.
+//!
+//! `MixKeys` therefore records the highest epoch its clock ever
+//! reported, in a small file in the base directory for sled backed keys
+//! and in memory otherwise. While the clock reports an earlier epoch,
+//! no new key is created for any epoch below the mark; keys whose caches
+//! still exist are loaded as usual. The `ClockRollbackPolicy` decides
+//! whether a `MixKeys` opened during a rollback refuses to start or
+//! starts with only the keys it already has.
+//!
+//! The mark is a single small record, replaced atomically:
+//!
+//!    magic (4) || epoch (8, LE)
+//!
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use errors::MixKeyError;
+use fsutil;
+
+
+const MARK_FILE_NAME: &str = "high_water";
+const MARK_TMP_FILE_NAME: &str = "high_water.tmp";
+const MARK_MAGIC: &[u8; 4] = b"HIWM";
+const MARK_SIZE: usize = 12;
+
+
+/// HighWaterMark persists the highest epoch seen in a base directory.
+#[derive(Clone, Debug)]
+pub(crate) struct HighWaterMark {
+    path: PathBuf,
+    tmp_path: PathBuf,
+}
+
+impl HighWaterMark {
+    pub fn new(base_dir: &Path) -> HighWaterMark {
+        HighWaterMark{
+            path: base_dir.join(MARK_FILE_NAME),
+            tmp_path: base_dir.join(MARK_TMP_FILE_NAME),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the recorded epoch, or None if none was recorded.
+    pub fn load(&self) -> Result<Option<u64>, MixKeyError> {
+        let mut raw = vec![];
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_end(&mut raw)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(MixKeyError::IoError(e)),
+        };
+        if raw.len() != MARK_SIZE || &raw[..4] != MARK_MAGIC {
+            return Err(MixKeyError::InvalidHighWaterMark)
+        }
+        Ok(Some(LittleEndian::read_u64(&raw[4..])))
+    }
+
+    /// Durably replace the recorded epoch.
+    pub fn store(&self, epoch: u64) -> Result<(), MixKeyError> {
+        let mut raw = [0u8; MARK_SIZE];
+        raw[..4].copy_from_slice(MARK_MAGIC);
+        LittleEndian::write_u64(&mut raw[4..], epoch);
+        let mut file = File::create(&self.tmp_path)?;
+        file.write_all(&raw)?;
+        fsutil::sync_file(&file)?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        if let Some(parent) = self.path.parent() {
+            fsutil::sync_dir(parent)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn high_water_mark_test() {
+        let base_dir = TempDir::new().unwrap();
+        let mark = HighWaterMark::new(base_dir.path());
+        assert_eq!(mark.load().unwrap(), None);
+        mark.store(42).unwrap();
+        assert_eq!(mark.load().unwrap(), Some(42));
+        fs::write(mark.path(), b"HIWM").unwrap();
+        assert!(mark.load().is_err());
+    }
+}
@@ -0,0 +1,225 @@
+// identity.rs - Mix identity bundles for failover.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! An identity bundle carries every active mix private key, sealed to an
+//! operator supplied public key, along with a reference to each epoch's
+//! on-disk tag store. A standby mix opens the bundle with the operator's
+//! private key and starts its caches from the referenced tag stores so
+//! that no replay window is opened during failover.
+//!
+//! Each key is sealed with `secrets::seal` under the secret shared
+//! between the bundle's ephemeral key and the operator's key, bound to
+//! the bundle version, the ephemeral key, the epoch, the key's public
+//! key and its tag store path, so that none of them can be swapped.
+//!
+
+use std::path::PathBuf;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use ecdh_wrapper::{PublicKey, PrivateKey, KEY_SIZE};
+
+use entropy;
+use errors::MixKeyError;
+use secrets::{self, SEAL_OVERHEAD};
+
+
+pub(crate) const BUNDLE_VERSION: u8 = 1;
+const SEALED_KEY_SIZE: usize = KEY_SIZE + SEAL_OVERHEAD;
+
+
+/// BundleEntry is a single sealed epoch key and its tag store reference.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BundleEntry {
+    pub epoch: u64,
+    pub public_key: PublicKey,
+    pub cache_path: PathBuf,
+    sealed_key: Vec<u8>,
+}
+
+/// IdentityBundle is the set of sealed mix keys exported for failover.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IdentityBundle {
+    ephemeral_key: PublicKey,
+    pub entries: Vec<BundleEntry>,
+}
+
+/// Returns the context a key is sealed to: the bundle version and
+/// ephemeral key, the epoch, the key's public key and its tag store
+/// path, length prefixed.
+fn entry_context(ephemeral_key: &PublicKey, epoch: u64, public_key: &PublicKey, cache_path: &PathBuf) -> Vec<u8> {
+    let mut context = vec![BUNDLE_VERSION];
+    context.extend(ephemeral_key.to_vec());
+    let mut raw_epoch = [0u8; 8];
+    LittleEndian::write_u64(&mut raw_epoch, epoch);
+    context.extend_from_slice(&raw_epoch);
+    context.extend(public_key.to_vec());
+    let path = cache_path.to_string_lossy().into_owned().into_bytes();
+    let mut raw_u32 = [0u8; 4];
+    LittleEndian::write_u32(&mut raw_u32, path.len() as u32);
+    context.extend_from_slice(&raw_u32);
+    context.extend(path);
+    context
+}
+
+fn read_bytes<'a>(b: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8], MixKeyError> {
+    if b.len() < *offset + len {
+        return Err(MixKeyError::InvalidBundle);
+    }
+    let out = &b[*offset..*offset + len];
+    *offset += len;
+    Ok(out)
+}
+
+impl IdentityBundle {
+    /// Seal the given epoch keys to the operator's public key.
+    pub fn seal(operator_key: &PublicKey, keys: &[(u64, PrivateKey, PathBuf)]) -> Result<IdentityBundle, MixKeyError> {
+        let mut rng = entropy::os_rng()?;
+        let ephemeral = PrivateKey::generate(&mut rng)?;
+        let shared_secret = ephemeral.exp(operator_key);
+        let mut entries = vec![];
+        for &(epoch, ref private_key, ref cache_path) in keys {
+            let public_key = private_key.public_key();
+            let context = entry_context(&ephemeral.public_key(), epoch, &public_key, cache_path);
+            entries.push(BundleEntry{
+                epoch: epoch,
+                public_key: public_key,
+                cache_path: cache_path.clone(),
+                sealed_key: secrets::seal(&shared_secret, &context, &private_key.to_vec())?,
+            });
+        }
+        Ok(IdentityBundle{
+            ephemeral_key: ephemeral.public_key(),
+            entries: entries,
+        })
+    }
+
+    /// Open the bundle with the operator's private key, returning the
+    /// epoch keys and their tag store paths.
+    pub fn open(&self, operator_key: &PrivateKey) -> Result<Vec<(u64, PrivateKey, PathBuf)>, MixKeyError> {
+        let shared_secret = operator_key.exp(&self.ephemeral_key);
+        let mut keys = vec![];
+        for entry in self.entries.iter() {
+            let context = entry_context(&self.ephemeral_key, entry.epoch, &entry.public_key, &entry.cache_path);
+            let raw_key = match secrets::open(&shared_secret, &context, &entry.sealed_key) {
+                Ok(raw_key) => raw_key,
+                Err(_) => return Err(MixKeyError::InvalidBundle),
+            };
+            let private_key = PrivateKey::from_bytes(&raw_key)?;
+            if private_key.public_key() != entry.public_key {
+                return Err(MixKeyError::InvalidBundle);
+            }
+            keys.push((entry.epoch, private_key, entry.cache_path.clone()));
+        }
+        Ok(keys)
+    }
+
+    /// Serialize the bundle for transport to the standby.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = vec![BUNDLE_VERSION];
+        out.extend(self.ephemeral_key.to_vec());
+        let mut raw_u32 = [0u8; 4];
+        LittleEndian::write_u32(&mut raw_u32, self.entries.len() as u32);
+        out.extend_from_slice(&raw_u32);
+        for entry in self.entries.iter() {
+            let mut raw_epoch = [0u8; 8];
+            LittleEndian::write_u64(&mut raw_epoch, entry.epoch);
+            out.extend_from_slice(&raw_epoch);
+            out.extend(entry.public_key.to_vec());
+            out.extend_from_slice(&entry.sealed_key);
+            let path = entry.cache_path.to_string_lossy().into_owned().into_bytes();
+            LittleEndian::write_u32(&mut raw_u32, path.len() as u32);
+            out.extend_from_slice(&raw_u32);
+            out.extend(path);
+        }
+        out
+    }
+
+    /// Deserialize a bundle produced by `to_vec`.
+    pub fn from_bytes(b: &[u8]) -> Result<IdentityBundle, MixKeyError> {
+        let mut offset = 0;
+        if read_bytes(b, &mut offset, 1)?[0] != BUNDLE_VERSION {
+            return Err(MixKeyError::InvalidBundle);
+        }
+        let mut ephemeral_key = PublicKey::default();
+        ephemeral_key.from_bytes(read_bytes(b, &mut offset, KEY_SIZE)?)?;
+        let count = LittleEndian::read_u32(read_bytes(b, &mut offset, 4)?);
+        let mut entries = vec![];
+        for _ in 0..count {
+            let epoch = LittleEndian::read_u64(read_bytes(b, &mut offset, 8)?);
+            let mut public_key = PublicKey::default();
+            public_key.from_bytes(read_bytes(b, &mut offset, KEY_SIZE)?)?;
+            let sealed_key = read_bytes(b, &mut offset, SEALED_KEY_SIZE)?.to_vec();
+            let path_len = LittleEndian::read_u32(read_bytes(b, &mut offset, 4)?) as usize;
+            let path = match String::from_utf8(read_bytes(b, &mut offset, path_len)?.to_vec()) {
+                Ok(x) => x,
+                Err(_) => return Err(MixKeyError::InvalidBundle),
+            };
+            entries.push(BundleEntry{
+                epoch: epoch,
+                public_key: public_key,
+                cache_path: PathBuf::from(path),
+                sealed_key: sealed_key,
+            });
+        }
+        if offset != b.len() {
+            return Err(MixKeyError::InvalidBundle);
+        }
+        Ok(IdentityBundle{
+            ephemeral_key: ephemeral_key,
+            entries: entries,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate rand;
+
+    use self::rand::os::OsRng;
+    use super::*;
+
+
+    #[test]
+    fn identity_bundle_seal_open_test() {
+        let mut rng = OsRng::new().unwrap();
+        let operator_key = PrivateKey::generate(&mut rng).unwrap();
+        let mix_key = PrivateKey::generate(&mut rng).unwrap();
+        let keys = vec![(7, mix_key.clone(), PathBuf::from("/tmp/mix_key.7"))];
+
+        let bundle = IdentityBundle::seal(&operator_key.public_key(), &keys).unwrap();
+        let decoded = IdentityBundle::from_bytes(&bundle.to_vec()).unwrap();
+        assert_eq!(bundle, decoded);
+
+        let opened = decoded.open(&operator_key).unwrap();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0].0, 7);
+        assert_eq!(opened[0].1, mix_key);
+        assert_eq!(opened[0].2, PathBuf::from("/tmp/mix_key.7"));
+
+        let wrong_key = PrivateKey::generate(&mut rng).unwrap();
+        assert!(decoded.open(&wrong_key).is_err());
+
+        let mut tampered = decoded.clone();
+        tampered.entries[0].cache_path = PathBuf::from("/tmp/mix_key.8");
+        match tampered.open(&operator_key) {
+            Err(MixKeyError::InvalidBundle) => {},
+            x => panic!("opened a bundle with a swapped tag store path: {:?}", x.map(|keys| keys.len()).map_err(|e| e.to_string())),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+// inspect.rs - Read only inspection of epoch caches.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Opens a `mix_key.<epoch>` cache directory read only to report what
+//! it holds, for use by operator tooling such as the
+//! `sphinx-replay-cache` command. Sled no longer opens a database read
+//! only, so the cache must not be in use by a running mix; the
+//! inspector only ever reads from it.
+//!
+
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use sled::{self, Tree};
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+use ecdh_wrapper::PublicKey;
+
+use errors::{MixKeyError, Op, ResultExt};
+use fsutil;
+use store;
+use super::{Tag, EPOCH_KEY, FORMAT_VERSION_KEY, PUBLIC_KEY_KEY, WRITER_VERSION_KEY};
+
+
+/// CacheInfo summarizes the contents of an epoch cache.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheInfo {
+    pub epoch: Option<u64>,
+    pub public_key: Option<PublicKey>,
+    /// The cache format, or None if it predates format versions.
+    pub format_version: Option<u8>,
+    /// The version of the crate that created the cache.
+    pub writer_version: Option<String>,
+    pub tag_count: u64,
+    pub disk_bytes: u64,
+}
+
+/// CacheInspector is a read only handle to an epoch cache.
+pub struct CacheInspector {
+    tree: Tree,
+    path: PathBuf,
+}
+
+impl CacheInspector {
+    pub fn open_read_only(path: &Path) -> Result<CacheInspector, MixKeyError> {
+        let epoch = path.file_name().and_then(|x| x.to_str()).and_then(fsutil::parse_epoch_dir).unwrap_or(0);
+        if !path.is_dir() {
+            return Err(MixKeyError::LoadCacheFailed.context(epoch, Op::OpenCache, path))
+        }
+        let config = sled::Config::new().path(path);
+        let tree = store::open_tree(&config).context(epoch, Op::OpenCache, path)?;
+        Ok(CacheInspector{
+            tree: tree,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Scan the cache, counting its tags.
+    pub fn info(&self) -> Result<CacheInfo, MixKeyError> {
+        let mut info = CacheInfo{
+            epoch: None,
+            public_key: None,
+            format_version: None,
+            writer_version: None,
+            tag_count: 0,
+            disk_bytes: fsutil::disk_usage(&self.path)?,
+        };
+        for item in self.tree.iter() {
+            let (key, value) = match item {
+                Ok(x) => x,
+                Err(e) => return Err(e.into()),
+            };
+            if key.len() == SPHINX_REPLAY_TAG_SIZE {
+                info.tag_count += 1;
+            } else if key.len() == 8 && value.is_empty() {
+                info.epoch = Some(LittleEndian::read_u64(&key));
+            } else if &key[..] == EPOCH_KEY.as_bytes() && value.len() == 8 {
+                info.epoch = Some(LittleEndian::read_u64(&value));
+            } else if &key[..] == PUBLIC_KEY_KEY.as_bytes() {
+                let mut public_key = PublicKey::default();
+                if public_key.from_bytes(&value).is_ok() {
+                    info.public_key = Some(public_key);
+                }
+            } else if &key[..] == FORMAT_VERSION_KEY.as_bytes() {
+                info.format_version = value.first().cloned();
+            } else if &key[..] == WRITER_VERSION_KEY.as_bytes() {
+                info.writer_version = Some(String::from_utf8_lossy(&value).into_owned());
+            }
+        }
+        Ok(info)
+    }
+
+    /// Returns true if the tag is stored in the cache.
+    pub fn contains(&self, tag: &Tag) -> Result<bool, MixKeyError> {
+        match self.tree.get(&tag.0) {
+            Ok(x) => Ok(x.is_some()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::super::MixKey;
+    use version::{CACHE_FORMAT_VERSION, CRATE_VERSION};
+    use super::*;
+
+
+    #[test]
+    fn inspect_test() {
+        let base_dir = TempDir::new().unwrap();
+        let mut mix_key = MixKey::new(1024 * 1024, 5, 60, &base_dir.path().to_str().unwrap().to_string()).unwrap();
+        let tag = Tag::new([3u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(mix_key.is_replay(&tag).unwrap(), false);
+        mix_key.flush().unwrap();
+        let public_key = mix_key.public_key();
+        drop(mix_key);
+
+        let inspector = CacheInspector::open_read_only(&fsutil::epoch_dir(base_dir.path(), 5)).unwrap();
+        let info = inspector.info().unwrap();
+        assert_eq!(info.epoch, Some(5));
+        assert_eq!(info.public_key, Some(public_key));
+        assert_eq!(info.format_version, Some(CACHE_FORMAT_VERSION));
+        assert_eq!(info.writer_version.as_ref().map(|x| x.as_str()), Some(CRATE_VERSION));
+        assert_eq!(info.tag_count, 1);
+        assert!(info.disk_bytes > 0);
+        assert!(inspector.contains(&tag).unwrap());
+        assert!(!inspector.contains(&Tag::new([4u8; SPHINX_REPLAY_TAG_SIZE])).unwrap());
+
+        assert!(CacheInspector::open_read_only(&fsutil::epoch_dir(base_dir.path(), 6)).is_err());
+    }
+}
@@ -0,0 +1,478 @@
+// katzenpost.rs - Katzenpost Go server mix key compatibility.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! The Katzenpost Go server keeps each epoch's private key and replay
+//! tags in a BoltDB file named `mixkey-<epoch>.db` in its data
+//! directory. The file has two buckets:
+//!
+//! * `metadata`: `version` (a single zero byte), `epoch` (8 bytes, big
+//!   endian) and `privateKey` (the 32 byte X25519 private key)
+//! * `replay`: one key per replay tag
+//!
+//! `migrate` reads every such file and creates the equivalent sled
+//! cache, so that a node moves from the Go implementation to this one
+//! without rotating its keys. `export` writes a `MixKey` back out in the
+//! Go layout for the reverse move.
+//!
+//! Only the parts of the BoltDB file format these files use are
+//! supported, and, like the Go server on the platforms it runs on, the
+//! files are read and written little endian.
+//!
+//! This module is only available with the `katzenpost-compat` feature.
+//!
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use ecdh_wrapper::PrivateKey;
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use super::MixKey;
+
+
+const KEY_FILE_PREFIX: &str = "mixkey-";
+const KEY_FILE_EXTENSION: &str = ".db";
+const METADATA_BUCKET: &[u8] = b"metadata";
+const REPLAY_BUCKET: &[u8] = b"replay";
+const VERSION_KEY: &[u8] = b"version";
+const EPOCH_KEY: &[u8] = b"epoch";
+const PRIVATE_KEY_KEY: &[u8] = b"privateKey";
+pub(crate) const KEY_VERSION: u8 = 0;
+
+const BOLT_MAGIC: u32 = 0xED0C_DAED;
+const BOLT_VERSION: u32 = 2;
+const PAGE_SIZE: usize = 4096;
+const PAGE_HEADER_SIZE: usize = 16;
+const ELEMENT_SIZE: usize = 16;
+const META_SIZE: usize = 64;
+const META_CHECKSUM_OFFSET: usize = 56;
+const BUCKET_HEADER_SIZE: usize = 16;
+const BRANCH_PAGE_FLAG: u16 = 0x01;
+const LEAF_PAGE_FLAG: u16 = 0x02;
+const META_PAGE_FLAG: u16 = 0x04;
+const FREELIST_PAGE_FLAG: u16 = 0x10;
+const BUCKET_LEAF_FLAG: u32 = 0x01;
+const FREELIST_PAGE: u64 = 2;
+/// Elements written per page, which keeps pages of tags within one page.
+const ENTRIES_PER_PAGE: usize = 64;
+/// Deeper trees than this are taken to be corrupt.
+const MAX_DEPTH: usize = 32;
+
+
+/// KatzenpostKey is the private key and replay tags of one epoch.
+pub struct KatzenpostKey {
+    pub epoch: u64,
+    pub private_key: PrivateKey,
+    pub tags: Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>,
+}
+
+/// Returns the path of the Go server's mix key file for the epoch.
+pub fn key_path(data_dir: &Path, epoch: u64) -> PathBuf {
+    data_dir.join(format!("{}{}{}", KEY_FILE_PREFIX, epoch, KEY_FILE_EXTENSION))
+}
+
+/// Create a sled cache in `base_dir` for every mix key file in the Go
+/// server's `data_dir`, returning the migrated epochs.
+pub fn migrate(data_dir: &Path, base_dir: &String, line_rate: u64, epoch_duration: u64) -> Result<Vec<u64>, MixKeyError> {
+    let mut epochs = vec![];
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let epoch = match name.to_str()
+            .and_then(|name| name.strip_prefix(KEY_FILE_PREFIX))
+            .and_then(|name| name.strip_suffix(KEY_FILE_EXTENSION))
+            .and_then(|epoch| epoch.parse::<u64>().ok()) {
+            Some(epoch) => epoch,
+            None => continue,
+        };
+        let key = read_key(&entry.path())?;
+        if key.epoch != epoch {
+            return Err(MixKeyError::InvalidKatzenpostKey)
+        }
+        let mut mix_key = MixKey::from_katzenpost(&key, line_rate, epoch_duration, base_dir)?;
+        mix_key.flush()?;
+        epochs.push(epoch);
+    }
+    epochs.sort();
+    Ok(epochs)
+}
+
+/// Write the key and tags of `mix_key` to its mix key file in the Go
+/// server's `data_dir`, returning the file's path.
+pub fn export(mix_key: &MixKey, data_dir: &Path) -> Result<PathBuf, MixKeyError> {
+    let path = key_path(data_dir, mix_key.epoch());
+    write_key(&path, &mix_key.to_katzenpost()?)?;
+    Ok(path)
+}
+
+/// Read a Go server mix key file.
+pub fn read_key(path: &Path) -> Result<KatzenpostKey, MixKeyError> {
+    let db = BoltFile::open(fs::read(path)?)?;
+    let root = db.root()?;
+    let metadata = db.bucket(root, METADATA_BUCKET)?.ok_or(MixKeyError::InvalidKatzenpostKey)?;
+    let (mut version, mut epoch, mut private_key) = (None, None, None);
+    db.walk(metadata, 0, &mut |_flags, key, value| {
+        match key {
+            VERSION_KEY => version = Some(value),
+            EPOCH_KEY => epoch = Some(value),
+            PRIVATE_KEY_KEY => private_key = Some(value),
+            _ => {},
+        }
+        Ok(())
+    })?;
+    if version != Some(&[KEY_VERSION][..]) {
+        return Err(MixKeyError::InvalidKatzenpostKey)
+    }
+    let epoch = match epoch {
+        Some(raw) if raw.len() == 8 => BigEndian::read_u64(raw),
+        _ => return Err(MixKeyError::InvalidKatzenpostKey),
+    };
+    let private_key = PrivateKey::from_bytes(private_key.ok_or(MixKeyError::InvalidKatzenpostKey)?)?;
+
+    let mut tags = vec![];
+    if let Some(replay) = db.bucket(root, REPLAY_BUCKET)? {
+        db.walk(replay, 0, &mut |_flags, key, _value| {
+            if key.len() != SPHINX_REPLAY_TAG_SIZE {
+                return Err(MixKeyError::InvalidKatzenpostKey)
+            }
+            let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            tag.copy_from_slice(key);
+            tags.push(tag);
+            Ok(())
+        })?;
+    }
+    Ok(KatzenpostKey{
+        epoch: epoch,
+        private_key: private_key,
+        tags: tags,
+    })
+}
+
+/// Write a Go server mix key file. The metadata bucket is written
+/// inline in the root page, as BoltDB does for small buckets.
+pub fn write_key(path: &Path, key: &KatzenpostKey) -> Result<(), MixKeyError> {
+    let mut builder = Builder::new();
+
+    let mut raw_epoch = [0u8; 8];
+    BigEndian::write_u64(&mut raw_epoch, key.epoch);
+    let private_key = key.private_key.to_vec();
+    let metadata = encode_page(0, LEAF_PAGE_FLAG, &[
+        (0, EPOCH_KEY, &raw_epoch[..]),
+        (0, PRIVATE_KEY_KEY, &private_key[..]),
+        (0, VERSION_KEY, &[KEY_VERSION][..]),
+    ]);
+    let mut inline_metadata = vec![0u8; BUCKET_HEADER_SIZE];
+    inline_metadata.extend_from_slice(&metadata);
+
+    let mut tags = key.tags.clone();
+    tags.sort();
+    tags.dedup();
+    let entries: Vec<(u64, &[u8], &[u8])> = tags.iter().map(|tag| (0, &tag[..], &[][..])).collect();
+    let replay_root = builder.push_tree(&entries);
+    let mut replay = vec![0u8; BUCKET_HEADER_SIZE];
+    LittleEndian::write_u64(&mut replay[..8], replay_root);
+
+    let root = builder.push_page(LEAF_PAGE_FLAG, &[
+        (BUCKET_LEAF_FLAG as u64, METADATA_BUCKET, &inline_metadata[..]),
+        (BUCKET_LEAF_FLAG as u64, REPLAY_BUCKET, &replay[..]),
+    ]);
+    fs::write(path, builder.finish(root))?;
+    Ok(())
+}
+
+fn fnv64a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8], MixKeyError> {
+    match start.checked_add(len) {
+        Some(end) if end <= data.len() => Ok(&data[start..end]),
+        _ => Err(MixKeyError::InvalidKatzenpostKey),
+    }
+}
+
+/// BoltFile reads the pages of a BoltDB file held in memory.
+struct BoltFile {
+    data: Vec<u8>,
+    page_size: usize,
+    root: u64,
+}
+
+impl BoltFile {
+    fn open(data: Vec<u8>) -> Result<BoltFile, MixKeyError> {
+        let first = BoltFile::meta(&data, 0);
+        let second = match first {
+            Some((page_size, _, _)) => BoltFile::meta(&data, page_size),
+            None => None,
+        };
+        let (page_size, root, _txid) = match (first, second) {
+            (Some(a), Some(b)) => if b.2 > a.2 { b } else { a },
+            (Some(a), None) => a,
+            _ => return Err(MixKeyError::InvalidKatzenpostKey),
+        };
+        Ok(BoltFile{
+            data: data,
+            page_size: page_size,
+            root: root,
+        })
+    }
+
+    /// Returns the page size, root bucket page and transaction id of the
+    /// meta page at `offset`, or None if it is invalid.
+    fn meta(data: &[u8], offset: usize) -> Option<(usize, u64, u64)> {
+        let meta = slice(data, offset + PAGE_HEADER_SIZE, META_SIZE).ok()?;
+        if LittleEndian::read_u32(&meta[0..4]) != BOLT_MAGIC || LittleEndian::read_u32(&meta[4..8]) != BOLT_VERSION {
+            return None
+        }
+        if fnv64a(&meta[..META_CHECKSUM_OFFSET]) != LittleEndian::read_u64(&meta[META_CHECKSUM_OFFSET..]) {
+            return None
+        }
+        let page_size = LittleEndian::read_u32(&meta[8..12]) as usize;
+        if page_size < PAGE_HEADER_SIZE + META_SIZE {
+            return None
+        }
+        Some((page_size, LittleEndian::read_u64(&meta[16..24]), LittleEndian::read_u64(&meta[48..56])))
+    }
+
+    fn page(&self, id: u64) -> Result<&[u8], MixKeyError> {
+        let offset = (id as usize).checked_mul(self.page_size).ok_or(MixKeyError::InvalidKatzenpostKey)?;
+        let header = slice(&self.data, offset, PAGE_HEADER_SIZE)?;
+        let pages = LittleEndian::read_u32(&header[12..16]) as usize + 1;
+        slice(&self.data, offset, pages.checked_mul(self.page_size).ok_or(MixKeyError::InvalidKatzenpostKey)?)
+    }
+
+    fn root(&self) -> Result<&[u8], MixKeyError> {
+        self.page(self.root)
+    }
+
+    /// Returns the root page of the named bucket in `parent`.
+    fn bucket<'a>(&'a self, parent: &'a [u8], name: &[u8]) -> Result<Option<&'a [u8]>, MixKeyError> {
+        let mut found = None;
+        self.walk(parent, 0, &mut |flags, key, value| {
+            if flags & BUCKET_LEAF_FLAG != 0 && key == name {
+                found = Some(value);
+            }
+            Ok(())
+        })?;
+        let value = match found {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let header = slice(value, 0, BUCKET_HEADER_SIZE)?;
+        match LittleEndian::read_u64(&header[..8]) {
+            0 => Ok(Some(&value[BUCKET_HEADER_SIZE..])),
+            root => Ok(Some(self.page(root)?)),
+        }
+    }
+
+    /// Call `f` with the flags, key and value of every leaf element
+    /// under `page`, in key order.
+    fn walk<'a, F>(&'a self, page: &'a [u8], depth: usize, f: &mut F) -> Result<(), MixKeyError>
+        where F: FnMut(u32, &'a [u8], &'a [u8]) -> Result<(), MixKeyError>
+    {
+        if depth > MAX_DEPTH {
+            return Err(MixKeyError::InvalidKatzenpostKey)
+        }
+        let header = slice(page, 0, PAGE_HEADER_SIZE)?;
+        let flags = LittleEndian::read_u16(&header[8..10]);
+        let count = LittleEndian::read_u16(&header[10..12]) as usize;
+        for i in 0..count {
+            let offset = PAGE_HEADER_SIZE + i * ELEMENT_SIZE;
+            let element = slice(page, offset, ELEMENT_SIZE)?;
+            if flags & LEAF_PAGE_FLAG != 0 {
+                let pos = offset + LittleEndian::read_u32(&element[4..8]) as usize;
+                let key_size = LittleEndian::read_u32(&element[8..12]) as usize;
+                let value_size = LittleEndian::read_u32(&element[12..16]) as usize;
+                let key = slice(page, pos, key_size)?;
+                let value = slice(page, pos + key_size, value_size)?;
+                f(LittleEndian::read_u32(&element[0..4]), key, value)?;
+            } else if flags & BRANCH_PAGE_FLAG != 0 {
+                let child = self.page(LittleEndian::read_u64(&element[8..16]))?;
+                self.walk(child, depth + 1, f)?;
+            } else {
+                return Err(MixKeyError::InvalidKatzenpostKey)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Encode a leaf or branch page. The first field of every entry is the
+/// element flags of a leaf page, or the child page of a branch page.
+fn encode_page(id: u64, flags: u16, entries: &[(u64, &[u8], &[u8])]) -> Vec<u8> {
+    let mut page = vec![0u8; PAGE_HEADER_SIZE + entries.len() * ELEMENT_SIZE];
+    LittleEndian::write_u64(&mut page[0..8], id);
+    LittleEndian::write_u16(&mut page[8..10], flags);
+    LittleEndian::write_u16(&mut page[10..12], entries.len() as u16);
+    for (i, &(field, key, value)) in entries.iter().enumerate() {
+        let offset = PAGE_HEADER_SIZE + i * ELEMENT_SIZE;
+        let pos = (page.len() - offset) as u32;
+        {
+            let element = &mut page[offset..offset + ELEMENT_SIZE];
+            if flags == LEAF_PAGE_FLAG {
+                LittleEndian::write_u32(&mut element[0..4], field as u32);
+                LittleEndian::write_u32(&mut element[4..8], pos);
+                LittleEndian::write_u32(&mut element[8..12], key.len() as u32);
+                LittleEndian::write_u32(&mut element[12..16], value.len() as u32);
+            } else {
+                LittleEndian::write_u32(&mut element[0..4], pos);
+                LittleEndian::write_u32(&mut element[4..8], key.len() as u32);
+                LittleEndian::write_u64(&mut element[8..16], field);
+            }
+        }
+        page.extend_from_slice(key);
+        page.extend_from_slice(value);
+    }
+    page
+}
+
+/// Builder lays out the pages of a new BoltDB file, after two meta
+/// pages and an empty freelist.
+struct Builder {
+    data: Vec<u8>,
+}
+
+impl Builder {
+    fn new() -> Builder {
+        let mut builder = Builder{
+            data: vec![0u8; 2 * PAGE_SIZE],
+        };
+        let id = builder.push_page(FREELIST_PAGE_FLAG, &[]);
+        debug_assert_eq!(id, FREELIST_PAGE);
+        builder
+    }
+
+    fn push_page(&mut self, flags: u16, entries: &[(u64, &[u8], &[u8])]) -> u64 {
+        let id = (self.data.len() / PAGE_SIZE) as u64;
+        let mut page = encode_page(id, flags, entries);
+        let pages = (page.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+        page.resize(pages * PAGE_SIZE, 0);
+        LittleEndian::write_u32(&mut page[12..16], pages as u32 - 1);
+        self.data.extend_from_slice(&page);
+        id
+    }
+
+    /// Write a tree of the given sorted leaf entries, returning its root
+    /// page.
+    fn push_tree(&mut self, entries: &[(u64, &[u8], &[u8])]) -> u64 {
+        if entries.is_empty() {
+            return self.push_page(LEAF_PAGE_FLAG, &[])
+        }
+        let mut level = vec![];
+        for chunk in entries.chunks(ENTRIES_PER_PAGE) {
+            level.push((chunk[0].1.to_vec(), self.push_page(LEAF_PAGE_FLAG, chunk)));
+        }
+        while level.len() > 1 {
+            let mut parents = vec![];
+            for chunk in level.chunks(ENTRIES_PER_PAGE) {
+                let children: Vec<(u64, &[u8], &[u8])> = chunk.iter().map(|child| (child.1, &child.0[..], &[][..])).collect();
+                parents.push((chunk[0].0.clone(), self.push_page(BRANCH_PAGE_FLAG, &children)));
+            }
+            level = parents;
+        }
+        level[0].1
+    }
+
+    /// Write both meta pages and return the file.
+    fn finish(mut self, root: u64) -> Vec<u8> {
+        let pages = (self.data.len() / PAGE_SIZE) as u64;
+        for txid in 0..2u64 {
+            let offset = txid as usize * PAGE_SIZE;
+            let page = &mut self.data[offset..offset + PAGE_SIZE];
+            LittleEndian::write_u64(&mut page[0..8], txid);
+            LittleEndian::write_u16(&mut page[8..10], META_PAGE_FLAG);
+            let meta = &mut page[PAGE_HEADER_SIZE..PAGE_HEADER_SIZE + META_SIZE];
+            LittleEndian::write_u32(&mut meta[0..4], BOLT_MAGIC);
+            LittleEndian::write_u32(&mut meta[4..8], BOLT_VERSION);
+            LittleEndian::write_u32(&mut meta[8..12], PAGE_SIZE as u32);
+            LittleEndian::write_u64(&mut meta[16..24], root);
+            LittleEndian::write_u64(&mut meta[32..40], FREELIST_PAGE);
+            LittleEndian::write_u64(&mut meta[40..48], pages);
+            LittleEndian::write_u64(&mut meta[48..56], txid);
+            let checksum = fnv64a(&meta[..META_CHECKSUM_OFFSET]);
+            LittleEndian::write_u64(&mut meta[META_CHECKSUM_OFFSET..], checksum);
+        }
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use rand::Rng;
+    use rand::os::OsRng;
+
+    use self::tempfile::TempDir;
+    use keyprovider::LocalKeyProvider;
+    use super::super::Tag;
+    use super::*;
+
+
+    #[test]
+    fn katzenpost_key_test() {
+        let dir = TempDir::new().unwrap();
+        let mut rng = OsRng::new().unwrap();
+        let mut tags = vec![[0u8; SPHINX_REPLAY_TAG_SIZE]; 500];
+        for tag in tags.iter_mut() {
+            rng.fill_bytes(&mut tag[..]);
+        }
+        let key = KatzenpostKey{
+            epoch: 1234,
+            private_key: PrivateKey::generate(&mut rng).unwrap(),
+            tags: tags.clone(),
+        };
+        let path = key_path(dir.path(), 1234);
+        write_key(&path, &key).unwrap();
+
+        let loaded = read_key(&path).unwrap();
+        assert_eq!(loaded.epoch, 1234);
+        assert_eq!(loaded.private_key.to_vec(), key.private_key.to_vec());
+        tags.sort();
+        assert_eq!(loaded.tags, tags);
+
+        let base_dir = dir.path().join("mix").to_str().unwrap().to_string();
+        assert_eq!(migrate(dir.path(), &base_dir, 1024 * 1024, 60).unwrap(), vec![1234]);
+        let mix_key = MixKey::with_key_provider(&LocalKeyProvider, 1024 * 1024, 1234, 60, &base_dir).unwrap();
+        assert_eq!(mix_key.public_key().to_vec(), key.private_key.public_key().to_vec());
+        assert_eq!(mix_key.tag_count(), tags.len() as u64);
+        assert_eq!(mix_key.is_replay(&Tag(tags[0])).unwrap(), true);
+
+        let go_dir = TempDir::new().unwrap();
+        let exported = read_key(&export(&mix_key, go_dir.path()).unwrap()).unwrap();
+        assert_eq!(exported.private_key.to_vec(), key.private_key.to_vec());
+        assert_eq!(exported.tags, tags);
+
+        let mut raw = fs::read(&path).unwrap();
+        raw[PAGE_HEADER_SIZE] ^= 1;
+        raw[PAGE_SIZE + PAGE_HEADER_SIZE] ^= 1;
+        fs::write(&path, &raw).unwrap();
+        match read_key(&path) {
+            Err(MixKeyError::InvalidKatzenpostKey) => {},
+            _ => panic!("read a file with corrupt meta pages"),
+        }
+    }
+}
@@ -0,0 +1,424 @@
+// keyprovider.rs - Pluggable private key storage.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A `KeyProvider` creates and holds the per-epoch private keys. The
+//! cache of each epoch only stores the identifier the provider returns
+//! for its key. The default `LocalKeyProvider` generates keys in process
+//! and uses the private key itself as the identifier, whereas a provider
+//! backed by an HSM or external keystore would return a key label and
+//! perform the Diffie-Hellman operations on the device.
+//!
+//! Keys belong to a key encapsulation mechanism, named by `Kem`. This
+//! crate only implements X25519, where the encapsulation of a packet is
+//! its ephemeral public key and decapsulating it is a Diffie-Hellman
+//! operation. A provider can hand out keys of a post-quantum KEM or an
+//! X25519 hybrid by overriding `EpochKey::kem`, `public_key_bytes` and
+//! `decapsulate`. Each cache records the KEM of its key, and refuses to
+//! open with a key of another.
+//!
+//! The `SeedKeyProvider` derives every epoch's key from a single master
+//! seed with HKDF-SHA256, so that a node can be restored from a backup
+//! of the seed alone.
+//!
+
+use std::fmt;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use clear_on_drop::clear::Clear;
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use ecdh_wrapper::{PublicKey, PrivateKey, KEY_SIZE};
+
+use entropy;
+use errors::MixKeyError;
+use lifecycle::KeyState;
+
+
+/// The minimum size of a master seed in bytes.
+pub const MIN_SEED_SIZE: usize = 32;
+
+const SEED_KDF_INFO: &str = "sphinx-replay-cache-epoch-key-v0";
+
+/// Kem names the key encapsulation mechanism of an epoch key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kem {
+    X25519,
+    Kyber768,
+    /// X25519 and Kyber768 combined, so that the shared secret stays
+    /// secret unless both are broken.
+    X25519Kyber768,
+}
+
+impl Kem {
+    /// Returns the identifier recorded in the cache.
+    pub fn id(&self) -> u8 {
+        match *self {
+            Kem::X25519 => 0,
+            Kem::Kyber768 => 1,
+            Kem::X25519Kyber768 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Kem> {
+        match id {
+            0 => Some(Kem::X25519),
+            1 => Some(Kem::Kyber768),
+            2 => Some(Kem::X25519Kyber768),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Kem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Kem::X25519 => write!(f, "X25519"),
+            Kem::Kyber768 => write!(f, "Kyber768"),
+            Kem::X25519Kyber768 => write!(f, "X25519Kyber768"),
+        }
+    }
+}
+
+/// EpochKey is the interface to a single epoch's private key. This is
+/// an extension point for keys held outside the process.
+pub trait EpochKey: Send + Sync {
+    /// Returns the X25519 public key, which for a hybrid key is its
+    /// X25519 part.
+    fn public_key(&self) -> PublicKey;
+
+    /// Perform a Diffie-Hellman operation with the given public key.
+    fn exp(&self, public_key: &PublicKey) -> Result<[u8; KEY_SIZE], MixKeyError>;
+
+    /// Returns a copy of the private key, or `KeyNotExportable` if the
+    /// key may not leave the provider.
+    fn export(&self) -> Result<PrivateKey, MixKeyError>;
+
+    fn kem(&self) -> Kem {
+        Kem::X25519
+    }
+
+    /// Returns the public key in the encoding of its KEM.
+    fn public_key_bytes(&self) -> Vec<u8> {
+        self.public_key().to_vec()
+    }
+
+    /// Returns the shared secret of a packet's key encapsulation, which
+    /// for X25519 is the packet's ephemeral public key.
+    fn decapsulate(&self, encapsulation: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        let mut public_key = PublicKey::default();
+        public_key.from_bytes(encapsulation)?;
+        Ok(self.exp(&public_key)?.to_vec())
+    }
+}
+
+/// KeyProvider creates and opens the per-epoch private keys. This is
+/// an extension point for HSMs and external keystores.
+pub trait KeyProvider: Send + Sync {
+    /// Create a key for the given epoch, returning the identifier to
+    /// store in the epoch's cache.
+    fn generate(&self, epoch: u64) -> Result<Vec<u8>, MixKeyError>;
+
+    /// Open the key with the given identifier.
+    fn open(&self, epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError>;
+
+    /// Returns false if `generate` always creates the same key for an
+    /// epoch, so that a revoked key can not be replaced.
+    fn can_replace(&self) -> bool {
+        true
+    }
+}
+
+impl EpochKey for PrivateKey {
+    fn public_key(&self) -> PublicKey {
+        PrivateKey::public_key(self)
+    }
+
+    fn exp(&self, public_key: &PublicKey) -> Result<[u8; KEY_SIZE], MixKeyError> {
+        Ok(PrivateKey::exp(self, public_key))
+    }
+
+    fn export(&self) -> Result<PrivateKey, MixKeyError> {
+        Ok(self.clone())
+    }
+}
+
+/// RevokedKey stands in for a revoked key, keeping only what is public.
+pub(crate) struct RevokedKey {
+    public_key: PublicKey,
+    public_key_bytes: Vec<u8>,
+    kem: Kem,
+}
+
+impl RevokedKey {
+    pub(crate) fn of(key: &dyn EpochKey) -> RevokedKey {
+        RevokedKey{
+            public_key: key.public_key(),
+            public_key_bytes: key.public_key_bytes(),
+            kem: key.kem(),
+        }
+    }
+}
+
+impl EpochKey for RevokedKey {
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    fn exp(&self, _public_key: &PublicKey) -> Result<[u8; KEY_SIZE], MixKeyError> {
+        Err(MixKeyError::KeyUnusable(KeyState::Revoked))
+    }
+
+    fn export(&self) -> Result<PrivateKey, MixKeyError> {
+        Err(MixKeyError::KeyUnusable(KeyState::Revoked))
+    }
+
+    fn kem(&self) -> Kem {
+        self.kem
+    }
+
+    fn public_key_bytes(&self) -> Vec<u8> {
+        self.public_key_bytes.clone()
+    }
+
+    fn decapsulate(&self, _encapsulation: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        Err(MixKeyError::KeyUnusable(KeyState::Revoked))
+    }
+}
+
+/// LocalKeyProvider generates keys in process and stores them in the
+/// epoch caches.
+#[derive(Clone, Debug, Default)]
+pub struct LocalKeyProvider;
+
+impl KeyProvider for LocalKeyProvider {
+    fn generate(&self, _epoch: u64) -> Result<Vec<u8>, MixKeyError> {
+        let mut rng = entropy::os_rng()?;
+        Ok(PrivateKey::generate(&mut rng)?.to_vec())
+    }
+
+    fn open(&self, _epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+        let mut private_key = PrivateKey::default();
+        private_key.load_bytes(id)?;
+        Ok(Arc::new(private_key))
+    }
+}
+
+/// SeedKeyProvider derives each epoch's key from a master seed. The
+/// caches only store the derived public keys, which are checked when a
+/// key is opened to catch a node being restored with the wrong seed.
+#[derive(Clone)]
+pub struct SeedKeyProvider {
+    seed: Vec<u8>,
+}
+
+impl SeedKeyProvider {
+    pub fn new(seed: &[u8]) -> Result<SeedKeyProvider, MixKeyError> {
+        if seed.len() < MIN_SEED_SIZE {
+            return Err(MixKeyError::InvalidSeed)
+        }
+        Ok(SeedKeyProvider{
+            seed: seed.to_vec(),
+        })
+    }
+
+    /// Returns the private key of the given epoch.
+    pub fn derive(&self, epoch: u64) -> Result<PrivateKey, MixKeyError> {
+        let mut info = SEED_KDF_INFO.as_bytes().to_vec();
+        let mut raw_epoch = [0u8; 8];
+        LittleEndian::write_u64(&mut raw_epoch, epoch);
+        info.extend_from_slice(&raw_epoch);
+        let mut raw_key = [0u8; KEY_SIZE];
+        let hk = Hkdf::<Sha256>::extract(None, &self.seed);
+        hk.expand(&info, &mut raw_key).unwrap();
+        let private_key = PrivateKey::from_bytes(&raw_key);
+        raw_key.clear();
+        Ok(private_key?)
+    }
+}
+
+impl KeyProvider for SeedKeyProvider {
+    fn generate(&self, epoch: u64) -> Result<Vec<u8>, MixKeyError> {
+        Ok(self.derive(epoch)?.public_key().to_vec())
+    }
+
+    fn open(&self, epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+        let private_key = self.derive(epoch)?;
+        if private_key.public_key().to_vec() != id {
+            return Err(MixKeyError::InvalidSeed)
+        }
+        Ok(Arc::new(private_key))
+    }
+
+    fn can_replace(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    use rand::os::OsRng;
+
+    use self::tempfile::TempDir;
+    use super::super::MixKey;
+    use super::*;
+
+
+    /// A keystore that only hands out key labels.
+    #[derive(Default)]
+    struct Keystore {
+        keys: Mutex<HashMap<Vec<u8>, PrivateKey>>,
+    }
+
+    struct KeystoreKey(PrivateKey);
+
+    impl EpochKey for KeystoreKey {
+        fn public_key(&self) -> PublicKey {
+            self.0.public_key()
+        }
+
+        fn exp(&self, public_key: &PublicKey) -> Result<[u8; KEY_SIZE], MixKeyError> {
+            Ok(self.0.exp(public_key))
+        }
+
+        fn export(&self) -> Result<PrivateKey, MixKeyError> {
+            Err(MixKeyError::KeyNotExportable)
+        }
+    }
+
+    impl KeyProvider for Keystore {
+        fn generate(&self, epoch: u64) -> Result<Vec<u8>, MixKeyError> {
+            let mut rng = OsRng::new()?;
+            let label = format!("epoch-{}", epoch).into_bytes();
+            self.keys.lock().unwrap().insert(label.clone(), PrivateKey::generate(&mut rng)?);
+            Ok(label)
+        }
+
+        fn open(&self, _epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+            let key = self.keys.lock().unwrap().get(id).cloned().unwrap();
+            Ok(Arc::new(KeystoreKey(key)))
+        }
+    }
+
+    #[test]
+    fn key_provider_test() {
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let keystore = Keystore::default();
+        let mix_key = MixKey::with_key_provider(&keystore, 1024 * 1024, 1, 60, &base_dir).unwrap();
+        let private_key = keystore.keys.lock().unwrap().get(&b"epoch-1".to_vec()).cloned().unwrap();
+        assert_eq!(mix_key.public_key(), private_key.public_key());
+        match mix_key.export_private_key() {
+            Err(MixKeyError::KeyNotExportable) => {},
+            _ => panic!("exported a keystore key"),
+        }
+
+        let mut rng = OsRng::new().unwrap();
+        let peer = PrivateKey::generate(&mut rng).unwrap();
+        assert_eq!(mix_key.exp(&peer.public_key()).unwrap(), peer.exp(&private_key.public_key()));
+        drop(mix_key);
+
+        let mix_key = MixKey::with_key_provider(&keystore, 1024 * 1024, 1, 60, &base_dir).unwrap();
+        assert_eq!(mix_key.public_key(), private_key.public_key());
+        assert_eq!(keystore.keys.lock().unwrap().len(), 1);
+    }
+
+    /// Hands out X25519 keys that claim to be of another KEM.
+    struct KemProvider(Kem);
+
+    struct KemKey(PrivateKey, Kem);
+
+    impl EpochKey for KemKey {
+        fn public_key(&self) -> PublicKey {
+            self.0.public_key()
+        }
+
+        fn exp(&self, public_key: &PublicKey) -> Result<[u8; KEY_SIZE], MixKeyError> {
+            Ok(self.0.exp(public_key))
+        }
+
+        fn export(&self) -> Result<PrivateKey, MixKeyError> {
+            Ok(self.0.clone())
+        }
+
+        fn kem(&self) -> Kem {
+            self.1
+        }
+    }
+
+    impl KeyProvider for KemProvider {
+        fn generate(&self, epoch: u64) -> Result<Vec<u8>, MixKeyError> {
+            LocalKeyProvider.generate(epoch)
+        }
+
+        fn open(&self, _epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+            Ok(Arc::new(KemKey(PrivateKey::from_bytes(id)?, self.0)))
+        }
+    }
+
+    #[test]
+    fn kem_test() {
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mix_key = MixKey::with_key_provider(&KemProvider(Kem::X25519Kyber768), 1024 * 1024, 1, 60, &base_dir).unwrap();
+        assert_eq!(mix_key.kem(), Kem::X25519Kyber768);
+        let mut rng = OsRng::new().unwrap();
+        let peer = PrivateKey::generate(&mut rng).unwrap();
+        assert_eq!(mix_key.decapsulate(&peer.public_key().to_vec()).unwrap(), peer.exp(&mix_key.public_key()).to_vec());
+        assert!(mix_key.decapsulate(&[1, 2, 3]).is_err());
+        drop(mix_key);
+
+        match MixKey::with_key_provider(&KemProvider(Kem::X25519), 1024 * 1024, 1, 60, &base_dir) {
+            Err(MixKeyError::Context{source, ..}) => match *source {
+                MixKeyError::KemMismatch{cache, key} => {
+                    assert_eq!(cache, Some(Kem::X25519Kyber768));
+                    assert_eq!(key, Kem::X25519);
+                },
+                x => panic!("unexpected error: {:?}", x),
+            },
+            _ => panic!("opened a cache with a key of another KEM"),
+        }
+        assert!(MixKey::with_key_provider(&KemProvider(Kem::X25519Kyber768), 1024 * 1024, 1, 60, &base_dir).is_ok());
+        for id in 0..4 {
+            assert_eq!(Kem::from_id(id).map(|kem| kem.id()), if id < 3 { Some(id) } else { None });
+        }
+    }
+
+    #[test]
+    fn seed_key_provider_test() {
+        assert!(SeedKeyProvider::new(&[0u8; MIN_SEED_SIZE - 1]).is_err());
+        let provider = SeedKeyProvider::new(&[7u8; MIN_SEED_SIZE]).unwrap();
+        assert_eq!(provider.derive(1).unwrap(), provider.derive(1).unwrap());
+        assert!(provider.derive(1).unwrap() != provider.derive(2).unwrap());
+
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mix_key = MixKey::with_key_provider(&provider, 1024 * 1024, 1, 60, &base_dir).unwrap();
+        assert_eq!(mix_key.export_private_key().unwrap(), provider.derive(1).unwrap());
+        drop(mix_key);
+
+        let other = SeedKeyProvider::new(&[8u8; MIN_SEED_SIZE]).unwrap();
+        assert!(MixKey::with_key_provider(&other, 1024 * 1024, 1, 60, &base_dir).is_err());
+        let restored_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let restored = MixKey::with_key_provider(&provider, 1024 * 1024, 1, 60, &restored_dir).unwrap();
+        assert_eq!(restored.export_private_key().unwrap(), provider.derive(1).unwrap());
+    }
+}
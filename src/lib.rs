@@ -31,277 +31,4319 @@
 //!    128974848 = 123 * 1024 * 1024.
 //!
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(all(feature = "std", not(feature = "tracing")))]
 #[macro_use]
 extern crate log;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 
+#[cfg(feature = "std")]
 extern crate sled;
+#[cfg(feature = "std")]
+extern crate sled_legacy;
+#[cfg(feature = "bloom")]
 extern crate bloom;
+#[cfg(feature = "std")]
 extern crate rand;
+#[cfg(feature = "std")]
 extern crate byteorder;
+#[cfg(feature = "std")]
+extern crate sha2;
+#[cfg(feature = "std")]
+extern crate hkdf;
+#[cfg(feature = "std")]
+extern crate chacha;
+#[cfg(feature = "std")]
+extern crate keystream;
+#[cfg(feature = "std")]
+extern crate blake2b_simd;
+#[cfg(feature = "std")]
+extern crate subtle;
+#[cfg(feature = "std")]
+extern crate fs2;
+#[cfg(feature = "std")]
+extern crate clear_on_drop;
+#[cfg(all(feature = "std", target_os = "macos"))]
+extern crate libc;
 
+#[cfg(feature = "std")]
 extern crate sphinxcrypto;
+#[cfg(feature = "std")]
 extern crate ecdh_wrapper;
+#[cfg(feature = "std")]
 extern crate epoch;
 
-pub mod errors;
-pub mod constants;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "archive")]
+extern crate zstd;
+#[cfg(feature = "redis")]
+extern crate redis;
+#[cfg(feature = "grpc")]
+extern crate tonic;
+#[cfg(feature = "grpc")]
+extern crate prost;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "std")]
+extern crate memmap;
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+
+/// Declares items that need the standard library. Builds without the
+/// `std` feature leave them out.
+macro_rules! cfg_std {
+    ($($item:item)*) => {
+        $(
+            #[cfg(feature = "std")]
+            $item
+        )*
+    }
+}
+
+pub mod replaycore;
+
+#[cfg(all(feature = "std", target_arch = "wasm32", target_os = "unknown"))]
+compile_error!("wasm32-unknown-unknown has no file system, clock or random source; build with default-features = false and use replaycore");
 
-use std::collections::HashMap;
-use std::collections::hash_map::RandomState;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+cfg_std! {
+    pub mod errors;
+    pub mod alarms;
+    pub mod backup;
+    pub mod builder;
+    pub mod checkqueue;
+    pub mod constants;
+    pub mod countdown;
+    pub mod decisioncache;
+    pub mod dedup;
+    pub mod descriptor;
+    pub mod dump;
+    pub mod entropy;
+    pub mod events;
+    pub mod durability;
+    pub mod flushcontrol;
+    pub mod frozen;
+    pub mod handle;
+    #[cfg(feature = "grpc")]
+    pub mod grpc;
+    pub mod blake2;
+    pub mod fsutil;
+    pub mod hashfilter;
+    pub mod health;
+    pub mod highwater;
+    pub mod identity;
+    pub mod inspect;
+    pub mod keyprovider;
+    pub mod lifecycle;
+    pub mod migration;
+    pub mod namespace;
+    pub mod prelude;
+    pub mod preflight;
+    #[cfg(feature = "quotient")]
+    pub mod quotient;
+    pub mod recovery;
+    pub mod replica;
+    #[cfg(feature = "replication")]
+    pub mod replication;
+    pub mod rollover;
+    #[cfg(feature = "bloom")]
+    pub mod scalable;
+    pub mod scheduler;
+    pub mod secrets;
+    pub mod shard;
+    pub mod sledupgrade;
+    pub mod stats;
+    pub mod sim;
+    pub mod store;
+    pub mod storm;
+    pub mod tagimport;
+    pub mod testing;
+    pub mod tiered;
+    pub mod timesource;
+    pub mod unwrap;
+    pub mod version;
+    pub mod writeback;
+    mod metafile;
+    #[cfg(feature = "async")]
+    pub mod asynchronous;
+    #[cfg(feature = "metrics")]
+    pub mod metrics;
+    #[cfg(feature = "archive")]
+    pub mod archive;
+    #[cfg(feature = "accumulator")]
+    pub mod accumulator;
+    #[cfg(feature = "redis")]
+    pub mod redisstore;
+    #[cfg(feature = "taglog")]
+    pub mod taglog;
+    #[cfg(feature = "katzenpost-compat")]
+    pub mod katzenpost;
+    #[cfg(all(unix, feature = "server"))]
+    pub mod server;
+    #[cfg(all(unix, feature = "console"))]
+    pub mod console;
 
-use self::byteorder::{ByteOrder, LittleEndian};
+    pub use version::version_info;
 
-use self::rand::os::OsRng;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::fmt;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+    use std::sync::mpsc::Receiver;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+    use std::time::{Duration, SystemTime};
+    use std::vec;
 
-use sled::Tree;
-use bloom::{ASMS,BloomFilter};
+    use self::byteorder::{ByteOrder, LittleEndian};
+    use clear_on_drop::ClearOnDrop;
 
-use sphinxcrypto::constants::{SPHINX_REPLAY_TAG_SIZE, PACKET_SIZE};
-use ecdh_wrapper::{PublicKey, PrivateKey};
-use epoch::Clock;
 
-use errors::MixKeyError;
-use constants::MIX_KEY_FLUSH_FREQUENCY;
+    use sled::Tree;
 
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+    use ecdh_wrapper::{PublicKey, PrivateKey, KEY_SIZE};
+    use epoch::{Clock, Time};
 
-const MIX_CACHE_KEY: &str = "private_key";
-const EPOCH_KEY: &str = "epoch";
+    use errors::{MixKeyError, Op, ResultExt};
+    use alarms::{AlertSink, CapacityAlarm, CapacityForecaster, LogAlertSink};
+    use countdown::{EarlyTagPolicy, EpochKeyInfo, KeyCountdown};
+    use descriptor::{DescriptorBundle, DescriptorEntry, DescriptorSigner};
+    use decisioncache::{ReplayDecisionCache, ReplayHit};
+    use constants::{MIX_KEY_CAPACITY_CHECK_INTERVAL, MIX_KEY_HEALTH_FLUSH_INTERVALS, MIX_KEY_IDLE_PERIOD, MIX_KEY_QUOTA_CHECK_TAGS,
+                    MIX_KEY_REPLAY_CACHE_CAPACITY, MIX_KEY_SHARDS};
+    use builder::{CacheConfig, ClockRollbackPolicy, FutureCachePolicy, MixKeysBuilder, OverflowBehavior};
+    use identity::IdentityBundle;
+    use preflight::{PreflightConfig, PreflightReport};
+    use keyprovider::{EpochKey, Kem, KeyProvider, LocalKeyProvider, RevokedKey, SeedKeyProvider};
+    use lifecycle::KeyState;
+    use namespace::TagNamespace;
+    use backup::{BackupEntry, BackupManifest};
+    use frozen::FrozenStore;
+    use recovery::RecoveryReport;
+    use fsutil::BaseDirLock;
+    use handle::MixKeysHandle;
+    use health::{Check, HealthReport, KeyHealth};
+    use highwater::HighWaterMark;
+    use replica::{DeltaLog, FilterReplica};
+    use rollover::{RolloverJournal, RolloverRecord, RolloverStage};
+    use shard::{Shards, shard_of};
+    use stats::{KeyStats, MixKeysStats};
+    use tagimport::{ImportConfig, ImportProgress};
+    use store::{CacheBackend, FilterStore, MemoryStore, ReplayStore, ReplayStoreFactory, SledStore, SledTreeStores, is_tag_size};
+    use dump::ChunkDigest;
+    use durability::{DurabilityPolicy, ReplayWindow};
+    use entropy::EntropyStatus;
+    use events::{KeyEvent, ReplayEvent, Subscribers};
+    use storm::{ReplayStorm, StormDetector};
+    use flushcontrol::{FlushAdaptation, FlushBounds, FlushController};
+    use timesource::{ClockSource, MonotonicClock, SystemMonotonicClock};
+    use unwrap::UnwrapBatch;
+    use version::{CACHE_FORMAT_VERSION, CRATE_VERSION};
+    use writeback::{WriteBackStore, WriteBatch};
+    #[cfg(feature = "metrics")]
+    use metrics::{EpochGauges, Metrics};
+    #[cfg(feature = "accumulator")]
+    use accumulator::{Accumulator, AccumulatorRoot, InclusionProof};
+    #[cfg(feature = "katzenpost-compat")]
+    use katzenpost::KatzenpostKey;
+}
+
+
+cfg_std! {
+    const MIX_CACHE_KEY: &str = "private_key";
+    const EPOCH_KEY: &str = "epoch";
+    const PUBLIC_KEY_KEY: &str = "public_key";
+    const KEM_KEY: &str = "kem";
+    const STATE_KEY: &str = "state";
+    /// The metadata `MixKey::health` writes and reads back to probe a store.
+    const HEALTH_PROBE_KEY: &str = "health_probe";
+    const FORMAT_VERSION_KEY: &str = "format_version";
+    const WRITER_VERSION_KEY: &str = "writer_version";
+    const OVERFLOW_DIR_NAME: &str = "overflow";
+    /// Set once a namespace of the key was used, so that it is not frozen.
+    const NAMESPACES_KEY: &str = "namespaces";
+    /// The bytes of each tag the cache stores, if fewer than
+    /// `SPHINX_REPLAY_TAG_SIZE`.
+    const TAG_SIZE_KEY: &str = "tag_size";
+    /// The metadata a frozen cache keeps.
+    const FROZEN_METADATA_KEYS: [&str; 7] = [MIX_CACHE_KEY, PUBLIC_KEY_KEY, KEM_KEY, STATE_KEY, FORMAT_VERSION_KEY, WRITER_VERSION_KEY, TAG_SIZE_KEY];
+}
 
+/// The filter in front of each epoch's store: a quotient filter in
+/// builds with the `quotient` feature, otherwise a bloom filter, or a
+/// hash set in builds without the `bloom` feature.
+#[cfg(feature = "quotient")]
+pub(crate) type TagFilter = quotient::QuotientFilter;
+#[cfg(all(feature = "bloom", not(feature = "quotient")))]
+pub(crate) type TagFilter = scalable::ScalableBloomFilter;
+#[cfg(all(feature = "std", not(any(feature = "bloom", feature = "quotient"))))]
+pub(crate) type TagFilter = hashfilter::HashSetFilter;
 
+
+#[cfg(feature = "std")]
 #[derive(Clone)]
 pub struct MixKeys {
-    keys: Arc<Mutex<HashMap<u64, MixKey>>>,
-    clock: Clock,
+    keys: Arc<RwLock<HashMap<u64, MixKey>>>,
+    clock: Arc<dyn ClockSource>,
     num_mix_keys: u8,
+    publish_ahead: u8,
     base_dir: String,
     line_rate: u64,
+    idle_period: u64,
+    timer: Arc<dyn MonotonicClock>,
+    provider: Arc<dyn KeyProvider>,
+    backend: CacheBackend,
+    stores: Option<Arc<dyn ReplayStoreFactory>>,
+    cache_config: CacheConfig,
+    memory_budget: Option<u64>,
+    base_dir_quota: Option<u64>,
+    grace_period: u64,
+    early_tags: EarlyTagPolicy,
+    future_policy: FutureCachePolicy,
+    future_caches: Vec<u64>,
+    clock_rollback: ClockRollbackPolicy,
+    highest_epoch: Arc<Mutex<u64>>,
+    high_water: Option<HighWaterMark>,
+    journal: Option<RolloverJournal>,
+    active: Arc<Mutex<Option<u64>>>,
+    events: Arc<Mutex<Subscribers>>,
+    replay_events: Arc<Mutex<Subscribers<ReplayEvent>>>,
+    storm: Option<Arc<Mutex<StormDetector>>>,
+    flushes: Arc<Mutex<FlushController>>,
+    flush_due_at: Arc<Mutex<Option<Duration>>>,
+    alert_sink: Arc<dyn AlertSink>,
+    capacity: Arc<Mutex<CapacityForecaster>>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+    #[cfg(feature = "archive")]
+    archive_dir: Option<PathBuf>,
+    _lock: Option<Arc<BaseDirLock>>,
 }
 
+#[cfg(feature = "std")]
 impl MixKeys {
+    /// Returns a builder for configuring a `MixKeys` beyond what the
+    /// constructors below allow.
+    pub fn builder<C: ClockSource + 'static>(clock: C) -> MixKeysBuilder {
+        MixKeysBuilder::new(clock)
+    }
+
     pub fn new(clock: Clock, num_mix_keys: u8, base_dir: String, line_rate: u64) -> Result<Self, MixKeyError> {
+        MixKeys::with_key_provider(clock, num_mix_keys, base_dir, line_rate, Arc::new(LocalKeyProvider))
+    }
+
+    /// Like `new`, but every epoch's private key is derived from the
+    /// given master seed of at least `keyprovider::MIN_SEED_SIZE` bytes,
+    /// so that the keys can be restored from the seed alone.
+    pub fn new_with_seed(clock: Clock, num_mix_keys: u8, base_dir: String, line_rate: u64, seed: &[u8]) -> Result<Self, MixKeyError> {
+        let provider = SeedKeyProvider::new(seed)?;
+        MixKeys::with_key_provider(clock, num_mix_keys, base_dir, line_rate, Arc::new(provider))
+    }
+
+    /// Like `new`, but the keys and their tags are only kept in memory,
+    /// so nothing is written to disk and nothing survives a restart.
+    pub fn in_memory(clock: Clock, num_mix_keys: u8, line_rate: u64) -> Result<Self, MixKeyError> {
+        MixKeys::with_backend(clock, num_mix_keys, String::new(), line_rate, Arc::new(LocalKeyProvider), CacheBackend::Memory)
+    }
+
+    /// Like `new`, but the private keys are created and held by the
+    /// given key provider.
+    pub fn with_key_provider(clock: Clock, num_mix_keys: u8, base_dir: String, line_rate: u64, provider: Arc<dyn KeyProvider>) -> Result<Self, MixKeyError> {
+        MixKeys::with_backend(clock, num_mix_keys, base_dir, line_rate, provider, CacheBackend::Sled)
+    }
+
+    /// Like `with_key_provider`, but every key keeps its tags in the
+    /// given backend. `base_dir` is neither locked nor used by the
+    /// `Memory` backend. `Custom` stores are opened by the factory given
+    /// to `with_store_factory` instead.
+    pub fn with_backend(clock: Clock, num_mix_keys: u8, base_dir: String, line_rate: u64, provider: Arc<dyn KeyProvider>, backend: CacheBackend) -> Result<Self, MixKeyError> {
+        MixKeysBuilder::new(clock)
+            .num_mix_keys(num_mix_keys)
+            .base_dir(base_dir)
+            .line_rate(line_rate)
+            .key_provider(provider)
+            .backend(backend)
+            .build()
+    }
+
+    /// Like `with_key_provider`, but every key keeps its tags in the
+    /// store the factory opens for its epoch. Processes whose factories
+    /// open the same shared store share a single set of tags, and agree
+    /// on each epoch's key, provided their providers can open the keys
+    /// the others generate.
+    pub fn with_store_factory(clock: Clock, num_mix_keys: u8, line_rate: u64, provider: Arc<dyn KeyProvider>, stores: Arc<dyn ReplayStoreFactory>) -> Result<Self, MixKeyError> {
+        MixKeysBuilder::new(clock)
+            .num_mix_keys(num_mix_keys)
+            .line_rate(line_rate)
+            .key_provider(provider)
+            .store_factory(stores)
+            .build()
+    }
+
+    /// Like `with_store_factory`, but the tags are kept in a sled tree
+    /// the application already runs, under keys prefixed with the given
+    /// namespace. See `store::SledTreeStores`.
+    pub fn with_sled_tree(clock: Clock, num_mix_keys: u8, line_rate: u64, provider: Arc<dyn KeyProvider>, tree: Tree, namespace: &str) -> Result<Self, MixKeyError> {
+        MixKeys::with_store_factory(clock, num_mix_keys, line_rate, provider, Arc::new(SledTreeStores::new(tree, namespace)))
+    }
+
+    fn open(builder: MixKeysBuilder) -> Result<Self, MixKeyError> {
+        let base_dir = builder.base_dir;
+        let (lock, journal, high_water) = match builder.backend {
+            CacheBackend::Sled => (Some(Arc::new(BaseDirLock::acquire(Path::new(&base_dir))?)), Some(RolloverJournal::new(Path::new(&base_dir))),
+                                   Some(HighWaterMark::new(Path::new(&base_dir)))),
+            _ => (None, None, None),
+        };
+        if lock.is_some() {
+            fsutil::clear_staging(Path::new(&base_dir))?;
+        }
         let mut m = MixKeys{
-            keys: Arc::new(Mutex::new(HashMap::new())),
-            clock: clock,
-            num_mix_keys: num_mix_keys,
+            keys: Arc::new(RwLock::new(HashMap::new())),
+            clock: builder.clock,
+            num_mix_keys: builder.num_mix_keys,
+            publish_ahead: builder.publish_ahead,
             base_dir: base_dir,
-            line_rate: line_rate,
+            line_rate: builder.line_rate,
+            idle_period: MIX_KEY_IDLE_PERIOD,
+            timer: builder.timer,
+            provider: builder.provider,
+            backend: builder.backend,
+            stores: builder.stores,
+            cache_config: builder.cache,
+            memory_budget: builder.memory_budget,
+            base_dir_quota: builder.base_dir_quota,
+            grace_period: builder.grace_period,
+            early_tags: builder.early_tags,
+            future_policy: builder.future_caches,
+            future_caches: vec![],
+            clock_rollback: builder.clock_rollback,
+            highest_epoch: Arc::new(Mutex::new(0)),
+            high_water: high_water,
+            journal: journal,
+            active: Arc::new(Mutex::new(None)),
+            events: Arc::new(Mutex::new(Subscribers::default())),
+            replay_events: Arc::new(Mutex::new(Subscribers::default())),
+            storm: builder.replay_storm.map(|config| Arc::new(Mutex::new(StormDetector::new(config)))),
+            flushes: Arc::new(Mutex::new(FlushController::new(builder.flush_bounds))),
+            flush_due_at: Arc::new(Mutex::new(None)),
+            alert_sink: Arc::new(LogAlertSink),
+            capacity: Arc::new(Mutex::new(CapacityForecaster::default())),
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(Metrics::with_labels(&builder.node_id, builder.backend)),
+            #[cfg(feature = "archive")]
+            archive_dir: None,
+            _lock: lock,
         };
         m.init()?;
         Ok(m)
     }
 
+    /// Check, without creating any keys, that this host has the disk
+    /// space, memory, file descriptors and write throughput a mix with
+    /// the given configuration needs.
+    pub fn preflight(config: &PreflightConfig) -> Result<PreflightReport, MixKeyError> {
+        preflight::run(config)
+    }
+
     /// Generate or load the initial set of MixKey.
     fn init(&mut self) -> Result<(), MixKeyError> {
         let time = self.clock.now();
-        let _ = self.generate(time.epoch)?;
-        // Clean up stale mix keys.
-        // XXX...
+        if let Some(ref high_water) = self.high_water {
+            *self.highest_epoch.lock().unwrap() = high_water.load().context(time.epoch, Op::LoadEpoch, high_water.path())?.unwrap_or(0);
+        }
+        match self.generate(time.epoch) {
+            Err(MixKeyError::ClockRollback{epoch, highest}) if self.clock_rollback == ClockRollbackPolicy::Gate => {
+                warn!("clock reports epoch {} but epoch {} was already seen; starting with only the existing keys", epoch, highest);
+            },
+            Err(e) => return Err(e),
+            Ok(_) => {},
+        }
+        let removed = self.remove_stale()?;
+        if removed > 0 {
+            info!("removed {} stale mix key caches", removed);
+        }
+        self.future_caches = self.handle_future_caches(time.epoch)?;
+        self.resume_rollover()
+    }
+
+    /// Find the caches in `base_dir` for epochs beyond the keys kept
+    /// from the given epoch, and apply the future cache policy to them.
+    fn handle_future_caches(&self, epoch: u64) -> Result<Vec<u64>, MixKeyError> {
+        if self.backend != CacheBackend::Sled {
+            return Ok(vec![])
+        }
+        let horizon = epoch + self.window();
+        let mut found = vec![];
+        for entry in fs::read_dir(&self.base_dir)? {
+            let entry = entry?;
+            match entry.file_name().to_str().and_then(fsutil::parse_epoch_dir) {
+                Some(x) if x >= horizon => found.push((x, entry.path())),
+                _ => {},
+            }
+        }
+        found.sort();
+        for &(future, ref path) in &found {
+            warn!("found a mix key cache for epoch {}, {} epochs ahead of the clock, applying {:?}", future, future - epoch, self.future_policy);
+            match self.future_policy {
+                FutureCachePolicy::Quarantine => {
+                    let target = fsutil::quarantine_dir(Path::new(&self.base_dir), future, self.clock.unix_time());
+                    fs::create_dir_all(target.parent().unwrap()).context(future, Op::QuarantineCache, path)?;
+                    fs::rename(path, &target).context(future, Op::QuarantineCache, path)?;
+                },
+                FutureCachePolicy::Adopt => {},
+                FutureCachePolicy::Delete => fsutil::wipe_dir(path).context(future, Op::RemoveCache, path)?,
+            }
+        }
+        Ok(found.into_iter().map(|(future, _)| future).collect())
+    }
+
+    /// Returns the epochs of the caches found at startup beyond the
+    /// keys kept, which the future cache policy was applied to. Anything
+    /// here suggests the clock was set back or an old backup restored.
+    pub fn future_caches(&self) -> &[u64] {
+        &self.future_caches
+    }
+
+    /// Finish a rollover that was interrupted by a crash, provided its
+    /// epoch is still live.
+    fn resume_rollover(&mut self) -> Result<(), MixKeyError> {
+        let record = match self.load_rollover(self.clock.now().epoch)? {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if !self.is_live(record.epoch, &self.clock.now()) {
+            return Ok(())
+        }
+        if record.stage < RolloverStage::PruneScheduled {
+            info!("resuming interrupted rollover to epoch {}", record.epoch);
+            return self.rollover(record.epoch)
+        }
+        *self.active.lock().unwrap() = Some(record.epoch);
+        Ok(())
+    }
+
+    fn load_rollover(&self, epoch: u64) -> Result<Option<RolloverRecord>, MixKeyError> {
+        match self.journal {
+            Some(ref journal) => journal.load().context(epoch, Op::Rollover, journal.path()),
+            None => Ok(None),
+        }
+    }
+
+    fn store_rollover(&self, epoch: u64, stage: RolloverStage) -> Result<(), MixKeyError> {
+        if let Some(ref journal) = self.journal {
+            journal.store(&RolloverRecord{
+                epoch: epoch,
+                stage: stage,
+            }).context(epoch, Op::Rollover, journal.path())?;
+        }
+        Ok(())
+    }
+
+    /// Roll over to a new epoch: generate and flush the keys from `epoch`
+    /// onwards, make the epoch's key the active key and schedule the
+    /// previous key to be pruned once the grace period has passed. Each
+    /// stage is journaled, and stages the journal shows as already done
+    /// for this epoch are skipped, so an interrupted rollover can simply
+    /// be repeated.
+    pub fn rollover(&mut self, epoch: u64) -> Result<(), MixKeyError> {
+        #[cfg(feature = "tracing")]
+        let _span = info_span!("rollover", epoch = epoch).entered();
+        let done = match self.load_rollover(epoch)? {
+            Some(ref record) if record.epoch == epoch => Some(record.stage),
+            _ => None,
+        };
+        if done.is_none() {
+            self.store_rollover(epoch, RolloverStage::Started)?;
+        }
+        if done < Some(RolloverStage::Generated) {
+            self.generate(epoch)?;
+            for (_epoch, mut key) in self.snapshot_keys().into_iter().filter(|&(e, _)| e >= epoch) {
+                key.flush()?;
+            }
+            self.store_rollover(epoch, RolloverStage::Generated)?;
+        }
+        *self.active.lock().unwrap() = Some(epoch);
+        self.update_states()?;
+        if done < Some(RolloverStage::Activated) {
+            self.store_rollover(epoch, RolloverStage::Activated)?;
+            let previous = epoch.checked_sub(1).filter(|e| self.keys.read().unwrap().contains_key(e));
+            if let Some(previous) = previous {
+                self.publish(KeyEvent::KeyExpired{ epoch: previous });
+            }
+        }
+        if done < Some(RolloverStage::PruneScheduled) {
+            self.store_rollover(epoch, RolloverStage::PruneScheduled)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a receiver of every key lifecycle event from now on:
+    /// keys generated, epochs ended at a rollover, and keys pruned or
+    /// revoked.
+    pub fn subscribe(&self) -> Receiver<KeyEvent> {
+        self.events.lock().unwrap().subscribe()
+    }
+
+    fn publish(&self, event: KeyEvent) {
+        self.events.lock().unwrap().publish(event);
+    }
+
+    /// Returns a receiver of every replay the keys detect from now on,
+    /// with its epoch, tag and time.
+    pub fn subscribe_replays(&self) -> Receiver<ReplayEvent> {
+        self.replay_events.lock().unwrap().subscribe()
+    }
+
+    /// Returns a receiver of every replay storm raised from now on, or
+    /// None unless `MixKeysBuilder::replay_storm_alarm` was set.
+    pub fn subscribe_storms(&self) -> Option<Receiver<ReplayStorm>> {
+        self.storm.as_ref().map(|storm| storm.lock().unwrap().subscribe())
+    }
+
+    /// Returns the epoch of the key activated by the latest rollover.
+    pub fn active_epoch(&self) -> Option<u64> {
+        *self.active.lock().unwrap()
+    }
+
+    /// Wipe the cache directories in `base_dir` belonging to epochs
+    /// whose grace period has ended and that have no key, returning how
+    /// many were removed. Directories of later epochs are left to the
+    /// future cache policy.
+    pub fn remove_stale(&mut self) -> Result<usize, MixKeyError> {
+        if self.backend != CacheBackend::Sled {
+            return Ok(0)
+        }
+        let time = self.clock.now();
+        let mut stale = vec![];
+        {
+            let keys = self.keys.read().unwrap();
+            for entry in fs::read_dir(&self.base_dir)? {
+                let entry = entry?;
+                let epoch = match entry.file_name().to_str().and_then(fsutil::parse_epoch_dir) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if epoch + 1 < time.epoch && !self.is_live(epoch, &time) && !keys.contains_key(&epoch) {
+                    stale.push((epoch, entry.path()));
+                }
+            }
+        }
+        for &(epoch, ref path) in &stale {
+            fsutil::wipe_dir(path).context(epoch, Op::RemoveCache, path)?;
+        }
+        Ok(stale.len())
+    }
+
+    /// Returns the highest epoch the clock has reported.
+    pub fn highest_epoch(&self) -> u64 {
+        *self.highest_epoch.lock().unwrap()
+    }
+
+    /// Record the clock's epoch if it is the highest seen, or return
+    /// `ClockRollback` if it is below it.
+    fn observe_clock(&self) -> Result<(), MixKeyError> {
+        let epoch = self.clock.now().epoch;
+        let mut highest = self.highest_epoch.lock().unwrap();
+        if epoch < *highest {
+            return Err(MixKeyError::ClockRollback{
+                epoch: epoch,
+                highest: *highest,
+            })
+        }
+        if epoch > *highest {
+            if let Some(ref high_water) = self.high_water {
+                high_water.store(epoch).context(epoch, Op::StoreEpoch, high_water.path())?;
+            }
+            *highest = epoch;
+        }
         Ok(())
     }
 
+    /// Returns true if a cache, and so a key, already exists for the
+    /// epoch.
+    fn has_cache(&self, epoch: u64) -> Result<bool, MixKeyError> {
+        match self.stores {
+            Some(ref stores) => {
+                let mut store = stores.open(epoch).context(epoch, Op::OpenCache, &fsutil::epoch_dir(Path::new(""), epoch))?;
+                Ok(store.metadata(MIX_CACHE_KEY).context(epoch, Op::LoadKey, &fsutil::epoch_dir(Path::new(""), epoch))?.is_some())
+            },
+            None => Ok(self.backend == CacheBackend::Sled && fsutil::epoch_dir(Path::new(&self.base_dir), epoch).exists()),
+        }
+    }
+
+    /// Returns the number of epochs, starting with the current one, that
+    /// keys are kept for: `num_mix_keys`, or more to publish ahead.
+    fn window(&self) -> u64 {
+        (self.num_mix_keys as u64).max(self.publish_ahead as u64 + 1)
+    }
+
+    /// Generate or load the keys of the epochs from `base_epoch` on,
+    /// `num_mix_keys` of them or enough to publish ahead. While the clock
+    /// reports an earlier epoch than it did before, keys below the
+    /// highest epoch seen are only loaded, and `ClockRollback` is
+    /// returned once the others are generated.
     pub fn generate(&mut self, base_epoch: u64) -> Result<bool, MixKeyError> {
+        let rollback = match self.observe_clock() {
+            Err(MixKeyError::ClockRollback{epoch, highest}) => Some((epoch, highest)),
+            Err(e) => return Err(e),
+            Ok(()) => None,
+        };
+        let mut refused = false;
         let mut did_generate = false;
-        for epoch in base_epoch..base_epoch+self.num_mix_keys as u64{
-            if let Some(_key) = self.keys.lock().unwrap().get(&epoch) {
+        for epoch in base_epoch..base_epoch+self.window() {
+            if self.keys.read().unwrap().contains_key(&epoch) {
                 continue
             }
-            let key = MixKey::new(self.line_rate, epoch, self.clock.period(), &self.base_dir)?;
+            if let Some((_, highest)) = rollback {
+                if epoch < highest && !self.has_cache(epoch)? {
+                    warn!("not creating a key for epoch {} after a clock rollback", epoch);
+                    refused = true;
+                    continue
+                }
+            }
+            let key = self.open_key(epoch)?;
             did_generate = true;
-            self.keys.lock().unwrap().insert(epoch, key);
+            let public_key = key.public_key();
+            self.keys.write().unwrap().insert(epoch, key);
+            self.publish(KeyEvent::KeyGenerated{
+                epoch: epoch,
+                public_key: public_key,
+            });
+        }
+        match rollback {
+            Some((epoch, highest)) if refused => Err(MixKeyError::ClockRollback{
+                epoch: epoch,
+                highest: highest,
+            }),
+            _ => Ok(did_generate),
         }
-        Ok(did_generate)
     }
 
-    pub fn prune(&mut self) -> bool {
-        let mut did_prune = false;
-        let time = self.clock.now();
-        self.keys.lock().unwrap().retain(|key, _value| {
-            if *key < time.epoch - 1 {
-                did_prune = true;
-                return true
+    /// Generate or load the key of the epoch, in the lifecycle state the
+    /// clock puts it in.
+    fn open_key(&self, epoch: u64) -> Result<MixKey, MixKeyError> {
+        #[cfg(feature = "tracing")]
+        let _span = info_span!("generate_key", epoch = epoch).entered();
+        let config = self.key_cache_config();
+        let mut key = match self.stores {
+            Some(ref stores) => {
+                let store = stores.open(epoch).context(epoch, Op::OpenCache, &fsutil::epoch_dir(Path::new(""), epoch))?;
+                let path = fsutil::epoch_dir(Path::new(""), epoch);
+                MixKey::from_store(CacheBackend::Custom, self.provider.as_ref(), self.line_rate, epoch, self.clock.period(), &config, store, path)?
+            },
+            None => MixKey::with_config(self.backend, self.provider.as_ref(), self.line_rate, epoch, self.clock.period(), &self.base_dir, &config)?,
+        };
+        key.set_monotonic_clock(self.timer.clone());
+        key.replay_events = self.replay_events.clone();
+        key.storm = self.storm.clone();
+        #[cfg(feature = "metrics")]
+        key.set_metrics(self.metrics.clone());
+        key.advance(KeyState::at(epoch, &self.clock.now(), self.grace_period))?;
+        Ok(key)
+    }
+
+    /// Revoke the epoch's key, as when its private key may have leaked,
+    /// and replace it with a new key for the rest of the epoch, returning
+    /// the new public key. The old key is wiped from memory and its cache
+    /// destroyed before the new key is generated, and `KeyRevoked` is
+    /// published so that the new key can be uploaded to the PKI. Tags
+    /// seen by the old key are not carried over, since packets for the
+    /// new key derive different tags. Keys of a `SeedKeyProvider` can
+    /// not be replaced and fail with `RevocationUnsupported`.
+    ///
+    /// The revoked key stays in place, refusing packets, until its
+    /// replacement is. If the replacement can not be generated,
+    /// `KeyRevoked` is published without a public key and the error
+    /// returned; revoking the epoch again retries.
+    pub fn revoke(&mut self, epoch: u64) -> Result<PublicKey, MixKeyError> {
+        let path = fsutil::epoch_dir(Path::new(&self.base_dir), epoch);
+        if !self.provider.can_replace() {
+            return Err(MixKeyError::RevocationUnsupported.context(epoch, Op::RevokeKey, &path))
+        }
+        let old = match self.keys.read().unwrap().get(&epoch) {
+            Some(key) => key.clone(),
+            None => return Err(MixKeyError::UnknownEpoch(epoch).context(epoch, Op::RevokeKey, &path)),
+        };
+        let revoked = match old.state() {
+            KeyState::Revoked => old.clone().destroy(),
+            _ => old.clone().revoke(),
+        };
+        let replaced = revoked.and_then(|()| {
+            if let Some(ref stores) = self.stores {
+                stores.remove(epoch)?;
             }
-            return false
+            self.open_key(epoch)
         });
-        did_prune
+        match replaced {
+            Ok(key) => {
+                let public_key = key.public_key();
+                self.keys.write().unwrap().insert(epoch, key);
+                warn!("revoked the key of epoch {}", epoch);
+                self.publish(KeyEvent::KeyRevoked{
+                    epoch: epoch,
+                    public_key: Some(public_key),
+                });
+                Ok(public_key)
+            },
+            Err(e) => {
+                if old.state() == KeyState::Revoked {
+                    warn!("revoked the key of epoch {} without a replacement: {}", epoch, e);
+                    self.publish(KeyEvent::KeyRevoked{
+                        epoch: epoch,
+                        public_key: None,
+                    });
+                }
+                Err(e.context(epoch, Op::RevokeKey, &path))
+            },
+        }
     }
 
-    pub fn public_key(&self, epoch: u64) -> Option<PublicKey> {
-        if let Some(ref key) = self.keys.lock().unwrap().get(&epoch) {
-            let k = key.public_key();
-            return Some(k)
+    /// Returns the configuration of each new key: the cache
+    /// configuration, fitted to a share of the memory budget and of the
+    /// base directory's disk quota if they are set. Both are shared by
+    /// the current and upcoming keys and the previous epoch's key.
+    fn key_cache_config(&self) -> CacheConfig {
+        let live_keys = self.window() + 1;
+        let mut config = match self.memory_budget {
+            Some(budget) => self.cache_config.within_budget(budget / live_keys, self.backend == CacheBackend::Sled,
+                                                            self.line_rate, self.clock.period()),
+            None => self.cache_config,
+        };
+        if let Some(quota) = self.base_dir_quota {
+            let share = quota / live_keys;
+            config.disk_quota = Some(config.disk_quota.map_or(share, |x| x.min(share)));
         }
-        None
+        config
     }
 
-    pub fn shadow(&mut self, dst: &mut HashMap<u64, MixKey>) {
-        dst.retain(|key, _value| {
-            self.keys.lock().unwrap().contains_key(key)
-        });
-        for (key, val) in self.keys.lock().unwrap().iter() {
-            if !dst.contains_key(&key) {
-                dst.insert(*key, val.clone());
+    /// Returns the bytes the filters and sled caches of the live keys
+    /// share, if a memory budget was set.
+    pub fn memory_budget(&self) -> Option<u64> {
+        self.memory_budget
+    }
+
+    /// Returns the bytes of disk the cache directories of the live keys
+    /// share, if a base directory quota was set.
+    pub fn base_dir_quota(&self) -> Option<u64> {
+        self.base_dir_quota
+    }
+
+    /// Returns true if the key for the given epoch may be used: keys for
+    /// the current and future epochs always may, and the key for the
+    /// previous epoch may until the grace period has passed.
+    fn is_live(&self, epoch: u64, now: &Time) -> bool {
+        epoch >= now.epoch || (epoch + 1 == now.epoch && now.elapsed < self.grace_period)
+    }
+
+    /// Move every key forward to the lifecycle state the clock puts it
+    /// in.
+    fn update_states(&self) -> Result<(), MixKeyError> {
+        let now = self.clock.now();
+        for (epoch, key) in self.snapshot_keys() {
+            key.advance(KeyState::at(epoch, &now, self.grace_period))?;
+            if self.cache_config.freeze && key.state() == KeyState::Grace {
+                if let Err(e) = key.freeze() {
+                    warn!("failed to freeze mix key cache of epoch {}: {}", epoch, e);
+                }
             }
         }
+        Ok(())
     }
-}
 
+    /// Returns the lifecycle state of every key, ordered by epoch.
+    pub fn key_states(&self) -> Vec<(u64, KeyState)> {
+        let mut states: Vec<(u64, KeyState)> = self.keys.read().unwrap().iter().map(|(epoch, key)| (*epoch, key.state())).collect();
+        states.sort();
+        states
+    }
 
+    /// Returns the number of seconds the previous epoch's key is kept
+    /// after each epoch boundary.
+    pub fn grace_period(&self) -> u64 {
+        self.grace_period
+    }
 
-#[derive(PartialEq, Eq, Hash)]
-pub struct Tag([u8; SPHINX_REPLAY_TAG_SIZE]);
+    /// Remove the keys that are no longer live, closing their stores and
+    /// destroying their caches, and return their epochs. Every key is
+    /// first moved to the lifecycle state the clock puts it in, and the
+    /// keys that no longer check tags, such as frozen keys, are removed:
+    /// keys of the current and future epochs are kept, as is the
+    /// previous epoch's key until the grace period has passed. If an archive directory
+    /// is set each key's tags are archived first, and a key whose archive
+    /// fails is kept until a later prune succeeds. A cache that can not
+    /// be removed is left to `remove_stale` at the next start.
+    pub fn prune(&mut self) -> Vec<u64> {
+        #[cfg(feature = "tracing")]
+        let _span = info_span!("prune").entered();
+        let mut pruned = vec![];
+        if let Err(e) = self.update_states() {
+            warn!("failed to update mix key states: {}", e);
+        }
+        let time = self.clock.now();
+        let mut keys = self.keys.write().unwrap();
+        let stale: Vec<u64> = keys.iter().filter(|&(_, key)| !key.state().is_usable()).map(|(epoch, _)| *epoch).collect();
+        for epoch in stale {
+            #[cfg(feature = "archive")]
+            {
+                if let Some(ref archive_dir) = self.archive_dir {
+                    if let Err(e) = keys[&epoch].archive(archive_dir) {
+                        warn!("failed to archive mix key cache: {}", e);
+                        continue
+                    }
+                }
+            }
+            pruned.push(keys.remove(&epoch).unwrap());
+            self.publish(KeyEvent::KeyPruned{ epoch: epoch });
+        }
+        if !pruned.is_empty() {
+            match self.load_rollover(time.epoch) {
+                Ok(Some(record)) => {
+                    let previous_pruned = record.epoch.checked_sub(1).map_or(true, |e| !keys.contains_key(&e));
+                    if record.stage == RolloverStage::PruneScheduled && previous_pruned {
+                        if let Err(e) = self.store_rollover(record.epoch, RolloverStage::Complete) {
+                            warn!("failed to complete rollover journal: {}", e);
+                        }
+                    }
+                },
+                Ok(None) => {},
+                Err(e) => warn!("failed to load rollover journal: {}", e),
+            }
+        }
+        drop(keys);
+        // Wiping a cache takes a while, so it is done without holding
+        // the keys lock.
+        let mut epochs = vec![];
+        for key in pruned {
+            let epoch = key.epoch();
+            if let Err(e) = key.destroy() {
+                warn!("failed to remove mix key cache of epoch {}: {}", epoch, e);
+            }
+            if let Some(ref stores) = self.stores {
+                if let Err(e) = stores.remove(epoch) {
+                    warn!("failed to remove mix key store of epoch {}: {}", epoch, e);
+                }
+            }
+            epochs.push(epoch);
+        }
+        epochs.sort();
+        epochs
+    }
 
-impl Tag {
-    pub fn new(tag: [u8; SPHINX_REPLAY_TAG_SIZE]) -> Self {
-        Tag(tag)
+    /// Archive the tags of every pruned key into `archive_dir`, creating
+    /// it if needed.
+    #[cfg(feature = "archive")]
+    pub fn set_archive_dir(&mut self, archive_dir: PathBuf) -> Result<(), MixKeyError> {
+        fs::create_dir_all(&archive_dir)?;
+        self.archive_dir = Some(archive_dir);
+        Ok(())
     }
 
-    fn to_vec(&self) -> Vec<u8> {
-        self.0.to_vec()
+    /// Set the monotonic clock used for idle tracking and flush
+    /// scheduling of all current and future keys.
+    pub fn set_monotonic_clock(&mut self, timer: Arc<dyn MonotonicClock>) {
+        self.timer = timer;
+        for (_epoch, key) in self.keys.write().unwrap().iter_mut() {
+            key.set_monotonic_clock(self.timer.clone());
+        }
     }
-}
 
-impl Clone for Tag {
-    fn clone(&self) -> Tag {
-        Tag(self.0)
+    /// Flush every key whose last flush is at least the flush interval
+    /// ago, returning the flushed epochs. The interval starts at
+    /// `MIX_KEY_FLUSH_FREQUENCY` milliseconds and is adapted to how long
+    /// the flushes take, see the `flushcontrol` module. Keys whose
+    /// durability policy is not an interval are never flushed here. A
+    /// failed flush does not keep the other keys from being flushed; the
+    /// first failure is returned once they are.
+    pub fn flush_due(&mut self) -> Result<Vec<u64>, MixKeyError> {
+        let mut flushes = self.flushes.lock().unwrap();
+        *self.flush_due_at.lock().unwrap() = Some(self.timer.now());
+        let mut flushed = vec![];
+        let mut result = Ok(());
+        for (epoch, mut key) in self.snapshot_keys() {
+            let due = match key.flush_if_due(flushes.interval()) {
+                Ok(due) => due,
+                Err(e) => {
+                    warn!("failed to flush the cache of epoch {}: {}", epoch, e);
+                    result = result.and(Err(e));
+                    false
+                },
+            };
+            if due {
+                flushed.push(epoch);
+                let adaptation = flushes.observe(self.timer.now(), epoch, key.flush_duration());
+                if adaptation.map_or(false, |x| x.stalled) {
+                    #[cfg(feature = "metrics")]
+                    self.metrics.flush_stalled();
+                }
+            }
+        }
+        flushed.sort();
+        drop(flushes);
+        let due = self.capacity.lock().unwrap().is_due(self.timer.now(), Duration::from_secs(MIX_KEY_CAPACITY_CHECK_INTERVAL));
+        if due {
+            if let Err(e) = self.check_capacity() {
+                warn!("capacity check failed: {}", e);
+            }
+        }
+        result.map(|_| flushed)
     }
-}
 
-#[derive(Clone)]
-pub struct MixKey {
-    filter: Arc<Mutex<BloomFilter<RandomState, RandomState>>>,
-    cache: Arc<Mutex<Tree>>,
-    private_key: PrivateKey,
-    epoch: u64,
-}
+    /// Deliver capacity alarms to the given sink rather than the log.
+    pub fn set_alert_sink(&mut self, sink: Arc<dyn AlertSink>) {
+        self.alert_sink = sink;
+    }
 
-impl MixKey {
-    pub fn new(line_rate: u64, epoch: u64, epoch_duration: u64, base_dir: &String) -> Result<MixKey, MixKeyError> {
-        let false_positive_rate: f32 = 0.01;
-        let expected_num_items: u32 = (line_rate as f64 / PACKET_SIZE as f64) as u32 * epoch_duration as u32;
-        let cache_capacity: usize = (((epoch_duration * line_rate) / PACKET_SIZE as u64) as usize * SPHINX_REPLAY_TAG_SIZE) / 2;
-
-        let cache_cfg_builder = sled::ConfigBuilder::default()
-            .path(Path::new(base_dir).join(format!("mix_key.{}", epoch)))
-            .cache_capacity(cache_capacity)
-            .use_compression(false)
-            .flush_every_ms(Some(MIX_KEY_FLUSH_FREQUENCY))
-            .snapshot_after_ops(100_000); // XXX
-        let cache_cfg = cache_cfg_builder.build();
-
-        let cache = match Tree::start(cache_cfg) {
-            Ok(x) => x,
-            Err(e) => {
-                print!("create cache failed: {}", e);
-                return Err(MixKeyError::CreateCacheFailed);
-            },
+    /// Forecast, at each epoch's tag rate since the previous check,
+    /// whether its filter fills up or the disk runs out before the epoch
+    /// ends, returning the alarms in force. Newly raised alarms are sent
+    /// to the alert sink. See the `alarms` module.
+    pub fn check_capacity(&self) -> Result<Vec<CapacityAlarm>, MixKeyError> {
+        let now = self.clock.now();
+        let period = self.clock.period();
+        let mut keys = vec![];
+        for (epoch, key) in self.snapshot_keys() {
+            let remaining = KeyCountdown::new(epoch, &now, period, self.grace_period).until_expiry;
+            keys.push((key.stats()?, key.expected_tags(), remaining));
+        }
+        let free_disk = match self.backend {
+            CacheBackend::Sled => Some(fs2::available_space(&self.base_dir)?),
+            _ => None,
         };
+        let (alarms, raised) = self.capacity.lock().unwrap().check(self.timer.now(), &keys, free_disk);
+        for alarm in &alarms[..raised] {
+            self.alert_sink.alert(alarm);
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.capacity_alarms(&alarms, raised);
+        Ok(alarms)
+    }
 
-        if let Ok(Some(raw_epoch)) = cache.get(EPOCH_KEY.to_string().as_bytes()) {
-            let stored_epoch = LittleEndian::read_u64(&raw_epoch);
-            if epoch != stored_epoch {
-                warn!("mix key mismatched epoch during load.");
-                return Err(MixKeyError::LoadCacheFailed);
-            }
-        } else {
-            let mut raw_epoch = vec![0u8; 8];
-            LittleEndian::write_u64(&mut raw_epoch, epoch);
-            if let Err(e) = cache.set(raw_epoch, vec![]) {
-                warn!("mix key failed to set epoch in cache: {}", e);
-                return Err(MixKeyError::SledError);
-            }
+    /// Set the bounds within which `flush_due` adapts the flush
+    /// interval, restarting from the minimum.
+    pub fn set_flush_bounds(&mut self, bounds: FlushBounds) {
+        *self.flushes.lock().unwrap() = FlushController::new(bounds);
+    }
+
+    /// Returns the current flush interval.
+    pub fn flush_interval(&self) -> Duration {
+        self.flushes.lock().unwrap().interval()
+    }
+
+    /// Returns the bounds of the flush interval.
+    pub fn flush_bounds(&self) -> FlushBounds {
+        self.flushes.lock().unwrap().bounds()
+    }
+
+    /// Returns the number of flushes that took longer than the flush
+    /// interval since the bounds were last set.
+    pub fn flush_stalls(&self) -> u64 {
+        self.flushes.lock().unwrap().stalls()
+    }
+
+    /// Returns the recent changes to the flush interval and the stalled
+    /// flushes that caused them, oldest first.
+    pub fn flush_adaptations(&self) -> Vec<FlushAdaptation> {
+        self.flushes.lock().unwrap().history()
+    }
+
+    /// Returns the counters shared by all keys.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Render the metrics of all keys in the Prometheus text exposition
+    /// format.
+    #[cfg(feature = "metrics")]
+    pub fn render_metrics(&self) -> Result<String, MixKeyError> {
+        let mut epochs = vec![];
+        for (epoch, key) in self.keys.read().unwrap().iter() {
+            epochs.push(EpochGauges{
+                epoch: *epoch,
+                tags: key.tag_count(),
+                disk_bytes: match key.backend() {
+                    CacheBackend::Sled => fsutil::disk_usage(key.path())?,
+                    _ => 0,
+                },
+            });
         }
+        epochs.sort_by_key(|g| g.epoch);
+        Ok(self.metrics.render(self.clock.now().epoch, &epochs))
+    }
 
-        let mut private_key = PrivateKey::default();
-        if let Ok(Some(key_blob)) = cache.get(MIX_CACHE_KEY.to_string().as_bytes()) {
-            private_key.load_bytes(&key_blob)?;
-        } else {
-            let mut rng = OsRng::new()?;
-            private_key = PrivateKey::generate(&mut rng)?;
-            if let Err(e) = cache.set(MIX_CACHE_KEY.as_bytes().to_vec(), private_key.to_vec()) {
-                warn!("mix key failed to write to disk cache: {}", e);
-                return Err(MixKeyError::CreateCacheFailed);
-            }
+    /// Returns the durability policy the keys' caches are flushed with.
+    /// An interval policy reports the current, possibly backed off,
+    /// flush interval.
+    pub fn durability_policy(&self) -> DurabilityPolicy {
+        match self.cache_config.durability {
+            DurabilityPolicy::IntervalMs(_) => DurabilityPolicy::IntervalMs(self.flush_interval().as_millis() as u64),
+            durability => durability,
         }
+    }
 
-        Ok(MixKey{
-            filter: Arc::new(Mutex::new(BloomFilter::with_rate(false_positive_rate, expected_num_items))),
-            cache: Arc::new(Mutex::new(cache)),
-            private_key: private_key,
-            epoch: epoch,
-        })
+    /// Returns the worst case traffic that could be replayed after a
+    /// crash at the configured line rate, or None if it is unbounded.
+    pub fn worst_case_replay_window(&self) -> Option<ReplayWindow> {
+        self.durability_policy().worst_case_replay_window(self.line_rate)
     }
 
-    pub fn private_key(&self) -> &PrivateKey {
-        &self.private_key
+    /// Returns the lifecycle countdown of every live key, ordered by
+    /// epoch.
+    pub fn countdowns(&self) -> Vec<KeyCountdown> {
+        let now = self.clock.now();
+        let period = self.clock.period();
+        let mut countdowns: Vec<KeyCountdown> = self.keys.read().unwrap().keys()
+            .filter(|epoch| self.is_live(**epoch, &now))
+            .map(|epoch| KeyCountdown::new(*epoch, &now, period, self.grace_period))
+            .collect();
+        countdowns.sort_by_key(|countdown| countdown.epoch);
+        countdowns
     }
 
-    pub fn public_key(&self) -> PublicKey {
-        self.private_key.public_key()
+    /// Returns the UNIX time, in seconds, at which the epoch starts,
+    /// as the clock source tells it.
+    fn epoch_start(&self, epoch: u64) -> u64 {
+        self.clock.epoch_zero().saturating_add(epoch.saturating_mul(self.clock.period()))
     }
 
-    pub fn is_replay(&mut self, tag: Tag) -> Result<bool, MixKeyError> {
-        let maybe_replay = self.filter.lock().unwrap().contains(&tag);
-        if !maybe_replay {
-            self.filter.lock().unwrap().insert(&tag);
-            if let Ok(_v) = self.cache.lock().unwrap().set(tag.to_vec(), vec![]) {
-                return Ok(false)
-            } else {
-                return Err(MixKeyError::SledError)
+    /// Returns the epoch, public key and expiry of every live key, ordered
+    /// by epoch, for publishing.
+    pub fn key_info(&self) -> Vec<EpochKeyInfo> {
+        let now = self.clock.now();
+        let mut info: Vec<EpochKeyInfo> = self.keys.read().unwrap().iter()
+            .filter(|&(epoch, _)| self.is_live(*epoch, &now))
+            .map(|(epoch, key)| EpochKeyInfo{
+                epoch: *epoch,
+                public_key: key.public_key(),
+                expiry: self.epoch_start(*epoch + 1),
+            })
+            .collect();
+        info.sort_by_key(|info| info.epoch);
+        info
+    }
+
+    /// Returns an iterator over the epoch, public key and expiry of
+    /// every live key, in order of epoch, as `key_info` does.
+    pub fn iter(&self) -> vec::IntoIter<EpochKeyInfo> {
+        self.key_info().into_iter()
+    }
+
+    /// Returns the epochs of the live keys, in order.
+    pub fn epochs(&self) -> Vec<u64> {
+        self.iter().map(|info| info.epoch).collect()
+    }
+
+    /// Returns the epoch, public key and validity of the keys of the
+    /// current and upcoming epochs, ordered by epoch, for building the
+    /// PKI descriptor. A key is valid from `not_before`, the start of its
+    /// epoch, until `not_after`, the end of the grace period after it,
+    /// both in seconds since the UNIX epoch. How far ahead keys are
+    /// generated is set by `MixKeysBuilder::publish_ahead`.
+    pub fn upcoming_keys(&self) -> Vec<(u64, PublicKey, u64, u64)> {
+        let now = self.clock.now();
+        let mut keys: Vec<(u64, PublicKey, u64, u64)> = self.keys.read().unwrap().iter()
+            .filter(|&(epoch, key)| *epoch >= now.epoch && key.state().is_usable())
+            .map(|(epoch, key)| (*epoch, key.public_key(), self.epoch_start(*epoch), self.epoch_start(*epoch + 1) + self.grace_period))
+            .collect();
+        keys.sort_by_key(|key| key.0);
+        keys
+    }
+
+    /// Returns the public keys of the live epochs, as `key_info` does,
+    /// bundled for upload in the mix's PKI descriptor and signed by the
+    /// signer if one is given.
+    pub fn descriptor_bundle(&self, signer: Option<&dyn DescriptorSigner>) -> Result<DescriptorBundle, MixKeyError> {
+        let entries = self.key_info().into_iter().map(|info| DescriptorEntry{
+            epoch: info.epoch,
+            public_key: info.public_key,
+        }).collect();
+        DescriptorBundle::new(entries, signer)
+    }
+
+    /// Returns the statistics of every key, with their totals.
+    pub fn stats(&self) -> Result<MixKeysStats, MixKeyError> {
+        let epochs = self.snapshot_keys().iter().map(|(_, key)| key.stats()).collect::<Result<Vec<_>, _>>()?;
+        Ok(MixKeysStats::new(epochs))
+    }
+
+    /// Probe every key's store and key, and check that `flush_due` is
+    /// still being called. See the `health` module.
+    pub fn health(&self) -> HealthReport {
+        let mut keys: Vec<KeyHealth> = self.snapshot_keys().iter().map(|(_, key)| key.health(self.provider.as_ref())).collect();
+        keys.sort_by_key(|key| key.epoch);
+        let flush = match *self.flush_due_at.lock().unwrap() {
+            Some(at) => {
+                let since = self.timer.now().checked_sub(at).unwrap_or(Duration::from_secs(0));
+                let deadline = self.flush_bounds().max_interval * MIX_KEY_HEALTH_FLUSH_INTERVALS;
+                match since > deadline {
+                    true => Check::Failed(format!("flush_due last ran {:?} ago", since)),
+                    false => Check::Passed,
+                }
+            },
+            None => Check::Skipped,
+        };
+        HealthReport{
+            keys: keys,
+            flush: flush,
+        }
+    }
+
+    /// Warm up every key that is not warm, as `MixKey::warm_up` does,
+    /// returning the epochs warmed up.
+    pub fn warm_up(&self) -> Result<Vec<u64>, MixKeyError> {
+        let mut warmed = vec![];
+        for (epoch, key) in self.snapshot_keys() {
+            if !key.is_warm() {
+                key.warm_up()?;
+                warmed.push(epoch);
             }
         }
-        if let Ok(_) = self.cache.lock().unwrap().get(&tag.0) {
-            return Ok(true)
-        } else {
-            self.filter.lock().unwrap().insert(&tag);
-            if let Ok(_v) = self.cache.lock().unwrap().set(tag.to_vec(), vec![]) {
-                return Ok(false)
-            } else {
-                return Err(MixKeyError::SledError)
+        warmed.sort();
+        Ok(warmed)
+    }
+
+    /// Returns true once every key is warm, so that a mix can hold back
+    /// from announcing itself until its first packets will be served
+    /// at full speed.
+    pub fn is_ready(&self) -> bool {
+        self.snapshot_keys().iter().all(|&(_, ref key)| key.is_warm())
+    }
+
+    /// Set the number of seconds a key may go without processing a
+    /// packet before `shed_idle` releases its resources.
+    pub fn set_idle_period(&mut self, idle_period: u64) {
+        self.idle_period = idle_period;
+    }
+
+    /// Release the filter memory and cache handles of every key that
+    /// has been idle for the idle period, returning the shed epochs.
+    /// Shed keys are lazily reopened on their next replay check. A key
+    /// whose cache fails to flush is kept, and tried again next time.
+    pub fn shed_idle(&mut self) -> Vec<u64> {
+        let mut shed = vec![];
+        for (epoch, mut key) in self.snapshot_keys() {
+            if key.backend() == CacheBackend::Sled && !key.is_shed() && key.is_idle(self.idle_period) {
+                match key.shed() {
+                    Ok(()) => shed.push(epoch),
+                    Err(e) => warn!("failed to shed the key of epoch {}: {}", epoch, e),
+                }
             }
         }
+        shed.sort();
+        shed
     }
 
-    pub fn flush(&mut self) {
-        self.cache.lock().unwrap().flush().unwrap()
+    /// Returns whether the OS random number generator, which new keys
+    /// are generated from, was available when last used.
+    pub fn entropy_status(&self) -> EntropyStatus {
+        entropy::status()
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Returns an empty batch of packets to unwrap with the keys a
+    /// packet may currently be made for: the current epoch's key,
+    /// during the grace period the previous epoch's, and shortly before
+    /// the next epoch its key if the early tag policy admits it.
+    pub fn unwrap_batch<T>(&self) -> UnwrapBatch<T> {
+        let epoch = self.clock.now().epoch;
+        let keys = [epoch + 1, epoch, epoch.saturating_sub(1)].iter().filter_map(|epoch| self.key_for_packet(*epoch).ok()).collect();
+        UnwrapBatch::new(keys)
+    }
 
-    extern crate tempfile;
-    extern crate rand;
+    /// Returns the key to process a packet made for the given epoch
+    /// with. Unlike `key`, which also returns upcoming keys so that
+    /// they can be published, this fails with `EpochNotYetValid` for a
+    /// key whose epoch has not started, unless the early tag policy
+    /// admits it, and with `UnknownEpoch` if there is no live key.
+    pub fn key_for_packet(&self, epoch: u64) -> Result<MixKey, MixKeyError> {
+        let now = self.clock.now();
+        let key = self.key(epoch).ok_or(MixKeyError::UnknownEpoch(epoch))?;
+        let countdown = KeyCountdown::new(epoch, &now, self.clock.period(), self.grace_period);
+        if !self.early_tags.admits(&countdown) {
+            return Err(MixKeyError::EpochNotYetValid{
+                epoch: epoch,
+                starts_in: countdown.until_activation,
+            })
+        }
+        Ok(key)
+    }
 
-    use self::rand::Rng;
-    use self::rand::os::OsRng;
-    use self::tempfile::TempDir;
-    use super::*;
+    /// Check the tag of a packet made for the given epoch with that
+    /// epoch's key, storing it if it is fresh, and return true if it was
+    /// seen before. A missing key of the current or an upcoming epoch is
+    /// generated first. Epochs whose grace period has ended fail with
+    /// `EpochTooOld`, epochs beyond those keys are generated for with
+    /// `EpochTooNew`, and the previous epoch, if its key is gone, with
+    /// `NoKeyForEpoch`. Keys of epochs that have not started are subject
+    /// to the early tag policy, as in `key_for_packet`.
+    pub fn is_replay(&self, epoch: u64, tag: &Tag) -> Result<bool, MixKeyError> {
+        let now = self.clock.now();
+        if !self.is_live(epoch, &now) && epoch < now.epoch {
+            return Err(MixKeyError::EpochTooOld{
+                epoch: epoch,
+                current: now.epoch,
+            })
+        }
+        if epoch >= now.epoch + self.window() {
+            return Err(MixKeyError::EpochTooNew{
+                epoch: epoch,
+                current: now.epoch,
+            })
+        }
+        if !self.keys.read().unwrap().contains_key(&epoch) {
+            if epoch < now.epoch {
+                return Err(MixKeyError::NoKeyForEpoch(epoch))
+            }
+            self.observe_clock()?;
+            self.ensure_key(epoch)?;
+        }
+        self.key_for_packet(epoch)?.is_replay(tag)
+    }
 
+    /// Like `is_replay`, but checks the tag where it lies in the packet
+    /// buffer. See `MixKey::is_replay_bytes`.
+    pub fn is_replay_bytes(&self, epoch: u64, tag: &[u8]) -> Result<bool, MixKeyError> {
+        self.is_replay(epoch, Tag::from_slice(tag)?)
+    }
 
-    #[test]
-    fn basic_mix_keys_test() {
-        let clock = epoch::Clock::new_katzenpost();
-        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
-        let line_rate = 128974848;
-        let mut mix_keys = MixKeys::new(clock, 3, base_dir, line_rate).unwrap();
+    /// Generate or load the key of the epoch unless it is already kept.
+    /// The keys lock is held meanwhile, so that no two threads open the
+    /// same cache.
+    fn ensure_key(&self, epoch: u64) -> Result<(), MixKeyError> {
+        let public_key = {
+            let mut keys = self.keys.write().unwrap();
+            if keys.contains_key(&epoch) {
+                return Ok(())
+            }
+            let key = self.open_key(epoch)?;
+            let public_key = key.public_key();
+            keys.insert(epoch, key);
+            public_key
+        };
+        self.publish(KeyEvent::KeyGenerated{
+            epoch: epoch,
+            public_key: public_key,
+        });
+        Ok(())
+    }
 
-        let mut local_keys: HashMap<u64, MixKey> = HashMap::new();
+    /// Call `f` with the private key to unwrap a packet made for the
+    /// given epoch, failing as `key_for_packet` does if there is none.
+    /// The key is only lent, so it never needs to be copied out of the
+    /// crate, and keys of a provider that does not export them work too.
+    pub fn with_private_key<R, F: FnOnce(&dyn EpochKey) -> R>(&self, epoch: u64, f: F) -> Result<R, MixKeyError> {
+        Ok(self.key_for_packet(epoch)?.with_private_key(f))
+    }
 
-        mix_keys.shadow(&mut local_keys);
-        for (k, _v) in mix_keys.keys.lock().unwrap().iter() {
-            assert!(local_keys.contains_key(&k));
+    pub fn public_key(&self, epoch: u64) -> Option<PublicKey> {
+        if let Some(key) = self.key(epoch) {
+            return Some(key.public_key())
         }
+        None
     }
 
-    #[test]
-    fn basic_mix_key_test() {
-        let cache_dir = TempDir::new().unwrap();
-        {
-            let cache_dir_path = cache_dir.path().clone();
-            //let epoch_duration = 3 * 60 * 60; // 3 hours
-            //let epoch_duration = 1 * 60 * 60; // 1 hours
+    /// Returns the key for the given epoch if it is live, including the
+    /// previous epoch's key during the grace period.
+    pub fn key(&self, epoch: u64) -> Option<MixKey> {
+        if !self.is_live(epoch, &self.clock.now()) {
+            return None
+        }
+        self.keys.read().unwrap().get(&epoch).cloned()
+    }
+
+    /// Export every active private key, sealed to the operator's public
+    /// key, along with references to each epoch's tag store. The caches
+    /// are flushed first so the referenced tag stores are complete.
+    pub fn export_identity_bundle(&mut self, operator_key: &PublicKey) -> Result<IdentityBundle, MixKeyError> {
+        let mut keys = vec![];
+        for (epoch, mut key) in self.snapshot_keys() {
+            key.flush()?;
+            keys.push((epoch, key.export_private_key()?, key.path().to_path_buf()));
+        }
+        keys.sort_by_key(|k| k.0);
+        IdentityBundle::seal(operator_key, &keys)
+    }
+
+    /// Returns a handle for worker threads, which finds keys, checks
+    /// tags and returns public keys, sharing the keys of this `MixKeys`.
+    /// See the `handle` module.
+    pub fn handle(&self) -> MixKeysHandle {
+        MixKeysHandle::new(self.clone())
+    }
+
+    /// Maintain a map of filter replicas of the keys for a worker
+    /// thread, adding the new keys' and dropping the pruned keys'.
+    pub fn shadow_replicas(&mut self, dst: &mut HashMap<u64, FilterReplica>) -> Result<(), MixKeyError> {
+        let keys = self.keys.read().unwrap();
+        dst.retain(|epoch, _replica| keys.contains_key(epoch));
+        for (epoch, key) in keys.iter() {
+            if !dst.contains_key(epoch) {
+                dst.insert(*epoch, key.replica()?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Back up every live key, with its tags, into `backup_dir`, which
+    /// must not exist yet, while the keys keep checking packets. The
+    /// backup holds every tag stored before it started. See the
+    /// `backup` module.
+    pub fn backup(&self, backup_dir: &Path) -> Result<BackupManifest, MixKeyError> {
+        backup::create_backup_dir(backup_dir)?;
+        let mut keys = self.snapshot_keys();
+        keys.sort_by_key(|&(epoch, _)| epoch);
+        let mut entries = vec![];
+        for (_epoch, key) in keys {
+            entries.push(key.backup_to(backup_dir)?);
+        }
+        let manifest = BackupManifest{
+            entries: entries,
+        };
+        manifest.write(backup_dir)?;
+        info!("backed up {} keys to {}", manifest.entries.len(), backup_dir.display());
+        Ok(manifest)
+    }
+
+    /// Rebuild the caches of a backup written by `backup` in
+    /// `base_dir`, returning the restored epochs. The whole backup is
+    /// verified before anything is written, and the keys must open with
+    /// the given key provider. An epoch that already has a cache in
+    /// `base_dir` is refused with `RestoreConflict`, unless `force` is
+    /// set, in which case that cache is quarantined under the clock's
+    /// time. `base_dir` must not be in use.
+    pub fn restore(provider: &dyn KeyProvider, clock: &dyn ClockSource, backup_dir: &Path, base_dir: &Path, force: bool) -> Result<Vec<u64>, MixKeyError> {
+        let manifest = BackupManifest::read(backup_dir)?;
+        let _lock = BaseDirLock::acquire(base_dir)?;
+        let mut verified = vec![];
+        for entry in &manifest.entries {
+            let metadata = backup::verify_entry(backup_dir, entry, provider).context(entry.epoch, Op::RestoreCache, &entry.dir(backup_dir))?;
+            if fsutil::epoch_dir(base_dir, entry.epoch).exists() && !force {
+                return Err(MixKeyError::RestoreConflict(entry.epoch))
+            }
+            verified.push((entry, metadata));
+        }
+
+        let mut restored = vec![];
+        for (entry, metadata) in verified {
+            let path = fsutil::epoch_dir(base_dir, entry.epoch);
+            let staging = fsutil::staging_dir(base_dir, entry.epoch);
+            if staging.exists() {
+                fs::remove_dir_all(&staging).context(entry.epoch, Op::RemoveCache, &staging)?;
+            }
+            fs::create_dir_all(&staging).context(entry.epoch, Op::RestoreCache, &staging)?;
+            {
+                let cache_cfg_builder = sled::Config::new().path(staging.clone());
+                let mut store = MixKey::open_sled(entry.epoch, &staging, &cache_cfg_builder)?;
+                for (name, value) in &metadata {
+                    store.set_metadata(name, value).context(entry.epoch, Op::RestoreCache, &staging)?;
+                }
+                store.load_tag_size().context(entry.epoch, Op::RestoreCache, &staging)?;
+                let mut dump = backup::read_tags(backup_dir, entry).context(entry.epoch, Op::RestoreCache, &staging)?;
+                while let Some(chunk) = dump.next_chunk().context(entry.epoch, Op::RestoreCache, &staging)? {
+                    for tag in chunk.verify().context(entry.epoch, Op::RestoreCache, &staging)? {
+                        store.insert(&Tag(tag)).context(entry.epoch, Op::RestoreCache, &staging)?;
+                    }
+                }
+                store.flush().context(entry.epoch, Op::RestoreCache, &staging)?;
+            }
+            fsutil::sync_dir(&staging).context(entry.epoch, Op::RestoreCache, &staging)?;
+            if path.exists() {
+                let target = fsutil::quarantine_dir(base_dir, entry.epoch, clock.unix_time());
+                warn!("quarantining the cache of epoch {} to {} to restore its backup", entry.epoch, target.display());
+                fs::create_dir_all(target.parent().unwrap()).context(entry.epoch, Op::QuarantineCache, &path)?;
+                fs::rename(&path, &target).context(entry.epoch, Op::QuarantineCache, &path)?;
+            }
+            fsutil::atomic_rename(&staging, &path).context(entry.epoch, Op::RestoreCache, &path)?;
+            restored.push(entry.epoch);
+        }
+        info!("restored {} keys from {}", restored.len(), backup_dir.display());
+        Ok(restored)
+    }
+
+    /// Returns a clone of every key. Clones share their key's state, so
+    /// slow work such as flushing is done on them without holding the
+    /// lock that packet processing threads take to find their key.
+    pub(crate) fn snapshot_keys(&self) -> Vec<(u64, MixKey)> {
+        self.keys.read().unwrap().iter().map(|(epoch, key)| (*epoch, key.clone())).collect()
+    }
+}
+
+
+
+#[cfg(feature = "std")]
+#[derive(PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct Tag([u8; SPHINX_REPLAY_TAG_SIZE]);
+
+#[cfg(feature = "std")]
+impl Tag {
+    pub fn new(tag: [u8; SPHINX_REPLAY_TAG_SIZE]) -> Self {
+        Tag(tag)
+    }
+
+    /// Returns the tag in place, without copying it out of the packet
+    /// buffer that holds it.
+    pub fn from_bytes(tag: &[u8; SPHINX_REPLAY_TAG_SIZE]) -> &Tag {
+        // Tag is a transparent wrapper of the array, so the two share
+        // their layout.
+        unsafe { &*(tag as *const [u8; SPHINX_REPLAY_TAG_SIZE] as *const Tag) }
+    }
+
+    /// Like `from_bytes`, but for a slice of the packet buffer, which
+    /// must be exactly `SPHINX_REPLAY_TAG_SIZE` bytes long.
+    pub fn from_slice(tag: &[u8]) -> Result<&Tag, MixKeyError> {
+        let raw = <&[u8; SPHINX_REPLAY_TAG_SIZE]>::try_from(tag).map_err(|_| MixKeyError::InvalidTag)?;
+        Ok(Tag::from_bytes(raw))
+    }
+
+    /// Returns the hex of the tag's first bytes, enough to tell replays
+    /// apart in traces without logging whole tags.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn prefix_hex(&self) -> String {
+        self.0[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Returns the tag of a Sphinx variant whose tags are shorter than
+    /// `SPHINX_REPLAY_TAG_SIZE`, padded with zeros, for keys configured
+    /// with `MixKeysBuilder::tag_size`. The padded tag is full size; only
+    /// a sled tree stores the shorter tag.
+    pub fn padded(tag: &[u8]) -> Result<Tag, MixKeyError> {
+        if tag.len() > SPHINX_REPLAY_TAG_SIZE {
+            return Err(MixKeyError::InvalidTag)
+        }
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        raw[..tag.len()].copy_from_slice(tag);
+        Ok(Tag(raw))
+    }
+
+    /// Returns the tag's bytes, for `ReplayStore` implementations.
+    pub fn as_bytes(&self) -> &[u8; SPHINX_REPLAY_TAG_SIZE] {
+        &self.0
+    }
+
+    /// Parse a tag from its hex encoding, in either case.
+    pub fn from_hex(hex: &str) -> Result<Tag, MixKeyError> {
+        let hex = hex.as_bytes();
+        if hex.len() != 2 * SPHINX_REPLAY_TAG_SIZE {
+            return Err(MixKeyError::InvalidTag)
+        }
+        let nibble = |c: u8| (c as char).to_digit(16).ok_or(MixKeyError::InvalidTag);
+        let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        for (i, pair) in hex.chunks(2).enumerate() {
+            tag[i] = (nibble(pair[0])? << 4 | nibble(pair[1])?) as u8;
+        }
+        Ok(Tag(tag))
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clone for Tag {
+    fn clone(&self) -> Tag {
+        Tag(self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<[u8; SPHINX_REPLAY_TAG_SIZE]> for Tag {
+    fn from(tag: [u8; SPHINX_REPLAY_TAG_SIZE]) -> Tag {
+        Tag(tag)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> TryFrom<&'a [u8]> for Tag {
+    type Error = MixKeyError;
+
+    fn try_from(raw: &'a [u8]) -> Result<Tag, MixKeyError> {
+        if raw.len() != SPHINX_REPLAY_TAG_SIZE {
+            return Err(MixKeyError::InvalidTag)
+        }
+        let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        tag.copy_from_slice(raw);
+        Ok(Tag(tag))
+    }
+}
+
+#[cfg(feature = "std")]
+impl AsRef<[u8]> for Tag {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Tag {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Tag {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Tag, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Tag::from_hex(&hex).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Debug for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tag({})", self)
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone)]
+pub struct MixKey {
+    shards: Arc<RwLock<Option<Shards>>>,
+    cache_cfg_builder: sled::Config,
+    backend: CacheBackend,
+    timer: Arc<dyn MonotonicClock>,
+    last_used: Arc<AtomicU64>,
+    last_flush: Arc<Mutex<Duration>>,
+    flush_duration: Arc<Mutex<Duration>>,
+    deltas: Arc<Mutex<DeltaLog>>,
+    tags: Arc<AtomicU64>,
+    max_tags: Option<u64>,
+    overflow_behavior: OverflowBehavior,
+    overflow: Arc<Mutex<Option<Box<dyn ReplayStore>>>>,
+    overflowed: Arc<AtomicU64>,
+    disk_quota: Option<u64>,
+    quota_fallback: bool,
+    disk_bytes: Arc<AtomicU64>,
+    quota_checks: Arc<AtomicU64>,
+    spill: Arc<Mutex<MemoryStore>>,
+    namespaces: Arc<Mutex<HashMap<u8, TagNamespace>>>,
+    replays: Arc<Vec<Mutex<ReplayDecisionCache>>>,
+    replays_detected: Arc<AtomicU64>,
+    replay_events: Arc<Mutex<Subscribers<ReplayEvent>>>,
+    storm: Option<Arc<Mutex<StormDetector>>>,
+    warm: Arc<AtomicBool>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+    #[cfg(feature = "accumulator")]
+    accumulator: Arc<Mutex<Accumulator>>,
+    false_positive_rate: f32,
+    expected_num_items: u32,
+    counting_filter: bool,
+    tag_size: usize,
+    durability: DurabilityPolicy,
+    write_batch: Option<WriteBatch>,
+    state: Arc<AtomicU8>,
+    key: Arc<RwLock<Arc<dyn EpochKey>>>,
+    epoch: u64,
+    path: PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl MixKey {
+    pub fn new(line_rate: u64, epoch: u64, epoch_duration: u64, base_dir: &String) -> Result<MixKey, MixKeyError> {
+        MixKey::with_key_provider(&LocalKeyProvider, line_rate, epoch, epoch_duration, base_dir)
+    }
+
+    /// Like `new`, but the private key is created and held by the given
+    /// key provider. The cache only stores the key's identifier.
+    pub fn with_key_provider(provider: &dyn KeyProvider, line_rate: u64, epoch: u64, epoch_duration: u64, base_dir: &String) -> Result<MixKey, MixKeyError> {
+        MixKey::with_backend(CacheBackend::Sled, provider, line_rate, epoch, epoch_duration, base_dir)
+    }
+
+    /// Like `with_key_provider`, but the tags are kept in the given
+    /// backend. A `Memory` key writes nothing to `base_dir`. `Custom`
+    /// stores are passed to `with_store` instead.
+    pub fn with_backend(backend: CacheBackend, provider: &dyn KeyProvider, line_rate: u64, epoch: u64, epoch_duration: u64, base_dir: &String) -> Result<MixKey, MixKeyError> {
+        MixKey::with_config(backend, provider, line_rate, epoch, epoch_duration, base_dir, &CacheConfig::default())
+    }
+
+    /// Like `with_backend`, but the filter and cache are tuned by the
+    /// given configuration rather than the defaults.
+    pub fn with_config(backend: CacheBackend, provider: &dyn KeyProvider, line_rate: u64, epoch: u64, epoch_duration: u64, base_dir: &String, config: &CacheConfig) -> Result<MixKey, MixKeyError> {
+        let path = fsutil::epoch_dir(Path::new(base_dir), epoch);
+        let store: Box<dyn ReplayStore> = match backend {
+            CacheBackend::Sled => {
+                if !path.exists() {
+                    MixKey::stage_sled(provider, line_rate, epoch, epoch_duration, config, Path::new(base_dir), &path)?;
+                }
+                if frozen::is_frozen(&path) {
+                    Box::new(FrozenStore::open(&path).context(epoch, Op::OpenCache, &path)?)
+                } else {
+                    sledupgrade::upgrade(epoch, &path, config.use_compression)?;
+                    let cache_cfg_builder = MixKey::cache_config(&path, line_rate, epoch_duration, config);
+                    let store = MixKey::open_sled(epoch, &path, &cache_cfg_builder)?;
+                    if store.tag_size() != config.tag_size {
+                        let error = MixKeyError::TagSizeMismatch{
+                            cache: store.tag_size(),
+                            config: config.tag_size,
+                        };
+                        return Err(error.context(epoch, Op::OpenCache, &path))
+                    }
+                    MixKey::batch_writes(Box::new(store), config.write_batch, epoch, &path)?
+                }
+            },
+            CacheBackend::Memory => Box::new(MemoryStore::default()),
+            CacheBackend::Custom => return Err(MixKeyError::CreateCacheFailed.context(epoch, Op::OpenCache, &path)),
+        };
+        MixKey::from_store(backend, provider, line_rate, epoch, epoch_duration, config, store, path)
+    }
+
+    /// Like `with_config` for a sled backed key, but a cache that fails
+    /// to load because it is damaged is quarantined, named by the
+    /// clock's time, and rebuilt from what can still be read of it,
+    /// rather than reported. The report says whether tags or the private
+    /// key were lost; see the `recovery` module. Errors recovery can not
+    /// fix are returned as they are.
+    pub fn open_with_recovery(provider: &dyn KeyProvider, clock: &dyn ClockSource, line_rate: u64, epoch: u64, epoch_duration: u64, base_dir: &String, config: &CacheConfig) -> Result<(MixKey, RecoveryReport), MixKeyError> {
+        let base_dir_path = Path::new(base_dir);
+        let path = fsutil::epoch_dir(base_dir_path, epoch);
+        match MixKey::with_config(CacheBackend::Sled, provider, line_rate, epoch, epoch_duration, base_dir, config) {
+            Ok(mix_key) => return Ok((mix_key, RecoveryReport::clean(epoch))),
+            Err(ref e) if recovery::is_damage(e) && !frozen::is_frozen(&path) => warn!("repairing the cache of epoch {}: {}", epoch, e),
+            Err(e) => return Err(e),
+        }
+        let mut salvage = recovery::salvage(&path, epoch);
+        let key_lost = match salvage.key_id() {
+            Some(key_id) => provider.open(epoch, key_id).is_err(),
+            None => true,
+        };
+        if key_lost {
+            warn!("the private key of epoch {} is lost; the repaired cache gets a new one", epoch);
+            salvage.drop_key();
+        }
+
+        let quarantined = fsutil::quarantine_dir(base_dir_path, epoch, clock.unix_time());
+        fs::create_dir_all(quarantined.parent().unwrap()).context(epoch, Op::QuarantineCache, &path)?;
+        fs::rename(&path, &quarantined).context(epoch, Op::QuarantineCache, &path)?;
+
+        let staging = fsutil::staging_dir(base_dir_path, epoch);
+        if staging.exists() {
+            fs::remove_dir_all(&staging).context(epoch, Op::RemoveCache, &staging)?;
+        }
+        fs::create_dir_all(&staging).context(epoch, Op::OpenCache, &staging)?;
+        {
+            let cache_cfg_builder = MixKey::cache_config(&staging, line_rate, epoch_duration, config);
+            let mut store = MixKey::open_sled(epoch, &staging, &cache_cfg_builder)?;
+            for &(name, ref value) in &salvage.metadata {
+                store.set_metadata(name, value).context(epoch, Op::StoreEpoch, &staging)?;
+            }
+            store.load_tag_size().context(epoch, Op::StoreEpoch, &staging)?;
+            for tag in &salvage.tags {
+                store.insert(&Tag(*tag)).context(epoch, Op::ImportTags, &staging)?;
+            }
+            store.flush().context(epoch, Op::FlushCache, &staging)?;
+        }
+        fsutil::sync_dir(&staging).context(epoch, Op::StoreKey, &staging)?;
+        fsutil::atomic_rename(&staging, &path).context(epoch, Op::StoreKey, &path)?;
+
+        let mix_key = MixKey::with_config(CacheBackend::Sled, provider, line_rate, epoch, epoch_duration, base_dir, config)?;
+        let report = RecoveryReport{
+            epoch: epoch,
+            repaired: true,
+            quarantined: Some(quarantined),
+            salvaged_tags: mix_key.tag_count(),
+            complete: salvage.complete,
+            key_lost: key_lost,
+        };
+        warn!("repaired the cache of epoch {}: {:?}", epoch, report);
+        Ok((mix_key, report))
+    }
+
+    /// Like `with_key_provider`, but the tags are kept in the given
+    /// store, which may be shared with other processes. The key's
+    /// identifier is kept in the store too, so that every process
+    /// sharing it uses the same key.
+    pub fn with_store(provider: &dyn KeyProvider, line_rate: u64, epoch: u64, epoch_duration: u64, store: Box<dyn ReplayStore>) -> Result<MixKey, MixKeyError> {
+        let path = fsutil::epoch_dir(Path::new(""), epoch);
+        MixKey::from_store(CacheBackend::Custom, provider, line_rate, epoch, epoch_duration, &CacheConfig::default(), store, path)
+    }
+
+    fn from_store(backend: CacheBackend, provider: &dyn KeyProvider, line_rate: u64, epoch: u64, epoch_duration: u64, config: &CacheConfig, mut store: Box<dyn ReplayStore>, path: PathBuf) -> Result<MixKey, MixKeyError> {
+        let false_positive_rate: f32 = config.false_positive_rate;
+        let expected_num_items: u32 = config.expected_tags_per_epoch(line_rate, epoch_duration);
+        if !is_tag_size(config.tag_size) {
+            return Err(MixKeyError::InvalidConfig("the tag size must be between 16 and 32 bytes".to_string()).context(epoch, Op::OpenCache, &path))
+        }
+        MixKey::check_format(store.as_mut(), epoch, &path)?;
+        let key = MixKey::load_key(provider, store.as_mut(), epoch, &path)?;
+        let state = MixKey::load_state(store.as_mut(), epoch, &path)?;
+        let (shards, tags) = Shards::open(store, false_positive_rate, expected_num_items, config.counting_filter).context(epoch, Op::LoadFilter, &path)?;
+        let cache_cfg_builder = MixKey::cache_config(&path, line_rate, epoch_duration, config);
+        let mut overflow = None;
+        let mut overflowed = 0;
+        if backend == CacheBackend::Sled && path.join(OVERFLOW_DIR_NAME).exists() {
+            let mut tree = MixKey::open_overflow_tree(epoch, &path, &cache_cfg_builder)?;
+            overflowed = shards.fill(tree.as_mut()).context(epoch, Op::LoadFilter, &path)?;
+            overflow = Some(tree);
+        }
+        #[cfg(feature = "accumulator")]
+        let accumulator = {
+            let mut accumulator = Accumulator::default();
+            accumulator.extend(shards.store().as_mut()).context(epoch, Op::LoadFilter, &path)?;
+            if let Some(ref mut tree) = overflow {
+                accumulator.extend(tree.as_mut()).context(epoch, Op::LoadFilter, &path)?;
+            }
+            accumulator
+        };
+        let timer = Arc::new(SystemMonotonicClock::new());
+        let replays = (0..MIX_KEY_SHARDS).map(|_| Mutex::new(ReplayDecisionCache::new(MIX_KEY_REPLAY_CACHE_CAPACITY / MIX_KEY_SHARDS))).collect();
+        let mix_key = MixKey{
+            shards: Arc::new(RwLock::new(Some(shards))),
+            cache_cfg_builder: cache_cfg_builder,
+            backend: backend,
+            last_used: Arc::new(AtomicU64::new(MixKey::nanos(timer.now()))),
+            last_flush: Arc::new(Mutex::new(timer.now())),
+            flush_duration: Arc::new(Mutex::new(Duration::from_secs(0))),
+            deltas: Arc::new(Mutex::new(DeltaLog::new())),
+            tags: Arc::new(AtomicU64::new(tags)),
+            max_tags: config.max_tags,
+            overflow_behavior: config.overflow,
+            overflow: Arc::new(Mutex::new(overflow)),
+            overflowed: Arc::new(AtomicU64::new(overflowed)),
+            disk_quota: config.disk_quota,
+            quota_fallback: config.quota_fallback,
+            disk_bytes: Arc::new(AtomicU64::new(0)),
+            quota_checks: Arc::new(AtomicU64::new(0)),
+            spill: Arc::new(Mutex::new(MemoryStore::default())),
+            namespaces: Arc::new(Mutex::new(HashMap::new())),
+            replays: Arc::new(replays),
+            replays_detected: Arc::new(AtomicU64::new(0)),
+            replay_events: Arc::new(Mutex::new(Subscribers::default())),
+            storm: None,
+            warm: Arc::new(AtomicBool::new(false)),
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(Metrics::new()),
+            #[cfg(feature = "accumulator")]
+            accumulator: Arc::new(Mutex::new(accumulator)),
+            timer: timer,
+            false_positive_rate: false_positive_rate,
+            expected_num_items: expected_num_items,
+            counting_filter: config.counting_filter,
+            tag_size: config.tag_size,
+            durability: config.durability,
+            write_batch: config.write_batch,
+            state: Arc::new(AtomicU8::new(state.id())),
+            key: Arc::new(RwLock::new(key)),
+            epoch: epoch,
+            path: path,
+        };
+        if config.warm_up {
+            mix_key.warm_up()?;
+        }
+        Ok(mix_key)
+    }
+
+    fn cache_config(path: &Path, line_rate: u64, epoch_duration: u64, config: &CacheConfig) -> sled::Config {
+        let cache_capacity = config.cache_capacity_per_epoch(line_rate, epoch_duration);
+        sled::Config::new()
+            .path(path)
+            .cache_capacity(cache_capacity as u64)
+            .use_compression(config.use_compression)
+            .flush_every_ms(MixKey::background_flush_ms(config))
+            .snapshot_after_ops(config.snapshot_after_ops as u64)
+    }
+
+    /// Returns how often sled's background thread flushes the cache.
+    /// Minimal builds run no such thread, and leave flushing to
+    /// `MixKeys::flush_due`, as does the `Manual` durability policy.
+    #[cfg(not(feature = "minimal"))]
+    fn background_flush_ms(config: &CacheConfig) -> Option<u64> {
+        match config.durability {
+            DurabilityPolicy::Manual => None,
+            _ => Some(config.flush_every_ms),
+        }
+    }
+
+    #[cfg(feature = "minimal")]
+    fn background_flush_ms(_config: &CacheConfig) -> Option<u64> {
+        None
+    }
+
+    /// Open the epoch's sled cache, checking the epoch it was made for.
+    fn open_sled(epoch: u64, path: &Path, cache_cfg_builder: &sled::Config) -> Result<SledStore, MixKeyError> {
+        let cache = MixKey::open_cache(cache_cfg_builder).context(epoch, Op::OpenCache, path)?;
+        let mut store = SledStore::new(cache);
+        let mut raw_epoch = [0u8; 8];
+        LittleEndian::write_u64(&mut raw_epoch, epoch);
+        match store.metadata(EPOCH_KEY).context(epoch, Op::LoadEpoch, path)? {
+            Some(ref stored) if stored[..] == raw_epoch[..] => {},
+            Some(_) => {
+                warn!("mix key mismatched epoch during load.");
+                return Err(MixKeyError::LoadCacheFailed.context(epoch, Op::LoadEpoch, path));
+            },
+            None => {
+                // Earlier releases marked the epoch with a key of its own.
+                let marked = store.tree.contains_key(&raw_epoch).context(epoch, Op::LoadEpoch, path)?;
+                if !marked && !store.tree.is_empty() {
+                    warn!("mix key cache has no epoch marker.");
+                    return Err(MixKeyError::LoadCacheFailed.context(epoch, Op::LoadEpoch, path));
+                }
+                store.set_metadata(EPOCH_KEY, &raw_epoch).context(epoch, Op::StoreEpoch, path)?;
+            },
+        }
+        store.load_tag_size().context(epoch, Op::LoadEpoch, path)?;
+        Ok(store)
+    }
+
+    /// Create the epoch's sled cache and key in the staging directory
+    /// and rename it into place once both are synced, so that a crash
+    /// part way through never leaves a half initialized cache directory.
+    fn stage_sled(provider: &dyn KeyProvider, line_rate: u64, epoch: u64, epoch_duration: u64, config: &CacheConfig, base_dir: &Path, path: &Path) -> Result<(), MixKeyError> {
+        let staging = fsutil::staging_dir(base_dir, epoch);
+        if staging.exists() {
+            fs::remove_dir_all(&staging).context(epoch, Op::RemoveCache, &staging)?;
+        }
+        fs::create_dir_all(&staging).context(epoch, Op::OpenCache, &staging)?;
+        {
+            let cache_cfg_builder = MixKey::cache_config(&staging, line_rate, epoch_duration, config);
+            let mut store = MixKey::open_sled(epoch, &staging, &cache_cfg_builder)?;
+            MixKey::check_format(&mut store, epoch, &staging)?;
+            store.init_metadata(TAG_SIZE_KEY, &[config.tag_size as u8]).context(epoch, Op::StoreEpoch, &staging)?;
+            MixKey::load_key(provider, &mut store, epoch, &staging)?;
+            store.flush().context(epoch, Op::StoreKey, &staging)?;
+        }
+        fsutil::sync_dir(&staging).context(epoch, Op::StoreKey, &staging)?;
+        fsutil::atomic_rename(&staging, path).context(epoch, Op::StoreKey, path)?;
+        Ok(())
+    }
+
+    /// Record the cache format and crate version a new store is written
+    /// with, refuse a store written in a newer format, and migrate one
+    /// written in an older format.
+    fn check_format(store: &mut dyn ReplayStore, epoch: u64, path: &Path) -> Result<(), MixKeyError> {
+        let fresh = store.metadata(MIX_CACHE_KEY).context(epoch, Op::LoadEpoch, path)?.is_none();
+        let format = match store.metadata(FORMAT_VERSION_KEY).context(epoch, Op::LoadEpoch, path)? {
+            Some(ref format) if format.len() == 1 => format[0],
+            Some(_) => return Err(MixKeyError::LoadCacheFailed.context(epoch, Op::LoadEpoch, path)),
+            None if fresh => store.init_metadata(FORMAT_VERSION_KEY, &[CACHE_FORMAT_VERSION]).context(epoch, Op::StoreEpoch, path)?[0],
+            None => 0,
+        };
+        if fresh {
+            store.init_metadata(WRITER_VERSION_KEY, CRATE_VERSION.as_bytes()).context(epoch, Op::StoreEpoch, path)?;
+        }
+        if format > CACHE_FORMAT_VERSION {
+            let writer = store.metadata(WRITER_VERSION_KEY).context(epoch, Op::LoadEpoch, path)?;
+            let error = MixKeyError::IncompatibleCache{
+                format: format,
+                writer: writer.map(|x| String::from_utf8_lossy(&x).into_owned()),
+            };
+            return Err(error.context(epoch, Op::LoadEpoch, path))
+        }
+        if format < CACHE_FORMAT_VERSION {
+            migration::migrate(store, format).context(epoch, Op::UpgradeCache, path)?;
+            info!("migrated the cache of epoch {} from format {} to {}", epoch, format, CACHE_FORMAT_VERSION);
+        }
+        Ok(())
+    }
+
+    /// Load the key the store references, or generate and store a new
+    /// one. If another process sharing the store stored a key first,
+    /// that key is used instead.
+    fn load_key(provider: &dyn KeyProvider, store: &mut dyn ReplayStore, epoch: u64, path: &Path) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+        let key_id = ClearOnDrop::new(match store.metadata(MIX_CACHE_KEY).context(epoch, Op::LoadKey, path)? {
+            Some(key_id) => key_id,
+            None => {
+                let key_id = ClearOnDrop::new(provider.generate(epoch).context(epoch, Op::GenerateKey, path)?);
+                store.init_metadata(MIX_CACHE_KEY, &key_id).context(epoch, Op::StoreKey, path)?
+            },
+        });
+        let key = provider.open(epoch, &key_id).context(epoch, Op::LoadKey, path)?;
+        let kem = store.init_metadata(KEM_KEY, &[key.kem().id()]).context(epoch, Op::StoreKey, path)?;
+        if kem != [key.kem().id()] {
+            return Err(MixKeyError::KemMismatch{
+                cache: kem.first().and_then(|&id| Kem::from_id(id)),
+                key: key.kem(),
+            }.context(epoch, Op::LoadKey, path))
+        }
+        store.init_metadata(PUBLIC_KEY_KEY, &key.public_key().to_vec()).context(epoch, Op::StoreKey, path)?;
+        Ok(key)
+    }
+
+    /// Load the lifecycle state stored in the cache. A cache without
+    /// one was never moved past `PreGenerated`.
+    fn load_state(store: &mut dyn ReplayStore, epoch: u64, path: &Path) -> Result<KeyState, MixKeyError> {
+        match store.metadata(STATE_KEY).context(epoch, Op::LoadEpoch, path)? {
+            Some(ref raw) if raw.len() == 1 => KeyState::from_id(raw[0]).ok_or(MixKeyError::LoadCacheFailed.context(epoch, Op::LoadEpoch, path)),
+            Some(_) => Err(MixKeyError::LoadCacheFailed.context(epoch, Op::LoadEpoch, path)),
+            None => Ok(KeyState::PreGenerated),
+        }
+    }
+
+    fn open_cache(cache_cfg_builder: &sled::Config) -> Result<Tree, MixKeyError> {
+        store::open_tree(cache_cfg_builder)
+    }
+
+    /// Build a bloom filter holding every tag already stored in the
+    /// cache, returning it along with the number of tags.
+    fn load_filter(cache: &mut dyn ReplayStore, false_positive_rate: f32, expected_num_items: u32, counting: bool) -> Result<(TagFilter, u64), MixKeyError> {
+        let mut filter = TagFilter::new(false_positive_rate, expected_num_items, counting);
+        let tags = MixKey::fill_filter(cache, &mut filter)?;
+        Ok((filter, tags))
+    }
+
+    /// Insert every tag stored in the cache into the filter, returning
+    /// the number of tags.
+    fn fill_filter(cache: &mut dyn ReplayStore, filter: &mut TagFilter) -> Result<u64, MixKeyError> {
+        let mut tags = 0;
+        for raw in cache.tags() {
+            filter.insert(&Tag(raw?));
+            tags += 1;
+        }
+        Ok(tags)
+    }
+
+    /// Open the sled tree holding the tags of an epoch past its limit.
+    fn open_overflow_tree(epoch: u64, path: &Path, cache_cfg_builder: &sled::Config) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        let overflow_path = path.join(OVERFLOW_DIR_NAME);
+        let tree = MixKey::open_cache(&cache_cfg_builder.clone().path(overflow_path.clone())).context(epoch, Op::OpenCache, &overflow_path)?;
+        Ok(Box::new(SledStore::new(tree)))
+    }
+
+    /// Reopen the sled cache of a shed key.
+    fn reopen_cache(&self) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        if !self.path.exists() {
+            return Err(MixKeyError::LoadCacheFailed.context(self.epoch, Op::OpenCache, &self.path))
+        }
+        if frozen::is_frozen(&self.path) {
+            return Ok(Box::new(FrozenStore::open(&self.path).context(self.epoch, Op::OpenCache, &self.path)?))
+        }
+        let tree = MixKey::open_cache(&self.cache_cfg_builder).context(self.epoch, Op::OpenCache, &self.path)?;
+        let mut store = SledStore::new(tree);
+        store.load_tag_size().context(self.epoch, Op::LoadEpoch, &self.path)?;
+        MixKey::batch_writes(Box::new(store), self.write_batch, self.epoch, &self.path)
+    }
+
+    /// Put a write buffer in front of the store if writes are batched.
+    fn batch_writes(store: Box<dyn ReplayStore>, batch: Option<WriteBatch>, epoch: u64, path: &Path) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        match batch {
+            Some(batch) => Ok(Box::new(WriteBackStore::new(store, batch).context(epoch, Op::OpenCache, path)?)),
+            None => Ok(store),
+        }
+    }
+
+    /// Reopen the cache and rebuild the shard filters of a shed key.
+    fn reopen_shards(&self) -> Result<Shards, MixKeyError> {
+        let (shards, _tags) = Shards::open(self.reopen_cache()?, self.false_positive_rate, self.expected_num_items, self.counting_filter)
+            .context(self.epoch, Op::LoadFilter, &self.path)?;
+        if let Some(ref mut overflow) = *self.overflow.lock().unwrap() {
+            shards.fill(overflow.as_mut()).context(self.epoch, Op::LoadFilter, &self.path)?;
+        }
+        shards.store().set_metadata(STATE_KEY, &[self.state().id()]).context(self.epoch, Op::Transition, &self.path)?;
+        Ok(shards)
+    }
+
+    /// Returns the shards, reopening them first if they were shed while
+    /// idle. Shedding and reopening take the lock exclusively, while
+    /// replay checks share it.
+    fn wake(&self) -> Result<RwLockReadGuard<'_, Option<Shards>>, MixKeyError> {
+        loop {
+            {
+                let shards = self.shards.read().unwrap();
+                if shards.is_some() {
+                    self.last_used.store(MixKey::nanos(self.timer.now()), Ordering::Relaxed);
+                    return Ok(shards)
+                }
+            }
+            let mut shards = self.shards.write().unwrap();
+            if shards.is_none() {
+                *shards = Some(self.reopen_shards()?);
+            }
+        }
+    }
+
+    fn nanos(time: Duration) -> u64 {
+        time.as_secs() * 1_000_000_000 + time.subsec_nanos() as u64
+    }
+
+    /// Build a copy of the filter for a replica, returning it along with
+    /// the delta log position it is current as of. Replay checks wait
+    /// until it is built, so that none of their tags are missed.
+    fn snapshot_filter(&self) -> Result<(TagFilter, u64), MixKeyError> {
+        let mut shards = self.shards.write().unwrap();
+        if shards.is_none() {
+            *shards = Some(self.reopen_shards()?);
+        }
+        let mut deltas = self.deltas.lock().unwrap();
+        deltas.enable();
+        let (mut filter, _tags) = MixKey::load_filter(shards.as_ref().unwrap().store().as_mut(), self.false_positive_rate, self.expected_num_items, self.counting_filter)
+            .context(self.epoch, Op::LoadFilter, &self.path)?;
+        if let Some(ref mut overflow) = *self.overflow.lock().unwrap() {
+            MixKey::fill_filter(overflow.as_mut(), &mut filter).context(self.epoch, Op::LoadFilter, &self.path)?;
+        }
+        Ok((filter, deltas.end()))
+    }
+
+    /// Returns the epoch's key, which once revoked only answers for its
+    /// public key.
+    fn epoch_key(&self) -> Arc<dyn EpochKey> {
+        self.key.read().unwrap().clone()
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.epoch_key().public_key()
+    }
+
+    /// Perform a Diffie-Hellman operation with this epoch's private key.
+    pub fn exp(&self, public_key: &PublicKey) -> Result<[u8; KEY_SIZE], MixKeyError> {
+        self.epoch_key().exp(public_key)
+    }
+
+    /// Returns the number of tags the filter is sized for.
+    pub fn expected_tags(&self) -> u64 {
+        self.expected_num_items as u64
+    }
+
+    /// Returns the key encapsulation mechanism of this epoch's key.
+    pub fn kem(&self) -> Kem {
+        self.epoch_key().kem()
+    }
+
+    /// Returns the public key in the encoding of its KEM.
+    pub fn public_key_bytes(&self) -> Vec<u8> {
+        self.epoch_key().public_key_bytes()
+    }
+
+    /// Returns the shared secret of a packet's key encapsulation, with
+    /// this epoch's private key.
+    pub fn decapsulate(&self, encapsulation: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        self.epoch_key().decapsulate(encapsulation)
+    }
+
+    /// Call `f` with this epoch's private key, which stays with the key
+    /// provider, for unwrapping packets with operations `MixKey` does
+    /// not wrap.
+    pub fn with_private_key<R, F: FnOnce(&dyn EpochKey) -> R>(&self, f: F) -> R {
+        f(self.epoch_key().as_ref())
+    }
+
+    /// Returns a copy of the private key, or `KeyNotExportable` if the
+    /// key provider does not allow it to leave.
+    pub fn export_private_key(&self) -> Result<PrivateKey, MixKeyError> {
+        self.epoch_key().export()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the backend the tags are kept in.
+    pub fn backend(&self) -> CacheBackend {
+        self.backend
+    }
+
+    /// Returns when the key flushes the tags it stores.
+    pub fn durability_policy(&self) -> DurabilityPolicy {
+        self.durability
+    }
+
+    /// Returns the key's lifecycle state. See the `lifecycle` module.
+    pub fn state(&self) -> KeyState {
+        KeyState::from_id(self.state.load(Ordering::Acquire)).unwrap()
+    }
+
+    /// Move the key to the given lifecycle state and store it in the
+    /// cache, or fail with `InvalidTransition` if the lifecycle does not
+    /// allow the move. The new state is in effect even if storing it
+    /// fails. A shed key stores its state once it is reopened.
+    pub fn transition(&self, to: KeyState) -> Result<(), MixKeyError> {
+        let mut current = self.state.load(Ordering::Acquire);
+        loop {
+            let from = KeyState::from_id(current).unwrap();
+            if !from.can_transition(to) {
+                return Err(MixKeyError::InvalidTransition{
+                    from: from,
+                    to: to,
+                }.context(self.epoch, Op::Transition, &self.path))
+            }
+            match self.state.compare_exchange(current, to.id(), Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(x) => current = x,
+            }
+        }
+        if let Some(ref shards) = *self.shards.read().unwrap() {
+            shards.store().set_metadata(STATE_KEY, &[to.id()]).context(self.epoch, Op::Transition, &self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Write the key's sled cache, sorted, to an immutable file and
+    /// remove the tree, returning false if the key is kept elsewhere or
+    /// was already frozen. Tags stored from then on are kept beside the
+    /// file. Replay checks wait until it is done. See the `frozen`
+    /// module.
+    pub fn freeze(&self) -> Result<bool, MixKeyError> {
+        if self.backend != CacheBackend::Sled || frozen::is_frozen(&self.path) {
+            return Ok(false)
+        }
+        let mut shards = self.shards.write().unwrap();
+        if shards.is_none() {
+            *shards = Some(self.reopen_shards()?);
+        }
+        {
+            let mut store = shards.as_ref().unwrap().store();
+            if store.metadata(NAMESPACES_KEY).context(self.epoch, Op::FreezeCache, &self.path)?.is_some() {
+                info!("not freezing mix key cache of epoch {}, which holds tag namespaces", self.epoch);
+                return Ok(false)
+            }
+            store.flush().context(self.epoch, Op::FreezeCache, &self.path)?;
+            let tags = store.tags().collect::<Result<Vec<_>, _>>().context(self.epoch, Op::FreezeCache, &self.path)?;
+            let mut metadata = HashMap::new();
+            for name in FROZEN_METADATA_KEYS.iter() {
+                if let Some(value) = store.metadata(name).context(self.epoch, Op::FreezeCache, &self.path)? {
+                    metadata.insert(name.to_string(), value);
+                }
+            }
+            frozen::freeze(&self.path, tags, &metadata).context(self.epoch, Op::FreezeCache, &self.path)?;
+        }
+        *shards = None;
+        *shards = Some(self.reopen_shards()?);
+        info!("froze mix key cache of epoch {}", self.epoch);
+        Ok(true)
+    }
+
+    /// Move the key to the given state unless it is already there, or
+    /// past it, returning true if it moved.
+    pub(crate) fn advance(&self, to: KeyState) -> Result<bool, MixKeyError> {
+        if !self.state().can_transition(to) {
+            return Ok(false)
+        }
+        self.transition(to).map(|_| true)
+    }
+
+    /// Returns the tag namespace of the given id, opening it on first
+    /// use. Its tags are checked and stored apart from the key's own
+    /// tags and those of every other namespace. See the `namespace`
+    /// module.
+    pub fn namespace(&self, id: u8) -> Result<TagNamespace, MixKeyError> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if let Some(namespace) = namespaces.get(&id) {
+            return Ok(namespace.clone())
+        }
+        let store = {
+            let shards = self.wake()?;
+            let mut main = shards.as_ref().unwrap().store();
+            let store = main.namespace(id).ok_or(MixKeyError::NamespacesUnsupported.context(self.epoch, Op::OpenNamespace, &self.path))?;
+            main.set_metadata(NAMESPACES_KEY, &[1]).context(self.epoch, Op::OpenNamespace, &self.path)?;
+            store
+        };
+        let namespace = TagNamespace::open(id, self.epoch, &self.path, store, self.false_positive_rate, self.expected_num_items)?;
+        namespaces.insert(id, namespace.clone());
+        Ok(namespace)
+    }
+
+    /// Returns the number of tags stored in the cache.
+    pub fn tag_count(&self) -> u64 {
+        self.tags.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of fresh tags that arrived after the cache
+    /// reached its tag limit: those rejected, those kept in the overflow
+    /// store or secondary filter, or those stored regardless. Tags kept
+    /// in memory past the disk quota count too.
+    pub fn overflow_count(&self) -> u64 {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the root of the Merkle tree over the key's stored tags,
+    /// for publishing to auditors.
+    #[cfg(feature = "accumulator")]
+    pub fn accumulator_root(&self) -> AccumulatorRoot {
+        self.accumulator.lock().unwrap().root()
+    }
+
+    /// Returns the current root along with a proof that the tag is
+    /// under it, or None if the tag was never stored.
+    #[cfg(feature = "accumulator")]
+    pub fn inclusion_proof(&self, tag: &Tag) -> Option<(AccumulatorRoot, InclusionProof)> {
+        let accumulator = self.accumulator.lock().unwrap();
+        accumulator.proof(tag).map(|proof| (accumulator.root(), proof))
+    }
+
+    /// Returns the `n` recently replayed tags that were replayed the
+    /// most often, most replays first.
+    pub fn top_replays(&self, n: usize) -> Vec<ReplayHit> {
+        let mut hits: Vec<ReplayHit> = self.replays.iter().flat_map(|replays| replays.lock().unwrap().top(n)).collect();
+        hits.sort_by(|a, b| b.hits.cmp(&a.hits));
+        hits.truncate(n);
+        hits
+    }
+
+    /// Returns the number of replays answered without a filter or
+    /// cache lookup.
+    pub fn replay_cache_hits(&self) -> u64 {
+        self.replays.iter().map(|replays| replays.lock().unwrap().total_hits()).sum()
+    }
+
+    /// Returns the most layers any shard's bloom filter has grown to,
+    /// or None while they are shed.
+    pub fn filter_layers(&self) -> Option<usize> {
+        self.shards.read().unwrap().as_ref().map(|shards| shards.layers())
+    }
+
+    /// Check the key's store can be written and read, and that its
+    /// stored key identifier opens to the key in use. A shed key is not
+    /// reopened, and skips both checks.
+    pub(crate) fn health(&self, provider: &dyn KeyProvider) -> KeyHealth {
+        let shards = self.shards.read().unwrap();
+        let (store, key) = match *shards {
+            Some(ref shards) => {
+                let mut store = shards.store();
+                (Check::from_result(self.probe_store(store.as_mut())), Check::from_result(self.check_key(provider, store.as_mut())))
+            },
+            None => (Check::Skipped, Check::Skipped),
+        };
+        KeyHealth{
+            epoch: self.epoch,
+            store: store,
+            key: key,
+        }
+    }
+
+    /// Prepare the key for its first packets: fault in the memory of
+    /// its filters, compute a shared secret, and write a probe to its
+    /// store's metadata and read it back, so that none of the first packets of
+    /// the epoch wait for page faults, lazily initialised key material
+    /// or a cold store. A shed key is reopened first.
+    pub fn warm_up(&self) -> Result<(), MixKeyError> {
+        {
+            let shards = self.wake()?;
+            let shards = shards.as_ref().unwrap();
+            shards.prefault();
+            self.probe_store(shards.store().as_mut()).map_err(MixKeyError::StoreError).context(self.epoch, Op::WarmUp, &self.path)?;
+        }
+        self.epoch_key().exp(&self.public_key()).context(self.epoch, Op::WarmUp, &self.path)?;
+        self.warm.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Returns true if the key was warmed up and has not been shed since.
+    pub fn is_warm(&self) -> bool {
+        self.warm.load(Ordering::Relaxed) && !self.is_shed()
+    }
+
+    /// Write the time to the store's metadata and read it back, leaving
+    /// its tags alone. Stores whose metadata can only be initialised are
+    /// probed with the value first written.
+    fn probe_store(&self, store: &mut dyn ReplayStore) -> Result<(), String> {
+        let mut nonce = [0u8; 8];
+        LittleEndian::write_u64(&mut nonce, MixKey::nanos(self.timer.now()));
+        let expected = if store.set_metadata(HEALTH_PROBE_KEY, &nonce).map_err(|e| e.to_string())? {
+            nonce.to_vec()
+        } else {
+            store.init_metadata(HEALTH_PROBE_KEY, &nonce).map_err(|e| e.to_string())?
+        };
+        if store.metadata(HEALTH_PROBE_KEY).map_err(|e| e.to_string())? != Some(expected) {
+            return Err("health probe was written but not read back".to_string())
+        }
+        Ok(())
+    }
+
+    fn check_key(&self, provider: &dyn KeyProvider, store: &mut dyn ReplayStore) -> Result<(), String> {
+        let key_id = match store.metadata(MIX_CACHE_KEY).map_err(|e| e.to_string())? {
+            Some(key_id) => ClearOnDrop::new(key_id),
+            None => return Err("no key identifier is stored".to_string()),
+        };
+        let key = provider.open(self.epoch, &key_id).map_err(|e| e.to_string())?;
+        if key.public_key() != self.public_key() {
+            return Err("the stored key identifier opens to a different key".to_string())
+        }
+        Ok(())
+    }
+
+    /// Returns the key's statistics. A shed key is not reopened, and
+    /// reports no filter estimates.
+    pub fn stats(&self) -> Result<KeyStats, MixKeyError> {
+        let estimates = self.shards.read().unwrap().as_ref().map(|shards| shards.filter_estimates());
+        Ok(KeyStats{
+            epoch: self.epoch,
+            tags: self.tag_count() + self.overflow_count(),
+            fill_ratio: estimates.map(|(fill, _)| fill),
+            false_positive_rate: estimates.map(|(_, rate)| rate),
+            disk_bytes: match self.backend {
+                CacheBackend::Sled if self.path.exists() => fsutil::disk_usage(&self.path)?,
+                _ => 0,
+            },
+            replays: self.replays_detected.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Set the counters this key's replay checks and flushes update.
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    /// Write an archive of every stored tag into `archive_dir`, returning
+    /// the archive's path.
+    #[cfg(feature = "archive")]
+    pub fn archive(&self, archive_dir: &Path) -> Result<PathBuf, MixKeyError> {
+        let shards = self.wake()?;
+        let mut cache = shards.as_ref().unwrap().store();
+        let path = archive::archive_path(archive_dir, self.epoch);
+        archive::write_archive(&path, self.epoch, cache.tags()).context(self.epoch, Op::ArchiveCache, &self.path)?;
+        Ok(path)
+    }
+
+    /// Back up the key's metadata and tags into `backup_dir`. See the
+    /// `backup` module.
+    pub(crate) fn backup_to(&self, backup_dir: &Path) -> Result<BackupEntry, MixKeyError> {
+        let mut metadata = HashMap::new();
+        {
+            let shards = self.wake()?;
+            let mut store = shards.as_ref().unwrap().store();
+            store.flush().context(self.epoch, Op::BackupCache, &self.path)?;
+            for name in FROZEN_METADATA_KEYS.iter() {
+                if let Some(value) = store.metadata(name).context(self.epoch, Op::BackupCache, &self.path)? {
+                    metadata.insert(name.to_string(), value);
+                }
+            }
+        }
+        backup::write_entry(backup_dir, self.epoch, &metadata, |writer| self.export_tags(writer)).context(self.epoch, Op::BackupCache, &self.path)
+    }
+
+    /// Write every stored tag, including any in the overflow store, to
+    /// `writer` in the `dump` format, returning the number of tags
+    /// written.
+    pub fn export_tags<W: Write>(&self, writer: W) -> Result<u64, MixKeyError> {
+        let shards = self.wake()?;
+        let mut cache = shards.as_ref().unwrap().store();
+        let mut overflow = self.overflow.lock().unwrap();
+        let overflow_tags = overflow.as_mut().map(|store| store.tags()).into_iter().flatten();
+        dump::write_dump(writer, self.epoch, cache.tags().chain(overflow_tags)).context(self.epoch, Op::ExportTags, &self.path)
+    }
+
+    /// Like `export_tags`, but write the tags in canonical sorted order
+    /// with `dump::write_sorted_dump`, returning the manifest of content
+    /// digests of the chunks written. Every tag is held in memory while
+    /// the dump is written.
+    pub fn export_tags_sorted<W: Write>(&self, writer: W) -> Result<Vec<ChunkDigest>, MixKeyError> {
+        let tags = self.stored_tags()?;
+        dump::write_sorted_dump(writer, self.epoch, tags).context(self.epoch, Op::ExportTags, &self.path)
+    }
+
+    /// Returns every stored tag, including any in the overflow store.
+    fn stored_tags(&self) -> Result<Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>, MixKeyError> {
+        let shards = self.wake()?;
+        let mut cache = shards.as_ref().unwrap().store();
+        let mut overflow = self.overflow.lock().unwrap();
+        let overflow_tags = overflow.as_mut().map(|store| store.tags()).into_iter().flatten();
+        cache.tags().chain(overflow_tags).collect::<Result<Vec<_>, _>>().context(self.epoch, Op::ExportTags, &self.path)
+    }
+
+    /// Insert every tag of a dump of this key's epoch read from
+    /// `reader`, returning the number of tags that were not already
+    /// stored. Tags of the chunks before an invalid chunk are kept.
+    pub fn import_tags<R: Read>(&mut self, reader: R) -> Result<u64, MixKeyError> {
+        let dump = dump::DumpReader::new(reader).context(self.epoch, Op::ImportTags, &self.path)?;
+        if dump.epoch() != self.epoch {
+            return Err(MixKeyError::InvalidDump.context(self.epoch, Op::ImportTags, &self.path))
+        }
+        self.insert_tags(dump)
+    }
+
+    /// Like `import_tags`, but the dump's chunks are verified and
+    /// inserted by several threads, and progress is flushed and passed
+    /// to `progress` at every checkpoint. An interrupted import resumes
+    /// from the last checkpoint through `ImportConfig::resume_from`.
+    pub fn import_tags_parallel<R, F>(&mut self, reader: R, config: &ImportConfig, progress: F) -> Result<ImportProgress, MixKeyError>
+        where R: Read, F: FnMut(&ImportProgress)
+    {
+        tagimport::import(self, reader, config, progress)
+    }
+
+    /// Remove a tag, returning true if it was stored, so that a packet
+    /// carrying it is accepted again. This needs a counting filter and a
+    /// store that can delete tags, and otherwise fails with
+    /// `RemovalUnsupported`. Filter replicas keep finding the tag until
+    /// they are next rebuilt from the cache.
+    pub fn remove_tag(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if !self.counting_filter {
+            return Err(MixKeyError::RemovalUnsupported)
+        }
+        let shards = self.wake()?;
+        let shard = shards.as_ref().unwrap().get(tag);
+        let mut cache = shard.store.lock().unwrap();
+        let mut filter = shard.filter.lock().unwrap();
+
+        let mut removed = false;
+        if cache.remove(tag).context(self.epoch, Op::RemoveTag, &self.path)? {
+            self.tags.fetch_sub(1, Ordering::Relaxed);
+            removed = true;
+        }
+        if let Some(ref mut overflow) = *self.overflow.lock().unwrap() {
+            if overflow.remove(tag).context(self.epoch, Op::RemoveTag, &self.path)? {
+                self.overflowed.fetch_sub(1, Ordering::Relaxed);
+                removed = true;
+            }
+        }
+        if self.spill.lock().unwrap().remove(tag).context(self.epoch, Op::RemoveTag, &self.path)? {
+            self.overflowed.fetch_sub(1, Ordering::Relaxed);
+            removed = true;
+        }
+        if removed {
+            filter.remove(tag);
+            self.replays[shard_of(tag)].lock().unwrap().forget(tag);
+        }
+        Ok(removed)
+    }
+
+    /// Refuse a tag with bytes set past the configured tag size, which
+    /// the store would drop.
+    fn check_padding(&self, tag: &Tag) -> Result<(), MixKeyError> {
+        if tag.0[self.tag_size..].iter().any(|&b| b != 0) {
+            return Err(MixKeyError::InvalidTag.context(self.epoch, Op::LookupTag, &self.path))
+        }
+        Ok(())
+    }
+
+    /// Insert the given tags without counting them as packets seen,
+    /// returning the number that were not already stored.
+    fn insert_tags<I>(&self, tags: I) -> Result<u64, MixKeyError>
+        where I: IntoIterator<Item=Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>>
+    {
+        let shards = self.wake()?;
+        let shards = shards.as_ref().unwrap();
+
+        let mut imported = 0;
+        for raw in tags {
+            let tag = Tag(raw.context(self.epoch, Op::ImportTags, &self.path)?);
+            self.check_padding(&tag)?;
+            let shard = shards.get(&tag);
+            shard.filter.lock().unwrap().insert(&tag);
+            if !shard.store.lock().unwrap().insert(&tag).context(self.epoch, Op::ImportTags, &self.path)? {
+                self.deltas.lock().unwrap().push(&tag);
+                #[cfg(feature = "accumulator")]
+                self.accumulator.lock().unwrap().push(&tag);
+                self.tags.fetch_add(1, Ordering::Relaxed);
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Create the sled cache of a key migrated from the Katzenpost Go
+    /// server, holding its private key and tags.
+    #[cfg(feature = "katzenpost-compat")]
+    pub fn from_katzenpost(key: &KatzenpostKey, line_rate: u64, epoch_duration: u64, base_dir: &String) -> Result<MixKey, MixKeyError> {
+        let path = fsutil::epoch_dir(Path::new(base_dir), key.epoch);
+        let config = CacheConfig::default();
+        let cache_cfg_builder = MixKey::cache_config(&path, line_rate, epoch_duration, &config);
+        let mut store = MixKey::open_sled(key.epoch, &path, &cache_cfg_builder)?;
+        let key_id = key.private_key.to_vec();
+        if store.init_metadata(MIX_CACHE_KEY, &key_id).context(key.epoch, Op::StoreKey, &path)? != key_id {
+            return Err(MixKeyError::InvalidKatzenpostKey.context(key.epoch, Op::StoreKey, &path))
+        }
+        let mix_key = MixKey::from_store(CacheBackend::Sled, &LocalKeyProvider, line_rate, key.epoch, epoch_duration, &config, Box::new(store), path)?;
+        mix_key.insert_tags(key.tags.iter().map(|tag| Ok(*tag)))?;
+        Ok(mix_key)
+    }
+
+    /// Returns this key's private key and tags for the Katzenpost Go
+    /// server.
+    #[cfg(feature = "katzenpost-compat")]
+    pub fn to_katzenpost(&self) -> Result<KatzenpostKey, MixKeyError> {
+        let shards = self.wake()?;
+        let tags = shards.as_ref().unwrap().store().tags().collect::<Result<Vec<_>, _>>().context(self.epoch, Op::ExportTags, &self.path)?;
+        Ok(KatzenpostKey{
+            epoch: self.epoch,
+            private_key: self.epoch_key().export()?,
+            tags: tags,
+        })
+    }
+
+    /// Make a read replica of this key's filter for a worker thread.
+    pub fn replica(&self) -> Result<FilterReplica, MixKeyError> {
+        FilterReplica::new(self.clone())
+    }
+
+    /// Set the monotonic clock used for idle tracking and flush
+    /// scheduling, restarting both from the clock's current time.
+    pub fn set_monotonic_clock(&mut self, timer: Arc<dyn MonotonicClock>) {
+        self.last_used.store(MixKey::nanos(timer.now()), Ordering::Relaxed);
+        *self.last_flush.lock().unwrap() = timer.now();
+        self.timer = timer;
+    }
+
+    /// Returns true if no packet has been processed for at least
+    /// `idle_period` seconds.
+    pub fn is_idle(&self, idle_period: u64) -> bool {
+        let last_used = Duration::from_nanos(self.last_used.load(Ordering::Relaxed));
+        self.timer.now() - last_used >= Duration::from_secs(idle_period)
+    }
+
+    /// Returns true if the filter and cache are currently shed.
+    pub fn is_shed(&self) -> bool {
+        self.shards.read().unwrap().is_none()
+    }
+
+    /// Flush and release the filter memory and the cache handle. They
+    /// are lazily reopened on the next replay check. Only keys kept in
+    /// sled can be reopened, so keys kept elsewhere are never shed. If
+    /// the flush fails, nothing is released.
+    pub fn shed(&mut self) -> Result<(), MixKeyError> {
+        if self.backend != CacheBackend::Sled {
+            return Ok(())
+        }
+        let mut shards = self.shards.write().unwrap();
+        if let Some(ref shards) = *shards {
+            shards.store().flush().context(self.epoch, Op::FlushCache, &self.path)?;
+        }
+        *shards = None;
+        self.warm.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Revoke the key, as when its private key may have leaked: the
+    /// private key is dropped by every clone at once, which wipes it
+    /// from memory unless the provider holds it elsewhere, and the cache
+    /// is destroyed. Clones then refuse tags and Diffie-Hellman
+    /// operations with `KeyUnusable`, but still return the public key.
+    pub fn revoke(self) -> Result<(), MixKeyError> {
+        self.transition(KeyState::Revoked)?;
+        {
+            let mut key = self.key.write().unwrap();
+            let revoked = RevokedKey::of(key.as_ref());
+            *key = Arc::new(revoked);
+        }
+        self.destroy()
+    }
+
+    /// Destroy the key's cache, overwriting its files with zeros before
+    /// removing them, so that a key kept in the cache does not outlive
+    /// its epoch on disk. Overwriting is best effort: SSDs and copy on
+    /// write filesystems may keep the old blocks. Stores other than sled
+    /// are only closed; removing their contents is up to their factory.
+    /// The private key itself is wiped from memory when the last clone
+    /// of the key is dropped, whether or not it was destroyed.
+    pub fn destroy(self) -> Result<(), MixKeyError> {
+        if !self.state().is_final() {
+            self.state.store(KeyState::Destroyed.id(), Ordering::Release);
+        }
+        *self.shards.write().unwrap() = None;
+        *self.overflow.lock().unwrap() = None;
+        if self.backend == CacheBackend::Sled && self.path.exists() {
+            fsutil::wipe_dir(&self.path).context(self.epoch, Op::RemoveCache, &self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Check the tag, storing it if it is fresh, and return true if it
+    /// was seen before. Clones of the key share its state, and threads
+    /// checking tags of different shards do not wait for each other.
+    /// Keys whose lifecycle state does not check tags fail with
+    /// `KeyUnusable`, and keys of a smaller tag size refuse tags that are
+    /// not zero padded with `InvalidTag`.
+    pub fn is_replay(&self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let state = self.state();
+        if !state.is_usable() {
+            return Err(MixKeyError::KeyUnusable(state).context(self.epoch, Op::InsertTag, &self.path))
+        }
+        self.check_padding(tag)?;
+        let replays = &self.replays[shard_of(tag)];
+        if replays.lock().unwrap().lookup(tag) {
+            #[cfg(feature = "metrics")]
+            self.metrics.replay_hit();
+            self.replay_detected(tag);
+            return Ok(true)
+        }
+        let replay = self.check_tag(tag)?;
+        if replay {
+            replays.lock().unwrap().record(tag);
+            self.replay_detected(tag);
+        }
+        Ok(replay)
+    }
+
+    /// Count a replay `is_replay` detected, tell the replay subscribers
+    /// and count it towards a replay storm.
+    fn replay_detected(&self, tag: &Tag) {
+        self.replays_detected.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "tracing")]
+        debug!(epoch = self.epoch, tag = %tag.prefix_hex(), "replay detected");
+        let mut subscribers = self.replay_events.lock().unwrap();
+        if !subscribers.is_empty() {
+            subscribers.publish(ReplayEvent{
+                epoch: self.epoch,
+                tag: tag.clone(),
+                timestamp: SystemTime::now(),
+            });
+        }
+        drop(subscribers);
+        if let Some(ref storm) = self.storm {
+            storm.lock().unwrap().record(self.timer.now(), self.epoch, tag);
+        }
+    }
+
+    /// Returns a receiver of every replay this key detects from now on.
+    /// Keys of a `MixKeys` share the subscribers of
+    /// `MixKeys::subscribe_replays`.
+    pub fn subscribe_replays(&self) -> Receiver<ReplayEvent> {
+        self.replay_events.lock().unwrap().subscribe()
+    }
+
+    /// Like `is_replay`, but checks the tag where it lies in the packet
+    /// buffer instead of an owned `Tag`. Slices of any other length
+    /// than `SPHINX_REPLAY_TAG_SIZE` fail with `InvalidTag`.
+    pub fn is_replay_bytes(&self, tag: &[u8]) -> Result<bool, MixKeyError> {
+        self.is_replay(Tag::from_slice(tag)?)
+    }
+
+    /// Returns true if the tag was seen before, without storing it. A
+    /// packet pipeline can use this to drop obvious replays before
+    /// unwrapping them, and call `is_replay` once the unwrap succeeds,
+    /// which still decides the race between two copies of a packet.
+    pub fn contains(&self, tag: &Tag) -> Result<bool, MixKeyError> {
+        self.check_padding(tag)?;
+        if self.replays[shard_of(tag)].lock().unwrap().hits(tag).is_some() {
+            return Ok(true)
+        }
+        let shards = self.wake()?;
+        let shard = shards.as_ref().unwrap().get(tag);
+        let mut cache = shard.store.lock().unwrap();
+        if !shard.filter.lock().unwrap().contains(tag) {
+            return Ok(false)
+        }
+        if cache.contains(tag).context(self.epoch, Op::LookupTag, &self.path)? {
+            return Ok(true)
+        }
+        self.overflow_contains(tag).context(self.epoch, Op::LookupTag, &self.path)
+    }
+
+    fn check_tag(&self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let shards = self.wake()?;
+        let shard = shards.as_ref().unwrap().get(tag);
+        let mut cache_guard = shard.store.lock().unwrap();
+        let mut filter = shard.filter.lock().unwrap();
+        let cache = cache_guard.as_mut();
+
+        let maybe_replay = filter.contains(tag);
+        if !maybe_replay {
+            return self.insert_tag(cache, &mut filter, tag)
+        }
+        if cache.contains(tag).context(self.epoch, Op::LookupTag, &self.path)? || self.overflow_contains(tag).context(self.epoch, Op::LookupTag, &self.path)? {
+            #[cfg(feature = "metrics")]
+            self.metrics.replay_hit();
+            return Ok(true)
+        }
+        #[cfg(feature = "metrics")]
+        self.metrics.false_positive();
+        self.insert_tag(cache, &mut filter, tag)
+    }
+
+    /// Insert the tag, returning true if the store already held it,
+    /// which happens when another process sharing the store saw it first.
+    fn insert_tag(&self, cache: &mut dyn ReplayStore, filter: &mut TagFilter, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.overflow_contains(tag).context(self.epoch, Op::InsertTag, &self.path)? {
+            #[cfg(feature = "metrics")]
+            self.metrics.replay_hit();
+            return Ok(true)
+        }
+        if let Some(quota) = self.over_quota()? {
+            if cache.contains(tag).context(self.epoch, Op::InsertTag, &self.path)? {
+                #[cfg(feature = "metrics")]
+                self.metrics.replay_hit();
+                return Ok(true)
+            }
+            return self.insert_spilled(filter, tag, quota)
+        }
+        if let Some(max_tags) = self.max_tags {
+            if self.tags.load(Ordering::Relaxed) >= max_tags {
+                if cache.contains(tag).context(self.epoch, Op::InsertTag, &self.path)? {
+                    #[cfg(feature = "metrics")]
+                    self.metrics.replay_hit();
+                    return Ok(true)
+                }
+                if self.overflow_behavior != OverflowBehavior::LogAndContinue {
+                    return self.insert_overflow(filter, tag, max_tags)
+                }
+                if self.overflowed.fetch_add(1, Ordering::Relaxed) == 0 {
+                    warn!("epoch {} reached its limit of {} tags; storing further tags regardless", self.epoch, max_tags);
+                }
+            }
+        }
+        filter.insert(tag);
+        match cache.insert(tag) {
+            Ok(false) => {
+                self.sync_write(cache)?;
+                self.deltas.lock().unwrap().push(tag);
+                #[cfg(feature = "accumulator")]
+                self.accumulator.lock().unwrap().push(tag);
+                self.tags.fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "metrics")]
+                self.metrics.fresh_tag();
+                Ok(false)
+            },
+            Ok(true) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.replay_hit();
+                Ok(true)
+            },
+            Err(e) => Err(e.context(self.epoch, Op::InsertTag, &self.path)),
+        }
+    }
+
+    /// Flush the store a fresh tag was just inserted into, if the
+    /// durability policy asks for every write to be flushed.
+    fn sync_write(&self, store: &mut dyn ReplayStore) -> Result<(), MixKeyError> {
+        if self.durability == DurabilityPolicy::EveryWrite {
+            store.flush().context(self.epoch, Op::FlushCache, &self.path)?;
+        }
+        Ok(())
+    }
+
+    fn overflow_contains(&self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.disk_quota.is_some() && self.spill.lock().unwrap().contains(tag)? {
+            return Ok(true)
+        }
+        match *self.overflow.lock().unwrap() {
+            Some(ref mut overflow) => overflow.contains(tag),
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the disk quota if the cache directory is over it,
+    /// measuring the directory again every `MIX_KEY_QUOTA_CHECK_TAGS`
+    /// calls.
+    fn over_quota(&self) -> Result<Option<u64>, MixKeyError> {
+        let quota = match self.disk_quota {
+            Some(quota) => quota,
+            None => return Ok(None),
+        };
+        if self.quota_checks.fetch_add(1, Ordering::Relaxed) % MIX_KEY_QUOTA_CHECK_TAGS == 0 {
+            self.measure_disk()?;
+        }
+        if self.disk_bytes.load(Ordering::Relaxed) > quota {
+            return Ok(Some(quota))
+        }
+        Ok(None)
+    }
+
+    fn measure_disk(&self) -> Result<(), MixKeyError> {
+        if self.path.exists() {
+            let bytes = fsutil::disk_usage(&self.path).context(self.epoch, Op::InsertTag, &self.path)?;
+            self.disk_bytes.store(bytes, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Handle a fresh tag arriving after the cache directory went over
+    /// its disk quota, warning the first time it happens.
+    fn insert_spilled(&self, filter: &mut TagFilter, tag: &Tag, quota: u64) -> Result<bool, MixKeyError> {
+        if !self.quota_fallback {
+            return Err(MixKeyError::DiskQuotaExceeded(quota).context(self.epoch, Op::InsertTag, &self.path))
+        }
+        let mut spill = self.spill.lock().unwrap();
+        if spill.is_empty() {
+            warn!("epoch {} exceeds its disk quota of {} bytes; keeping further tags in memory only", self.epoch, quota);
+        }
+        filter.insert(tag);
+        if spill.insert(tag).context(self.epoch, Op::InsertTag, &self.path)? {
+            #[cfg(feature = "metrics")]
+            self.metrics.replay_hit();
+            return Ok(true)
+        }
+        self.deltas.lock().unwrap().push(tag);
+        #[cfg(feature = "accumulator")]
+        self.accumulator.lock().unwrap().push(tag);
+        self.overflowed.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.metrics.fresh_tag();
+        Ok(false)
+    }
+
+    /// Handle a fresh tag arriving after the cache reached its limit,
+    /// warning the first time it happens.
+    fn insert_overflow(&self, filter: &mut TagFilter, tag: &Tag, max_tags: u64) -> Result<bool, MixKeyError> {
+        if self.overflowed.load(Ordering::Relaxed) == 0 {
+            warn!("epoch {} reached its limit of {} tags; handling further tags as {:?}", self.epoch, max_tags, self.overflow_behavior);
+        }
+        if self.overflow_behavior == OverflowBehavior::Reject {
+            self.overflowed.fetch_add(1, Ordering::Relaxed);
+            return Err(MixKeyError::TagLimitReached(max_tags).context(self.epoch, Op::InsertTag, &self.path))
+        }
+        let mut overflow = self.overflow.lock().unwrap();
+        if overflow.is_none() {
+            *overflow = Some(match self.overflow_behavior {
+                OverflowBehavior::OverflowTree => MixKey::open_overflow_tree(self.epoch, &self.path, &self.cache_cfg_builder)?,
+                OverflowBehavior::SecondaryFilter => Box::new(FilterStore::new(self.false_positive_rate, max_tags.min(u32::MAX as u64) as u32)),
+                _ => Box::new(MemoryStore::default()),
+            });
+        }
+        // The secondary filter keeps the flood out of the first one.
+        if self.overflow_behavior != OverflowBehavior::SecondaryFilter {
+            filter.insert(tag);
+        }
+        match overflow.as_mut().unwrap().insert(tag) {
+            Ok(false) => {
+                self.sync_write(overflow.as_mut().unwrap().as_mut())?;
+                self.deltas.lock().unwrap().push(tag);
+                #[cfg(feature = "accumulator")]
+                self.accumulator.lock().unwrap().push(tag);
+                self.overflowed.fetch_add(1, Ordering::Relaxed);
+                #[cfg(feature = "metrics")]
+                self.metrics.fresh_tag();
+                Ok(false)
+            },
+            Ok(true) => {
+                #[cfg(feature = "metrics")]
+                self.metrics.replay_hit();
+                Ok(true)
+            },
+            Err(e) => Err(e.context(self.epoch, Op::InsertTag, &self.path)),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), MixKeyError> {
+        #[cfg(feature = "tracing")]
+        let _span = info_span!("flush", epoch = self.epoch).entered();
+        let start = self.timer.now();
+        if let Some(ref shards) = *self.shards.read().unwrap() {
+            shards.store().flush().context(self.epoch, Op::FlushCache, &self.path)?;
+        }
+        if let Some(ref mut overflow) = *self.overflow.lock().unwrap() {
+            overflow.flush().context(self.epoch, Op::FlushCache, &self.path.join(OVERFLOW_DIR_NAME))?;
+        }
+        for namespace in self.namespaces.lock().unwrap().values() {
+            namespace.flush()?;
+        }
+        if self.disk_quota.is_some() {
+            if let Err(e) = self.measure_disk() {
+                warn!("failed to measure the cache of epoch {}: {}", self.epoch, e);
+            }
+        }
+        let now = self.timer.now();
+        #[cfg(feature = "metrics")]
+        self.metrics.flushed(now - start);
+        *self.flush_duration.lock().unwrap() = now - start;
+        *self.last_flush.lock().unwrap() = now;
+        Ok(())
+    }
+
+    /// Returns how long the last flush took.
+    pub fn flush_duration(&self) -> Duration {
+        *self.flush_duration.lock().unwrap()
+    }
+
+    /// Flush if at least `interval` has passed since the last flush,
+    /// and the durability policy is an interval.
+    pub fn flush_if_due(&mut self, interval: Duration) -> Result<bool, MixKeyError> {
+        match self.durability {
+            DurabilityPolicy::IntervalMs(_) => {},
+            _ => return Ok(false),
+        }
+        let last_flush = *self.last_flush.lock().unwrap();
+        if self.timer.now() - last_flush < interval {
+            return Ok(false)
+        }
+        self.flush()?;
+        Ok(true)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+
+    extern crate tempfile;
+    extern crate rand;
+
+    use self::rand::Rng;
+    use self::rand::os::OsRng;
+    use self::tempfile::TempDir;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+    use constants::{MIX_KEY_CLOCK_SKEW, MIX_KEY_FALSE_POSITIVE_RATE, MIX_KEY_FLUSH_FREQUENCY, MIX_KEY_GRACE_PERIOD};
+    use timesource::ManualMonotonicClock;
+    use super::*;
+
+
+    #[test]
+    fn basic_mix_keys_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let line_rate = 128974848;
+        let mix_keys = MixKeys::new(clock, 3, base_dir, line_rate).unwrap();
+
+        let handle = mix_keys.handle();
+        assert_eq!(handle.epochs().len(), mix_keys.keys.read().unwrap().len());
+        for k in handle.epochs() {
+            assert!(handle.public_key(k).is_some());
+        }
+
+        let epoch = mix_keys.clock.now().epoch;
+        let _reader = mix_keys.keys.read().unwrap();
+        assert!(mix_keys.key(epoch).is_some());
+    }
+
+    #[test]
+    fn export_identity_bundle_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mut mix_keys = MixKeys::new(clock, 3, base_dir, 128974848).unwrap();
+
+        let mut rng = OsRng::new().unwrap();
+        let operator_key = PrivateKey::generate(&mut rng).unwrap();
+        let bundle = mix_keys.export_identity_bundle(&operator_key.public_key()).unwrap();
+        let keys = bundle.open(&operator_key).unwrap();
+        assert_eq!(keys.len(), 3);
+        for (epoch, private_key, path) in keys {
+            assert_eq!(mix_keys.public_key(epoch).unwrap(), private_key.public_key());
+            assert!(path.ends_with(format!("mix_key.{}", epoch)));
+        }
+    }
+
+    #[test]
+    fn shed_idle_mix_keys_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mut mix_keys = MixKeys::new(clock.clone(), 2, base_dir, 128974848).unwrap();
+        let epoch = clock.now().epoch;
+
+        let mut rng = OsRng::new().unwrap();
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        rng.fill_bytes(&mut raw);
+        let tag = Tag(raw);
+        let key = mix_keys.keys.read().unwrap().get(&epoch).unwrap().clone();
+        assert_eq!(key.is_replay(&tag).unwrap(), false);
+
+        assert!(mix_keys.shed_idle().is_empty());
+        mix_keys.set_idle_period(0);
+        assert_eq!(mix_keys.shed_idle(), vec![epoch, epoch + 1]);
+        assert!(key.is_shed());
+
+        assert_eq!(key.is_replay(&tag).unwrap(), true);
+        assert!(!key.is_shed());
+    }
+
+    #[test]
+    fn flush_due_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mut mix_keys = MixKeys::new(clock.clone(), 2, base_dir, 1024 * 1024).unwrap();
+        let timer = Arc::new(ManualMonotonicClock::new());
+        mix_keys.set_monotonic_clock(timer.clone());
+        let epoch = clock.now().epoch;
+
+        assert!(mix_keys.flush_due().unwrap().is_empty());
+        timer.advance(Duration::from_millis(MIX_KEY_FLUSH_FREQUENCY));
+        assert_eq!(mix_keys.flush_due().unwrap(), vec![epoch, epoch + 1]);
+        assert!(mix_keys.flush_due().unwrap().is_empty());
+
+        let key = mix_keys.key(epoch).unwrap();
+        assert!(!key.is_idle(60));
+        timer.advance(Duration::from_secs(60));
+        assert!(key.is_idle(60));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn render_metrics_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mut mix_keys = MixKeys::builder(clock.clone()).num_mix_keys(2).base_dir(base_dir).line_rate(1024 * 1024).node_id("mix1").build().unwrap();
+        let epoch = clock.now().epoch;
+
+        let mut rng = OsRng::new().unwrap();
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        rng.fill_bytes(&mut raw);
+        let key = mix_keys.key(epoch).unwrap();
+        assert_eq!(key.is_replay(&Tag(raw)).unwrap(), false);
+        assert_eq!(key.is_replay(&Tag(raw)).unwrap(), true);
+        mix_keys.flush_due().unwrap();
+        assert_eq!(key.tag_count(), 1);
+        assert_eq!(mix_keys.metrics().fresh_tags(), 1);
+        assert_eq!(mix_keys.metrics().replay_hits(), 1);
+
+        let out = mix_keys.render_metrics().unwrap();
+        assert!(out.contains(&format!("sphinx_replay_cache_epoch_tags{{backend=\"sled\",epoch=\"{}\",node_id=\"mix1\"}} 1\n", epoch)));
+        assert!(out.contains(&format!("sphinx_replay_cache_epoch_tags{{backend=\"sled\",epoch=\"{}\",node_id=\"mix1\"}} 0\n", epoch + 1)));
+        assert!(out.contains(&format!("sphinx_replay_cache_replay_hits_total{{backend=\"sled\",epoch=\"{}\",node_id=\"mix1\"}} 1\n", epoch)));
+    }
+
+    fn clock_at(elapsed: u64) -> epoch::Clock {
+        let period = 1000;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        epoch::Clock::new(epoch::Config{
+            epoch: now - (5 * period + elapsed),
+            period: period,
+        })
+    }
+
+    #[test]
+    fn countdowns_test() {
+        let clock = clock_at(100);
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        let countdowns = mix_keys.countdowns();
+        assert_eq!(countdowns.len(), 2);
+        assert_eq!(countdowns[0], KeyCountdown{
+            epoch: epoch,
+            until_activation: 0,
+            until_expiry: 900,
+            until_destruction: 900 + MIX_KEY_GRACE_PERIOD as u64,
+        });
+        assert_eq!(countdowns[1].epoch, epoch + 1);
+        assert_eq!(countdowns[1].until_activation, 900);
+    }
+
+    #[test]
+    fn remove_stale_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let stale_dir = fsutil::epoch_dir(base_dir.path(), epoch - 2);
+        fs::create_dir_all(&stale_dir).unwrap();
+        fs::create_dir_all(base_dir.path().join("unrelated")).unwrap();
+
+        let mut mix_keys = MixKeys::new(clock, 2, base_dir.path().to_str().unwrap().to_string(), 1024 * 1024).unwrap();
+        assert!(!stale_dir.exists());
+        assert!(base_dir.path().join("unrelated").exists());
+        assert!(fsutil::epoch_dir(base_dir.path(), epoch).exists());
+        assert_eq!(mix_keys.remove_stale().unwrap(), 0);
+
+        let future_dir = fsutil::epoch_dir(base_dir.path(), epoch + 5);
+        fs::create_dir_all(&future_dir).unwrap();
+        fs::create_dir_all(&stale_dir).unwrap();
+        assert_eq!(mix_keys.remove_stale().unwrap(), 1);
+        assert!(!stale_dir.exists());
+        assert!(future_dir.exists());
+    }
+
+    #[test]
+    fn health_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let mut mix_keys = MixKeys::new(clock, 2, base_dir.path().to_str().unwrap().to_string(), 1024 * 1024).unwrap();
+        let timer = Arc::new(ManualMonotonicClock::new());
+        mix_keys.set_monotonic_clock(timer.clone());
+        let report = mix_keys.health();
+        assert!(report.is_healthy());
+        assert_eq!(report.flush, Check::Skipped);
+        assert_eq!(report.keys, vec![
+            KeyHealth{ epoch: epoch, store: Check::Passed, key: Check::Passed },
+            KeyHealth{ epoch: epoch + 1, store: Check::Passed, key: Check::Passed },
+        ]);
+        assert_eq!(mix_keys.key(epoch).unwrap().tag_count(), 0);
+        assert_eq!(mix_keys.key(epoch).unwrap().shards.read().unwrap().as_ref().unwrap().store().tags().count(), 0);
+
+        mix_keys.flush_due().unwrap();
+        assert_eq!(mix_keys.health().flush, Check::Passed);
+        timer.advance(mix_keys.flush_bounds().max_interval * (MIX_KEY_HEALTH_FLUSH_INTERVALS + 1));
+        assert!(!mix_keys.health().is_healthy());
+        mix_keys.flush_due().unwrap();
+        assert!(mix_keys.health().is_healthy());
+    }
+
+    /// A clock stopped five minutes into epoch 42 of twenty minutes.
+    struct StoppedClock;
+
+    impl ClockSource for StoppedClock {
+        fn now(&self) -> Time {
+            Time{ epoch: 42, elapsed: 300, till: 900 }
+        }
+
+        fn period(&self) -> u64 {
+            1200
+        }
+    }
+
+    #[test]
+    fn clock_source_test() {
+        let mix_keys = MixKeys::builder(StoppedClock).num_mix_keys(2).backend(CacheBackend::Memory).build().unwrap();
+        assert!(mix_keys.key(42).is_some());
+        assert!(mix_keys.key(43).is_some());
+        let countdowns = mix_keys.countdowns();
+        assert_eq!(countdowns[0].until_expiry, 900);
+        assert_eq!(countdowns[1].until_activation, 900);
+        assert_eq!(countdowns[1].until_expiry, 2100);
+    }
+
+    #[test]
+    fn warm_up_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let mix_keys = MixKeys::new(clock.clone(), 2, base_dir.path().to_str().unwrap().to_string(), 1024 * 1024).unwrap();
+        assert!(!mix_keys.is_ready());
+        assert_eq!(mix_keys.warm_up().unwrap(), vec![epoch, epoch + 1]);
+        assert!(mix_keys.is_ready());
+        assert!(mix_keys.warm_up().unwrap().is_empty());
+        assert_eq!(mix_keys.key(epoch).unwrap().tag_count(), 0);
+        assert_eq!(mix_keys.key(epoch).unwrap().shards.read().unwrap().as_ref().unwrap().store().tags().count(), 0);
+
+        let mut key = mix_keys.key(epoch).unwrap();
+        key.shed().unwrap();
+        assert!(!key.is_warm());
+        assert!(!mix_keys.is_ready());
+        drop(key);
+        drop(mix_keys);
+
+        let mix_keys = MixKeys::builder(clock).num_mix_keys(2).backend(CacheBackend::Memory).warm_up(true).build().unwrap();
+        assert!(mix_keys.is_ready());
+    }
+
+    #[derive(Default)]
+    struct RecordingSink(Mutex<Vec<CapacityAlarm>>);
+
+    impl AlertSink for RecordingSink {
+        fn alert(&self, alarm: &CapacityAlarm) {
+            self.0.lock().unwrap().push(*alarm);
+        }
+    }
+
+    #[test]
+    fn capacity_alarm_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::builder(clock).num_mix_keys(2).backend(CacheBackend::Memory).expected_tags(64).build().unwrap();
+        let timer = Arc::new(ManualMonotonicClock::new());
+        mix_keys.set_monotonic_clock(timer.clone());
+        let sink = Arc::new(RecordingSink::default());
+        mix_keys.set_alert_sink(sink.clone());
+        assert_eq!(mix_keys.check_capacity().unwrap(), vec![]);
+
+        let key = mix_keys.key(epoch).unwrap();
+        for i in 0..32u8 {
+            key.is_replay(&Tag([i; SPHINX_REPLAY_TAG_SIZE])).unwrap();
+        }
+        timer.advance(Duration::from_secs(MIX_KEY_CAPACITY_CHECK_INTERVAL));
+        mix_keys.flush_due().unwrap();
+        let alarm = CapacityAlarm::FilterCapacity{ epoch: epoch, seconds: MIX_KEY_CAPACITY_CHECK_INTERVAL };
+        assert_eq!(*sink.0.lock().unwrap(), vec![alarm]);
+        assert_eq!(mix_keys.check_capacity().unwrap(), vec![alarm]);
+        assert_eq!(sink.0.lock().unwrap().len(), 1);
+        timer.advance(Duration::from_secs(MIX_KEY_CAPACITY_CHECK_INTERVAL));
+        assert_eq!(mix_keys.check_capacity().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn clock_rollback_test() {
+        let base_dir = TempDir::new().unwrap();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let open = |epoch: u64, policy| MixKeys::builder(epoch::Clock::new(epoch::Config{ epoch: now - (epoch * 1000 + 100), period: 1000 }))
+            .num_mix_keys(2)
+            .base_dir(base_dir.path().to_str().unwrap().to_string())
+            .future_cache_policy(FutureCachePolicy::Adopt)
+            .clock_rollback_policy(policy)
+            .build();
+        let mix_keys = open(5, ClockRollbackPolicy::Refuse).unwrap();
+        assert_eq!(mix_keys.highest_epoch(), 5);
+        drop(mix_keys);
+
+        match open(4, ClockRollbackPolicy::Refuse) {
+            Err(MixKeyError::ClockRollback{epoch: 4, highest: 5}) => {},
+            _ => panic!("opened after a clock rollback"),
+        }
+        let mut mix_keys = open(4, ClockRollbackPolicy::Gate).unwrap();
+        assert_eq!(mix_keys.highest_epoch(), 5);
+        assert!(mix_keys.key(4).is_none());
+        assert!(!fsutil::epoch_dir(base_dir.path(), 4).exists());
+        assert!(mix_keys.key(5).is_some());
+        match mix_keys.generate(3) {
+            Err(MixKeyError::ClockRollback{..}) => {},
+            _ => panic!("generated a key below the highest epoch seen"),
+        }
+        assert!(mix_keys.generate(6).unwrap());
+        assert!(mix_keys.key(7).is_some());
+        drop(mix_keys);
+
+        let mix_keys = open(6, ClockRollbackPolicy::Refuse).unwrap();
+        assert_eq!(mix_keys.highest_epoch(), 6);
+    }
+
+    #[test]
+    fn future_caches_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let open = |policy| MixKeys::builder(clock.clone())
+            .num_mix_keys(2)
+            .base_dir(base_dir.path().to_str().unwrap().to_string())
+            .line_rate(1024 * 1024)
+            .future_cache_policy(policy)
+            .build()
+            .unwrap();
+        let future_dir = fsutil::epoch_dir(base_dir.path(), epoch + 100);
+        fs::create_dir_all(&future_dir).unwrap();
+        fs::write(future_dir.join("db"), b"old").unwrap();
+
+        assert_eq!(open(FutureCachePolicy::Adopt).future_caches(), &[epoch + 100]);
+        assert!(future_dir.exists());
+        assert_eq!(open(FutureCachePolicy::Quarantine).future_caches(), &[epoch + 100]);
+        assert!(!future_dir.exists());
+        assert_eq!(fs::read_dir(base_dir.path().join("quarantine")).unwrap().count(), 1);
+        assert!(fsutil::epoch_dir(base_dir.path(), epoch + 1).exists());
+
+        fs::create_dir_all(&future_dir).unwrap();
+        assert_eq!(open(FutureCachePolicy::Delete).future_caches(), &[epoch + 100]);
+        assert!(!future_dir.exists());
+        assert!(open(FutureCachePolicy::Delete).future_caches().is_empty());
+    }
+
+    #[test]
+    fn unwrap_batch_test() {
+        let clock = clock_at(10);
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        mix_keys.generate(epoch - 1).unwrap();
+        let mut batch = mix_keys.unwrap_batch();
+        assert_eq!(batch.epochs(), vec![epoch, epoch - 1]);
+
+        let mut rng = OsRng::new().unwrap();
+        let senders: Vec<PrivateKey> = (0..3).map(|_| PrivateKey::generate(&mut rng).unwrap()).collect();
+        for (i, sender) in senders.iter().enumerate() {
+            batch.push(i, sender.public_key());
+        }
+        let results = batch.run().unwrap();
+        assert_eq!(results.len(), 3);
+        for (i, candidates) in results {
+            assert_eq!(candidates.iter().map(|x| x.epoch).collect::<Vec<_>>(), vec![epoch, epoch - 1]);
+            for candidate in candidates {
+                let key = mix_keys.key(candidate.epoch).unwrap();
+                assert_eq!(candidate.shared_secret, senders[i].exp(&key.public_key()));
+            }
+        }
+
+        let mix_keys = MixKeys::in_memory(clock_at(MIX_KEY_GRACE_PERIOD as u64 + 100), 2, 1024 * 1024).unwrap();
+        assert_eq!(mix_keys.unwrap_batch::<usize>().epochs(), vec![epoch]);
+    }
+
+    #[test]
+    fn key_for_packet_test() {
+        let clock = clock_at(975);
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 3, 1024 * 1024).unwrap();
+        assert_eq!(mix_keys.key_for_packet(epoch).unwrap().epoch(), epoch);
+        assert_eq!(mix_keys.key_for_packet(epoch + 1).unwrap().epoch(), epoch + 1);
+        match mix_keys.key_for_packet(epoch + 2) {
+            Err(MixKeyError::EpochNotYetValid{epoch: x, starts_in}) => {
+                assert_eq!(x, epoch + 2);
+                assert!(starts_in > 1000);
+            },
+            _ => panic!("used a key long before its epoch"),
+        }
+        match mix_keys.key_for_packet(epoch + 3) {
+            Err(MixKeyError::UnknownEpoch(_)) => {},
+            _ => panic!("used a key that does not exist"),
+        }
+        assert_eq!(mix_keys.unwrap_batch::<usize>().epochs(), vec![epoch + 1, epoch]);
+
+        let mix_keys = MixKeys::builder(clock_at(960)).backend(CacheBackend::Memory).build().unwrap();
+        assert!(mix_keys.key(epoch + 1).is_some());
+        match mix_keys.key_for_packet(epoch + 1) {
+            Err(MixKeyError::EpochNotYetValid{starts_in, ..}) => assert!(starts_in > MIX_KEY_CLOCK_SKEW),
+            _ => panic!("used a key before the clock skew tolerance"),
+        }
+
+        let mix_keys = MixKeys::builder(clock_at(975)).backend(CacheBackend::Memory).early_tag_policy(EarlyTagPolicy::Reject).build().unwrap();
+        assert!(mix_keys.key_for_packet(epoch + 1).is_err());
+        assert!(mix_keys.key_for_packet(epoch).is_ok());
+    }
+
+    #[test]
+    fn rollover_test() {
+        let clock = clock_at(MIX_KEY_GRACE_PERIOD as u64 + 100);
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let base_dir_path = base_dir.path().to_str().unwrap().to_string();
+        let journal = RolloverJournal::new(base_dir.path());
+
+        // A crash right after the rollover started is resumed on restart.
+        journal.store(&RolloverRecord{
+            epoch: epoch,
+            stage: RolloverStage::Started,
+        }).unwrap();
+        let mut mix_keys = MixKeys::new(clock.clone(), 2, base_dir_path.clone(), 1024 * 1024).unwrap();
+        assert_eq!(mix_keys.active_epoch(), Some(epoch));
+        assert_eq!(journal.load().unwrap().unwrap().stage, RolloverStage::PruneScheduled);
+
+        mix_keys.rollover(epoch).unwrap();
+        mix_keys.generate(epoch - 1).unwrap();
+        assert_eq!(mix_keys.prune(), vec![epoch - 1]);
+        assert_eq!(journal.load().unwrap().unwrap().stage, RolloverStage::Complete);
+        drop(mix_keys);
+
+        let mix_keys = MixKeys::new(clock, 2, base_dir_path, 1024 * 1024).unwrap();
+        assert_eq!(mix_keys.active_epoch(), Some(epoch));
+    }
+
+    #[test]
+    fn mix_keys_is_replay_test() {
+        let clock = clock_at(MIX_KEY_GRACE_PERIOD as u64 + 100);
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        let tag = Tag([6u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), false);
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), true);
+        match mix_keys.is_replay(epoch - 1, &tag) {
+            Err(MixKeyError::EpochTooOld{epoch: e, current}) => assert_eq!((e, current), (epoch - 1, epoch)),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match mix_keys.is_replay(epoch + 2, &tag) {
+            Err(MixKeyError::EpochTooNew{epoch: e, current}) => assert_eq!((e, current), (epoch + 2, epoch)),
+            x => panic!("unexpected result {:?}", x),
+        }
+        match mix_keys.is_replay(epoch + 1, &tag) {
+            Err(MixKeyError::EpochNotYetValid{..}) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+
+        let events = mix_keys.subscribe();
+        mix_keys.keys.write().unwrap().remove(&epoch);
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), false);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            KeyEvent::KeyGenerated{ epoch: epoch, public_key: mix_keys.public_key(epoch).unwrap() },
+        ]);
+
+        let clock = clock_at(10);
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        match mix_keys.is_replay(epoch - 1, &tag) {
+            Err(MixKeyError::NoKeyForEpoch(e)) => assert_eq!(e, epoch - 1),
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn replay_events_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        let replays = mix_keys.subscribe_replays();
+        let tag = Tag([5u8; SPHINX_REPLAY_TAG_SIZE]);
+        let before = SystemTime::now();
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), false);
+        assert!(replays.try_recv().is_err());
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), true);
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), true);
+        let events: Vec<ReplayEvent> = replays.try_iter().collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].epoch, epoch);
+        assert_eq!(events[0].tag, tag);
+        assert!(events[0].timestamp >= before && events[0].timestamp <= SystemTime::now());
+    }
+
+    #[test]
+    fn replay_storm_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::builder(clock).backend(CacheBackend::Memory).num_mix_keys(2)
+            .replay_storm_alarm(Duration::from_secs(10), 0.2).build().unwrap();
+        mix_keys.set_monotonic_clock(Arc::new(ManualMonotonicClock::new()));
+        let storms = mix_keys.subscribe_storms().unwrap();
+        let tag = Tag([8u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), false);
+        for _ in 0..2 {
+            assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), true);
+        }
+        assert!(storms.try_recv().is_err());
+        assert_eq!(mix_keys.is_replay(epoch, &tag).unwrap(), true);
+        let storm = storms.try_recv().unwrap();
+        assert_eq!((storm.replays, storm.distinct_tags), (3, 1));
+        assert_eq!(storm.epochs, vec![epoch]);
+        assert!(MixKeys::in_memory(epoch::Clock::new_katzenpost(), 1, 1024 * 1024).unwrap().subscribe_storms().is_none());
+    }
+
+    #[test]
+    fn is_replay_bytes_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        let mut packet = vec![0u8; 100];
+        packet[10..10 + SPHINX_REPLAY_TAG_SIZE].copy_from_slice(&[9u8; SPHINX_REPLAY_TAG_SIZE]);
+        let tag = &packet[10..10 + SPHINX_REPLAY_TAG_SIZE];
+        assert_eq!(Tag::from_slice(tag).unwrap(), &Tag([9u8; SPHINX_REPLAY_TAG_SIZE]));
+        assert_eq!(mix_keys.is_replay_bytes(epoch, tag).unwrap(), false);
+        assert_eq!(mix_keys.key(epoch).unwrap().is_replay_bytes(tag).unwrap(), true);
+        assert_eq!(mix_keys.is_replay(epoch, &Tag([9u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), true);
+        match mix_keys.is_replay_bytes(epoch, &packet[..5]) {
+            Err(MixKeyError::InvalidTag) => {},
+            x => panic!("unexpected result {:?}", x),
+        }
+    }
+
+    #[test]
+    fn with_private_key_test() {
+        let clock = clock_at(100);
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        let mut rng = OsRng::new().unwrap();
+        let ephemeral = PrivateKey::generate(&mut rng).unwrap();
+        let shared = mix_keys.with_private_key(epoch, |key| key.exp(&ephemeral.public_key())).unwrap().unwrap();
+        assert_eq!(shared, ephemeral.exp(&mix_keys.public_key(epoch).unwrap()));
+        assert_eq!(mix_keys.with_private_key(epoch, |key| key.public_key()).unwrap(), mix_keys.public_key(epoch).unwrap());
+        assert!(mix_keys.with_private_key(epoch + 1, |_| ()).is_err());
+        assert!(mix_keys.with_private_key(epoch + 5, |_| ()).is_err());
+    }
+
+    #[test]
+    fn key_events_test() {
+        let clock = clock_at(MIX_KEY_GRACE_PERIOD as u64 + 100);
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        let events = mix_keys.subscribe();
+        mix_keys.generate(epoch - 1).unwrap();
+        let stale_key = mix_keys.keys.read().unwrap()[&(epoch - 1)].public_key();
+        mix_keys.rollover(epoch + 1).unwrap();
+        assert_eq!(mix_keys.prune(), vec![epoch - 1]);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            KeyEvent::KeyGenerated{ epoch: epoch - 1, public_key: stale_key },
+            KeyEvent::KeyGenerated{ epoch: epoch + 2, public_key: mix_keys.public_key(epoch + 2).unwrap() },
+            KeyEvent::KeyExpired{ epoch: epoch },
+            KeyEvent::KeyPruned{ epoch: epoch - 1 },
+        ]);
+    }
+
+    #[test]
+    fn revoke_test() {
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let base_dir_path = base_dir.path().to_str().unwrap().to_string();
+        let mut mix_keys = MixKeys::new(clock.clone(), 2, base_dir_path.clone(), 1024 * 1024).unwrap();
+        mix_keys.generate(epoch).unwrap();
+        let events = mix_keys.subscribe();
+        let old = mix_keys.key(epoch).unwrap();
+        let old_public_key = old.public_key();
+        let tag = Tag([4u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(old.is_replay(&tag).unwrap(), false);
+
+        let public_key = mix_keys.revoke(epoch).unwrap();
+        assert!(public_key != old_public_key);
+        assert_eq!(old.state(), KeyState::Revoked);
+        assert!(old.exp(&public_key).is_err());
+        assert!(old.export_private_key().is_err());
+        assert_eq!(old.public_key(), old_public_key);
+        let key = mix_keys.key(epoch).unwrap();
+        assert_eq!(key.public_key(), public_key);
+        assert_eq!(key.is_replay(&tag).unwrap(), false);
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), vec![
+            KeyEvent::KeyRevoked{ epoch: epoch, public_key: Some(public_key) },
+        ]);
+        assert!(mix_keys.revoke(epoch + 5).is_err());
+        drop(key);
+        drop(mix_keys);
+
+        let mut mix_keys = MixKeys::new(clock.clone(), 2, base_dir_path, 1024 * 1024).unwrap();
+        mix_keys.generate(epoch).unwrap();
+        assert_eq!(mix_keys.public_key(epoch), Some(public_key));
+
+        let seed_dir = TempDir::new().unwrap();
+        let mut seeded = MixKeys::new_with_seed(clock, 2, seed_dir.path().to_str().unwrap().to_string(), 1024 * 1024, &[7u8; 32]).unwrap();
+        seeded.generate(epoch).unwrap();
+        assert!(seeded.revoke(epoch).is_err());
+        assert!(seeded.key(epoch).is_some());
+    }
+
+    #[test]
+    fn key_state_test() {
+        let clock = clock_at(MIX_KEY_GRACE_PERIOD as u64 + 100);
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let base_dir_path = base_dir.path().to_str().unwrap().to_string();
+        let mut mix_keys = MixKeys::new(clock.clone(), 2, base_dir_path.clone(), 1024 * 1024).unwrap();
+        mix_keys.generate(epoch - 1).unwrap();
+        assert_eq!(mix_keys.key_states(), vec![(epoch - 1, KeyState::Frozen), (epoch, KeyState::Active), (epoch + 1, KeyState::PreGenerated)]);
+
+        let frozen = mix_keys.keys.read().unwrap()[&(epoch - 1)].clone();
+        match frozen.is_replay(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])) {
+            Err(MixKeyError::Context{source, ..}) => match *source {
+                MixKeyError::KeyUnusable(KeyState::Frozen) => {},
+                x => panic!("unexpected error: {:?}", x),
+            },
+            x => panic!("unexpected replay check result: {:?}", x),
+        }
+        match mix_keys.key(epoch).unwrap().transition(KeyState::PreGenerated) {
+            Err(MixKeyError::Context{source, ..}) => match *source {
+                MixKeyError::InvalidTransition{from: KeyState::Active, to: KeyState::PreGenerated} => {},
+                x => panic!("unexpected error: {:?}", x),
+            },
+            x => panic!("unexpected transition result: {:?}", x),
+        }
+        assert_eq!(mix_keys.prune(), vec![epoch - 1]);
+        assert_eq!(frozen.state(), KeyState::Destroyed);
+
+        mix_keys.key(epoch + 1).unwrap().transition(KeyState::Grace).unwrap();
+        drop(mix_keys);
+        let mix_keys = MixKeys::new(clock, 2, base_dir_path, 1024 * 1024).unwrap();
+        assert_eq!(mix_keys.key(epoch + 1).unwrap().state(), KeyState::Grace);
+        assert_eq!(mix_keys.key(epoch).unwrap().state(), KeyState::Active);
+    }
+
+    #[test]
+    fn in_memory_mix_keys_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        assert_eq!(mix_keys.entropy_status(), EntropyStatus::Available);
+        let timer = Arc::new(ManualMonotonicClock::new());
+        mix_keys.set_monotonic_clock(timer.clone());
+
+        let mut key = mix_keys.key(epoch).unwrap();
+        assert_eq!(key.backend(), CacheBackend::Memory);
+        assert!(!key.path().exists());
+        let tag = Tag([9u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(key.is_replay(&tag).unwrap(), false);
+        assert_eq!(key.is_replay(&tag).unwrap(), true);
+        key.flush().unwrap();
+
+        timer.advance(Duration::from_secs(MIX_KEY_IDLE_PERIOD));
+        assert!(mix_keys.shed_idle().is_empty());
+        assert_eq!(key.is_replay(&tag).unwrap(), true);
+        assert_eq!(key.tag_count(), 1);
+        assert_eq!(key.replay_cache_hits(), 1);
+        assert_eq!(key.top_replays(1)[0].hits, 1);
+        assert_eq!(mix_keys.remove_stale().unwrap(), 0);
+    }
+
+    /// Hands every process the same in memory store for an epoch.
+    struct SharedStore(Arc<Mutex<MemoryStore>>);
+
+    impl ReplayStore for SharedStore {
+        fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+            self.0.lock().unwrap().contains(tag)
+        }
+
+        fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+            self.0.lock().unwrap().insert(tag)
+        }
+
+        fn flush(&mut self) -> Result<(), MixKeyError> {
+            Ok(())
+        }
+
+        fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+            let tags: Vec<_> = self.0.lock().unwrap().tags().collect();
+            Box::new(tags.into_iter())
+        }
+
+        fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+            self.0.lock().unwrap().metadata(name)
+        }
+
+        fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+            self.0.lock().unwrap().init_metadata(name, value)
+        }
+    }
+
+    #[derive(Default)]
+    struct SharedStores(Mutex<HashMap<u64, Arc<Mutex<MemoryStore>>>>);
+
+    impl ReplayStoreFactory for SharedStores {
+        fn open(&self, epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+            let store = self.0.lock().unwrap().entry(epoch).or_insert_with(Default::default).clone();
+            Ok(Box::new(SharedStore(store)))
+        }
+    }
+
+    #[test]
+    fn shared_store_mix_keys_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let stores = Arc::new(SharedStores::default());
+        let active = MixKeys::with_store_factory(clock.clone(), 2, 1024 * 1024, Arc::new(LocalKeyProvider), stores.clone()).unwrap();
+        let standby = MixKeys::with_store_factory(clock, 2, 1024 * 1024, Arc::new(LocalKeyProvider), stores).unwrap();
+        assert_eq!(active.public_key(epoch), standby.public_key(epoch));
+
+        let a = active.key(epoch).unwrap();
+        let b = standby.key(epoch).unwrap();
+        assert_eq!(a.backend(), CacheBackend::Custom);
+        let tag = Tag([3u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(a.is_replay(&tag).unwrap(), false);
+        assert_eq!(b.is_replay(&tag).unwrap(), true);
+        assert_eq!(a.tag_count(), 1);
+        assert_eq!(b.tag_count(), 0);
+        assert_eq!(standby.key(epoch + 1).unwrap().is_replay(&tag).unwrap(), false);
+    }
+
+    /// Keeps the tags in memory, but takes 15 seconds to flush.
+    struct SlowStore(MemoryStore, Arc<ManualMonotonicClock>);
+
+    impl ReplayStore for SlowStore {
+        fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+            self.0.contains(tag)
+        }
+
+        fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+            self.0.insert(tag)
+        }
+
+        fn flush(&mut self) -> Result<(), MixKeyError> {
+            self.1.advance(Duration::from_secs(15));
+            Ok(())
+        }
+
+        fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+            self.0.tags()
+        }
+
+        fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+            self.0.metadata(name)
+        }
+
+        fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+            self.0.init_metadata(name, value)
+        }
+    }
+
+    struct SlowStores(Arc<ManualMonotonicClock>);
+
+    impl ReplayStoreFactory for SlowStores {
+        fn open(&self, _epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+            Ok(Box::new(SlowStore(MemoryStore::default(), self.0.clone())))
+        }
+    }
+
+    #[test]
+    fn flush_stall_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let timer = Arc::new(ManualMonotonicClock::new());
+        let mut mix_keys = MixKeys::with_store_factory(clock, 1, 1024 * 1024, Arc::new(LocalKeyProvider), Arc::new(SlowStores(timer.clone()))).unwrap();
+        mix_keys.set_monotonic_clock(timer.clone());
+        assert_eq!(mix_keys.flush_interval(), Duration::from_millis(MIX_KEY_FLUSH_FREQUENCY));
+
+        timer.advance(Duration::from_millis(MIX_KEY_FLUSH_FREQUENCY));
+        assert_eq!(mix_keys.flush_due().unwrap().len(), 1);
+        assert_eq!(mix_keys.flush_interval(), Duration::from_millis(2 * MIX_KEY_FLUSH_FREQUENCY));
+        assert_eq!(mix_keys.durability_policy(), DurabilityPolicy::IntervalMs(2 * MIX_KEY_FLUSH_FREQUENCY));
+        let adaptations = mix_keys.flush_adaptations();
+        assert_eq!(adaptations.len(), 1);
+        assert!(adaptations[0].stalled);
+        assert_eq!(adaptations[0].flush_duration, Duration::from_secs(15));
+        #[cfg(feature = "metrics")]
+        assert_eq!(mix_keys.metrics().flush_stalls(), 1);
+
+        timer.advance(Duration::from_millis(MIX_KEY_FLUSH_FREQUENCY));
+        assert!(mix_keys.flush_due().unwrap().is_empty());
+    }
+
+    #[test]
+    fn durability_policy_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let timer = Arc::new(ManualMonotonicClock::new());
+        let open = |durability| MixKeys::builder(clock.clone())
+            .num_mix_keys(1)
+            .store_factory(Arc::new(SlowStores(timer.clone())))
+            .durability_policy(durability)
+            .build()
+            .unwrap();
+
+        let mix_keys = open(DurabilityPolicy::EveryWrite);
+        let key = mix_keys.key(epoch).unwrap();
+        let tag = Tag([1u8; SPHINX_REPLAY_TAG_SIZE]);
+        let start = timer.now();
+        assert_eq!(key.is_replay(&tag).unwrap(), false);
+        assert_eq!(timer.now() - start, Duration::from_secs(15));
+        assert_eq!(key.is_replay(&tag).unwrap(), true);
+        assert_eq!(timer.now() - start, Duration::from_secs(15));
+        assert_eq!(mix_keys.worst_case_replay_window().unwrap().packets, 0);
+
+        let mut mix_keys = open(DurabilityPolicy::Manual);
+        mix_keys.set_monotonic_clock(timer.clone());
+        assert_eq!(mix_keys.key(epoch).unwrap().durability_policy(), DurabilityPolicy::Manual);
+        timer.advance(Duration::from_millis(MIX_KEY_FLUSH_FREQUENCY));
+        assert!(mix_keys.flush_due().unwrap().is_empty());
+        assert_eq!(mix_keys.worst_case_replay_window(), None);
+
+        let mix_keys = open(DurabilityPolicy::IntervalMs(2000));
+        assert_eq!(mix_keys.durability_policy(), DurabilityPolicy::IntervalMs(2000));
+        assert_eq!(mix_keys.flush_interval(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn export_import_tags_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let old = MixKeys::new(clock.clone(), 2, base_dir, 1024 * 1024).unwrap();
+        let key = old.key(epoch).unwrap();
+        let tags: Vec<Tag> = (0..3u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        for tag in &tags {
+            assert_eq!(key.is_replay(tag).unwrap(), false);
+        }
+        let mut raw = vec![];
+        assert_eq!(key.export_tags(&mut raw).unwrap(), 3);
+
+        let new = MixKeys::in_memory(clock, 2, 1024 * 1024).unwrap();
+        let mut key = new.key(epoch).unwrap();
+        assert_eq!(key.import_tags(&raw[..]).unwrap(), 3);
+        assert_eq!(key.import_tags(&raw[..]).unwrap(), 0);
+        assert_eq!(key.tag_count(), 3);
+        for tag in &tags {
+            assert_eq!(key.is_replay(tag).unwrap(), true);
+        }
+        assert!(new.key(epoch + 1).unwrap().import_tags(&raw[..]).is_err());
+
+        let (mut ours, mut theirs) = (vec![], vec![]);
+        let manifest = old.key(epoch).unwrap().export_tags_sorted(&mut ours).unwrap();
+        assert_eq!(key.export_tags_sorted(&mut theirs).unwrap(), manifest);
+        assert_eq!(ours, theirs);
+    }
+
+    #[test]
+    fn prune_grace_period_test() {
+        for &(elapsed, in_grace) in [(10, true), (MIX_KEY_GRACE_PERIOD as u64 + 100, false)].iter() {
+            let clock = clock_at(elapsed);
+            let epoch = clock.now().epoch;
+            let base_dir = TempDir::new().unwrap();
+            let mut mix_keys = MixKeys::new(clock, 2, base_dir.path().to_str().unwrap().to_string(), 1024 * 1024).unwrap();
+            mix_keys.generate(epoch - 2).unwrap();
+
+            assert!(mix_keys.key(epoch - 2).is_none());
+            assert_eq!(mix_keys.key(epoch - 1).is_some(), in_grace);
+            assert!(mix_keys.public_key(epoch).is_some());
+
+            let expected = if in_grace { vec![epoch - 2] } else { vec![epoch - 2, epoch - 1] };
+            assert_eq!(mix_keys.prune(), expected);
+            assert!(mix_keys.prune().is_empty());
+            assert!(!fsutil::epoch_dir(base_dir.path(), epoch - 2).exists());
+            assert_eq!(fsutil::epoch_dir(base_dir.path(), epoch - 1).exists(), in_grace);
+            assert!(fsutil::epoch_dir(base_dir.path(), epoch).exists());
+            assert!(!mix_keys.keys.read().unwrap().contains_key(&(epoch - 2)));
+            assert_eq!(mix_keys.keys.read().unwrap().contains_key(&(epoch - 1)), in_grace);
+            assert!(mix_keys.key(epoch).is_some());
+            assert!(mix_keys.key(epoch + 1).is_some());
+        }
+    }
+
+    #[cfg(feature = "archive")]
+    #[test]
+    fn prune_archive_test() {
+        let clock = clock_at(MIX_KEY_GRACE_PERIOD as u64 + 100);
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let mut mix_keys = MixKeys::new(clock, 2, base_dir.path().to_str().unwrap().to_string(), 1024 * 1024).unwrap();
+        mix_keys.set_archive_dir(base_dir.path().join("archive")).unwrap();
+        mix_keys.generate(epoch - 1).unwrap();
+
+        let mut rng = OsRng::new().unwrap();
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        rng.fill_bytes(&mut raw);
+        let key = mix_keys.keys.read().unwrap().get(&(epoch - 1)).unwrap().clone();
+        assert_eq!(key.is_replay(&Tag(raw)).unwrap(), false);
+
+        assert_eq!(mix_keys.prune(), vec![epoch - 1]);
+        let path = archive::archive_path(&base_dir.path().join("archive"), epoch - 1);
+        let mut archive = archive::Archive::open_read_only(&path).unwrap();
+        assert_eq!(archive.epoch(), epoch - 1);
+        assert_eq!(archive.len(), 1);
+        assert!(archive.contains(&Tag(raw)).unwrap());
+    }
+
+    #[test]
+    fn cache_format_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let info = version_info();
+        assert_eq!(info.crate_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.formats.cache, CACHE_FORMAT_VERSION);
+        assert_eq!(info.tag_size, SPHINX_REPLAY_TAG_SIZE);
+
+        let mix_key = MixKey::new(1024 * 1024, 4, 1, &base_dir).unwrap();
+        {
+            let shards = mix_key.shards.read().unwrap();
+            let mut store = shards.as_ref().unwrap().store();
+            assert_eq!(store.metadata(FORMAT_VERSION_KEY).unwrap(), Some(vec![CACHE_FORMAT_VERSION]));
+            assert_eq!(store.metadata(WRITER_VERSION_KEY).unwrap(), Some(CRATE_VERSION.as_bytes().to_vec()));
+        }
+        drop(mix_key);
+
+        let mut store = MemoryStore::default();
+        store.init_metadata(MIX_CACHE_KEY, b"key").unwrap();
+        store.init_metadata(FORMAT_VERSION_KEY, &[CACHE_FORMAT_VERSION + 1]).unwrap();
+        store.init_metadata(WRITER_VERSION_KEY, b"99.0.0").unwrap();
+        match MixKey::check_format(&mut store, 4, Path::new("mix_key.4")) {
+            Err(MixKeyError::Context{source, ..}) => match *source {
+                MixKeyError::IncompatibleCache{format, writer} => {
+                    assert_eq!(format, CACHE_FORMAT_VERSION + 1);
+                    assert_eq!(writer, Some("99.0.0".to_string()));
+                },
+                x => panic!("unexpected error: {:?}", x),
+            },
+            x => panic!("unexpected check result: {:?}", x),
+        }
+
+        let mut store = MemoryStore::default();
+        store.init_metadata(MIX_CACHE_KEY, b"key").unwrap();
+        MixKey::check_format(&mut store, 4, Path::new("mix_key.4")).unwrap();
+        assert_eq!(store.metadata(FORMAT_VERSION_KEY).unwrap(), Some(vec![CACHE_FORMAT_VERSION]));
+        assert_eq!(store.metadata(WRITER_VERSION_KEY).unwrap(), None);
+    }
+
+    #[test]
+    fn destroy_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mix_key = MixKey::new(1024 * 1024, 6, 1, &base_dir).unwrap();
+        let clone = mix_key.clone();
+        mix_key.destroy().unwrap();
+        assert!(!fsutil::epoch_dir(cache_dir.path(), 6).exists());
+        assert!(clone.is_replay(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])).is_err());
+        assert!(!fsutil::epoch_dir(cache_dir.path(), 6).exists());
+    }
+
+    #[test]
+    fn concurrent_is_replay_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mix_key = MixKey::new(1024 * 1024, 1, 1, &base_dir).unwrap();
+        let workers: Vec<_> = (0..4u8).map(|worker| {
+            let key = mix_key.clone();
+            thread::spawn(move || {
+                for i in 0..64u8 {
+                    let mut raw = [worker; SPHINX_REPLAY_TAG_SIZE];
+                    raw[0] = i;
+                    assert_eq!(key.is_replay(&Tag(raw)).unwrap(), false);
+                }
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(mix_key.tag_count(), 256);
+        let mut raw = [3u8; SPHINX_REPLAY_TAG_SIZE];
+        raw[0] = 17;
+        assert_eq!(mix_key.is_replay(&Tag(raw)).unwrap(), true);
+    }
+
+    #[test]
+    fn tag_conversions_test() {
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        raw[0] = 0xab;
+        raw[SPHINX_REPLAY_TAG_SIZE - 1] = 0x01;
+        let tag = Tag::from(raw);
+        let hex = tag.to_string();
+        assert_eq!(hex.len(), 2 * SPHINX_REPLAY_TAG_SIZE);
+        assert!(hex.starts_with("ab00") && hex.ends_with("0001"));
+        assert_eq!(format!("{:?}", tag), format!("Tag({})", hex));
+        assert!(Tag::from_hex(&hex).unwrap() == tag);
+        assert!(Tag::from_hex(&hex.to_uppercase()).unwrap() == tag);
+        assert!(Tag::from_hex(&hex[1..]).is_err());
+        assert!(Tag::from_hex(&hex.replace("ab", "zz")).is_err());
+        assert!(Tag::try_from(tag.as_ref()).unwrap() == tag);
+        assert!(Tag::try_from(&raw[1..]).is_err());
+    }
+
+    #[cfg(feature = "accumulator")]
+    #[test]
+    fn accumulator_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mix_key = MixKey::new(1024 * 1024, 1, 1, &base_dir).unwrap();
+        let tags: Vec<Tag> = (0..5u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), false);
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+        let root = mix_key.accumulator_root();
+        assert_eq!(root.size, 5);
+        let (proof_root, proof) = mix_key.inclusion_proof(&tags[3]).unwrap();
+        assert_eq!(proof_root, root);
+        assert!(proof.verify(&tags[3], &root));
+        assert!(mix_key.inclusion_proof(&Tag([9u8; SPHINX_REPLAY_TAG_SIZE])).is_none());
+        drop(mix_key);
+
+        let reopened = MixKey::new(1024 * 1024, 1, 1, &base_dir).unwrap();
+        assert_eq!(reopened.accumulator_root().size, 5);
+    }
+
+    #[test]
+    fn key_info_test() {
+        let clock = clock_at(100);
+        let mix_keys = MixKeys::in_memory(clock.clone(), 2, 1024 * 1024).unwrap();
+        let epoch = clock.now().epoch;
+        let info = mix_keys.key_info();
+        assert_eq!(info.iter().map(|info| info.epoch).collect::<Vec<_>>(), vec![epoch, epoch + 1]);
+        assert_eq!(info[0].public_key, mix_keys.public_key(epoch).unwrap());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!((info[0].expiry as i64 - (now + 900) as i64).abs() <= 1);
+        assert_eq!(info[1].expiry, info[0].expiry + 1000);
+        assert_eq!(mix_keys.iter().collect::<Vec<_>>(), info);
+        assert_eq!(mix_keys.epochs(), vec![epoch, epoch + 1]);
+
+        let bundle = mix_keys.descriptor_bundle(None).unwrap();
+        assert_eq!(bundle.signature, None);
+        assert_eq!(bundle.mix_keys().get(&epoch), Some(&info[0].public_key.to_vec()));
+        assert_eq!(bundle.entries.len(), 2);
+    }
+
+    #[test]
+    fn upcoming_keys_test() {
+        let clock = clock_at(100);
+        let epoch = clock.now().epoch;
+        let mut mix_keys = MixKeys::builder(clock).backend(CacheBackend::Memory).num_mix_keys(1).publish_ahead(2).grace_period(50).build().unwrap();
+        let keys = mix_keys.upcoming_keys();
+        assert_eq!(keys.iter().map(|key| key.0).collect::<Vec<_>>(), vec![epoch, epoch + 1, epoch + 2]);
+        assert_eq!(keys[1].1, mix_keys.public_key(epoch + 1).unwrap());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert!((keys[0].2 as i64 - (now - 100) as i64).abs() <= 1);
+        assert_eq!(keys[0].3, keys[0].2 + 1050);
+        assert_eq!(keys[2].2, keys[0].2 + 2000);
+
+        mix_keys.generate(epoch + 1).unwrap();
+        assert_eq!(mix_keys.upcoming_keys().last().unwrap().0, epoch + 3);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        extern crate serde_json;
+
+        let tag = Tag([0xabu8; SPHINX_REPLAY_TAG_SIZE]);
+        let encoded = serde_json::to_string(&tag).unwrap();
+        assert_eq!(encoded, format!("\"{}\"", tag));
+        assert!(serde_json::from_str::<Tag>(&encoded).unwrap() == tag);
+        assert!(serde_json::from_str::<Tag>("\"ab\"").is_err());
+
+        let mix_keys = MixKeys::in_memory(epoch::Clock::new_katzenpost(), 1, 1024 * 1024).unwrap();
+        let info = mix_keys.key_info()[0];
+        let encoded = serde_json::to_string(&info).unwrap();
+        assert!(encoded.contains(&info.public_key.to_base64()));
+        assert_eq!(serde_json::from_str::<EpochKeyInfo>(&encoded).unwrap(), info);
+    }
+
+    #[test]
+    fn stats_test() {
+        let clock = epoch::Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let mix_keys = MixKeys::new(clock, 2, base_dir.path().to_str().unwrap().to_string(), 1024 * 1024).unwrap();
+        let key = mix_keys.key(epoch).unwrap();
+        for i in 0..10u8 {
+            assert_eq!(key.is_replay(&Tag([i; SPHINX_REPLAY_TAG_SIZE])).unwrap(), false);
+        }
+        for _ in 0..3 {
+            assert_eq!(key.is_replay(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), true);
+        }
+        let stats = key.stats().unwrap();
+        assert_eq!((stats.epoch, stats.tags, stats.replays), (epoch, 10, 3));
+        assert!(stats.fill_ratio.unwrap() < 0.5);
+        assert!(stats.false_positive_rate.unwrap() < 0.01);
+        assert!(stats.disk_bytes > 0);
+
+        let all = mix_keys.stats().unwrap();
+        assert_eq!(all.epochs.iter().map(|stats| stats.epoch).collect::<Vec<_>>(), vec![epoch, epoch + 1]);
+        assert_eq!((all.tags, all.replays), (10, 3));
+        assert_eq!(all.disk_bytes, all.epochs.iter().map(|stats| stats.disk_bytes).sum::<u64>());
+    }
+
+    #[test]
+    fn contains_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mix_key = MixKey::new(1024 * 1024, 1, 1, &base_dir).unwrap();
+        let tag = Tag([5u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(mix_key.contains(&tag).unwrap(), false);
+        assert_eq!(mix_key.contains(&tag).unwrap(), false);
+        assert_eq!(mix_key.tag_count(), 0);
+        assert_eq!(mix_key.is_replay(&tag).unwrap(), false);
+        assert_eq!(mix_key.contains(&tag).unwrap(), true);
+        assert_eq!(mix_key.is_replay(&tag).unwrap(), true);
+        assert_eq!(mix_key.contains(&tag).unwrap(), true);
+    }
+
+    #[test]
+    #[cfg(all(feature = "bloom", not(feature = "quotient")))]
+    fn filter_growth_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mut config = CacheConfig::default();
+        config.expected_tags = Some(10);
+        let mix_key = MixKey::with_config(CacheBackend::Memory, &LocalKeyProvider, 1024 * 1024, 1, 1, &base_dir, &config).unwrap();
+        assert_eq!(mix_key.filter_layers(), Some(1));
+        let tags: Vec<Tag> = (0..100u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), false);
+        }
+        assert!(mix_key.filter_layers().unwrap() > 1);
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+    }
+
+    #[test]
+    fn remove_tag_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let tag = Tag([3u8; SPHINX_REPLAY_TAG_SIZE]);
+        let mut plain = MixKey::new(1024 * 1024, 1, 1, &base_dir).unwrap();
+        match plain.remove_tag(&tag) {
+            Err(MixKeyError::RemovalUnsupported) => {},
+            _ => panic!("removed a tag without a counting filter"),
+        }
+
+        let mut config = CacheConfig::default();
+        config.counting_filter = true;
+        let mut mix_key = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 2, 1, &base_dir, &config).unwrap();
+        assert_eq!(mix_key.is_replay(&tag).unwrap(), false);
+        assert_eq!(mix_key.is_replay(&tag).unwrap(), true);
+        assert_eq!(mix_key.remove_tag(&tag).unwrap(), true);
+        assert_eq!(mix_key.remove_tag(&tag).unwrap(), false);
+        assert_eq!(mix_key.tag_count(), 0);
+        assert_eq!(mix_key.is_replay(&tag).unwrap(), false);
+        assert_eq!(mix_key.remove_tag(&tag).unwrap(), true);
+        mix_key.flush().unwrap();
+        drop(mix_key);
+
+        let mix_key = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 2, 1, &base_dir, &config).unwrap();
+        assert_eq!(mix_key.tag_count(), 0);
+        assert_eq!(mix_key.is_replay(&tag).unwrap(), false);
+    }
+
+    #[test]
+    fn tag_limit_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mut config = CacheConfig::default();
+        config.max_tags = Some(2);
+        let tags: Vec<Tag> = (0..4u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+
+        let rejecting = MixKey::with_config(CacheBackend::Memory, &LocalKeyProvider, 1024 * 1024, 1, 1, &base_dir, &config).unwrap();
+        assert_eq!(rejecting.is_replay(&tags[0]).unwrap(), false);
+        assert_eq!(rejecting.is_replay(&tags[1]).unwrap(), false);
+        match rejecting.is_replay(&tags[2]) {
+            Err(MixKeyError::Context{ref source, ..}) if matches!(**source, MixKeyError::TagLimitReached(2)) => {},
+            x => panic!("unexpected replay check result: {:?}", x),
+        }
+        assert_eq!(rejecting.is_replay(&tags[0]).unwrap(), true);
+        assert_eq!(rejecting.overflow_count(), 1);
+
+        config.overflow = OverflowBehavior::OverflowTree;
+        let mut mix_key = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 2, 1, &base_dir, &config).unwrap();
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), false);
+        }
+        assert_eq!(mix_key.tag_count(), 2);
+        assert_eq!(mix_key.overflow_count(), 2);
+        mix_key.flush().unwrap();
+        drop(mix_key);
+
+        let mix_key = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 2, 1, &base_dir, &config).unwrap();
+        assert_eq!(mix_key.overflow_count(), 2);
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+
+        config.overflow = OverflowBehavior::SecondaryFilter;
+        let filtering = MixKey::with_config(CacheBackend::Memory, &LocalKeyProvider, 1024 * 1024, 3, 1, &base_dir, &config).unwrap();
+        for tag in &tags {
+            assert_eq!(filtering.is_replay(tag).unwrap(), false);
+        }
+        for tag in &tags {
+            assert_eq!(filtering.is_replay(tag).unwrap(), true);
+        }
+        assert_eq!(filtering.tag_count(), 2);
+        assert_eq!(filtering.overflow_count(), 2);
+
+        config.overflow = OverflowBehavior::LogAndContinue;
+        let continuing = MixKey::with_config(CacheBackend::Memory, &LocalKeyProvider, 1024 * 1024, 4, 1, &base_dir, &config).unwrap();
+        for tag in &tags {
+            assert_eq!(continuing.is_replay(tag).unwrap(), false);
+        }
+        for tag in &tags {
+            assert_eq!(continuing.is_replay(tag).unwrap(), true);
+        }
+        assert_eq!(continuing.tag_count(), 4);
+        assert_eq!(continuing.overflow_count(), 2);
+    }
+
+    #[test]
+    fn disk_quota_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mut config = CacheConfig::default();
+        config.disk_quota = Some(1);
+        let tags: Vec<Tag> = (0..4u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+
+        let refusing = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 1, 1, &base_dir, &config).unwrap();
+        match refusing.is_replay(&tags[0]) {
+            Err(MixKeyError::Context{ref source, ..}) if matches!(**source, MixKeyError::DiskQuotaExceeded(1)) => {},
+            x => panic!("unexpected replay check result: {:?}", x),
+        }
+        assert_eq!(refusing.tag_count(), 0);
+
+        config.quota_fallback = true;
+        let mut spilling = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 2, 1, &base_dir, &config).unwrap();
+        for tag in &tags {
+            assert_eq!(spilling.is_replay(tag).unwrap(), false);
+        }
+        for tag in &tags {
+            assert_eq!(spilling.is_replay(tag).unwrap(), true);
+        }
+        assert_eq!(spilling.tag_count(), 0);
+        assert_eq!(spilling.overflow_count(), 4);
+        spilling.flush().unwrap();
+    }
+
+    #[test]
+    fn tag_size_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mut config = CacheConfig::default();
+        config.tag_size = 16;
+        let tags: Vec<Tag> = (0..8u8).map(|i| Tag::padded(&[i + 1; 16]).unwrap()).collect();
+        {
+            let mut mix_key = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 1, 1, &base_dir, &config).unwrap();
+            for tag in &tags {
+                assert_eq!(mix_key.is_replay(tag).unwrap(), false);
+            }
+            match mix_key.is_replay(&Tag([1u8; SPHINX_REPLAY_TAG_SIZE])) {
+                Err(MixKeyError::Context{ref source, ..}) if matches!(**source, MixKeyError::InvalidTag) => {},
+                x => panic!("unexpected replay check result: {:?}", x),
+            }
+            mix_key.flush().unwrap();
+            let shards = mix_key.wake().unwrap();
+            // Only the first 16 bytes of each tag are stored.
+            let mut store = shards.as_ref().unwrap().store();
+            assert_eq!(store.tags().count(), tags.len());
+        }
+
+        let mut mix_key = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 1, 1, &base_dir, &config).unwrap();
+        assert_eq!(mix_key.tag_count(), tags.len() as u64);
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+
+        // A shed key reopens its cache with the tag size it was made with.
+        mix_key.shed().unwrap();
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+        drop(mix_key);
+        match MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 1, 1, &base_dir, &CacheConfig::default()) {
+            Err(MixKeyError::Context{ref source, ..}) if matches!(**source, MixKeyError::TagSizeMismatch{cache: 16, config: 32}) => {},
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("a cache of 16 byte tags opened for 32 byte tags"),
+        }
+        assert!(Tag::padded(&[0u8; SPHINX_REPLAY_TAG_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn write_batch_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let mut config = CacheConfig::default();
+        config.write_batch = Some(WriteBatch{
+            max_tags: 16,
+            max_delay: Duration::from_secs(60),
+        });
+        let tags: Vec<Tag> = (0..64u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        let mut mix_key = MixKey::with_config(CacheBackend::Sled, &LocalKeyProvider, 1024 * 1024, 1, 1, &base_dir, &config).unwrap();
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), false);
+        }
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+        mix_key.shed().unwrap();
+        assert_eq!(mix_key.is_replay(&tags[0]).unwrap(), true);
+        mix_key.flush().unwrap();
+        drop(mix_key);
+
+        let mut mix_key = MixKey::new(1024 * 1024, 1, 1, &base_dir).unwrap();
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+    }
+
+    #[test]
+    fn memory_budget_test() {
+        let clock = clock_at(100);
+        let epoch = clock.now().epoch;
+        let line_rate = 1024 * 1024;
+        let unbounded = MixKeys::builder(clock.clone()).num_mix_keys(2).line_rate(line_rate).backend(CacheBackend::Memory).build().unwrap();
+        let expected = unbounded.key(epoch).unwrap().expected_tags();
+
+        let budget = preflight::filter_bytes(expected, MIX_KEY_FALSE_POSITIVE_RATE) * 3 / 2;
+        let mix_keys = MixKeys::builder(clock).num_mix_keys(2).line_rate(line_rate).backend(CacheBackend::Memory).memory_budget(budget).build().unwrap();
+        assert_eq!(mix_keys.memory_budget(), Some(budget));
+        let tags = mix_keys.key(epoch).unwrap().expected_tags();
+        assert!(tags <= expected / 2 && tags > expected / 3);
+        assert!(preflight::filter_bytes(tags, MIX_KEY_FALSE_POSITIVE_RATE) <= budget / 3 + 1);
+    }
+
+    #[test]
+    fn freeze_test() {
+        let clock = clock_at(100);
+        let epoch = clock.now().epoch;
+        let base_dir = TempDir::new().unwrap();
+        let base_dir_path = base_dir.path().to_str().unwrap().to_string();
+        let mut mix_keys = MixKeys::builder(clock.clone()).base_dir(base_dir_path.clone()).num_mix_keys(2).freeze(true).build().unwrap();
+        mix_keys.generate(epoch - 1).unwrap();
+        let key = mix_keys.key(epoch - 1).unwrap();
+        assert_eq!(key.state(), KeyState::Grace);
+        let tags: Vec<Tag> = (0..32u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        for tag in &tags {
+            assert_eq!(key.is_replay(tag).unwrap(), false);
+        }
+        assert!(mix_keys.prune().is_empty());
+        assert!(frozen::is_frozen(key.path()));
+        assert!(!mix_keys.key(epoch).unwrap().path().join("frozen").exists());
+        assert_eq!(key.freeze().unwrap(), false);
+        for tag in &tags {
+            assert_eq!(key.is_replay(tag).unwrap(), true);
+        }
+        let late = Tag([0xaau8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(key.is_replay(&late).unwrap(), false);
+        assert_eq!(key.is_replay(&late).unwrap(), true);
+        mix_keys.flush_due().unwrap();
+        drop(key);
+        drop(mix_keys);
+
+        let mut mix_keys = MixKeys::builder(clock).base_dir(base_dir_path).num_mix_keys(2).freeze(true).build().unwrap();
+        mix_keys.generate(epoch - 1).unwrap();
+        let key = mix_keys.key(epoch - 1).unwrap();
+        assert_eq!(key.state(), KeyState::Grace);
+        assert_eq!(key.is_replay(&tags[7]).unwrap(), true);
+        assert_eq!(key.is_replay(&late).unwrap(), true);
+    }
+
+    #[test]
+    fn staged_mix_key_test() {
+        let cache_dir = TempDir::new().unwrap();
+        let base_dir = cache_dir.path().to_str().unwrap().to_string();
+        let epoch = 3;
+        let staging = fsutil::staging_dir(cache_dir.path(), epoch);
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(staging.join("db"), b"left behind by a crash").unwrap();
+
+        let mix_key = MixKey::new(1024 * 1024, epoch, 1, &base_dir).unwrap();
+        let public_key = mix_key.public_key();
+        drop(mix_key);
+        assert!(!staging.exists());
+        assert!(fsutil::epoch_dir(cache_dir.path(), epoch).exists());
+
+        let mix_key = MixKey::new(1024 * 1024, epoch, 1, &base_dir).unwrap();
+        assert_eq!(mix_key.public_key(), public_key);
+    }
+
+    #[test]
+    fn basic_mix_key_test() {
+        let cache_dir = TempDir::new().unwrap();
+        {
+            let cache_dir_path = cache_dir.path().clone();
+            //let epoch_duration = 3 * 60 * 60; // 3 hours
+            //let epoch_duration = 1 * 60 * 60; // 1 hours
             let epoch_duration = 1;
             let epoch = 1;
             let mut mix_key = MixKey::new(128974848, epoch, epoch_duration, &cache_dir_path.to_str().unwrap().to_string()).unwrap();
@@ -310,18 +4352,17 @@ mod tests {
             rng.fill_bytes(&mut raw);
             let tag = Tag(raw);
 
-            assert_eq!(mix_key.is_replay(tag.clone()).unwrap(), false);
-            assert_eq!(mix_key.is_replay(tag.clone()).unwrap(), true);
-            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+            assert_eq!(mix_key.is_replay(&tag).unwrap(), false);
+            assert_eq!(mix_key.is_replay(&tag).unwrap(), true);
+            assert_eq!(mix_key.is_replay(&tag).unwrap(), true);
 
-            mix_key.flush();
-            let mut priv_key = PrivateKey::default();
-            priv_key.load_bytes(&mix_key.private_key().to_vec()).unwrap();
+            mix_key.flush().unwrap();
+            let priv_key = mix_key.export_private_key().unwrap();
             drop(mix_key);
 
             let new_mix_key = MixKey::new(128974848, epoch, epoch_duration, &cache_dir_path.to_str().unwrap().to_string()).unwrap();
             assert_eq!(epoch, new_mix_key.epoch);
-            assert_eq!(priv_key, *new_mix_key.private_key());
+            assert_eq!(priv_key, new_mix_key.export_private_key().unwrap());
         }
         TempDir::close(cache_dir).unwrap();
     }
@@ -0,0 +1,170 @@
+// lifecycle.rs - Mix key lifecycle states.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Every `MixKey` carries an explicit `KeyState`:
+//!
+//! ```text
+//! PreGenerated -> Active -> Grace -> Frozen
+//!       \            \        \        \
+//!        +------------+--------+--------+--> Destroyed | Revoked | Quarantined
+//! ```
+//!
+//! A key only moves forward. It may skip states, as a key loaded after
+//! the node was down for an epoch does, but never returns to an earlier
+//! one, and the three final states are never left. `MixKey::transition`
+//! refuses anything else with `InvalidTransition`.
+//!
+//! Keys only check tags while `PreGenerated`, `Active` or in `Grace`. A
+//! `Frozen` key keeps its cache, for instance until it is archived, but
+//! refuses packets with `KeyUnusable`.
+//!
+//! The state is stored in the key's cache, where the store supports
+//! updating metadata, so a restarted node resumes each key where it
+//! left off. `MixKeys` moves its keys along as the clock advances, in
+//! `generate`, `rollover` and `prune`.
+//!
+
+use std::fmt;
+
+use epoch::Time;
+
+
+/// KeyState is the stage of an epoch's key in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum KeyState {
+    /// Generated ahead of its epoch, for publishing.
+    PreGenerated,
+    /// Its epoch is the current one.
+    Active,
+    /// Its epoch ended, but the grace period has not.
+    Grace,
+    /// The grace period ended. The cache is kept, but no tags are
+    /// checked.
+    Frozen,
+    /// The cache was destroyed.
+    Destroyed,
+    /// The key was revoked, and must not be used again.
+    Revoked,
+    /// The cache was set aside for an operator to inspect.
+    Quarantined,
+}
+
+impl KeyState {
+    /// Returns the state's identifier in a cache.
+    pub fn id(&self) -> u8 {
+        match *self {
+            KeyState::PreGenerated => 0,
+            KeyState::Active => 1,
+            KeyState::Grace => 2,
+            KeyState::Frozen => 3,
+            KeyState::Destroyed => 4,
+            KeyState::Revoked => 5,
+            KeyState::Quarantined => 6,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<KeyState> {
+        match id {
+            0 => Some(KeyState::PreGenerated),
+            1 => Some(KeyState::Active),
+            2 => Some(KeyState::Grace),
+            3 => Some(KeyState::Frozen),
+            4 => Some(KeyState::Destroyed),
+            5 => Some(KeyState::Revoked),
+            6 => Some(KeyState::Quarantined),
+            _ => None,
+        }
+    }
+
+    /// Returns the state a key of the given epoch should be in at time
+    /// `now`, with a grace period of `grace_period` seconds.
+    pub fn at(epoch: u64, now: &Time, grace_period: u64) -> KeyState {
+        if epoch > now.epoch {
+            KeyState::PreGenerated
+        } else if epoch == now.epoch {
+            KeyState::Active
+        } else if epoch + 1 == now.epoch && now.elapsed < grace_period {
+            KeyState::Grace
+        } else {
+            KeyState::Frozen
+        }
+    }
+
+    /// Returns true for the states a key never leaves.
+    pub fn is_final(&self) -> bool {
+        *self >= KeyState::Destroyed
+    }
+
+    /// Returns true if a key in this state checks tags.
+    pub fn is_usable(&self) -> bool {
+        *self <= KeyState::Grace
+    }
+
+    /// Returns true if a key may move from this state to `to`.
+    pub fn can_transition(&self, to: KeyState) -> bool {
+        !self.is_final() && (to.is_final() || to > *self)
+    }
+}
+
+impl fmt::Display for KeyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            KeyState::PreGenerated => "pre-generated",
+            KeyState::Active => "active",
+            KeyState::Grace => "grace",
+            KeyState::Frozen => "frozen",
+            KeyState::Destroyed => "destroyed",
+            KeyState::Revoked => "revoked",
+            KeyState::Quarantined => "quarantined",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn key_state_test() {
+        use self::KeyState::*;
+
+        let states = [PreGenerated, Active, Grace, Frozen, Destroyed, Revoked, Quarantined];
+        for state in states.iter() {
+            assert_eq!(KeyState::from_id(state.id()), Some(*state));
+            assert!(!state.can_transition(*state));
+        }
+        assert_eq!(KeyState::from_id(7), None);
+
+        assert!(PreGenerated.can_transition(Active));
+        assert!(PreGenerated.can_transition(Frozen));
+        assert!(Grace.can_transition(Revoked));
+        assert!(!Grace.can_transition(Active));
+        assert!(!Destroyed.can_transition(Revoked));
+        assert!(!Quarantined.can_transition(Frozen));
+        assert!(Grace.is_usable() && !Frozen.is_usable());
+
+        let now = Time{ epoch: 10, elapsed: 60, till: 940 };
+        assert_eq!(KeyState::at(11, &now, 120), PreGenerated);
+        assert_eq!(KeyState::at(10, &now, 120), Active);
+        assert_eq!(KeyState::at(9, &now, 120), Grace);
+        assert_eq!(KeyState::at(9, &now, 30), Frozen);
+        assert_eq!(KeyState::at(8, &now, 120), Frozen);
+    }
+}
@@ -0,0 +1,137 @@
+// metafile.rs - Metadata of stores kept outside sled.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Stores that keep their tags in plain files, rather than a sled tree,
+//! keep their metadata in a small file beside them, replaced atomically
+//! on every change. It is a sequence of entries:
+//!
+//!    name length (2, LE) || name || value length (4, LE) || value
+//!
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use errors::MixKeyError;
+use fsutil;
+
+
+const METADATA_FILE_NAME: &str = "metadata";
+const METADATA_TMP_FILE_NAME: &str = "metadata.tmp";
+
+
+/// MetadataFile persists a store's metadata in its directory.
+#[derive(Clone, Debug)]
+pub(crate) struct MetadataFile {
+    dir: PathBuf,
+}
+
+impl MetadataFile {
+    pub fn new(dir: &Path) -> MetadataFile {
+        MetadataFile{
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// Returns the stored metadata, which is empty if none was stored.
+    pub fn load(&self) -> Result<HashMap<String, Vec<u8>>, MixKeyError> {
+        let mut raw = vec![];
+        match File::open(self.path()) {
+            Ok(mut file) => file.read_to_end(&mut raw)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(MixKeyError::IoError(e)),
+        };
+        let mut metadata = HashMap::new();
+        let mut rest = &raw[..];
+        while !rest.is_empty() {
+            if rest.len() < 2 {
+                return Err(MixKeyError::LoadCacheFailed)
+            }
+            let name_len = LittleEndian::read_u16(rest) as usize;
+            if rest.len() < 2 + name_len + 4 {
+                return Err(MixKeyError::LoadCacheFailed)
+            }
+            let name = String::from_utf8(rest[2..2 + name_len].to_vec()).map_err(|_| MixKeyError::LoadCacheFailed)?;
+            rest = &rest[2 + name_len..];
+            let value_len = LittleEndian::read_u32(rest) as usize;
+            if rest.len() < 4 + value_len {
+                return Err(MixKeyError::LoadCacheFailed)
+            }
+            metadata.insert(name, rest[4..4 + value_len].to_vec());
+            rest = &rest[4 + value_len..];
+        }
+        Ok(metadata)
+    }
+
+    /// Durably replace the stored metadata.
+    pub fn store(&self, metadata: &HashMap<String, Vec<u8>>) -> Result<(), MixKeyError> {
+        let mut raw = vec![];
+        for (name, value) in metadata {
+            let mut len = [0u8; 4];
+            LittleEndian::write_u16(&mut len, name.len() as u16);
+            raw.extend_from_slice(&len[..2]);
+            raw.extend_from_slice(name.as_bytes());
+            LittleEndian::write_u32(&mut len, value.len() as u32);
+            raw.extend_from_slice(&len);
+            raw.extend_from_slice(value);
+        }
+        let tmp_path = self.dir.join(METADATA_TMP_FILE_NAME);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&raw)?;
+        fsutil::sync_file(&file)?;
+        fs::rename(&tmp_path, self.path())?;
+        fsutil::sync_dir(&self.dir)?;
+        Ok(())
+    }
+
+    /// Returns the path of the stored metadata.
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(METADATA_FILE_NAME)
+    }
+
+    /// Returns true if the file name is one the metadata file uses.
+    pub fn owns(name: &str) -> bool {
+        name == METADATA_FILE_NAME || name == METADATA_TMP_FILE_NAME
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn metadata_file_test() {
+        let dir = TempDir::new().unwrap();
+        let file = MetadataFile::new(dir.path());
+        assert!(file.load().unwrap().is_empty());
+        let mut metadata = HashMap::new();
+        metadata.insert("private_key".to_string(), b"key".to_vec());
+        metadata.insert("state".to_string(), vec![]);
+        file.store(&metadata).unwrap();
+        assert_eq!(file.load().unwrap(), metadata);
+        fs::write(dir.path().join(METADATA_FILE_NAME), &[5, 0, b'a']).unwrap();
+        assert!(file.load().is_err());
+    }
+}
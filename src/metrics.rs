@@ -0,0 +1,243 @@
+// metrics.rs - Prometheus metrics.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Counters shared by every key of a `MixKeys`, rendered in the
+//! Prometheus text exposition format by `MixKeys::render_metrics` so
+//! that the mix server can serve them from its scrape endpoint. This
+//! module is only available with the `metrics` feature.
+//!
+//! Every series carries `node_id`, `epoch` and `backend` labels, so that
+//! the metrics of several mixes can be aggregated without relabeling
+//! them at the scrape layer. The node id is set with
+//! `MixKeysBuilder::node_id`. Counters are shared by every key and
+//! carry the current epoch; per-epoch gauges carry their own. Labels
+//! set with `Metrics::set_label` override these or are added to every
+//! series. Capacity alarms in force carry their epoch and an `alarm`
+//! label naming the resource.
+//!
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use alarms::CapacityAlarm;
+use errors::MixKeyError;
+use store::CacheBackend;
+
+
+/// Metrics holds the counters updated on the replay check hot path.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    replay_hits: AtomicU64,
+    fresh_tags: AtomicU64,
+    false_positives: AtomicU64,
+    flushes: AtomicU64,
+    flush_micros: AtomicU64,
+    flush_stalls: AtomicU64,
+    capacity_alarms_raised: AtomicU64,
+    capacity_alarms: Mutex<Vec<CapacityAlarm>>,
+    node_id: String,
+    backend: &'static str,
+    overrides: Mutex<BTreeMap<String, String>>,
+}
+
+/// EpochGauges holds the per-epoch gauges, sampled at render time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EpochGauges {
+    pub epoch: u64,
+    pub tags: u64,
+    pub disk_bytes: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::with_labels("", CacheBackend::default())
+    }
+
+    /// Returns metrics labeled with the given node id and backend.
+    pub fn with_labels(node_id: &str, backend: CacheBackend) -> Self {
+        Metrics{
+            node_id: node_id.to_string(),
+            backend: match backend {
+                CacheBackend::Sled => "sled",
+                CacheBackend::Memory => "memory",
+                CacheBackend::Custom => "custom",
+            },
+            ..Metrics::default()
+        }
+    }
+
+    /// Set a label on every series, overriding `node_id`, `epoch` or
+    /// `backend` if it has one of their names.
+    pub fn set_label(&self, name: &str, value: &str) -> Result<(), MixKeyError> {
+        let valid = name.chars().enumerate().all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+        if name.is_empty() || !valid || name.starts_with("__") {
+            return Err(MixKeyError::InvalidConfig(format!("invalid metric label name {:?}", name)))
+        }
+        self.overrides.lock().unwrap().insert(name.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Returns the label set of a series of the given epoch.
+    fn labels(&self, epoch: u64) -> String {
+        let mut labels = BTreeMap::new();
+        labels.insert("node_id".to_string(), self.node_id.clone());
+        labels.insert("epoch".to_string(), epoch.to_string());
+        labels.insert("backend".to_string(), self.backend.to_string());
+        for (name, value) in self.overrides.lock().unwrap().iter() {
+            labels.insert(name.clone(), value.clone());
+        }
+        let pairs: Vec<String> = labels.iter().map(|(name, value)| {
+            format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+        }).collect();
+        format!("{{{}}}", pairs.join(","))
+    }
+
+    pub(crate) fn replay_hit(&self) {
+        self.replay_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn fresh_tag(&self) {
+        self.fresh_tags.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn false_positive(&self) {
+        self.false_positives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn flushed(&self, duration: Duration) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.flush_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn flush_stalled(&self) {
+        self.flush_stalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn capacity_alarms(&self, alarms: &[CapacityAlarm], raised: usize) {
+        self.capacity_alarms_raised.fetch_add(raised as u64, Ordering::Relaxed);
+        *self.capacity_alarms.lock().unwrap() = alarms.to_vec();
+    }
+
+    /// Returns the number of flushes that took longer than the flush
+    /// interval.
+    pub fn flush_stalls(&self) -> u64 {
+        self.flush_stalls.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of replayed tags detected.
+    pub fn replay_hits(&self) -> u64 {
+        self.replay_hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of fresh tags inserted.
+    pub fn fresh_tags(&self) -> u64 {
+        self.fresh_tags.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of bloom filter hits the cache showed to be
+    /// false positives.
+    pub fn false_positives(&self) -> u64 {
+        self.false_positives.load(Ordering::Relaxed)
+    }
+
+    /// Render the counters, labeled with the current epoch, and the
+    /// given per-epoch gauges in the Prometheus text exposition format.
+    pub fn render(&self, current_epoch: u64, epochs: &[EpochGauges]) -> String {
+        let mut out = String::new();
+        let labels = self.labels(current_epoch);
+        counter(&mut out, "sphinx_replay_cache_replay_hits_total", "Replayed tags detected.", &labels, self.replay_hits());
+        counter(&mut out, "sphinx_replay_cache_fresh_tags_total", "Fresh tags inserted.", &labels, self.fresh_tags());
+        counter(&mut out, "sphinx_replay_cache_bloom_false_positives_total", "Bloom filter false positives detected.", &labels, self.false_positives());
+        counter(&mut out, "sphinx_replay_cache_flush_stalls_total", "Flushes that took longer than the flush interval.", &labels, self.flush_stalls());
+        counter(&mut out, "sphinx_replay_cache_capacity_alarms_total", "Capacity alarms raised.", &labels,
+                self.capacity_alarms_raised.load(Ordering::Relaxed));
+
+        let name = "sphinx_replay_cache_flush_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time spent flushing caches to disk.", name);
+        let _ = writeln!(out, "# TYPE {} summary", name);
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.flush_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.flushes.load(Ordering::Relaxed));
+
+        let name = "sphinx_replay_cache_epoch_tags";
+        let _ = writeln!(out, "# HELP {} Tags stored for the epoch.", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for gauges in epochs {
+            let _ = writeln!(out, "{}{} {}", name, self.labels(gauges.epoch), gauges.tags);
+        }
+        let name = "sphinx_replay_cache_epoch_disk_bytes";
+        let _ = writeln!(out, "# HELP {} Disk space used by the epoch's cache.", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for gauges in epochs {
+            let _ = writeln!(out, "{}{} {}", name, self.labels(gauges.epoch), gauges.disk_bytes);
+        }
+        let name = "sphinx_replay_cache_capacity_alarm_seconds";
+        let _ = writeln!(out, "# HELP {} Forecast seconds until the epoch runs out of the resource.", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for alarm in self.capacity_alarms.lock().unwrap().iter() {
+            let labels = self.labels(alarm.epoch());
+            let _ = writeln!(out, "{}{},alarm=\"{}\"}} {}", name, &labels[..labels.len() - 1], alarm.resource(), alarm.seconds());
+        }
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, labels: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{}{} {}", name, labels, value);
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn render_test() {
+        let metrics = Metrics::with_labels("mix1", CacheBackend::Sled);
+        metrics.replay_hit();
+        metrics.fresh_tag();
+        metrics.fresh_tag();
+        metrics.flushed(Duration::from_millis(1500));
+        metrics.capacity_alarms(&[CapacityAlarm::DiskFull{ epoch: 7, seconds: 120 }], 1);
+        let out = metrics.render(8, &[EpochGauges{
+            epoch: 7,
+            tags: 2,
+            disk_bytes: 4096,
+        }]);
+        let labels = "{backend=\"sled\",epoch=\"8\",node_id=\"mix1\"}";
+        assert!(out.contains(&format!("sphinx_replay_cache_replay_hits_total{} 1\n", labels)));
+        assert!(out.contains(&format!("sphinx_replay_cache_fresh_tags_total{} 2\n", labels)));
+        assert!(out.contains(&format!("sphinx_replay_cache_bloom_false_positives_total{} 0\n", labels)));
+        assert!(out.contains(&format!("sphinx_replay_cache_flush_duration_seconds_sum{} 1.5\n", labels)));
+        assert!(out.contains(&format!("sphinx_replay_cache_flush_duration_seconds_count{} 1\n", labels)));
+        assert!(out.contains("sphinx_replay_cache_epoch_tags{backend=\"sled\",epoch=\"7\",node_id=\"mix1\"} 2\n"));
+        assert!(out.contains("sphinx_replay_cache_epoch_disk_bytes{backend=\"sled\",epoch=\"7\",node_id=\"mix1\"} 4096\n"));
+        assert!(out.contains(&format!("sphinx_replay_cache_capacity_alarms_total{} 1\n", labels)));
+        assert!(out.contains("sphinx_replay_cache_capacity_alarm_seconds{backend=\"sled\",epoch=\"7\",node_id=\"mix1\",alarm=\"disk\"} 120\n"));
+
+        assert!(metrics.set_label("0zone", "a").is_err());
+        metrics.set_label("node_id", "mix\"2").unwrap();
+        metrics.set_label("zone", "eu").unwrap();
+        let out = metrics.render(8, &[]);
+        assert!(out.contains("sphinx_replay_cache_fresh_tags_total{backend=\"sled\",epoch=\"8\",node_id=\"mix\\\"2\",zone=\"eu\"} 2\n"));
+    }
+}
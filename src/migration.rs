@@ -0,0 +1,87 @@
+// migration.rs - Forward migration of the cache format.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Every epoch's store records the version of the cache layout it was
+//! written in. A store in an older format than `CACHE_FORMAT_VERSION`
+//! is migrated forward when its key is opened, one format at a time,
+//! and the format reached is recorded after each step, so operators
+//! never have to wipe their caches when the layout changes.
+//!
+//! A layout change bumps `CACHE_FORMAT_VERSION` and appends the
+//! migration from the previous format to `MIGRATIONS`, such as
+//! `migrate_v1_to_v2`; the table is sized by the format version, so a
+//! build missing a migration does not compile. A migration is run again
+//! if a crash keeps the new format from being recorded, or if the store
+//! can only initialise its metadata, so it must be safe to repeat.
+//!
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use store::ReplayStore;
+use version::CACHE_FORMAT_VERSION;
+use super::{FORMAT_VERSION_KEY, TAG_SIZE_KEY};
+
+
+/// Migration moves a store from one format to the next.
+pub type Migration = fn(&mut dyn ReplayStore) -> Result<(), MixKeyError>;
+
+/// The migration at index `n` moves a store from format `n` to `n + 1`.
+pub const MIGRATIONS: [Migration; CACHE_FORMAT_VERSION as usize] = [
+    migrate_v0_to_v1,
+];
+
+
+/// Format 0 caches hold full size tags without recording their size.
+pub fn migrate_v0_to_v1(store: &mut dyn ReplayStore) -> Result<(), MixKeyError> {
+    store.init_metadata(TAG_SIZE_KEY, &[SPHINX_REPLAY_TAG_SIZE as u8])?;
+    Ok(())
+}
+
+/// Migrate the store from `format` to `CACHE_FORMAT_VERSION`, recording
+/// each format reached, and flush it.
+pub fn migrate(store: &mut dyn ReplayStore, format: u8) -> Result<(), MixKeyError> {
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(format as usize) {
+        migration(store)?;
+        let to = [from as u8 + 1];
+        if !store.set_metadata(FORMAT_VERSION_KEY, &to)? {
+            store.init_metadata(FORMAT_VERSION_KEY, &to)?;
+        }
+    }
+    store.flush()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use store::MemoryStore;
+    use super::*;
+
+
+    #[test]
+    fn migrate_test() {
+        let mut store = MemoryStore::default();
+        migrate(&mut store, 0).unwrap();
+        assert_eq!(store.metadata(FORMAT_VERSION_KEY).unwrap(), Some(vec![CACHE_FORMAT_VERSION]));
+        assert_eq!(store.metadata(TAG_SIZE_KEY).unwrap(), Some(vec![SPHINX_REPLAY_TAG_SIZE as u8]));
+
+        let mut store = MemoryStore::default();
+        store.init_metadata(FORMAT_VERSION_KEY, &[CACHE_FORMAT_VERSION]).unwrap();
+        migrate(&mut store, CACHE_FORMAT_VERSION).unwrap();
+        assert_eq!(store.metadata(TAG_SIZE_KEY).unwrap(), None);
+    }
+}
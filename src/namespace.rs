@@ -0,0 +1,198 @@
+// namespace.rs - Independent tag namespaces within one key.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A node terminating several packet formats, such as forward packets
+//! and SURB replies or several Sphinx geometries, must not let a tag of
+//! one format count as a replay of another. `MixKey::namespace` returns
+//! a `TagNamespace` that checks and records tags apart from the key's
+//! own tags and from every other namespace, while sharing the key's
+//! cache directory.
+//!
+//! Each namespace has a filter of its own. Its tags are stored in the
+//! key's store under the namespace's id byte followed by the tag, so
+//! they never collide with the key's own tags, and are loaded back into
+//! its filter when the namespace is first used after a restart. Only
+//! sled and memory backed keys have namespaces, and a key that ever
+//! had one is not frozen.
+//!
+//! Namespaces check their tags with `replaycore::check_and_insert`,
+//! the same membership check the crate offers without `std`.
+//!
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use errors::{MixKeyError, Op, ResultExt};
+use replaycore::{self, Membership, TagStore, TAG_SIZE};
+use store::ReplayStore;
+use super::{MixKey, Tag, TagFilter};
+
+
+struct Inner {
+    id: u8,
+    epoch: u64,
+    path: PathBuf,
+    filter: Mutex<TagFilter>,
+    store: Mutex<Box<dyn ReplayStore>>,
+    tags: AtomicU64,
+}
+
+impl Membership for TagFilter {
+    fn contains(&self, tag: &[u8; TAG_SIZE]) -> bool {
+        TagFilter::contains(self, Tag::from_bytes(tag))
+    }
+
+    fn insert(&mut self, tag: &[u8; TAG_SIZE]) {
+        TagFilter::insert(self, Tag::from_bytes(tag));
+    }
+}
+
+/// NamespaceStore adds the namespace's epoch and path to the errors of
+/// its store.
+struct NamespaceStore<'a> {
+    store: &'a mut dyn ReplayStore,
+    epoch: u64,
+    path: &'a Path,
+}
+
+impl<'a> TagStore for NamespaceStore<'a> {
+    type Error = MixKeyError;
+
+    fn contains(&mut self, tag: &[u8; TAG_SIZE]) -> Result<bool, MixKeyError> {
+        self.store.contains(Tag::from_bytes(tag)).context(self.epoch, Op::LookupTag, self.path)
+    }
+
+    fn insert(&mut self, tag: &[u8; TAG_SIZE]) -> Result<bool, MixKeyError> {
+        self.store.insert(Tag::from_bytes(tag)).context(self.epoch, Op::InsertTag, self.path)
+    }
+}
+
+/// TagNamespace checks the tags of one packet format against an epoch's
+/// key. Clones share the same filter and store.
+#[derive(Clone)]
+pub struct TagNamespace {
+    inner: Arc<Inner>,
+}
+
+impl TagNamespace {
+    /// Open the namespace kept in the store, loading the tags already
+    /// stored into a filter sized like the key's.
+    pub(crate) fn open(id: u8, epoch: u64, path: &Path, mut store: Box<dyn ReplayStore>, false_positive_rate: f32, expected_num_items: u32) -> Result<TagNamespace, MixKeyError> {
+        let mut filter = TagFilter::new(false_positive_rate, expected_num_items, false);
+        let tags = MixKey::fill_filter(store.as_mut(), &mut filter).context(epoch, Op::OpenNamespace, path)?;
+        Ok(TagNamespace{
+            inner: Arc::new(Inner{
+                id: id,
+                epoch: epoch,
+                path: path.to_path_buf(),
+                filter: Mutex::new(filter),
+                store: Mutex::new(store),
+                tags: AtomicU64::new(tags),
+            }),
+        })
+    }
+
+    pub fn id(&self) -> u8 {
+        self.inner.id
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.inner.epoch
+    }
+
+    /// Returns true if the tag was seen before in this namespace, and
+    /// records it otherwise.
+    pub fn is_replay(&self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let inner = &*self.inner;
+        let mut store = inner.store.lock().unwrap();
+        let mut filter = inner.filter.lock().unwrap();
+        let present = replaycore::check_and_insert(&mut *filter, &mut NamespaceStore{
+            store: &mut **store,
+            epoch: inner.epoch,
+            path: &inner.path,
+        }, tag.as_bytes())?;
+        if !present {
+            inner.tags.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(present)
+    }
+
+    /// Returns true if the tag was seen in this namespace, without
+    /// recording it.
+    pub fn contains(&self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let inner = &*self.inner;
+        if !inner.filter.lock().unwrap().contains(tag) {
+            return Ok(false)
+        }
+        inner.store.lock().unwrap().contains(tag).context(inner.epoch, Op::LookupTag, &inner.path)
+    }
+
+    /// Returns the number of tags stored in this namespace.
+    pub fn len(&self) -> u64 {
+        self.inner.tags.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn flush(&self) -> Result<(), MixKeyError> {
+        let inner = &self.inner;
+        inner.store.lock().unwrap().flush().context(inner.epoch, Op::FlushCache, &inner.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn tag_namespace_test() {
+        let base_dir = TempDir::new().unwrap();
+        let base_dir_path = base_dir.path().to_str().unwrap().to_string();
+        let tag = Tag([7u8; SPHINX_REPLAY_TAG_SIZE]);
+        {
+            let mut mix_key = MixKey::new(1024 * 1024, 3, 60, &base_dir_path).unwrap();
+            let forward = mix_key.namespace(1).unwrap();
+            let replies = mix_key.namespace(2).unwrap();
+            assert_eq!(mix_key.is_replay(&tag).unwrap(), false);
+            assert_eq!(forward.is_replay(&tag).unwrap(), false);
+            assert_eq!(forward.is_replay(&tag).unwrap(), true);
+            assert_eq!(replies.contains(&tag).unwrap(), false);
+            assert_eq!(replies.is_replay(&tag).unwrap(), false);
+            assert_eq!(mix_key.namespace(1).unwrap().contains(&tag).unwrap(), true);
+            assert_eq!(mix_key.tag_count(), 1);
+            mix_key.flush().unwrap();
+        }
+
+        let mix_key = MixKey::new(1024 * 1024, 3, 60, &base_dir_path).unwrap();
+        assert_eq!(mix_key.tag_count(), 1);
+        let forward = mix_key.namespace(1).unwrap();
+        assert_eq!(forward.len(), 1);
+        assert_eq!(forward.is_replay(&tag).unwrap(), true);
+        assert!(mix_key.namespace(3).unwrap().is_empty());
+        assert_eq!(mix_key.freeze().unwrap(), false);
+    }
+}
@@ -0,0 +1,300 @@
+// preflight.rs - Deployment capacity checks.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Estimates what a mix running at a given line rate and epoch duration
+//! will need, and compares that with what the host offers, without
+//! creating any keys. Deployment automation can run `MixKeys::preflight`
+//! before starting a mix and refuse to continue if a check fails.
+//!
+//! Limits this platform gives no way to read are reported as unknown
+//! and do not fail the preflight.
+//!
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use fs2;
+
+use sphinxcrypto::constants::{PACKET_SIZE, SPHINX_REPLAY_TAG_SIZE};
+
+use errors::MixKeyError;
+use constants::MIX_KEY_FALSE_POSITIVE_RATE;
+use fsutil;
+
+
+/// Size of the log segment sled preallocates for every cache.
+const SLED_SEGMENT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Assume each stored tag takes twice its size on disk.
+const DISK_BYTES_PER_TAG: u64 = 2 * SPHINX_REPLAY_TAG_SIZE as u64;
+
+/// Assume a hash set filter takes 16 bytes per tag.
+#[cfg(not(any(feature = "bloom", feature = "quotient")))]
+const HASH_FILTER_BYTES_PER_TAG: u64 = 16;
+
+/// Assume sled writes four bytes for every byte of tag inserted.
+const WRITE_AMPLIFICATION: u64 = 4;
+
+/// File descriptors each open cache may use, and those left over for
+/// the rest of the mix.
+const FILES_PER_KEY: u64 = 4;
+const SPARE_FILES: u64 = 64;
+
+/// Write 4 MiB when measuring disk throughput.
+const BENCHMARK_BYTES: u64 = 4 * 1024 * 1024;
+const BENCHMARK_FILE_NAME: &str = "preflight.benchmark";
+
+
+/// PreflightConfig describes the deployment to check for.
+#[derive(Clone, Debug)]
+pub struct PreflightConfig {
+    pub base_dir: PathBuf,
+    pub num_mix_keys: u8,
+    pub line_rate: u64,
+    pub epoch_duration: u64,
+    pub false_positive_rate: f32,
+    /// Tags each epoch's filter is sized for, or None for a whole epoch
+    /// at the line rate.
+    pub expected_tags: Option<u64>,
+    /// Bytes the filters and caches of every key are limited to, as by
+    /// `MixKeysBuilder::memory_budget`, or None for no limit.
+    pub memory_budget: Option<u64>,
+    /// Bytes written by the throughput benchmark.
+    pub benchmark_bytes: u64,
+}
+
+impl PreflightConfig {
+    pub fn new(base_dir: PathBuf, num_mix_keys: u8, line_rate: u64, epoch_duration: u64) -> PreflightConfig {
+        PreflightConfig{
+            base_dir: base_dir,
+            num_mix_keys: num_mix_keys,
+            line_rate: line_rate,
+            epoch_duration: epoch_duration,
+            false_positive_rate: MIX_KEY_FALSE_POSITIVE_RATE,
+            expected_tags: None,
+            memory_budget: None,
+            benchmark_bytes: BENCHMARK_BYTES,
+        }
+    }
+}
+
+/// PreflightCheck compares one requirement with what is available.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreflightCheck {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub required: u64,
+    /// None if the platform does not report it.
+    pub available: Option<u64>,
+}
+
+impl PreflightCheck {
+    pub fn passed(&self) -> bool {
+        self.available.map_or(true, |available| available >= self.required)
+    }
+}
+
+impl fmt::Display for PreflightCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = if self.passed() { "PASS" } else { "FAIL" };
+        match self.available {
+            Some(available) => write!(f, "{}: {} (required {} {}, available {} {})", self.name, status, self.required, self.unit, available, self.unit),
+            None => write!(f, "{}: {} (required {} {}, available unknown)", self.name, status, self.required, self.unit),
+        }
+    }
+}
+
+/// PreflightReport holds the result of every check.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PreflightReport {
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Returns true if every check passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed())
+    }
+
+    /// Returns the named check.
+    pub fn check(&self, name: &str) -> Option<&PreflightCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "{}", check)?;
+        }
+        write!(f, "preflight: {}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+/// Returns the memory of a filter holding the given number of tags.
+#[cfg(all(feature = "bloom", not(feature = "quotient")))]
+pub(crate) fn filter_bytes(tags: u64, false_positive_rate: f32) -> u64 {
+    let bits = tags as f64 * -(false_positive_rate as f64).ln() / (2f64.ln() * 2f64.ln());
+    (bits / 8.0).ceil() as u64
+}
+
+/// A quotient filter slot holds a remainder four bits longer than the
+/// false positive rate needs and three bits of metadata, and at most
+/// three quarters of the slots are used.
+#[cfg(feature = "quotient")]
+pub(crate) fn filter_bytes(tags: u64, false_positive_rate: f32) -> u64 {
+    let slot_bits = (1.0 / false_positive_rate as f64).log2().ceil() + 7.0;
+    (tags as f64 * slot_bits / 0.75 / 8.0).ceil() as u64
+}
+
+#[cfg(not(any(feature = "bloom", feature = "quotient")))]
+pub(crate) fn filter_bytes(tags: u64, _false_positive_rate: f32) -> u64 {
+    tags.saturating_mul(HASH_FILTER_BYTES_PER_TAG)
+}
+
+/// Run every check for the given configuration. `base_dir` is created
+/// if needed, and a short lived benchmark file is written in it.
+pub fn run(config: &PreflightConfig) -> Result<PreflightReport, MixKeyError> {
+    fs::create_dir_all(&config.base_dir)?;
+    let keys = config.num_mix_keys as u64;
+    let tags_per_second = config.line_rate / PACKET_SIZE as u64;
+    let tags_per_epoch = tags_per_second.saturating_mul(config.epoch_duration);
+
+    let disk_per_key = tags_per_epoch.saturating_mul(DISK_BYTES_PER_TAG).saturating_add(SLED_SEGMENT_SIZE);
+    let filter_tags = config.expected_tags.unwrap_or(tags_per_epoch);
+    let cache_capacity = tags_per_epoch.saturating_mul(SPHINX_REPLAY_TAG_SIZE as u64) / 2;
+    let memory_per_key = filter_bytes(filter_tags, config.false_positive_rate).saturating_add(cache_capacity);
+
+    let checks = vec![
+        PreflightCheck{
+            name: "disk space",
+            unit: "bytes",
+            required: disk_per_key.saturating_mul(keys),
+            available: Some(fs2::available_space(&config.base_dir)?),
+        },
+        PreflightCheck{
+            name: "memory",
+            unit: "bytes",
+            required: config.memory_budget.map_or(memory_per_key.saturating_mul(keys), |budget| budget.min(memory_per_key.saturating_mul(keys))),
+            available: available_memory(),
+        },
+        PreflightCheck{
+            name: "open files",
+            unit: "files",
+            required: keys * FILES_PER_KEY + SPARE_FILES,
+            available: limit("Max open files"),
+        },
+        PreflightCheck{
+            name: "write throughput",
+            unit: "bytes/s",
+            required: tags_per_second.saturating_mul(SPHINX_REPLAY_TAG_SIZE as u64 * WRITE_AMPLIFICATION),
+            available: Some(write_throughput(config)?),
+        },
+    ];
+    Ok(PreflightReport{
+        checks: checks,
+    })
+}
+
+/// Measure how many bytes per second can be written and synced to a
+/// file in `base_dir`.
+fn write_throughput(config: &PreflightConfig) -> Result<u64, MixKeyError> {
+    let path = config.base_dir.join(BENCHMARK_FILE_NAME);
+    let chunk = vec![0xa5u8; 64 * 1024];
+    let start = Instant::now();
+    let result = (|| {
+        let mut file = File::create(&path)?;
+        let mut written = 0;
+        while written < config.benchmark_bytes {
+            file.write_all(&chunk)?;
+            written += chunk.len() as u64;
+        }
+        fsutil::sync_file(&file)?;
+        Ok(written)
+    })();
+    let elapsed = start.elapsed();
+    let _ = fs::remove_file(&path);
+    let written: u64 = result.map_err(MixKeyError::IoError)?;
+    let micros = (elapsed.as_micros() as u64).max(1);
+    Ok(written.saturating_mul(1_000_000) / micros)
+}
+
+/// Returns the memory available to this process: the smaller of the
+/// free memory and the address space limit.
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let free = meminfo.lines()
+        .find(|line| line.starts_with("MemAvailable:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<u64>().ok())
+        .map(|kb| kb * 1024)?;
+    match limit("Max address space") {
+        Some(address_space) => Some(free.min(address_space)),
+        None => Some(free),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+/// Returns the soft resource limit of the given name, or None if it is
+/// unlimited or unknown.
+#[cfg(target_os = "linux")]
+fn limit(name: &str) -> Option<u64> {
+    let limits = fs::read_to_string("/proc/self/limits").ok()?;
+    let line = limits.lines().find(|line| line.starts_with(name))?;
+    line[name.len()..].split_whitespace().next()?.parse::<u64>().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn limit(_name: &str) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn preflight_test() {
+        let base_dir = TempDir::new().unwrap();
+        let mut config = PreflightConfig::new(base_dir.path().join("mix"), 3, 1024 * 1024, 60);
+        config.benchmark_bytes = 256 * 1024;
+        let report = run(&config).unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.checks.len(), 4);
+        assert!(report.check("write throughput").unwrap().available.unwrap() > 0);
+        assert_eq!(fs::read_dir(&config.base_dir).unwrap().count(), 0);
+
+        config.line_rate = 1 << 44;
+        let report = run(&config).unwrap();
+        assert!(!report.passed());
+        assert!(!report.check("disk space").unwrap().passed());
+        assert!(report.to_string().ends_with("preflight: FAIL"));
+    }
+}
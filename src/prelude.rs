@@ -0,0 +1,60 @@
+// prelude.rs - The stable public API.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Everything a mix server needs, in one import:
+//!
+//! ```rust,ignore
+//! use sphinx_replay_cache::prelude::*;
+//! ```
+//!
+//! The items re-exported here follow semantic versioning: they are only
+//! removed or changed incompatibly in a new major version, and the
+//! `tests/prelude.rs` guard fails when they are. Items only reachable
+//! through their own modules may change between minor versions.
+//!
+//! The traits are the extension points. Implement `KeyProvider` and
+//! `EpochKey` to hold keys elsewhere, `ReplayStore` and
+//! `ReplayStoreFactory` to keep tags elsewhere, `AlertSink` to deliver
+//! capacity alarms, `DescriptorSigner` to sign descriptor bundles,
+//! `ClockSource` for epochs other than `epoch::Clock`'s, and
+//! `MonotonicClock` to drive time from a test harness.
+//!
+
+pub use epoch::{Clock, Time};
+pub use ecdh_wrapper::{PrivateKey, PublicKey};
+
+pub use super::{MixKey, MixKeys, Tag};
+pub use alarms::{AlertSink, CapacityAlarm, LogAlertSink};
+pub use builder::{CacheConfig, ClockRollbackPolicy, FutureCachePolicy, MixKeysBuilder, MixKeysConfig, OverflowBehavior};
+pub use countdown::{EarlyTagPolicy, EpochKeyInfo, KeyCountdown};
+pub use dedup::DedupSet;
+pub use descriptor::{DescriptorBundle, DescriptorEntry, DescriptorSigner};
+pub use errors::MixKeyError;
+pub use events::{KeyEvent, ReplayEvent};
+pub use durability::{DurabilityPolicy, ReplayWindow};
+pub use flushcontrol::{FlushAdaptation, FlushBounds};
+pub use handle::MixKeysHandle;
+pub use health::{Check, HealthReport, KeyHealth};
+pub use keyprovider::{EpochKey, Kem, KeyProvider, LocalKeyProvider, SeedKeyProvider};
+pub use lifecycle::KeyState;
+pub use replica::FilterReplica;
+pub use scheduler::{MixKeyScheduler, RotationEvent};
+pub use stats::{KeyStats, MixKeysStats};
+pub use store::{CacheBackend, ReplayStore, ReplayStoreFactory};
+pub use storm::ReplayStorm;
+pub use timesource::{ClockSource, MonotonicClock, SystemMonotonicClock};
+pub use writeback::WriteBatch;
@@ -0,0 +1,550 @@
+// quotient.rs - Quotient filter.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Builds with the `quotient` feature keep each epoch's filter as a
+//! quotient filter instead of a bloom filter. A bloom filter touches a
+//! cache line per hash function on every lookup, scattered across the
+//! whole bit array; a quotient filter finds a tag by scanning a few
+//! neighbouring slots. Two quotient filters built with the same seed can
+//! also be merged, which replicas use to exchange what they have seen.
+//!
+//! A tag is hashed, with a keyed hash, to a fingerprint of `q + r` bits.
+//! The top `q` bits, the quotient, select one of `2^q` slots, and the
+//! remaining `r` bits, the remainder, are stored there, or as close
+//! after it as the run of remainders sharing the quotient allows. Three
+//! bits per slot record whether a slot is some run's home, whether it
+//! continues the run before it, and whether it was shifted from its
+//! home, which is enough to find a quotient's run again.
+//!
+//! Once three quarters of the slots are used the table doubles, moving
+//! the top remainder bit into the quotient. Filters start with
+//! `SPARE_BITS` more remainder bits than their false positive rate
+//! needs, so it holds until sixteen times the expected tags are
+//! inserted, and degrades gradually after that.
+//!
+//! A counting filter keeps every inserted fingerprint, duplicates
+//! included, so that removing a tag removes exactly what inserting it
+//! added. Like a counting bloom filter, callers must only remove tags
+//! they know are present.
+//!
+
+#[allow(deprecated)]
+use std::hash::SipHasher;
+use std::hash::{Hash, Hasher};
+
+use rand;
+
+
+/// Double the table once three quarters of its slots are used.
+const MAX_LOAD: f64 = 0.75;
+
+/// Remainder bits kept beyond those the false positive rate needs, one
+/// for each time the table may double before the rate degrades.
+const SPARE_BITS: u32 = 4;
+
+/// The smallest table has 64 slots.
+const MIN_QUOTIENT_BITS: u32 = 6;
+
+const OCCUPIED: u64 = 1;
+const CONTINUATION: u64 = 2;
+const SHIFTED: u64 = 4;
+const METADATA_BITS: u32 = 3;
+
+
+/// QuotientFilter is a quotient filter that doubles as it fills. It has
+/// the methods of `ScalableBloomFilter`, which it stands in for.
+pub struct QuotientFilter {
+    table: Vec<u64>,
+    quotient_bits: u32,
+    remainder_bits: u32,
+    entries: u64,
+    seed: (u64, u64),
+    counting: bool,
+}
+
+impl QuotientFilter {
+    pub fn with_rate(false_positive_rate: f32, expected_num_items: u32) -> QuotientFilter {
+        QuotientFilter::new(false_positive_rate, expected_num_items, false)
+    }
+
+    pub fn counting(false_positive_rate: f32, expected_num_items: u32) -> QuotientFilter {
+        QuotientFilter::new(false_positive_rate, expected_num_items, true)
+    }
+
+    pub(crate) fn new(false_positive_rate: f32, expected_num_items: u32, counting: bool) -> QuotientFilter {
+        QuotientFilter::with_seed((rand::random(), rand::random()), false_positive_rate, expected_num_items, counting)
+    }
+
+    /// Returns a filter hashing with the given seed. Only filters with
+    /// the same seed can be merged.
+    pub fn with_seed(seed: (u64, u64), false_positive_rate: f32, expected_num_items: u32, counting: bool) -> QuotientFilter {
+        let slots = (expected_num_items.max(1) as f64 / MAX_LOAD).ceil() as u64;
+        let quotient_bits = (64 - (slots - 1).leading_zeros()).max(MIN_QUOTIENT_BITS).min(48);
+        let needed = (1.0 / false_positive_rate.max(1e-12).min(0.5) as f64).log2().ceil() as u32;
+        let remainder_bits = (needed + SPARE_BITS).min(64 - METADATA_BITS - 1).min(64 - quotient_bits);
+        QuotientFilter::empty(seed, quotient_bits, remainder_bits, counting)
+    }
+
+    fn empty(seed: (u64, u64), quotient_bits: u32, remainder_bits: u32, counting: bool) -> QuotientFilter {
+        let width = (remainder_bits + METADATA_BITS) as u64;
+        let words = ((1u64 << quotient_bits) * width + 63) / 64;
+        QuotientFilter{
+            table: vec![0; words as usize],
+            quotient_bits: quotient_bits,
+            remainder_bits: remainder_bits,
+            entries: 0,
+            seed: seed,
+            counting: counting,
+        }
+    }
+
+    /// Returns the seed of the filter's hash.
+    pub fn seed(&self) -> (u64, u64) {
+        self.seed
+    }
+
+    pub fn is_counting(&self) -> bool {
+        self.counting
+    }
+
+    /// Returns the number of fingerprints held.
+    pub fn len(&self) -> u64 {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    #[allow(deprecated)]
+    fn fingerprint<T: Hash>(&self, item: &T) -> u64 {
+        let mut hasher = SipHasher::new_with_keys(self.seed.0, self.seed.1);
+        item.hash(&mut hasher);
+        hasher.finish() >> (64 - self.quotient_bits - self.remainder_bits)
+    }
+
+    fn split(&self, fingerprint: u64) -> (u64, u64) {
+        (fingerprint >> self.remainder_bits, fingerprint & ((1 << self.remainder_bits) - 1))
+    }
+
+    fn slots(&self) -> u64 {
+        1 << self.quotient_bits
+    }
+
+    fn width(&self) -> u64 {
+        (self.remainder_bits + METADATA_BITS) as u64
+    }
+
+    fn incr(&self, i: u64) -> u64 {
+        (i + 1) & (self.slots() - 1)
+    }
+
+    fn decr(&self, i: u64) -> u64 {
+        i.wrapping_sub(1) & (self.slots() - 1)
+    }
+
+    fn get(&self, i: u64) -> u64 {
+        let width = self.width();
+        let mask = (1u64 << width) - 1;
+        let bit = i * width;
+        let (word, offset) = ((bit / 64) as usize, bit % 64);
+        let mut value = self.table[word] >> offset;
+        if offset + width > 64 {
+            value |= self.table[word + 1] << (64 - offset);
+        }
+        value & mask
+    }
+
+    fn set(&mut self, i: u64, value: u64) {
+        let width = self.width();
+        let mask = (1u64 << width) - 1;
+        let bit = i * width;
+        let (word, offset) = ((bit / 64) as usize, bit % 64);
+        self.table[word] &= !(mask << offset);
+        self.table[word] |= value << offset;
+        if offset + width > 64 {
+            self.table[word + 1] &= !(mask >> (64 - offset));
+            self.table[word + 1] |= value >> (64 - offset);
+        }
+    }
+
+    fn is_run_start(slot: u64) -> bool {
+        slot & CONTINUATION == 0 && slot & (OCCUPIED | SHIFTED) != 0
+    }
+
+    fn is_cluster_start(slot: u64) -> bool {
+        slot & (OCCUPIED | CONTINUATION | SHIFTED) == OCCUPIED
+    }
+
+    /// Returns the slot the run of the quotient starts at, or would.
+    fn run_start(&self, quotient: u64) -> u64 {
+        let mut b = quotient;
+        while self.get(b) & SHIFTED != 0 {
+            b = self.decr(b);
+        }
+        let mut s = b;
+        while b != quotient {
+            loop {
+                s = self.incr(s);
+                if self.get(s) & CONTINUATION == 0 {
+                    break
+                }
+            }
+            loop {
+                b = self.incr(b);
+                if self.get(b) & OCCUPIED != 0 {
+                    break
+                }
+            }
+        }
+        s
+    }
+
+    /// Put the slot value at `s`, shifting the rest of the cluster
+    /// right. Home bits stay with their slots.
+    fn shift_in(&mut self, mut s: u64, value: u64) {
+        let mut curr = value;
+        loop {
+            let mut prev = self.get(s);
+            let empty = prev == 0;
+            if !empty {
+                prev |= SHIFTED;
+                if prev & OCCUPIED != 0 {
+                    curr |= OCCUPIED;
+                    prev &= !OCCUPIED;
+                }
+            }
+            self.set(s, curr);
+            curr = prev;
+            s = self.incr(s);
+            if empty {
+                return
+            }
+        }
+    }
+
+    /// Remove the slot value at `s`, shifting the rest of the cluster
+    /// left.
+    fn shift_out(&mut self, mut s: u64, mut quotient: u64) {
+        let orig = s;
+        let mut curr = self.get(s);
+        let mut sp = self.incr(s);
+        loop {
+            let next = self.get(sp);
+            let curr_occupied = curr & OCCUPIED != 0;
+            if next == 0 || QuotientFilter::is_cluster_start(next) || sp == orig {
+                self.set(s, 0);
+                return
+            }
+            let mut updated = next;
+            if QuotientFilter::is_run_start(next) {
+                loop {
+                    quotient = self.incr(quotient);
+                    if self.get(quotient) & OCCUPIED != 0 {
+                        break
+                    }
+                }
+                if curr_occupied && quotient == s {
+                    updated &= !SHIFTED;
+                }
+            }
+            self.set(s, if curr_occupied { updated | OCCUPIED } else { updated & !OCCUPIED });
+            s = sp;
+            sp = self.incr(sp);
+            curr = next;
+        }
+    }
+
+    /// Insert the fingerprint, returning true if it was not already
+    /// present.
+    fn insert_fingerprint(&mut self, fingerprint: u64) -> bool {
+        if (self.entries + 1) as f64 > self.slots() as f64 * MAX_LOAD {
+            self.grow();
+        }
+        if self.entries + 1 >= self.slots() {
+            return false
+        }
+        let (quotient, remainder) = self.split(fingerprint);
+        let home = self.get(quotient);
+        let mut entry = remainder << METADATA_BITS;
+        if home == 0 {
+            self.set(quotient, entry | OCCUPIED);
+            self.entries += 1;
+            return true
+        }
+        if home & OCCUPIED == 0 {
+            self.set(quotient, home | OCCUPIED);
+        }
+        let start = self.run_start(quotient);
+        let mut s = start;
+        let mut present = false;
+        if home & OCCUPIED != 0 {
+            loop {
+                let stored = self.get(s) >> METADATA_BITS;
+                if stored == remainder {
+                    if !self.counting {
+                        return false
+                    }
+                    present = true;
+                    break
+                } else if stored > remainder {
+                    break
+                }
+                s = self.incr(s);
+                if self.get(s) & CONTINUATION == 0 {
+                    break
+                }
+            }
+            if s == start {
+                let head = self.get(start);
+                self.set(start, head | CONTINUATION);
+            } else {
+                entry |= CONTINUATION;
+            }
+        }
+        if s != quotient {
+            entry |= SHIFTED;
+        }
+        self.shift_in(s, entry);
+        self.entries += 1;
+        !present
+    }
+
+    fn contains_fingerprint(&self, fingerprint: u64) -> bool {
+        let (quotient, remainder) = self.split(fingerprint);
+        if self.get(quotient) & OCCUPIED == 0 {
+            return false
+        }
+        let mut s = self.run_start(quotient);
+        loop {
+            let stored = self.get(s) >> METADATA_BITS;
+            if stored == remainder {
+                return true
+            } else if stored > remainder {
+                return false
+            }
+            s = self.incr(s);
+            if self.get(s) & CONTINUATION == 0 {
+                return false
+            }
+        }
+    }
+
+    fn remove_fingerprint(&mut self, fingerprint: u64) -> bool {
+        let (quotient, remainder) = self.split(fingerprint);
+        let mut home = self.get(quotient);
+        if home & OCCUPIED == 0 || self.entries == 0 {
+            return false
+        }
+        let mut s = self.run_start(quotient);
+        loop {
+            let stored = self.get(s) >> METADATA_BITS;
+            if stored == remainder {
+                break
+            } else if stored > remainder {
+                return false
+            }
+            s = self.incr(s);
+            if self.get(s) & CONTINUATION == 0 {
+                return false
+            }
+        }
+        let kill = if s == quotient { home } else { self.get(s) };
+        let replace_run_start = QuotientFilter::is_run_start(kill);
+        if replace_run_start && self.get(self.incr(s)) & CONTINUATION == 0 {
+            home &= !OCCUPIED;
+            self.set(quotient, home);
+        }
+        self.shift_out(s, quotient);
+        if replace_run_start {
+            let next = self.get(s);
+            let mut updated = next;
+            if updated & CONTINUATION != 0 {
+                updated &= !CONTINUATION;
+            }
+            if s == quotient && QuotientFilter::is_run_start(updated) {
+                updated &= !SHIFTED;
+            }
+            if updated != next {
+                self.set(s, updated);
+            }
+        }
+        self.entries -= 1;
+        true
+    }
+
+    /// Returns every fingerprint held, in no particular order.
+    fn fingerprints(&self) -> Vec<u64> {
+        let mut fingerprints = Vec::with_capacity(self.entries as usize);
+        if self.entries == 0 {
+            return fingerprints
+        }
+        let mut start = 0;
+        while !QuotientFilter::is_cluster_start(self.get(start)) {
+            start = self.incr(start);
+        }
+        let mut quotient = start;
+        let mut i = start;
+        for _ in 0..self.slots() {
+            let slot = self.get(i);
+            if QuotientFilter::is_cluster_start(slot) {
+                quotient = i;
+            } else if QuotientFilter::is_run_start(slot) {
+                loop {
+                    quotient = self.incr(quotient);
+                    if self.get(quotient) & OCCUPIED != 0 {
+                        break
+                    }
+                }
+            }
+            if slot != 0 {
+                fingerprints.push(quotient << self.remainder_bits | slot >> METADATA_BITS);
+            }
+            i = self.incr(i);
+        }
+        fingerprints
+    }
+
+    /// Double the table, moving a remainder bit into the quotient.
+    fn grow(&mut self) {
+        if self.remainder_bits <= 1 {
+            return
+        }
+        let mut grown = QuotientFilter::empty(self.seed, self.quotient_bits + 1, self.remainder_bits - 1, self.counting);
+        for fingerprint in self.fingerprints() {
+            grown.insert_fingerprint(fingerprint);
+        }
+        if self.remainder_bits == SPARE_BITS + 1 {
+            warn!("quotient filter grew past {} tags; its false positive rate now rises as it fills", self.entries);
+        }
+        *self = grown;
+    }
+
+    /// Insert the item, returning true if it was not already present.
+    pub fn insert<T: Hash>(&mut self, item: &T) -> bool {
+        let fingerprint = self.fingerprint(item);
+        self.insert_fingerprint(fingerprint)
+    }
+
+    /// Check if the item has been inserted. This can return false
+    /// positives, but not false negatives.
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.contains_fingerprint(self.fingerprint(item))
+    }
+
+    /// Remove an item known to have been inserted, returning false if
+    /// the filter does not hold it or is not a counting filter.
+    pub fn remove<T: Hash>(&mut self, item: &T) -> bool {
+        let fingerprint = self.fingerprint(item);
+        self.counting && self.remove_fingerprint(fingerprint)
+    }
+
+    /// Insert every fingerprint of the other filter, returning false
+    /// without inserting any if the filters have different seeds, or the
+    /// other's fingerprints are shorter than this one's.
+    pub fn merge(&mut self, other: &QuotientFilter) -> bool {
+        let bits = self.quotient_bits + self.remainder_bits;
+        let other_bits = other.quotient_bits + other.remainder_bits;
+        if self.seed != other.seed || other_bits < bits {
+            return false
+        }
+        for fingerprint in other.fingerprints() {
+            self.insert_fingerprint(fingerprint >> (other_bits - bits));
+        }
+        true
+    }
+
+    /// Fault in the table of an empty filter, which the allocator hands
+    /// out as untouched pages.
+    pub fn prefault(&mut self) {
+        if self.entries == 0 {
+            for word in self.table.iter_mut() {
+                *word = 0;
+            }
+        }
+    }
+
+    /// A quotient filter is a single layer.
+    pub fn layers(&self) -> usize {
+        1
+    }
+
+    /// Returns the fraction of slots used.
+    pub fn fill_ratio(&self) -> f64 {
+        self.entries as f64 / self.slots() as f64
+    }
+
+    /// Returns the probability that an item never inserted shares the
+    /// fingerprint of one that was.
+    pub fn false_positive_rate(&self) -> f64 {
+        1.0 - (-self.fill_ratio() / 2f64.powi(self.remainder_bits as i32)).exp()
+    }
+
+    /// Returns the memory used by the table, in bits.
+    pub fn num_bits(&self) -> usize {
+        self.table.len() * 64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn quotient_filter_test() {
+        let mut filter = QuotientFilter::with_rate(0.001, 100);
+        let slots = filter.slots();
+        for i in 0..1000u32 {
+            assert!(filter.insert(&i));
+        }
+        assert!(filter.slots() > slots);
+        assert_eq!(filter.len(), 1000);
+        assert!(!filter.insert(&7u32));
+        assert!((0..1000u32).all(|i| filter.contains(&i)));
+        assert!((1000..11000u32).filter(|i| filter.contains(i)).count() < 100);
+        assert!(!filter.remove(&7u32));
+
+        let mut counting = QuotientFilter::counting(0.001, 100);
+        for i in 0..500u32 {
+            counting.insert(&i);
+        }
+        assert!(!counting.insert(&7u32));
+        assert!(counting.remove(&7u32));
+        assert!(counting.contains(&7u32));
+        assert!(counting.remove(&7u32));
+        assert!(!counting.contains(&7u32));
+        for i in 0..250u32 {
+            if i != 7 {
+                assert!(counting.remove(&i));
+            }
+        }
+        assert_eq!(counting.len(), 250);
+        assert!((250..500u32).all(|i| counting.contains(&i)));
+
+        let mut a = QuotientFilter::with_seed(filter.seed(), 0.001, 100, false);
+        let mut b = QuotientFilter::with_seed(filter.seed(), 0.001, 100, false);
+        for i in 0..50u32 {
+            a.insert(&i);
+            b.insert(&(i + 50));
+        }
+        assert!(a.merge(&b));
+        assert!((0..100u32).all(|i| a.contains(&i)));
+        assert!(!a.merge(&QuotientFilter::with_rate(0.001, 100)));
+    }
+}
@@ -0,0 +1,276 @@
+// recovery.rs - Repair of damaged epoch caches.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! `MixKey::open_with_recovery` repairs a sled cache that fails to
+//! load, rather than leaving the operator to delete it by hand. The
+//! damaged directory is moved to `base_dir/quarantine`, whatever can
+//! still be read from it is salvaged, and a fresh cache is built from
+//! the salvage and renamed into place.
+//!
+//! A scan stops at the first entry sled can not read, so tags stored
+//! after it are lost. If the private key can not be read back, the
+//! recovered cache gets a new key, and packets made for the old one
+//! can no longer be unwrapped. The `RecoveryReport` says which of these
+//! happened.
+//!
+
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use sled;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use lifecycle::KeyState;
+use store::{self, is_tag_size};
+use super::{EPOCH_KEY, FORMAT_VERSION_KEY, FROZEN_METADATA_KEYS, KEM_KEY, MIX_CACHE_KEY, OVERFLOW_DIR_NAME, PUBLIC_KEY_KEY, STATE_KEY, TAG_SIZE_KEY};
+
+
+/// RecoveryReport says what opening an epoch's cache had to repair.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecoveryReport {
+    pub epoch: u64,
+    /// True if the cache failed to load and was rebuilt.
+    pub repaired: bool,
+    /// Where the damaged cache was moved.
+    pub quarantined: Option<PathBuf>,
+    /// Tags carried over into the rebuilt cache.
+    pub salvaged_tags: u64,
+    /// False if a scan stopped at an unreadable entry, or the tree did
+    /// not open at all, so that some tags may have been lost.
+    pub complete: bool,
+    /// True if the private key could not be read back and was replaced.
+    pub key_lost: bool,
+}
+
+impl RecoveryReport {
+    /// Returns the report of a cache that loaded without repair.
+    pub fn clean(epoch: u64) -> RecoveryReport {
+        RecoveryReport{
+            epoch: epoch,
+            repaired: false,
+            quarantined: None,
+            salvaged_tags: 0,
+            complete: true,
+            key_lost: false,
+        }
+    }
+}
+
+/// Salvage is what could be read from a damaged cache.
+#[derive(Default)]
+pub(crate) struct Salvage {
+    pub tags: Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>,
+    pub metadata: Vec<(&'static str, Vec<u8>)>,
+    pub complete: bool,
+}
+
+impl Salvage {
+    /// Returns the stored identifier of the private key, if it was read.
+    pub fn key_id(&self) -> Option<&[u8]> {
+        self.metadata.iter().find(|&&(name, _)| name == MIX_CACHE_KEY).map(|&(_, ref value)| &value[..])
+    }
+
+    /// Returns the size of the tags the cache stored.
+    fn tag_size(&self) -> usize {
+        match self.metadata.iter().find(|&&(name, _)| name == TAG_SIZE_KEY) {
+            Some(&(_, ref value)) => value[0] as usize,
+            None => SPHINX_REPLAY_TAG_SIZE,
+        }
+    }
+
+    /// Keep the scanned keys that are tags of the given size.
+    fn add_tags(&mut self, keys: Vec<Vec<u8>>, tag_size: usize) {
+        for key in keys.into_iter().filter(|key| key.len() == tag_size) {
+            let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            tag[..tag_size].copy_from_slice(&key);
+            self.tags.push(tag);
+        }
+    }
+
+    /// Forget the key and what was derived from it, so that the rebuilt
+    /// cache gets a new one. The lifecycle state and format are kept.
+    pub fn drop_key(&mut self) {
+        self.metadata.retain(|&(name, _)| name != MIX_CACHE_KEY && name != PUBLIC_KEY_KEY && name != KEM_KEY);
+    }
+}
+
+/// Returns true if the error is one a damaged cache produces, rather
+/// than a problem recovery can not fix, such as a cache written in a
+/// newer format or a key of another KEM.
+pub(crate) fn is_damage(error: &MixKeyError) -> bool {
+    match *error {
+        MixKeyError::Context{ref source, ..} => is_damage(source),
+        MixKeyError::LoadCacheFailed | MixKeyError::SledError(_) | MixKeyError::IoError(_) | MixKeyError::KeyError(_) => true,
+        _ => false,
+    }
+}
+
+/// Read what can be read from the epoch's cache in `path` and its
+/// overflow tree. Entries of another epoch's cache are not salvaged.
+pub(crate) fn salvage(path: &Path, epoch: u64) -> Salvage {
+    let mut salvage = Salvage{
+        complete: true,
+        ..Salvage::default()
+    };
+    let overflow_path = path.join(OVERFLOW_DIR_NAME);
+    match scan(path, epoch, true, &mut salvage) {
+        Some(keys) => {
+            let tag_size = salvage.tag_size();
+            salvage.add_tags(keys, tag_size);
+        },
+        None => {
+            salvage.metadata.clear();
+            salvage.complete = false;
+            return salvage
+        },
+    }
+    if overflow_path.exists() {
+        if let Some(keys) = scan(&overflow_path, epoch, false, &mut salvage) {
+            salvage.add_tags(keys, SPHINX_REPLAY_TAG_SIZE);
+        }
+    }
+    salvage
+}
+
+/// Returns false for a metadata value the cache could not load.
+fn is_valid(name: &str, value: &[u8]) -> bool {
+    match name {
+        STATE_KEY => value.len() == 1 && KeyState::from_id(value[0]).is_some(),
+        KEM_KEY | FORMAT_VERSION_KEY => value.len() == 1,
+        TAG_SIZE_KEY => value.len() == 1 && is_tag_size(value[0] as usize),
+        _ => true,
+    }
+}
+
+/// Scan one sled tree's metadata into the salvage, returning the keys
+/// that may be tags, or None if it holds another epoch's cache.
+fn scan(path: &Path, epoch: u64, with_metadata: bool, salvage: &mut Salvage) -> Option<Vec<Vec<u8>>> {
+    let tree = match store::open_tree(&sled::Config::new().path(path)) {
+        Ok(tree) => tree,
+        Err(e) => {
+            warn!("failed to open the damaged cache at {}: {}", path.display(), e);
+            salvage.complete = false;
+            return Some(vec![])
+        },
+    };
+    let mut keys = vec![];
+    for item in tree.iter() {
+        let (key, value) = match item {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("stopped salvaging the cache at {}: {}", path.display(), MixKeyError::from(e));
+                salvage.complete = false;
+                break
+            },
+        };
+        let marked = if key.len() == 8 && value.is_empty() {
+            Some(LittleEndian::read_u64(&key))
+        } else if &key[..] == EPOCH_KEY.as_bytes() && value.len() == 8 {
+            Some(LittleEndian::read_u64(&value))
+        } else {
+            None
+        };
+        if let Some(marked) = marked {
+            if marked != epoch {
+                warn!("the damaged cache at {} belongs to epoch {}", path.display(), marked);
+                return None
+            }
+        } else if value.is_empty() {
+            keys.push(key.to_vec());
+        } else if with_metadata {
+            match FROZEN_METADATA_KEYS.iter().find(|name| name.as_bytes() == &key[..]) {
+                Some(name) if is_valid(name, &value) => salvage.metadata.push((*name, value.to_vec())),
+                Some(name) => warn!("dropping the damaged {} of the cache at {}", name, path.display()),
+                None => {},
+            }
+        }
+    }
+    Some(keys)
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use builder::CacheConfig;
+    use fsutil;
+    use keyprovider::LocalKeyProvider;
+    use testing::TestClock;
+    use super::super::{MixKey, Tag};
+    use super::*;
+
+
+    #[test]
+    fn open_with_recovery_test() {
+        let base_dir = TempDir::new().unwrap();
+        let base_dir_path = base_dir.path().to_str().unwrap().to_string();
+        let config = CacheConfig::default();
+        let tags: Vec<Tag> = (0..16u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        let clock = TestClock::at(3, 60);
+        let (mut mix_key, report) = MixKey::open_with_recovery(&LocalKeyProvider, &clock, 1024 * 1024, 3, 60, &base_dir_path, &config).unwrap();
+        assert_eq!(report, RecoveryReport::clean(3));
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), false);
+        }
+        mix_key.flush().unwrap();
+        let public_key = mix_key.public_key();
+        drop(mix_key);
+
+        // Damage the stored lifecycle state.
+        let path = fsutil::epoch_dir(base_dir.path(), 3);
+        {
+            let tree = store::open_tree(&sled::Config::new().path(path.clone())).unwrap();
+            tree.insert(STATE_KEY.as_bytes().to_vec(), vec![9, 9]).unwrap();
+            tree.flush().unwrap();
+        }
+        assert!(MixKey::new(1024 * 1024, 3, 60, &base_dir_path).is_err());
+
+        let (mix_key, report) = MixKey::open_with_recovery(&LocalKeyProvider, &clock, 1024 * 1024, 3, 60, &base_dir_path, &config).unwrap();
+        assert!(report.repaired);
+        assert!(report.complete);
+        assert!(!report.key_lost);
+        assert_eq!(report.salvaged_tags, tags.len() as u64);
+        assert_eq!(report.quarantined, Some(fsutil::quarantine_dir(base_dir.path(), 3, 180)));
+        assert!(report.quarantined.unwrap().exists());
+        assert_eq!(mix_key.public_key(), public_key);
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+        drop(mix_key);
+
+        // Lose the epoch marker.
+        {
+            let tree = store::open_tree(&sled::Config::new().path(path.clone())).unwrap();
+            assert!(tree.remove(EPOCH_KEY.as_bytes()).unwrap().is_some());
+            tree.flush().unwrap();
+        }
+        assert!(MixKey::new(1024 * 1024, 3, 60, &base_dir_path).is_err());
+
+        let (mix_key, report) = MixKey::open_with_recovery(&LocalKeyProvider, &clock, 1024 * 1024, 3, 60, &base_dir_path, &config).unwrap();
+        assert!(report.repaired);
+        assert!(!report.key_lost);
+        assert_eq!(report.salvaged_tags, tags.len() as u64);
+        assert_eq!(mix_key.public_key(), public_key);
+        for tag in &tags {
+            assert_eq!(mix_key.is_replay(tag).unwrap(), true);
+        }
+    }
+}
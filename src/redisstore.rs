@@ -0,0 +1,181 @@
+// redisstore.rs - Redis backed replay tag store.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Keeps each epoch's tags in a Redis set, so that several packet
+//! processing processes on one host, or an active/standby pair, share
+//! a single authoritative set of tags. `SADD` checks and inserts a tag
+//! atomically, so a packet replayed to two processes at once is only
+//! accepted by one of them.
+//!
+//! For an epoch and namespace the store uses these keys:
+//!
+//!    <namespace>:mix_key.<epoch>:tags
+//!    <namespace>:mix_key.<epoch>:<metadata name>
+//!
+//! Durability is whatever the Redis server is configured for, so
+//! `flush` does nothing.
+//!
+
+use redis::{Client, Commands, Connection, RedisError};
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use store::{ReplayStore, ReplayStoreFactory};
+use super::Tag;
+
+
+fn store_error(e: RedisError) -> MixKeyError {
+    MixKeyError::StoreError(e.to_string())
+}
+
+
+/// RedisStore keeps the tags of one epoch in a Redis set.
+pub struct RedisStore {
+    conn: Connection,
+    prefix: String,
+    tags_key: String,
+}
+
+impl RedisStore {
+    pub fn open(client: &Client, namespace: &str, epoch: u64) -> Result<RedisStore, MixKeyError> {
+        let prefix = format!("{}:mix_key.{}", namespace, epoch);
+        Ok(RedisStore{
+            conn: client.get_connection().map_err(store_error)?,
+            tags_key: format!("{}:tags", prefix),
+            prefix: prefix,
+        })
+    }
+
+    fn metadata_key(&self, name: &str) -> String {
+        format!("{}:{}", self.prefix, name)
+    }
+}
+
+impl ReplayStore for RedisStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        self.conn.sismember(&self.tags_key, &tag.0[..]).map_err(store_error)
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let added: u64 = self.conn.sadd(&self.tags_key, &tag.0[..]).map_err(store_error)?;
+        Ok(added == 0)
+    }
+
+    fn remove(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let removed: u64 = self.conn.srem(&self.tags_key, &tag.0[..]).map_err(store_error)?;
+        Ok(removed == 1)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        Ok(())
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        match self.conn.sscan::<_, Vec<u8>>(&self.tags_key) {
+            Ok(iter) => Box::new(iter.filter(|member| member.len() == SPHINX_REPLAY_TAG_SIZE).map(|member| {
+                let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+                raw.copy_from_slice(&member);
+                Ok(raw)
+            })),
+            Err(e) => Box::new(Some(Err(store_error(e))).into_iter()),
+        }
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        let key = self.metadata_key(name);
+        self.conn.get(key).map_err(store_error)
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        let key = self.metadata_key(name);
+        let _set: bool = self.conn.set_nx(&key, value).map_err(store_error)?;
+        self.conn.get(&key).map_err(store_error)
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        let key = self.metadata_key(name);
+        let _: () = self.conn.set(key, value).map_err(store_error)?;
+        Ok(true)
+    }
+}
+
+/// RedisStoreFactory opens a `RedisStore` for every epoch of a
+/// `MixKeys`. Processes sharing tags use the same server and namespace.
+pub struct RedisStoreFactory {
+    client: Client,
+    namespace: String,
+}
+
+impl RedisStoreFactory {
+    pub fn new(url: &str, namespace: &str) -> Result<RedisStoreFactory, MixKeyError> {
+        Ok(RedisStoreFactory{
+            client: Client::open(url).map_err(store_error)?,
+            namespace: namespace.to_string(),
+        })
+    }
+}
+
+impl ReplayStoreFactory for RedisStoreFactory {
+    fn open(&self, epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        Ok(Box::new(RedisStore::open(&self.client, &self.namespace, epoch)?))
+    }
+
+    fn remove(&self, epoch: u64) -> Result<(), MixKeyError> {
+        let mut conn = self.client.get_connection().map_err(store_error)?;
+        let pattern = format!("{}:mix_key.{}:*", self.namespace, epoch);
+        let keys: Vec<String> = conn.scan_match(pattern).map_err(store_error)?.collect();
+        if keys.is_empty() {
+            return Ok(())
+        }
+        conn.del(keys).map_err(store_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+
+    use super::*;
+
+
+    // Needs a Redis server, at SPHINX_REPLAY_CACHE_REDIS_URL if set.
+    #[test]
+    #[ignore]
+    fn redis_store_test() {
+        let url = env::var("SPHINX_REPLAY_CACHE_REDIS_URL").unwrap_or("redis://127.0.0.1/".to_string());
+        let namespace = format!("redis_store_test.{}", ::std::process::id());
+        let factory = RedisStoreFactory::new(&url, &namespace).unwrap();
+        let mut a = factory.open(1).unwrap();
+        let mut b = factory.open(1).unwrap();
+
+        let tag = Tag([7u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(a.insert(&tag).unwrap(), false);
+        assert_eq!(b.insert(&tag).unwrap(), true);
+        assert_eq!(b.contains(&tag).unwrap(), true);
+        assert_eq!(b.tags().count(), 1);
+        assert_eq!(factory.open(2).unwrap().contains(&tag).unwrap(), false);
+
+        assert_eq!(a.init_metadata("private_key", b"a").unwrap(), b"a".to_vec());
+        assert_eq!(b.init_metadata("private_key", b"b").unwrap(), b"a".to_vec());
+        assert_eq!(b.metadata("private_key").unwrap(), Some(b"a".to_vec()));
+
+        factory.remove(1).unwrap();
+        assert_eq!(factory.open(1).unwrap().tags().count(), 0);
+    }
+}
@@ -0,0 +1,324 @@
+// replaycore.rs - Replay detection without the standard library.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! The membership check at the heart of replay detection: a tag is a
+//! replay if the epoch's filter holds it and the store confirms it,
+//! and is recorded in both otherwise. This module needs only `core` and
+//! `alloc`, so that embedded relays and other targets without an
+//! operating system can detect replays too. Built with
+//! `default-features = false`, it is all the crate has.
+//!
+//! Everything a `MixKeys` gets from the operating system is injected
+//! instead: the tags are kept by a `TagStore`, the current epoch is told
+//! by an `EpochSource`, and the key of each epoch's `KeyedFilter` is
+//! either passed in or drawn from a `RandomSource`. `MemoryTagStore`
+//! keeps the tags in memory.
+//!
+//! This is also what the crate offers on `wasm32-unknown-unknown`,
+//! where there is neither a file system nor a clock or random source
+//! but those the embedder hands in, so that mixnet simulators running
+//! in a browser or a wasm sandbox detect replays exactly as production
+//! nodes do.
+//!
+//! With the default `std` feature, `TagNamespace` checks its tags
+//! through `check_and_insert`.
+//!
+
+use core::convert::Infallible;
+use core::fmt;
+#[allow(deprecated)]
+use core::hash::{Hasher, SipHasher};
+use core::mem;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
+
+
+/// The size of a Sphinx replay tag.
+pub const TAG_SIZE: usize = 32;
+
+/// TagStore keeps the tags of one epoch.
+pub trait TagStore {
+    type Error;
+
+    fn contains(&mut self, tag: &[u8; TAG_SIZE]) -> Result<bool, Self::Error>;
+
+    /// Store the tag, returning true if it was already stored.
+    fn insert(&mut self, tag: &[u8; TAG_SIZE]) -> Result<bool, Self::Error>;
+}
+
+/// Membership is the filter in front of a `TagStore`. It may answer
+/// that it holds a tag it was never given, but never the reverse.
+pub trait Membership {
+    fn contains(&self, tag: &[u8; TAG_SIZE]) -> bool;
+
+    fn insert(&mut self, tag: &[u8; TAG_SIZE]);
+}
+
+/// EpochSource tells the current epoch.
+pub trait EpochSource {
+    fn epoch(&self) -> u64;
+}
+
+impl<F: Fn() -> u64> EpochSource for F {
+    fn epoch(&self) -> u64 {
+        self()
+    }
+}
+
+/// RandomSource fills buffers with random bytes, such as those of
+/// `crypto.getRandomValues` in a browser.
+pub trait RandomSource {
+    fn fill_bytes(&mut self, dest: &mut [u8]);
+}
+
+/// Returns true if the tag was seen before, and records it in the
+/// filter and the store otherwise. The store is only asked whether it
+/// holds the tag when the filter claims to.
+pub fn check_and_insert<F: Membership + ?Sized, S: TagStore + ?Sized>(filter: &mut F, store: &mut S, tag: &[u8; TAG_SIZE]) -> Result<bool, S::Error> {
+    if filter.contains(tag) && store.contains(tag)? {
+        return Ok(true)
+    }
+    let present = store.insert(tag)?;
+    filter.insert(tag);
+    Ok(present)
+}
+
+/// KeyedFilter is an exact filter of keyed 64 bit tag hashes. The key
+/// keeps an attacker from choosing tags that collide.
+pub struct KeyedFilter {
+    key: (u64, u64),
+    hashes: BTreeSet<u64>,
+}
+
+impl KeyedFilter {
+    pub fn new(key: [u8; 16]) -> KeyedFilter {
+        let mut k0 = [0u8; 8];
+        let mut k1 = [0u8; 8];
+        k0.copy_from_slice(&key[..8]);
+        k1.copy_from_slice(&key[8..]);
+        KeyedFilter{
+            key: (u64::from_le_bytes(k0), u64::from_le_bytes(k1)),
+            hashes: BTreeSet::new(),
+        }
+    }
+
+    #[allow(deprecated)]
+    fn hash(&self, tag: &[u8; TAG_SIZE]) -> u64 {
+        let mut hasher = SipHasher::new_with_keys(self.key.0, self.key.1);
+        hasher.write(tag);
+        hasher.finish()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+impl Membership for KeyedFilter {
+    fn contains(&self, tag: &[u8; TAG_SIZE]) -> bool {
+        self.hashes.contains(&self.hash(tag))
+    }
+
+    fn insert(&mut self, tag: &[u8; TAG_SIZE]) {
+        let hash = self.hash(tag);
+        self.hashes.insert(hash);
+    }
+}
+
+/// MemoryTagStore keeps the tags in memory, and never fails.
+#[derive(Default)]
+pub struct MemoryTagStore {
+    tags: BTreeSet<[u8; TAG_SIZE]>,
+}
+
+impl MemoryTagStore {
+    pub fn new() -> MemoryTagStore {
+        MemoryTagStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+}
+
+impl TagStore for MemoryTagStore {
+    type Error = Infallible;
+
+    fn contains(&mut self, tag: &[u8; TAG_SIZE]) -> Result<bool, Infallible> {
+        Ok(self.tags.contains(tag))
+    }
+
+    fn insert(&mut self, tag: &[u8; TAG_SIZE]) -> Result<bool, Infallible> {
+        Ok(!self.tags.insert(*tag))
+    }
+}
+
+/// CoreError is the error of a `ReplayCore`.
+#[derive(Debug, PartialEq)]
+pub enum CoreError<E> {
+    /// No cache was opened for the epoch, or it was pruned.
+    UnknownEpoch(u64),
+    /// The tag is not `TAG_SIZE` bytes long.
+    InvalidTag,
+    /// The epoch's store failed.
+    Store(E),
+}
+
+impl<E: fmt::Display> fmt::Display for CoreError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CoreError::UnknownEpoch(epoch) => write!(f, "no replay cache for epoch {}", epoch),
+            CoreError::InvalidTag => write!(f, "invalid replay tag"),
+            CoreError::Store(ref e) => write!(f, "replay cache store failure: {}", e),
+        }
+    }
+}
+
+/// ReplayCore checks tags against the caches of several epochs, as a
+/// `MixKeys` does, without keys, files or threads.
+pub struct ReplayCore<S, C> {
+    clock: C,
+    retain: u64,
+    caches: BTreeMap<u64, (KeyedFilter, S)>,
+}
+
+impl<S: TagStore, C: EpochSource> ReplayCore<S, C> {
+    /// Returns a core that keeps the caches of the current epoch and the
+    /// `retain` epochs before it when pruned.
+    pub fn new(clock: C, retain: u64) -> ReplayCore<S, C> {
+        ReplayCore{
+            clock: clock,
+            retain: retain,
+            caches: BTreeMap::new(),
+        }
+    }
+
+    /// Open the epoch's cache over the store, with a filter keyed by
+    /// `filter_key`. Tags already in the store are only found once the
+    /// filter claims them, so the store should start out empty.
+    pub fn open(&mut self, epoch: u64, store: S, filter_key: [u8; 16]) {
+        self.caches.insert(epoch, (KeyedFilter::new(filter_key), store));
+    }
+
+    /// Like `open`, with a filter key drawn from `rng`.
+    pub fn open_random<R: RandomSource + ?Sized>(&mut self, epoch: u64, store: S, rng: &mut R) {
+        let mut filter_key = [0u8; 16];
+        rng.fill_bytes(&mut filter_key);
+        self.open(epoch, store, filter_key)
+    }
+
+    /// Returns true if the tag was seen before in the epoch, and records
+    /// it otherwise.
+    pub fn is_replay(&mut self, epoch: u64, tag: &[u8; TAG_SIZE]) -> Result<bool, CoreError<S::Error>> {
+        let &mut (ref mut filter, ref mut store) = self.caches.get_mut(&epoch).ok_or(CoreError::UnknownEpoch(epoch))?;
+        check_and_insert(filter, store, tag).map_err(CoreError::Store)
+    }
+
+    /// Like `is_replay`, for a tag handed over as a slice, such as a
+    /// JavaScript `Uint8Array`.
+    pub fn is_replay_slice(&mut self, epoch: u64, tag: &[u8]) -> Result<bool, CoreError<S::Error>> {
+        if tag.len() != TAG_SIZE {
+            return Err(CoreError::InvalidTag)
+        }
+        let mut raw = [0u8; TAG_SIZE];
+        raw.copy_from_slice(tag);
+        self.is_replay(epoch, &raw)
+    }
+
+    /// Returns the epochs with an open cache, in order.
+    pub fn epochs(&self) -> Vec<u64> {
+        self.caches.keys().cloned().collect()
+    }
+
+    /// Drop the caches of the epochs before those retained, returning
+    /// their stores.
+    pub fn prune(&mut self) -> Vec<(u64, S)> {
+        let oldest = self.clock.epoch().saturating_sub(self.retain);
+        let kept = self.caches.split_off(&oldest);
+        let pruned = mem::replace(&mut self.caches, kept);
+        pruned.into_iter().map(|(epoch, (_, store))| (epoch, store)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use core::cell::Cell;
+
+    use super::*;
+
+
+    struct TestClock<'a>(&'a Cell<u64>);
+
+    impl<'a> EpochSource for TestClock<'a> {
+        fn epoch(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn replay_core_test() {
+        let now = Cell::new(10);
+        let mut core = ReplayCore::new(TestClock(&now), 1);
+        core.open(9, MemoryTagStore::new(), [1u8; 16]);
+        core.open(10, MemoryTagStore::new(), [2u8; 16]);
+        let tag = [7u8; TAG_SIZE];
+        assert_eq!(core.is_replay(10, &tag), Ok(false));
+        assert_eq!(core.is_replay(10, &tag), Ok(true));
+        assert_eq!(core.is_replay(9, &tag), Ok(false));
+        assert_eq!(core.is_replay(11, &tag), Err(CoreError::UnknownEpoch(11)));
+
+        now.set(11);
+        let pruned = core.prune();
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].0, 9);
+        assert_eq!(pruned[0].1.len(), 1);
+        assert_eq!(core.epochs(), [10]);
+        assert_eq!(core.is_replay(9, &tag), Err(CoreError::UnknownEpoch(9)));
+    }
+
+    struct CountingRandom(u8);
+
+    impl RandomSource for CountingRandom {
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for b in dest.iter_mut() {
+                self.0 = self.0.wrapping_add(1);
+                *b = self.0;
+            }
+        }
+    }
+
+    #[test]
+    fn injected_sources_test() {
+        let mut rng = CountingRandom(0);
+        let mut core = ReplayCore::new(|| 3u64, 0);
+        core.open_random(3, MemoryTagStore::new(), &mut rng);
+        assert_eq!(rng.0, 16);
+        assert_eq!(core.is_replay_slice(3, &[5u8; TAG_SIZE]), Ok(false));
+        assert_eq!(core.is_replay_slice(3, &[5u8; TAG_SIZE]), Ok(true));
+        assert_eq!(core.is_replay_slice(3, &[5u8; 16]), Err(CoreError::InvalidTag));
+    }
+}
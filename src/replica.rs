@@ -0,0 +1,216 @@
+// replica.rs - Per-worker bloom filter read replicas.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Every replay check against a shared `MixKey` reads the same bloom
+//! filter bit array, and on NUMA machines its cache lines bounce between
+//! sockets at very high packet rates. A `FilterReplica` gives a worker
+//! thread its own copy of the filter to read from. Fresh tags are still
+//! funneled to the owning `MixKey`, which remains the only writer of the
+//! cache, and which records every tag it inserts in a bounded delta log
+//! that the replicas periodically catch up from.
+//!
+//! A replica only ever lags behind its owner, so a tag not found in
+//! the replica is passed on to the owner to decide. A tag found in it
+//! may still be a false positive of the filter, so it is only taken for
+//! a replay once the owner's store confirms it.
+//!
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use errors::MixKeyError;
+use constants::{MIX_KEY_REPLICA_DELTA_CAPACITY, MIX_KEY_REPLICA_SYNC_FREQUENCY};
+use super::{MixKey, Tag, TagFilter};
+
+
+/// DeltaLog holds the most recent tags inserted by a `MixKey`. It stays
+/// empty until the first replica of the key is made.
+pub(crate) struct DeltaLog {
+    enabled: bool,
+    base: u64,
+    tags: VecDeque<Tag>,
+}
+
+impl DeltaLog {
+    pub(crate) fn new() -> DeltaLog {
+        DeltaLog{
+            enabled: false,
+            base: 0,
+            tags: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+    pub(crate) fn push(&mut self, tag: &Tag) {
+        if !self.enabled {
+            return
+        }
+        if self.tags.len() == MIX_KEY_REPLICA_DELTA_CAPACITY {
+            self.tags.pop_front();
+            self.base += 1;
+        }
+        self.tags.push_back(tag.clone());
+    }
+
+    /// Returns the position following the most recent tag.
+    pub(crate) fn end(&self) -> u64 {
+        self.base + self.tags.len() as u64
+    }
+
+    /// Returns the tags recorded from position `seq` onwards, or None if
+    /// some of them have already been dropped from the log.
+    pub(crate) fn since(&self, seq: u64) -> Option<::std::collections::vec_deque::Iter<'_, Tag>> {
+        if seq < self.base {
+            return None
+        }
+        Some(self.tags.range((seq - self.base) as usize..))
+    }
+}
+
+/// FilterReplica is a worker local, read mostly copy of a `MixKey`'s
+/// bloom filter.
+pub struct FilterReplica {
+    owner: MixKey,
+    filter: TagFilter,
+    seq: u64,
+    last_sync: Duration,
+}
+
+impl FilterReplica {
+    pub(crate) fn new(owner: MixKey) -> Result<FilterReplica, MixKeyError> {
+        let (filter, seq) = owner.snapshot_filter()?;
+        let last_sync = owner.timer.now();
+        Ok(FilterReplica{
+            owner: owner,
+            filter: filter,
+            seq: seq,
+            last_sync: last_sync,
+        })
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.owner.epoch
+    }
+
+    /// Catch up with the tags inserted by the owner since the last sync.
+    /// If the replica fell so far behind that the owner's delta log no
+    /// longer holds them, the filter is rebuilt from the cache instead.
+    pub fn sync(&mut self) -> Result<(), MixKeyError> {
+        let caught_up = {
+            let deltas = self.owner.deltas.lock().unwrap();
+            match deltas.since(self.seq) {
+                Some(tags) => {
+                    for tag in tags {
+                        self.filter.insert(tag);
+                    }
+                    self.seq = deltas.end();
+                    true
+                },
+                None => false,
+            }
+        };
+        if !caught_up {
+            let (filter, seq) = self.owner.snapshot_filter()?;
+            self.filter = filter;
+            self.seq = seq;
+        }
+        self.last_sync = self.owner.timer.now();
+        Ok(())
+    }
+
+    /// Check the tag against the local filter, syncing first if
+    /// `MIX_KEY_REPLICA_SYNC_FREQUENCY` milliseconds have passed since
+    /// the last sync. Tags found in the local filter are looked up in
+    /// the owner's store, and tags missing from either are checked and
+    /// inserted by the owner.
+    pub fn is_replay(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.owner.timer.now() - self.last_sync >= Duration::from_millis(MIX_KEY_REPLICA_SYNC_FREQUENCY) {
+            self.sync()?;
+        }
+        if self.filter.contains(tag) && self.owner.contains(tag)? {
+            return Ok(true)
+        }
+        let replay = self.owner.is_replay(tag)?;
+        self.filter.insert(tag);
+        Ok(replay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+    extern crate rand;
+
+    use std::sync::Arc;
+
+    use self::rand::Rng;
+    use self::rand::os::OsRng;
+    use self::tempfile::TempDir;
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+    use timesource::ManualMonotonicClock;
+    use super::*;
+
+
+    fn random_tag(rng: &mut OsRng) -> Tag {
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        rng.fill_bytes(&mut raw);
+        Tag::new(raw)
+    }
+
+    #[test]
+    fn filter_replica_test() {
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mut owner = MixKey::new(1024 * 1024, 1, 60, &base_dir).unwrap();
+        let timer = Arc::new(ManualMonotonicClock::new());
+        owner.set_monotonic_clock(timer.clone());
+
+        let mut rng = OsRng::new().unwrap();
+        let old_tag = random_tag(&mut rng);
+        assert_eq!(owner.is_replay(&old_tag).unwrap(), false);
+
+        let mut first = owner.replica().unwrap();
+        let mut second = owner.replica().unwrap();
+        assert_eq!(first.epoch(), 1);
+        assert_eq!(first.is_replay(&old_tag).unwrap(), true);
+
+        let tag = random_tag(&mut rng);
+        assert_eq!(first.is_replay(&tag).unwrap(), false);
+        assert!(!second.filter.contains(&tag));
+        timer.advance(Duration::from_millis(MIX_KEY_REPLICA_SYNC_FREQUENCY));
+        assert_eq!(second.is_replay(&tag).unwrap(), true);
+        assert!(second.filter.contains(&tag));
+
+        for _ in 0..MIX_KEY_REPLICA_DELTA_CAPACITY {
+            owner.deltas.lock().unwrap().push(&random_tag(&mut rng));
+        }
+        let tag = random_tag(&mut rng);
+        assert_eq!(owner.is_replay(&tag).unwrap(), false);
+        first.sync().unwrap();
+        assert!(first.filter.contains(&tag));
+
+        // A tag the local filter holds but the owner never saw is fresh.
+        let tag = random_tag(&mut rng);
+        first.filter.insert(&tag);
+        assert_eq!(first.is_replay(&tag).unwrap(), false);
+        assert_eq!(owner.contains(&tag).unwrap(), true);
+        assert_eq!(first.is_replay(&tag).unwrap(), true);
+    }
+}
@@ -0,0 +1,504 @@
+// replication.rs - Replay tag replication between redundant mixes.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! An active mix and its standby hold the same keys but see different
+//! packets, so a standby taking over would accept every packet the
+//! active mix saw as fresh. A `ReplicationSession` keeps the tags of
+//! two `MixKeys` in step over TCP: each side pushes the tags it stores
+//! to the other, batched every `MIX_KEY_REPLICATION_BATCH_DELAY`
+//! milliseconds, and stores the tags the other pushes. This module is
+//! only available with the `replication` feature.
+//!
+//! The tags to push are read from each key's delta log, the one its
+//! `FilterReplica`s catch up from. A session starts with an anti-entropy
+//! sync of every epoch both sides hold: each side sends the
+//! `ChunkDigest`s of a sorted dump of its tags, and the other sends back
+//! the tags of every chunk whose digest it does not find among them.
+//! Only the chunks where the two sides diverge are sent, so reconnecting
+//! after an outage costs little more than the tags missed during it. A
+//! side whose delta log dropped tags before they were pushed asks the
+//! other for a sync of that epoch instead.
+//!
+//! Both sides must be given the same secret. The handshake is:
+//!
+//!    magic (8) || version (1) || nonce (32)
+//!
+//! sent by each side, after which every frame is:
+//!
+//!    length (4, BE) || op (1) || epoch (8, BE) || payload || mac (32)
+//!
+//! The MAC is a keyed BLAKE2b of the frame's sequence number and its
+//! op, epoch and payload, under a key derived from the secret and both
+//! nonces, one for each direction. A forged, reordered or replayed
+//! frame ends the session. Frames are not encrypted; a link that must
+//! also hide which tags were seen should run over a VPN or TLS tunnel.
+//!
+//! A tag received from the peer is fresh to the key storing it, so it
+//! is pushed back once, and then found stored by the side it came from.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+use blake2::blake2b_keyed;
+use hkdf::Hkdf;
+use rand::Rng;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use constants::{MIX_KEY_REPLICATION_BATCH_DELAY, MIX_KEY_REPLICATION_BATCH_TAGS};
+use dump::{self, ChunkDigest};
+use entropy;
+use errors::MixKeyError;
+use super::{MixKey, MixKeys};
+
+
+const HELLO_MAGIC: &[u8; 8] = b"SRCREPL\0";
+const PROTOCOL_VERSION: u8 = 0;
+const NONCE_SIZE: usize = 32;
+const HELLO_SIZE: usize = 8 + 1 + NONCE_SIZE;
+const KDF_INFO: &str = "sphinx-replay-cache-replication-v0";
+const MIN_SECRET_SIZE: usize = 32;
+const KEY_SIZE: usize = 32;
+const MAC_SIZE: usize = 32;
+const DIGEST_SIZE: usize = 32;
+const HEADER_SIZE: usize = 1 + 8;
+const MAX_FRAME_SIZE: usize = 1 << 24;
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// The sender holds the secret too.
+const OP_CONFIRM: u8 = 0;
+/// The payload is tags for the epoch's key to store.
+const OP_TAGS: u8 = 1;
+/// The payload is the chunk digests of the sender's tags of the epoch.
+const OP_SYNC: u8 = 2;
+/// The sender lost track of the epoch's tags and asks for a sync.
+const OP_RESYNC: u8 = 3;
+
+
+fn direction_key(secret: &[u8], from: &[u8], to: &[u8]) -> [u8; KEY_SIZE] {
+    let mut info = KDF_INFO.as_bytes().to_vec();
+    info.extend_from_slice(from);
+    info.extend_from_slice(to);
+    let mut key = [0u8; KEY_SIZE];
+    let hk = Hkdf::<Sha256>::extract(None, secret);
+    hk.expand(&info, &mut key).unwrap();
+    key
+}
+
+fn frame_mac(key: &[u8], seq: u64, body: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; 8];
+    BigEndian::write_u64(&mut data, seq);
+    data.extend_from_slice(body);
+    blake2b_keyed(MAC_SIZE, key, &data)
+}
+
+/// FrameWriter authenticates the frames sent in one direction.
+struct FrameWriter {
+    stream: TcpStream,
+    key: [u8; KEY_SIZE],
+    seq: u64,
+}
+
+impl FrameWriter {
+    fn send(&mut self, op: u8, epoch: u64, payload: &[u8]) -> Result<(), MixKeyError> {
+        let mut frame = vec![0u8; 4 + HEADER_SIZE];
+        BigEndian::write_u32(&mut frame[..4], (HEADER_SIZE + payload.len()) as u32);
+        frame[4] = op;
+        BigEndian::write_u64(&mut frame[5..], epoch);
+        frame.extend_from_slice(payload);
+        let mac = frame_mac(&self.key, self.seq, &frame[4..]);
+        frame.extend_from_slice(&mac);
+        self.stream.write_all(&frame)?;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+/// FrameReader checks the frames received in one direction.
+struct FrameReader {
+    stream: TcpStream,
+    key: [u8; KEY_SIZE],
+    seq: u64,
+}
+
+impl FrameReader {
+    /// Read a frame's op, epoch and payload, returning None if the peer
+    /// closed the connection between frames.
+    fn recv(&mut self) -> Result<Option<(u8, u64, Vec<u8>)>, MixKeyError> {
+        let mut length = [0u8; 4];
+        match self.stream.read_exact(&mut length) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let length = BigEndian::read_u32(&length) as usize;
+        if length < HEADER_SIZE || length > MAX_FRAME_SIZE {
+            return Err(MixKeyError::InvalidReplicationFrame)
+        }
+        let mut body = vec![0u8; length + MAC_SIZE];
+        self.stream.read_exact(&mut body)?;
+        let mac = body.split_off(length);
+        if frame_mac(&self.key, self.seq, &body).as_slice().ct_eq(&mac).unwrap_u8() != 1 {
+            return Err(MixKeyError::ReplicationAuthFailed)
+        }
+        self.seq += 1;
+        let epoch = BigEndian::read_u64(&body[1..HEADER_SIZE]);
+        Ok(Some((body[0], epoch, body.split_off(HEADER_SIZE))))
+    }
+}
+
+/// Exchange nonces with the peer and check that it holds the secret.
+fn handshake(mut stream: TcpStream, secret: &[u8]) -> Result<(FrameReader, FrameWriter), MixKeyError> {
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)))?;
+    let mut hello = [0u8; HELLO_SIZE];
+    hello[..8].copy_from_slice(HELLO_MAGIC);
+    hello[8] = PROTOCOL_VERSION;
+    entropy::os_rng()?.fill_bytes(&mut hello[9..]);
+    stream.write_all(&hello)?;
+    let mut peer = [0u8; HELLO_SIZE];
+    stream.read_exact(&mut peer)?;
+    if &peer[..8] != HELLO_MAGIC || peer[8] != PROTOCOL_VERSION {
+        return Err(MixKeyError::InvalidReplicationFrame)
+    }
+    // A peer echoing our nonce would have our own frames accepted as
+    // its frames.
+    if peer[9..] == hello[9..] {
+        return Err(MixKeyError::ReplicationAuthFailed)
+    }
+    let mut writer = FrameWriter{
+        stream: stream.try_clone()?,
+        key: direction_key(secret, &hello[9..], &peer[9..]),
+        seq: 0,
+    };
+    let mut reader = FrameReader{
+        key: direction_key(secret, &peer[9..], &hello[9..]),
+        stream: stream,
+        seq: 0,
+    };
+    writer.send(OP_CONFIRM, 0, &[])?;
+    match reader.recv()? {
+        Some((OP_CONFIRM, _, _)) => {},
+        _ => return Err(MixKeyError::ReplicationAuthFailed),
+    }
+    reader.stream.set_read_timeout(None)?;
+    Ok((reader, writer))
+}
+
+/// Replicator starts replication sessions between a `MixKeys` and its
+/// peers.
+#[derive(Clone)]
+pub struct Replicator {
+    mix_keys: MixKeys,
+    secret: Arc<Vec<u8>>,
+}
+
+impl Replicator {
+    /// Create a replicator for the keys, authenticating peers with the
+    /// shared secret, which must be at least 32 bytes long.
+    pub fn new(mix_keys: MixKeys, secret: &[u8]) -> Result<Replicator, MixKeyError> {
+        if secret.len() < MIN_SECRET_SIZE {
+            return Err(MixKeyError::InvalidConfig(format!("the replication secret must be at least {} bytes", MIN_SECRET_SIZE)))
+        }
+        Ok(Replicator{
+            mix_keys: mix_keys,
+            secret: Arc::new(secret.to_vec()),
+        })
+    }
+
+    /// Connect to a peer and start replicating with it.
+    pub fn connect<A: ToSocketAddrs>(&self, addr: A) -> Result<ReplicationSession, MixKeyError> {
+        self.start(TcpStream::connect(addr)?)
+    }
+
+    /// Accept a peer on the listener and start replicating with it.
+    pub fn accept(&self, listener: &TcpListener) -> Result<ReplicationSession, MixKeyError> {
+        let (stream, _) = listener.accept()?;
+        self.start(stream)
+    }
+
+    fn start(&self, stream: TcpStream) -> Result<ReplicationSession, MixKeyError> {
+        stream.set_nodelay(true)?;
+        let (reader, writer) = handshake(stream.try_clone()?, &self.secret)?;
+        let writer = Arc::new(Mutex::new(writer));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let receiving = {
+            let (writer, mix_keys, stopped, stream) = (writer.clone(), self.mix_keys.clone(), stopped.clone(), stream.try_clone()?);
+            thread::spawn(move || finish(receive(reader, &writer, &mix_keys), &stopped, &stream))
+        };
+        let pushing = {
+            let (mix_keys, stopped, stream) = (self.mix_keys.clone(), stopped.clone(), stream.try_clone()?);
+            thread::spawn(move || finish(push(&writer, &mix_keys, &stopped), &stopped, &stream))
+        };
+        Ok(ReplicationSession{
+            stream: stream,
+            stopped: stopped,
+            threads: vec![receiving, pushing],
+        })
+    }
+}
+
+/// ReplicationSession replicates tags with one peer until it is stopped
+/// or the connection fails.
+pub struct ReplicationSession {
+    stream: TcpStream,
+    stopped: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<Result<(), MixKeyError>>>,
+}
+
+impl ReplicationSession {
+    /// Returns true once the session has ended, after which the peer
+    /// should be connected to again.
+    pub fn is_finished(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// End the session, returning the error that ended it first, if it
+    /// failed before it was stopped.
+    pub fn stop(mut self) -> Result<(), MixKeyError> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<(), MixKeyError> {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        let mut result = Ok(());
+        for handle in self.threads.drain(..) {
+            if let Ok(Err(e)) = handle.join() {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Drop for ReplicationSession {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            warn!("replication session failed: {}", e);
+        }
+    }
+}
+
+/// End the session once either of its threads returns. An error is
+/// only reported if the session was not already stopped.
+fn finish(result: Result<(), MixKeyError>, stopped: &AtomicBool, stream: &TcpStream) -> Result<(), MixKeyError> {
+    let was_stopped = stopped.swap(true, Ordering::SeqCst);
+    let _ = stream.shutdown(Shutdown::Both);
+    match result {
+        Err(_) if was_stopped => Ok(()),
+        result => result,
+    }
+}
+
+fn receive(mut reader: FrameReader, writer: &Mutex<FrameWriter>, mix_keys: &MixKeys) -> Result<(), MixKeyError> {
+    while let Some((op, epoch, payload)) = reader.recv()? {
+        let size = match op {
+            OP_TAGS => SPHINX_REPLAY_TAG_SIZE,
+            OP_SYNC => DIGEST_SIZE,
+            OP_RESYNC => 1,
+            _ => return Err(MixKeyError::InvalidReplicationFrame),
+        };
+        if payload.len() % size != 0 {
+            return Err(MixKeyError::InvalidReplicationFrame)
+        }
+        let key = match mix_keys.key(epoch) {
+            Some(key) => key,
+            None => {
+                info!("ignoring replicated frame of epoch {} without a key", epoch);
+                continue
+            },
+        };
+        match op {
+            OP_TAGS => {
+                key.insert_tags(payload.chunks(SPHINX_REPLAY_TAG_SIZE).map(|raw| {
+                    let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+                    tag.copy_from_slice(raw);
+                    Ok(tag)
+                }))?;
+            },
+            OP_SYNC => {
+                let theirs: HashSet<&[u8]> = payload.chunks(DIGEST_SIZE).collect();
+                send_missing(&key, &theirs, writer)?;
+            },
+            _ => send_sync(&key, writer)?,
+        }
+    }
+    Ok(())
+}
+
+/// What the pusher sends for an epoch.
+enum Push {
+    Sync,
+    Resync,
+    Tags(Vec<[u8; SPHINX_REPLAY_TAG_SIZE]>),
+}
+
+fn push(writer: &Mutex<FrameWriter>, mix_keys: &MixKeys, stopped: &AtomicBool) -> Result<(), MixKeyError> {
+    let mut cursors: HashMap<u64, u64> = HashMap::new();
+    while !stopped.load(Ordering::SeqCst) {
+        let keys = mix_keys.snapshot_keys();
+        cursors.retain(|epoch, _| keys.iter().any(|&(live, _)| live == *epoch));
+        for (epoch, key) in keys {
+            let next = {
+                let mut deltas = key.deltas.lock().unwrap();
+                let next = match cursors.get(&epoch) {
+                    None => {
+                        deltas.enable();
+                        Push::Sync
+                    },
+                    Some(&seq) => match deltas.since(seq) {
+                        Some(tags) => Push::Tags(tags.map(|tag| tag.0).collect()),
+                        None => Push::Resync,
+                    },
+                };
+                cursors.insert(epoch, deltas.end());
+                next
+            };
+            match next {
+                Push::Sync => send_sync(&key, writer)?,
+                Push::Resync => writer.lock().unwrap().send(OP_RESYNC, epoch, &[])?,
+                Push::Tags(tags) => send_tags(epoch, &tags, writer)?,
+            }
+        }
+        thread::sleep(Duration::from_millis(MIX_KEY_REPLICATION_BATCH_DELAY));
+    }
+    Ok(())
+}
+
+/// Send the chunk digests of the key's tags, for the peer to answer
+/// with the tags of the chunks it does not hold.
+fn send_sync(key: &MixKey, writer: &Mutex<FrameWriter>) -> Result<(), MixKeyError> {
+    let mut tags = key.stored_tags()?;
+    let mut payload = vec![];
+    for range in dump::sorted_chunks(&mut tags) {
+        payload.extend_from_slice(&ChunkDigest::new(&tags[range]).digest);
+    }
+    writer.lock().unwrap().send(OP_SYNC, key.epoch(), &payload)
+}
+
+/// Send the key's tags of every chunk whose digest the peer lacks.
+fn send_missing(key: &MixKey, theirs: &HashSet<&[u8]>, writer: &Mutex<FrameWriter>) -> Result<(), MixKeyError> {
+    let mut tags = key.stored_tags()?;
+    let mut missing = vec![];
+    for range in dump::sorted_chunks(&mut tags) {
+        if !theirs.contains(&ChunkDigest::new(&tags[range.clone()]).digest[..]) {
+            missing.extend_from_slice(&tags[range]);
+        }
+    }
+    send_tags(key.epoch(), &missing, writer)
+}
+
+fn send_tags(epoch: u64, tags: &[[u8; SPHINX_REPLAY_TAG_SIZE]], writer: &Mutex<FrameWriter>) -> Result<(), MixKeyError> {
+    for batch in tags.chunks(MIX_KEY_REPLICATION_BATCH_TAGS) {
+        let payload: Vec<u8> = batch.iter().flat_map(|tag| tag.iter().cloned()).collect();
+        writer.lock().unwrap().send(OP_TAGS, epoch, &payload)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use epoch::Clock;
+    use builder::MixKeysBuilder;
+    use store::CacheBackend;
+    use super::super::Tag;
+    use super::*;
+
+
+    fn wait_for(key: &MixKey, tags: &[Tag]) -> bool {
+        for _ in 0..1000 {
+            if tags.iter().all(|tag| key.contains(tag).unwrap()) {
+                return true
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn tag(i: u16) -> Tag {
+        let mut raw = [7u8; SPHINX_REPLAY_TAG_SIZE];
+        BigEndian::write_u16(&mut raw, i);
+        Tag(raw)
+    }
+
+    #[test]
+    fn replication_test() {
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let active = MixKeysBuilder::new(clock).backend(CacheBackend::Memory).num_mix_keys(2).build().unwrap();
+        let standby = MixKeysBuilder::new(Clock::new_katzenpost()).backend(CacheBackend::Memory).num_mix_keys(2).build().unwrap();
+        let secret = [5u8; 32];
+        assert!(Replicator::new(active.clone(), &secret[..16]).is_err());
+
+        // Tags seen before the session are caught up by the sync.
+        let before: Vec<Tag> = (0..5000).map(tag).collect();
+        for tag in &before {
+            assert_eq!(active.key(epoch).unwrap().is_replay(tag).unwrap(), false);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepting = Replicator::new(standby.clone(), &secret).unwrap();
+        let handle = thread::spawn(move || accepting.accept(&listener));
+        let session = Replicator::new(active.clone(), &secret).unwrap().connect(addr).unwrap();
+        let peer = handle.join().unwrap().unwrap();
+        assert!(wait_for(&standby.key(epoch).unwrap(), &before));
+
+        // Tags seen during the session are pushed, in both directions.
+        let during: Vec<Tag> = (5000..5100).map(tag).collect();
+        for tag in &during {
+            assert_eq!(standby.key(epoch).unwrap().is_replay(tag).unwrap(), false);
+        }
+        assert!(wait_for(&active.key(epoch).unwrap(), &during));
+        assert!(!session.is_finished());
+        session.stop().unwrap();
+        assert!(wait_for_finish(&peer));
+        drop(peer);
+
+        // A peer with another secret is refused.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepting = Replicator::new(standby, &[6u8; 32]).unwrap();
+        let handle = thread::spawn(move || accepting.accept(&listener));
+        match Replicator::new(active, &secret).unwrap().connect(addr) {
+            Err(MixKeyError::ReplicationAuthFailed) => {},
+            _ => panic!("a peer with another secret was accepted"),
+        }
+        assert!(handle.join().unwrap().is_err());
+    }
+
+    fn wait_for_finish(session: &ReplicationSession) -> bool {
+        for _ in 0..1000 {
+            if session.is_finished() {
+                return true
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+}
@@ -0,0 +1,169 @@
+// rollover.rs - Epoch rollover journal.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! At each epoch boundary `MixKeys::rollover` generates the keys that
+//! follow the new epoch, activates the new epoch's key and schedules the
+//! previous key for pruning once the grace period is over. Every stage
+//! is recorded in a journal in the base directory before the next one
+//! starts, so that a mix which crashes part way through resumes the
+//! rollover when it restarts instead of running half rotated.
+//!
+//! The journal is a single small record, replaced atomically:
+//!
+//!    magic (4) || epoch (8, LE) || stage (1)
+//!
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use errors::MixKeyError;
+use fsutil;
+
+
+const JOURNAL_FILE_NAME: &str = "rollover";
+const JOURNAL_TMP_FILE_NAME: &str = "rollover.tmp";
+const JOURNAL_MAGIC: &[u8; 4] = b"RLVR";
+const JOURNAL_SIZE: usize = 13;
+
+
+/// RolloverStage is the last completed stage of a rollover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RolloverStage {
+    /// The rollover to the epoch was started.
+    Started,
+    /// The keys from the epoch onwards were generated and flushed.
+    Generated,
+    /// The epoch's key was made the current key.
+    Activated,
+    /// The previous epoch's key was scheduled for pruning.
+    PruneScheduled,
+    /// The previous epoch's key was pruned.
+    Complete,
+}
+
+impl RolloverStage {
+    fn from_u8(stage: u8) -> Option<RolloverStage> {
+        match stage {
+            0 => Some(RolloverStage::Started),
+            1 => Some(RolloverStage::Generated),
+            2 => Some(RolloverStage::Activated),
+            3 => Some(RolloverStage::PruneScheduled),
+            4 => Some(RolloverStage::Complete),
+            _ => None,
+        }
+    }
+}
+
+/// RolloverRecord is the state of the latest rollover.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RolloverRecord {
+    pub epoch: u64,
+    pub stage: RolloverStage,
+}
+
+/// RolloverJournal persists the latest `RolloverRecord` in a base
+/// directory.
+#[derive(Clone, Debug)]
+pub(crate) struct RolloverJournal {
+    path: PathBuf,
+    tmp_path: PathBuf,
+}
+
+impl RolloverJournal {
+    pub fn new(base_dir: &Path) -> RolloverJournal {
+        RolloverJournal{
+            path: base_dir.join(JOURNAL_FILE_NAME),
+            tmp_path: base_dir.join(JOURNAL_TMP_FILE_NAME),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the latest record, or None if no rollover was journaled.
+    pub fn load(&self) -> Result<Option<RolloverRecord>, MixKeyError> {
+        let mut raw = vec![];
+        match File::open(&self.path) {
+            Ok(mut file) => file.read_to_end(&mut raw)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(MixKeyError::IoError(e)),
+        };
+        if raw.len() != JOURNAL_SIZE || &raw[..4] != JOURNAL_MAGIC {
+            return Err(MixKeyError::InvalidJournal)
+        }
+        match RolloverStage::from_u8(raw[12]) {
+            Some(stage) => Ok(Some(RolloverRecord{
+                epoch: LittleEndian::read_u64(&raw[4..12]),
+                stage: stage,
+            })),
+            None => Err(MixKeyError::InvalidJournal),
+        }
+    }
+
+    /// Durably replace the journaled record.
+    pub fn store(&self, record: &RolloverRecord) -> Result<(), MixKeyError> {
+        let mut raw = [0u8; JOURNAL_SIZE];
+        raw[..4].copy_from_slice(JOURNAL_MAGIC);
+        LittleEndian::write_u64(&mut raw[4..12], record.epoch);
+        raw[12] = record.stage as u8;
+        let mut file = File::create(&self.tmp_path)?;
+        file.write_all(&raw)?;
+        fsutil::sync_file(&file)?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        if let Some(parent) = self.path.parent() {
+            fsutil::sync_dir(parent)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn rollover_journal_test() {
+        let base_dir = TempDir::new().unwrap();
+        let journal = RolloverJournal::new(base_dir.path());
+        assert_eq!(journal.load().unwrap(), None);
+
+        for &stage in [RolloverStage::Started, RolloverStage::Activated, RolloverStage::Complete].iter() {
+            let record = RolloverRecord{
+                epoch: 42,
+                stage: stage,
+            };
+            journal.store(&record).unwrap();
+            assert_eq!(journal.load().unwrap(), Some(record));
+        }
+        assert!(!base_dir.path().join(JOURNAL_TMP_FILE_NAME).exists());
+
+        fs::write(journal.path(), b"RLVR").unwrap();
+        match journal.load() {
+            Err(MixKeyError::InvalidJournal) => {},
+            x => panic!("unexpected load result: {:?}", x),
+        }
+    }
+}
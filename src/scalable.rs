@@ -0,0 +1,298 @@
+// scalable.rs - Scalable bloom filter.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Each epoch's bloom filter is sized for the provisioned line rate. If
+//! traffic exceeds it, a fixed size filter saturates, its false positive
+//! rate climbs towards one, and nearly every replay check falls through
+//! to the cache on disk.
+//!
+//! A `ScalableBloomFilter` is a stack of bloom filters. Tags are
+//! inserted into the newest layer, and once its estimated fill ratio
+//! passes `FILL_THRESHOLD` a new layer twice as large, with half the
+//! false positive rate, is added on top. A tag is found if any layer
+//! holds it. The false positive rates of the layers form a geometric
+//! series, so the rate of the whole stack stays within a small multiple
+//! of the first layer's however many layers are added.
+//!
+//! The fill ratio of a layer with `m` bits and `k` hashes holding `n`
+//! tags is estimated as `1 - e^(-kn/m)`, which is one half when an
+//! optimally sized layer holds the tags it was sized for.
+//!
+//! A counting filter keeps a small counter instead of each bit, using
+//! `COUNTER_BITS` times the memory, so that tags can be removed again.
+//! Every insert increments the counters, even of a tag that looks
+//! present, so that removing it decrements exactly what inserting it
+//! incremented. For the same reason a counting filter never adds
+//! layers: a tag that an older layer mistakenly holds could not be told
+//! apart from one inserted there. Removing a tag that was never
+//! inserted would decrement counters of other tags, so callers must
+//! only remove tags they know are present.
+//!
+
+use std::collections::hash_map::RandomState;
+use std::hash::Hash;
+
+use bloom::{ASMS, BloomFilter, CountingBloomFilter};
+use bloom::{needed_bits, optimal_num_hashes};
+
+
+/// Add a layer once the newest one is estimated to be half full.
+pub const FILL_THRESHOLD: f64 = 0.5;
+
+/// Each layer expects twice the tags of the one below it.
+const GROWTH_FACTOR: u32 = 2;
+
+/// Each layer has half the false positive rate of the one below it.
+const TIGHTENING_RATIO: f32 = 0.5;
+
+/// Counting filters count up to 15 per counter. Removals from a
+/// saturated counter can leave it below its true count and cause false
+/// negatives, which `MixKey` tolerates by checking its store.
+pub const COUNTER_BITS: usize = 4;
+
+
+enum Bits {
+    Plain(BloomFilter<RandomState, RandomState>),
+    Counting(CountingBloomFilter<RandomState, RandomState>),
+}
+
+struct Layer {
+    bits: Bits,
+    num_bits: usize,
+    num_hashes: u32,
+    false_positive_rate: f32,
+    expected_num_items: u32,
+    items: u64,
+}
+
+impl Layer {
+    fn new(false_positive_rate: f32, expected_num_items: u32, counting: bool) -> Layer {
+        let expected_num_items = expected_num_items.max(1);
+        let num_bits = needed_bits(false_positive_rate, expected_num_items);
+        let num_hashes = optimal_num_hashes(num_bits, expected_num_items);
+        let bits = if counting {
+            Bits::Counting(CountingBloomFilter::with_size(num_bits, COUNTER_BITS, num_hashes))
+        } else {
+            Bits::Plain(BloomFilter::with_size(num_bits, num_hashes))
+        };
+        Layer{
+            bits: bits,
+            num_bits: num_bits,
+            num_hashes: num_hashes,
+            false_positive_rate: false_positive_rate,
+            expected_num_items: expected_num_items,
+            items: 0,
+        }
+    }
+
+    fn insert<T: Hash>(&mut self, item: &T) {
+        match self.bits {
+            Bits::Plain(ref mut filter) => { filter.insert(item); },
+            Bits::Counting(ref mut filter) => { filter.insert(item); },
+        }
+        self.items += 1;
+    }
+
+    fn contains<T: Hash>(&self, item: &T) -> bool {
+        match self.bits {
+            Bits::Plain(ref filter) => filter.contains(item),
+            Bits::Counting(ref filter) => filter.contains(item),
+        }
+    }
+
+    fn memory_bits(&self) -> usize {
+        match self.bits {
+            Bits::Plain(_) => self.num_bits,
+            Bits::Counting(_) => self.num_bits * COUNTER_BITS,
+        }
+    }
+
+    /// Write every word of the layer, so that its pages are mapped.
+    /// This empties the layer, so it is only done to layers holding no
+    /// items.
+    fn prefault(&mut self) {
+        match self.bits {
+            Bits::Plain(ref mut filter) => filter.clear(),
+            Bits::Counting(ref mut filter) => filter.clear(),
+        }
+    }
+
+    fn fill_ratio(&self) -> f64 {
+        let exponent = -(self.num_hashes as f64) * self.items as f64 / self.num_bits as f64;
+        1.0 - exponent.exp()
+    }
+}
+
+/// ScalableBloomFilter is a bloom filter that adds layers as it fills.
+pub struct ScalableBloomFilter {
+    layers: Vec<Layer>,
+    counting: bool,
+}
+
+impl ScalableBloomFilter {
+    /// Returns a filter whose first layer has the given false positive
+    /// rate at the given number of tags.
+    pub fn with_rate(false_positive_rate: f32, expected_num_items: u32) -> ScalableBloomFilter {
+        ScalableBloomFilter::new(false_positive_rate, expected_num_items, false)
+    }
+
+    /// Returns a counting filter, which supports `remove`.
+    pub fn counting(false_positive_rate: f32, expected_num_items: u32) -> ScalableBloomFilter {
+        ScalableBloomFilter::new(false_positive_rate, expected_num_items, true)
+    }
+
+    pub(crate) fn new(false_positive_rate: f32, expected_num_items: u32, counting: bool) -> ScalableBloomFilter {
+        ScalableBloomFilter{
+            layers: vec![Layer::new(false_positive_rate, expected_num_items, counting)],
+            counting: counting,
+        }
+    }
+
+    /// Returns true if this is a counting filter.
+    pub fn is_counting(&self) -> bool {
+        self.counting
+    }
+
+    /// Insert the item, returning true if it was not already present.
+    pub fn insert<T: Hash>(&mut self, item: &T) -> bool {
+        let present = self.contains(item);
+        if self.counting {
+            self.layers[0].insert(item);
+            return !present
+        }
+        if present {
+            return false
+        }
+        let saturated = {
+            let layer = self.layers.last_mut().unwrap();
+            layer.insert(item);
+            layer.fill_ratio() > FILL_THRESHOLD
+        };
+        if saturated {
+            self.grow();
+        }
+        true
+    }
+
+    /// Check if the item has been inserted. This can return false
+    /// positives, but not false negatives.
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.layers.iter().rev().any(|layer| layer.contains(item))
+    }
+
+    /// Remove an item known to have been inserted, returning false if
+    /// the filter does not hold it or is not a counting filter.
+    pub fn remove<T: Hash>(&mut self, item: &T) -> bool {
+        let layer = &mut self.layers[0];
+        if let Bits::Counting(ref mut filter) = layer.bits {
+            if filter.remove(item) > 0 {
+                layer.items = layer.items.saturating_sub(1);
+                return true
+            }
+        }
+        false
+    }
+
+    /// Fault in the memory of the layers that hold no items yet, which
+    /// the allocator hands out as untouched pages, so that the first
+    /// inserts do not each take a page fault. Layers holding items were
+    /// loaded into, and are left as they are.
+    pub fn prefault(&mut self) {
+        for layer in self.layers.iter_mut().filter(|layer| layer.items == 0) {
+            layer.prefault();
+        }
+    }
+
+    /// Returns the number of layers.
+    pub fn layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Returns the estimated fill ratio of the newest layer.
+    pub fn fill_ratio(&self) -> f64 {
+        self.layers.last().unwrap().fill_ratio()
+    }
+
+    /// Returns the estimated probability that an item never inserted
+    /// is found in some layer.
+    pub fn false_positive_rate(&self) -> f64 {
+        1.0 - self.layers.iter().map(|layer| 1.0 - layer.fill_ratio().powi(layer.num_hashes as i32)).product::<f64>()
+    }
+
+    /// Returns the memory used by the layers, in bits.
+    pub fn num_bits(&self) -> usize {
+        self.layers.iter().map(|layer| layer.memory_bits()).sum()
+    }
+
+    fn grow(&mut self) {
+        let (false_positive_rate, expected_num_items) = {
+            let top = self.layers.last().unwrap();
+            (top.false_positive_rate * TIGHTENING_RATIO, top.expected_num_items.saturating_mul(GROWTH_FACTOR))
+        };
+        warn!("bloom filter saturated after {} tags; adding a layer for {} more",
+              self.layers.iter().map(|layer| layer.items).sum::<u64>(), expected_num_items);
+        self.layers.push(Layer::new(false_positive_rate, expected_num_items, self.counting));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+
+    #[test]
+    fn scalable_bloom_filter_test() {
+        let mut filter = ScalableBloomFilter::with_rate(0.01, 100);
+        for i in 0..80u32 {
+            filter.insert(&i);
+        }
+        assert_eq!(filter.layers(), 1);
+        assert!(!filter.insert(&7u32));
+
+        for i in 80..10000u32 {
+            filter.insert(&i);
+        }
+        assert!(filter.layers() > 1);
+        assert!(filter.fill_ratio() <= FILL_THRESHOLD);
+        filter.prefault();
+        for i in 0..10000u32 {
+            assert!(filter.contains(&i));
+        }
+        let false_positives = (10000..30000u32).filter(|i| filter.contains(i)).count();
+        assert!(false_positives < 1000, "{} false positives", false_positives);
+        assert!(!filter.remove(&7u32));
+    }
+
+    #[test]
+    fn counting_bloom_filter_test() {
+        let mut filter = ScalableBloomFilter::counting(0.01, 100);
+        let plain = ScalableBloomFilter::with_rate(0.01, 100);
+        assert_eq!(filter.num_bits(), plain.num_bits() * COUNTER_BITS);
+        for i in 0..200u32 {
+            filter.insert(&i);
+        }
+        assert_eq!(filter.layers(), 1);
+        assert!(filter.remove(&7u32));
+        assert!(filter.remove(&150u32));
+        for i in (0..200u32).filter(|i| *i != 7 && *i != 150) {
+            assert!(filter.contains(&i));
+        }
+        filter.insert(&7u32);
+        assert!(filter.contains(&7u32));
+    }
+}
@@ -0,0 +1,186 @@
+// scheduler.rs - Background mix key rotation.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! The scheduler owns a `MixKeys` and rotates it from a background
+//! thread: the keys for the upcoming epochs are generated shortly
+//! before each epoch boundary, the keys roll over to the new epoch at
+//! the boundary, and stale keys are pruned once the grace period
+//! following the boundary has passed.
+//!
+
+use std::cmp::min;
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use errors::MixKeyError;
+use constants::MIX_KEY_GENERATE_AHEAD;
+use super::MixKeys;
+
+
+/// Events emitted by the scheduler thread.
+#[derive(Debug)]
+pub enum RotationEvent {
+    /// Keys were generated starting at the given epoch.
+    Generated(u64),
+    /// The keys rolled over to the given epoch.
+    RolledOver(u64),
+    /// Stale keys were pruned during the given epoch.
+    Pruned(u64),
+    /// A rotation step failed.
+    Error(MixKeyError),
+}
+
+pub struct MixKeyScheduler {
+    mix_keys: MixKeys,
+    halt: Option<Sender<()>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MixKeyScheduler {
+    /// Start rotating the given keys, generating `MIX_KEY_GENERATE_AHEAD`
+    /// seconds before each epoch boundary and pruning once the keys'
+    /// grace period after it has passed.
+    pub fn new(mix_keys: MixKeys) -> (MixKeyScheduler, Receiver<RotationEvent>) {
+        let grace_period = mix_keys.grace_period();
+        MixKeyScheduler::with_timing(mix_keys, MIX_KEY_GENERATE_AHEAD, grace_period)
+    }
+
+    /// Start rotating the given keys with the given generation lead time
+    /// and grace period, both in seconds.
+    pub fn with_timing(mix_keys: MixKeys, generate_ahead: u64, grace_period: u64) -> (MixKeyScheduler, Receiver<RotationEvent>) {
+        let (event_tx, event_rx) = channel();
+        let (halt_tx, halt_rx) = channel();
+        let worker_keys = mix_keys.clone();
+        let worker = thread::spawn(move || {
+            rotate(worker_keys, generate_ahead, grace_period, halt_rx, event_tx);
+        });
+        let scheduler = MixKeyScheduler{
+            mix_keys: mix_keys,
+            halt: Some(halt_tx),
+            worker: Some(worker),
+        };
+        (scheduler, event_rx)
+    }
+
+    pub fn mix_keys(&self) -> &MixKeys {
+        &self.mix_keys
+    }
+
+    /// Stop the scheduler thread and wait for it to exit.
+    pub fn halt(&mut self) {
+        if let Some(halt) = self.halt.take() {
+            let _ = halt.send(());
+        }
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for MixKeyScheduler {
+    fn drop(&mut self) {
+        self.halt();
+    }
+}
+
+fn rotate(mut mix_keys: MixKeys, generate_ahead: u64, grace_period: u64, halt: Receiver<()>, events: Sender<RotationEvent>) {
+    let mut generated_for = None;
+    let mut rolled_over_for = None;
+    let mut pruned_for = None;
+    loop {
+        let now = mix_keys.clock.now();
+        let next_epoch = now.epoch + 1;
+        if rolled_over_for != Some(now.epoch) {
+            let event = match mix_keys.rollover(now.epoch) {
+                Ok(_) => RotationEvent::RolledOver(now.epoch),
+                Err(e) => RotationEvent::Error(e),
+            };
+            rolled_over_for = Some(now.epoch);
+            if events.send(event).is_err() {
+                return
+            }
+        }
+        if generated_for != Some(next_epoch) && now.till <= generate_ahead {
+            let event = match mix_keys.generate(next_epoch) {
+                Ok(_) => RotationEvent::Generated(next_epoch),
+                Err(e) => RotationEvent::Error(e),
+            };
+            generated_for = Some(next_epoch);
+            if events.send(event).is_err() {
+                return
+            }
+        }
+        if pruned_for != Some(now.epoch) && now.elapsed >= grace_period {
+            mix_keys.prune();
+            pruned_for = Some(now.epoch);
+            if events.send(RotationEvent::Pruned(now.epoch)).is_err() {
+                return
+            }
+        }
+
+        let mut wait = now.till;
+        if generated_for != Some(next_epoch) {
+            wait = min(wait, now.till.saturating_sub(generate_ahead));
+        }
+        if pruned_for != Some(now.epoch) {
+            wait = min(wait, grace_period.saturating_sub(now.elapsed));
+        }
+        match halt.recv_timeout(Duration::from_secs(wait)) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use epoch::{Clock, Config};
+    use super::*;
+
+
+    #[test]
+    fn scheduler_rotation_test() {
+        let clock = Clock::new(Config{
+            epoch: 0,
+            period: 2,
+        });
+        let base_dir = TempDir::new().unwrap().path().to_str().unwrap().to_string();
+        let mix_keys = MixKeys::new(clock.clone(), 2, base_dir, 1024 * 1024).unwrap();
+        let (mut scheduler, events) = MixKeyScheduler::with_timing(mix_keys, 1, 1);
+
+        let mut generated = false;
+        let mut rolled_over = false;
+        let mut pruned = false;
+        while !(generated && rolled_over && pruned) {
+            match events.recv_timeout(Duration::from_secs(10)).unwrap() {
+                RotationEvent::Generated(_) => generated = true,
+                RotationEvent::RolledOver(epoch) => {
+                    assert_eq!(scheduler.mix_keys().active_epoch(), Some(epoch));
+                    rolled_over = true;
+                },
+                RotationEvent::Pruned(_) => pruned = true,
+                RotationEvent::Error(e) => panic!("rotation failed: {}", e),
+            }
+        }
+        scheduler.halt();
+    }
+}
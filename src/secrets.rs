@@ -0,0 +1,350 @@
+// secrets.rs - Pluggable custody of private key material.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A `SecretsBackend` holds the key encryption keys protecting the mix
+//! keys at rest, and may optionally store the mix keys themselves. The
+//! `EnvelopeKeyProvider` uses it for envelope encryption: each epoch's
+//! private key is encrypted under a fresh data key, and only the data
+//! key wrapped by the backend is stored next to it in the cache.
+//!
+//! `FileSecretsBackend` keeps its key encryption key in a local file.
+//! Backends for Vault, a cloud KMS or other secrets managers implement
+//! the same trait by forwarding `wrap_key` and `unwrap_key` to the
+//! manager's encrypt and decrypt operations.
+//!
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, BigEndian};
+use clear_on_drop::ClearOnDrop;
+use clear_on_drop::clear::Clear;
+use rand::Rng;
+use sha2::Sha256;
+use hkdf::Hkdf;
+use chacha::ChaCha as ChaCha20;
+use keystream::KeyStream;
+use blake2::blake2b_keyed;
+use subtle::ConstantTimeEq;
+
+use ecdh_wrapper::PrivateKey;
+
+use entropy;
+use errors::MixKeyError;
+use fsutil;
+use keyprovider::{EpochKey, KeyProvider};
+
+
+const SEAL_KDF_INFO: &str = "sphinx-replay-cache-secrets-v0";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const MAC_KEY_SIZE: usize = 32;
+const MAC_SIZE: usize = 16;
+const ENVELOPE_KEY_ID: u8 = b'E';
+const STORED_KEY_ID: u8 = b'S';
+
+/// The number of bytes `seal` adds to the plaintext.
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + MAC_SIZE;
+
+
+/// SecretsBackend is the custodian of the key encryption keys. This is
+/// an extension point for KMS and vault services.
+pub trait SecretsBackend: Send + Sync {
+    /// Encrypt a data key, bound to the given context.
+    fn wrap_key(&self, context: &[u8], data_key: &[u8]) -> Result<Vec<u8>, MixKeyError>;
+
+    /// Decrypt a data key wrapped with the same context.
+    fn unwrap_key(&self, context: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, MixKeyError>;
+
+    /// Store a secret under the given name, returning false if the
+    /// backend does not store secrets.
+    fn store_secret(&self, _name: &str, _secret: &[u8]) -> Result<bool, MixKeyError> {
+        Ok(false)
+    }
+
+    /// Load a secret stored under the given name.
+    fn load_secret(&self, _name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        Ok(None)
+    }
+}
+
+fn seal_keys(key: &[u8]) -> ([u8; KEY_SIZE], [u8; MAC_KEY_SIZE]) {
+    let mut output = [0u8; KEY_SIZE + MAC_KEY_SIZE];
+    let hk = Hkdf::<Sha256>::extract(None, key);
+    hk.expand(SEAL_KDF_INFO.as_bytes(), &mut output).unwrap();
+    let mut stream_key = [0u8; KEY_SIZE];
+    let mut mac_key = [0u8; MAC_KEY_SIZE];
+    stream_key.copy_from_slice(&output[..KEY_SIZE]);
+    mac_key.copy_from_slice(&output[KEY_SIZE..]);
+    (stream_key, mac_key)
+}
+
+fn seal_mac(mac_key: &[u8], context: &[u8], nonce_and_ciphertext: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; 4];
+    BigEndian::write_u32(&mut data, context.len() as u32);
+    data.extend_from_slice(context);
+    data.extend_from_slice(nonce_and_ciphertext);
+    blake2b_keyed(MAC_SIZE, mac_key, &data)
+}
+
+/// Encrypt and authenticate `plaintext` under `key`, binding it to the
+/// given context. The output is the nonce, ciphertext and MAC.
+pub fn seal(key: &[u8], context: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+    let (stream_key, mac_key) = seal_keys(key);
+    let mut rng = entropy::os_rng()?;
+    let mut nonce = [0u8; NONCE_SIZE];
+    rng.fill_bytes(&mut nonce);
+    let mut out = nonce.to_vec();
+    let mut ciphertext = plaintext.to_vec();
+    ChaCha20::new_ietf(&stream_key, &nonce).xor_read(&mut ciphertext).unwrap();
+    out.extend(ciphertext);
+    let mac = seal_mac(&mac_key, context, &out);
+    out.extend(mac);
+    Ok(out)
+}
+
+/// Authenticate and decrypt the output of `seal`.
+pub fn open(key: &[u8], context: &[u8], sealed: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+    if sealed.len() < NONCE_SIZE + MAC_SIZE {
+        return Err(MixKeyError::SecretsError("sealed secret is truncated".to_string()))
+    }
+    let (stream_key, mac_key) = seal_keys(key);
+    let (body, mac) = sealed.split_at(sealed.len() - MAC_SIZE);
+    if seal_mac(&mac_key, context, body).as_slice().ct_eq(mac).unwrap_u8() != 1 {
+        return Err(MixKeyError::SecretsError("sealed secret failed authentication".to_string()))
+    }
+    let (nonce, ciphertext) = body.split_at(NONCE_SIZE);
+    let mut plaintext = ciphertext.to_vec();
+    let mut iv = [0u8; NONCE_SIZE];
+    iv.copy_from_slice(nonce);
+    ChaCha20::new_ietf(&stream_key, &iv).xor_read(&mut plaintext).unwrap();
+    Ok(plaintext)
+}
+
+/// FileSecretsBackend keeps its key encryption key in a local file and
+/// optionally stores secrets as sealed files in a directory.
+pub struct FileSecretsBackend {
+    key: [u8; KEY_SIZE],
+    secret_dir: Option<PathBuf>,
+}
+
+impl FileSecretsBackend {
+    /// Load the key encryption key from `key_file`, generating it if
+    /// the file does not exist yet.
+    pub fn load_or_create(key_file: &Path) -> Result<FileSecretsBackend, MixKeyError> {
+        let mut key = [0u8; KEY_SIZE];
+        if key_file.exists() {
+            let mut raw = vec![];
+            File::open(key_file)?.read_to_end(&mut raw)?;
+            if raw.len() != KEY_SIZE {
+                return Err(MixKeyError::SecretsError("key encryption key file has the wrong size".to_string()))
+            }
+            key.copy_from_slice(&raw);
+        } else {
+            let mut rng = entropy::os_rng()?;
+            rng.fill_bytes(&mut key);
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut file = options.open(key_file)?;
+            file.write_all(&key)?;
+            fsutil::sync_file(&file)?;
+        }
+        Ok(FileSecretsBackend{
+            key: key,
+            secret_dir: None,
+        })
+    }
+
+    /// Store secrets as sealed files in the given directory.
+    pub fn with_secret_dir(mut self, secret_dir: PathBuf) -> Result<FileSecretsBackend, MixKeyError> {
+        fs::create_dir_all(&secret_dir)?;
+        self.secret_dir = Some(secret_dir);
+        Ok(self)
+    }
+}
+
+impl SecretsBackend for FileSecretsBackend {
+    fn wrap_key(&self, context: &[u8], data_key: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        seal(&self.key, context, data_key)
+    }
+
+    fn unwrap_key(&self, context: &[u8], wrapped: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        open(&self.key, context, wrapped)
+    }
+
+    fn store_secret(&self, name: &str, secret: &[u8]) -> Result<bool, MixKeyError> {
+        let secret_dir = match self.secret_dir {
+            Some(ref x) => x,
+            None => return Ok(false),
+        };
+        let mut file = OpenOptions::new().write(true).create_new(true).open(secret_dir.join(name))?;
+        file.write_all(&seal(&self.key, name.as_bytes(), secret)?)?;
+        fsutil::sync_file(&file)?;
+        fsutil::sync_dir(secret_dir)?;
+        Ok(true)
+    }
+
+    fn load_secret(&self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        let path = match self.secret_dir {
+            Some(ref x) => x.join(name),
+            None => return Ok(None),
+        };
+        if !path.exists() {
+            return Ok(None)
+        }
+        let mut sealed = vec![];
+        File::open(path)?.read_to_end(&mut sealed)?;
+        Ok(Some(open(&self.key, name.as_bytes(), &sealed)?))
+    }
+}
+
+/// EnvelopeKeyProvider generates keys in process and protects them at
+/// rest with a `SecretsBackend`. If the backend stores secrets the key
+/// is kept there and the cache only holds its name, otherwise the cache
+/// holds the key encrypted under a data key wrapped by the backend.
+pub struct EnvelopeKeyProvider {
+    backend: Arc<dyn SecretsBackend>,
+}
+
+impl EnvelopeKeyProvider {
+    pub fn new(backend: Arc<dyn SecretsBackend>) -> EnvelopeKeyProvider {
+        EnvelopeKeyProvider{
+            backend: backend,
+        }
+    }
+}
+
+fn secret_name(epoch: u64) -> String {
+    format!("mix_key.{}", epoch)
+}
+
+impl KeyProvider for EnvelopeKeyProvider {
+    fn generate(&self, epoch: u64) -> Result<Vec<u8>, MixKeyError> {
+        let mut rng = entropy::os_rng()?;
+        let private_key = PrivateKey::generate(&mut rng)?;
+        let raw_key = ClearOnDrop::new(private_key.to_vec());
+        let name = secret_name(epoch);
+        if self.backend.store_secret(&name, &raw_key)? {
+            let mut id = vec![STORED_KEY_ID];
+            id.extend_from_slice(name.as_bytes());
+            return Ok(id)
+        }
+
+        let mut data_key = [0u8; KEY_SIZE];
+        rng.fill_bytes(&mut data_key);
+        let wrapped = self.backend.wrap_key(name.as_bytes(), &data_key);
+        let sealed = seal(&data_key, name.as_bytes(), &raw_key);
+        data_key.clear();
+        let wrapped = wrapped?;
+        let mut id = vec![ENVELOPE_KEY_ID, 0, 0, 0, 0];
+        BigEndian::write_u32(&mut id[1..5], wrapped.len() as u32);
+        id.extend(wrapped);
+        id.extend(sealed?);
+        Ok(id)
+    }
+
+    fn open(&self, epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+        let name = secret_name(epoch);
+        let raw_key = ClearOnDrop::new(match id.first() {
+            Some(&STORED_KEY_ID) => {
+                if &id[1..] != name.as_bytes() {
+                    return Err(MixKeyError::SecretsError("stored key belongs to another epoch".to_string()))
+                }
+                match self.backend.load_secret(&name)? {
+                    Some(x) => x,
+                    None => return Err(MixKeyError::SecretsError(format!("secret {} is missing", name))),
+                }
+            },
+            Some(&ENVELOPE_KEY_ID) if id.len() >= 5 => {
+                let wrapped_len = BigEndian::read_u32(&id[1..5]) as usize;
+                if id.len() < 5 + wrapped_len {
+                    return Err(MixKeyError::SecretsError("envelope is truncated".to_string()))
+                }
+                let (wrapped, sealed) = id[5..].split_at(wrapped_len);
+                let data_key = ClearOnDrop::new(self.backend.unwrap_key(name.as_bytes(), wrapped)?);
+                open(&data_key, name.as_bytes(), sealed)?
+            },
+            _ => return Err(MixKeyError::SecretsError("unknown key identifier".to_string())),
+        });
+        Ok(Arc::new(PrivateKey::from_bytes(&raw_key)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::super::MixKey;
+    use super::*;
+
+
+    #[test]
+    fn seal_test() {
+        let sealed = seal(&[1u8; KEY_SIZE], b"context", b"secret").unwrap();
+        assert_eq!(open(&[1u8; KEY_SIZE], b"context", &sealed).unwrap(), b"secret".to_vec());
+        assert!(open(&[2u8; KEY_SIZE], b"context", &sealed).is_err());
+        assert!(open(&[1u8; KEY_SIZE], b"other", &sealed).is_err());
+    }
+
+    #[test]
+    fn envelope_key_provider_test() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().join("cache").to_str().unwrap().to_string();
+        let key_file = dir.path().join("kek");
+        let backend = Arc::new(FileSecretsBackend::load_or_create(&key_file).unwrap());
+        let provider = EnvelopeKeyProvider::new(backend);
+        let mix_key = MixKey::with_key_provider(&provider, 1024 * 1024, 1, 60, &base_dir).unwrap();
+        let private_key = mix_key.export_private_key().unwrap();
+        drop(mix_key);
+
+        let backend = Arc::new(FileSecretsBackend::load_or_create(&key_file).unwrap());
+        let provider = EnvelopeKeyProvider::new(backend);
+        let mix_key = MixKey::with_key_provider(&provider, 1024 * 1024, 1, 60, &base_dir).unwrap();
+        assert_eq!(mix_key.export_private_key().unwrap(), private_key);
+        drop(mix_key);
+
+        fs::remove_file(&key_file).unwrap();
+        let backend = Arc::new(FileSecretsBackend::load_or_create(&key_file).unwrap());
+        let provider = EnvelopeKeyProvider::new(backend);
+        assert!(MixKey::with_key_provider(&provider, 1024 * 1024, 1, 60, &base_dir).is_err());
+    }
+
+    #[test]
+    fn stored_key_provider_test() {
+        let dir = TempDir::new().unwrap();
+        let base_dir = dir.path().join("cache").to_str().unwrap().to_string();
+        let backend = FileSecretsBackend::load_or_create(&dir.path().join("kek")).unwrap()
+            .with_secret_dir(dir.path().join("secrets")).unwrap();
+        let provider = EnvelopeKeyProvider::new(Arc::new(backend));
+        let mix_key = MixKey::with_key_provider(&provider, 1024 * 1024, 1, 60, &base_dir).unwrap();
+        assert!(dir.path().join("secrets").join("mix_key.1").exists());
+        let private_key = mix_key.export_private_key().unwrap();
+        drop(mix_key);
+
+        let mix_key = MixKey::with_key_provider(&provider, 1024 * 1024, 1, 60, &base_dir).unwrap();
+        assert_eq!(mix_key.export_private_key().unwrap(), private_key);
+    }
+}
@@ -0,0 +1,243 @@
+// server.rs - Replay oracle daemon.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Serves one `MixKeys` over a Unix domain socket, so that several
+//! worker processes on a mix share a single cache instead of each
+//! keeping its own cache directory. This module is only available on
+//! unix with the `server` feature.
+//!
+//! Every message is a frame of a 4 byte big endian length followed by
+//! that many bytes. A request is:
+//!
+//!    op (1) || epoch (8, BE) || tag (32)
+//!
+//! The only op is `OP_CHECK`, which checks and records the tag exactly
+//! like `MixKey::is_replay`. The response is a status byte, followed
+//! for `STATUS_ERROR` by a UTF-8 error message. A worker may send any
+//! number of requests on one connection.
+//!
+//! Requests are not authenticated: anyone who can connect can record
+//! tags, and so make the mix drop the packets that carry them. The
+//! socket is therefore made accessible to its owner alone as soon as it
+//! is bound; workers must run as the same user as the server.
+//!
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use super::{MixKeys, Tag};
+
+
+/// Check a tag, recording it if it is new.
+pub const OP_CHECK: u8 = 1;
+
+/// The tag was not seen before.
+pub const STATUS_FRESH: u8 = 0;
+/// The tag is a replay.
+pub const STATUS_REPLAY: u8 = 1;
+/// The check failed; an error message follows.
+pub const STATUS_ERROR: u8 = 2;
+
+const REQUEST_SIZE: usize = 1 + 8 + SPHINX_REPLAY_TAG_SIZE;
+const MAX_FRAME_SIZE: usize = 1024;
+
+
+/// Read a frame, returning None if the peer closed the connection
+/// between frames.
+fn read_frame(stream: &mut UnixStream) -> Result<Option<Vec<u8>>, MixKeyError> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let length = BigEndian::read_u32(&length) as usize;
+    if length > MAX_FRAME_SIZE {
+        return Err(MixKeyError::InvalidRequest)
+    }
+    let mut frame = vec![0u8; length];
+    stream.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+fn write_frame(stream: &mut UnixStream, frame: &[u8]) -> Result<(), MixKeyError> {
+    let mut length = [0u8; 4];
+    BigEndian::write_u32(&mut length, frame.len() as u32);
+    stream.write_all(&length)?;
+    stream.write_all(frame)?;
+    Ok(())
+}
+
+/// ReplayServer answers replay checks for the workers of a mix.
+pub struct ReplayServer {
+    listener: UnixListener,
+    path: PathBuf,
+    mix_keys: MixKeys,
+}
+
+impl ReplayServer {
+    /// Listen on the socket at `path`, which only its owner may connect
+    /// to. A socket left behind by a server that is no longer running is
+    /// replaced.
+    pub fn bind<P: AsRef<Path>>(path: P, mix_keys: MixKeys) -> Result<ReplayServer, MixKeyError> {
+        let path = path.as_ref();
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() || UnixStream::connect(path).is_ok() {
+                return Err(MixKeyError::IoError(ErrorKind::AddrInUse.into()))
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        Ok(ReplayServer{
+            listener: listener,
+            path: path.to_path_buf(),
+            mix_keys: mix_keys,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Accept connections until accepting fails, serving each one on a
+    /// thread of its own.
+    pub fn serve(&self) -> Result<(), MixKeyError> {
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let mix_keys = self.mix_keys.clone();
+            thread::spawn(move || {
+                if let Err(e) = serve_connection(stream, mix_keys) {
+                    warn!("replay oracle connection failed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ReplayServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve_connection(mut stream: UnixStream, mix_keys: MixKeys) -> Result<(), MixKeyError> {
+    while let Some(request) = read_frame(&mut stream)? {
+        if request.len() != REQUEST_SIZE || request[0] != OP_CHECK {
+            write_frame(&mut stream, &error_response("invalid request"))?;
+            return Err(MixKeyError::InvalidRequest)
+        }
+        let epoch = BigEndian::read_u64(&request[1..9]);
+        let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        tag.copy_from_slice(&request[9..]);
+        let response = match mix_keys.key_for_packet(epoch).and_then(|key| key.is_replay(&Tag(tag))) {
+            Ok(true) => vec![STATUS_REPLAY],
+            Ok(false) => vec![STATUS_FRESH],
+            Err(e) => error_response(&e.to_string()),
+        };
+        write_frame(&mut stream, &response)?;
+    }
+    Ok(())
+}
+
+fn error_response(message: &str) -> Vec<u8> {
+    let mut response = vec![STATUS_ERROR];
+    response.extend_from_slice(message.as_bytes());
+    response
+}
+
+/// ReplayClient is a worker's connection to a `ReplayServer`.
+pub struct ReplayClient {
+    stream: UnixStream,
+}
+
+impl ReplayClient {
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<ReplayClient, MixKeyError> {
+        Ok(ReplayClient{
+            stream: UnixStream::connect(path)?,
+        })
+    }
+
+    /// Returns true if the server has seen the tag for the given epoch
+    /// before, and records it otherwise.
+    pub fn is_replay(&mut self, epoch: u64, tag: &Tag) -> Result<bool, MixKeyError> {
+        let mut request = [0u8; REQUEST_SIZE];
+        request[0] = OP_CHECK;
+        BigEndian::write_u64(&mut request[1..9], epoch);
+        request[9..].copy_from_slice(&tag.0);
+        write_frame(&mut self.stream, &request)?;
+        let response = read_frame(&mut self.stream)?.ok_or(MixKeyError::InvalidRequest)?;
+        match response.first() {
+            Some(&STATUS_FRESH) => Ok(false),
+            Some(&STATUS_REPLAY) => Ok(true),
+            Some(&STATUS_ERROR) => Err(MixKeyError::StoreError(String::from_utf8_lossy(&response[1..]).into_owned())),
+            _ => Err(MixKeyError::InvalidRequest),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use std::sync::Arc;
+
+    use self::tempfile::TempDir;
+    use epoch::Clock;
+    use countdown::EarlyTagPolicy;
+    use store::CacheBackend;
+    use super::*;
+
+
+    #[test]
+    fn replay_server_test() {
+        let dir = TempDir::new().unwrap();
+        let clock = Clock::new_katzenpost();
+        let epoch = clock.now().epoch;
+        let mix_keys = MixKeys::builder(clock).num_mix_keys(2).backend(CacheBackend::Memory).early_tag_policy(EarlyTagPolicy::Reject).build().unwrap();
+        let server = Arc::new(ReplayServer::bind(dir.path().join("replay.sock"), mix_keys.clone()).unwrap());
+        let path = server.path().to_path_buf();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(ReplayServer::bind(&path, mix_keys).is_err());
+        let serving = server.clone();
+        thread::spawn(move || serving.serve());
+
+        let mut a = ReplayClient::connect(&path).unwrap();
+        let mut b = ReplayClient::connect(&path).unwrap();
+        let tag = Tag([3u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(a.is_replay(epoch, &tag).unwrap(), false);
+        assert_eq!(b.is_replay(epoch, &tag).unwrap(), true);
+        for early in &[epoch + 1, epoch + 100] {
+            match a.is_replay(*early, &tag) {
+                Err(MixKeyError::StoreError(_)) => {},
+                x => panic!("unexpected check result: {:?}", x),
+            }
+        }
+        assert_eq!(a.is_replay(epoch, &Tag([4u8; SPHINX_REPLAY_TAG_SIZE])).unwrap(), false);
+    }
+}
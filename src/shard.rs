@@ -0,0 +1,168 @@
+// shard.rs - Per-epoch replay state split by tag prefix.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A single filter and store behind one lock would let only one packet
+//! processing thread check tags of an epoch at a time. A `MixKey`
+//! instead splits its filter into `MIX_KEY_SHARDS` shards by the first
+//! byte of the tag, each behind its own lock, so that threads checking
+//! tags of different shards never wait for each other.
+//!
+//! Each shard also has its own handle onto the store, where the store
+//! supports it: sled trees are safe to use from several threads, and
+//! since their keys begin with the tag, the shards already partition the
+//! keyspace. Stores that can only be used through one handle are shared
+//! by every shard, and only their filters are split.
+//!
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use constants::MIX_KEY_SHARDS;
+use errors::MixKeyError;
+use store::ReplayStore;
+use super::{Tag, TagFilter};
+
+
+/// Returns the shard a tag belongs to.
+pub fn shard_of(tag: &Tag) -> usize {
+    tag.0[0] as usize % MIX_KEY_SHARDS
+}
+
+/// Shard holds the filter of the tags of one prefix and a handle onto
+/// the store holding them.
+pub(crate) struct Shard {
+    pub(crate) store: Arc<Mutex<Box<dyn ReplayStore>>>,
+    pub(crate) filter: Mutex<TagFilter>,
+}
+
+/// Shards holds every shard of an epoch's replay state.
+pub(crate) struct Shards {
+    shards: Vec<Shard>,
+}
+
+impl Shards {
+    /// Split the store's tags into shard filters, each sized for its
+    /// share of the expected tags, returning the shards along with the
+    /// number of tags.
+    pub(crate) fn open(mut store: Box<dyn ReplayStore>, false_positive_rate: f32, expected_num_items: u32, counting: bool) -> Result<(Shards, u64), MixKeyError> {
+        let expected_per_shard = expected_num_items / MIX_KEY_SHARDS as u32 + 1;
+        let mut filters: Vec<TagFilter> = (0..MIX_KEY_SHARDS)
+            .map(|_| TagFilter::new(false_positive_rate, expected_per_shard, counting))
+            .collect();
+        let mut tags = 0;
+        for raw in store.tags() {
+            let tag = Tag(raw?);
+            filters[shard_of(&tag)].insert(&tag);
+            tags += 1;
+        }
+        let handles: Vec<Option<Box<dyn ReplayStore>>> = (1..MIX_KEY_SHARDS).map(|_| store.handle()).collect();
+        let primary = Arc::new(Mutex::new(store));
+        let stores = Some(primary.clone()).into_iter().chain(handles.into_iter().map(|handle| {
+            match handle {
+                Some(handle) => Arc::new(Mutex::new(handle)),
+                None => primary.clone(),
+            }
+        }));
+        let shards = stores.zip(filters).map(|(store, filter)| Shard{
+            store: store,
+            filter: Mutex::new(filter),
+        }).collect();
+        Ok((Shards{ shards: shards }, tags))
+    }
+
+    /// Insert every tag of another store, such as the overflow store,
+    /// into the shard filters, returning the number of tags.
+    pub(crate) fn fill(&self, store: &mut dyn ReplayStore) -> Result<u64, MixKeyError> {
+        let mut tags = 0;
+        for raw in store.tags() {
+            let tag = Tag(raw?);
+            self.get(&tag).filter.lock().unwrap().insert(&tag);
+            tags += 1;
+        }
+        Ok(tags)
+    }
+
+    /// Returns the shard the tag belongs to.
+    pub(crate) fn get(&self, tag: &Tag) -> &Shard {
+        &self.shards[shard_of(tag)]
+    }
+
+    /// Returns the first shard's handle onto the store, for operations
+    /// on the whole store such as flushing and iterating over every tag.
+    pub(crate) fn store(&self) -> MutexGuard<'_, Box<dyn ReplayStore>> {
+        self.shards[0].store.lock().unwrap()
+    }
+
+    /// Fault in the memory of every shard filter.
+    pub(crate) fn prefault(&self) {
+        for shard in &self.shards {
+            shard.filter.lock().unwrap().prefault();
+        }
+    }
+
+    /// Returns the mean estimated fill ratio and false positive rate of
+    /// the shard filters. Tags spread evenly over the shards, so these
+    /// are also the filter's as a whole.
+    pub(crate) fn filter_estimates(&self) -> (f64, f64) {
+        let (fill, rate) = self.shards.iter().fold((0.0, 0.0), |(fill, rate), shard| {
+            let filter = shard.filter.lock().unwrap();
+            (fill + filter.fill_ratio(), rate + filter.false_positive_rate())
+        });
+        (fill / self.shards.len() as f64, rate / self.shards.len() as f64)
+    }
+
+    /// Returns the most layers any shard filter has grown to.
+    pub(crate) fn layers(&self) -> usize {
+        self.shards.iter().map(|shard| shard.filter.lock().unwrap().layers()).max().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use sled;
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use store::{MemoryStore, SledStore, open_tree};
+    use super::*;
+
+
+    #[test]
+    fn shards_test() {
+        let mut store = MemoryStore::default();
+        for i in 0..64u8 {
+            store.insert(&Tag([i; SPHINX_REPLAY_TAG_SIZE])).unwrap();
+        }
+        let (shards, tags) = Shards::open(Box::new(store), 0.01, 64, false).unwrap();
+        assert_eq!(tags, 64);
+        for i in 0..64u8 {
+            let tag = Tag([i; SPHINX_REPLAY_TAG_SIZE]);
+            assert!(shards.get(&tag).filter.lock().unwrap().contains(&tag));
+        }
+        assert!(Arc::ptr_eq(&shards.shards[0].store, &shards.shards[1].store));
+
+        let dir = TempDir::new().unwrap();
+        let tree = open_tree(&sled::Config::new().path(dir.path().join("cache.db"))).unwrap();
+        let (shards, _tags) = Shards::open(Box::new(SledStore::new(tree)), 0.01, 64, false).unwrap();
+        assert!(!Arc::ptr_eq(&shards.shards[0].store, &shards.shards[1].store));
+        let tag = Tag([1u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(shards.get(&tag).store.lock().unwrap().insert(&tag).unwrap(), false);
+        assert_eq!(shards.store().contains(&tag).unwrap(), true);
+    }
+}
@@ -0,0 +1,421 @@
+// sim.rs - Deterministic simulation of multi-epoch operation.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Drives `MixKey`s through randomized schedules of packets, flushes,
+//! epoch rotations and crashes, all derived from a single seed so that
+//! any failing schedule can be replayed exactly.
+//!
+//! Time comes from a `ManualMonotonicClock`. Crashes are injected with a
+//! `VirtualDisk`, which copies an epoch's cache aside every time it is
+//! flushed and, on a crash, throws away the live cache and restores the
+//! last flushed copy. Tags accepted since the last flush are therefore
+//! really lost, exactly as the `DurabilityPolicy` documents.
+//!
+//! The invariant checked is that no packet is ever accepted twice for
+//! the same epoch unless a crash lost the first acceptance.
+//!
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use fsutil;
+use keyprovider::SeedKeyProvider;
+use timesource::ManualMonotonicClock;
+use super::{MixKey, Tag};
+
+
+/// SimConfig describes the shape of a simulated schedule.
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Number of epochs to rotate through.
+    pub epochs: u64,
+    /// Packets sent during each epoch.
+    pub packets_per_epoch: u64,
+    /// Packets into an epoch during which the previous epoch's key is
+    /// still accepting traffic.
+    pub grace_packets: u64,
+    /// Percentage of packets that replay an earlier packet.
+    pub replay_percent: u32,
+    /// Percentage of grace period packets sent for the previous epoch.
+    pub late_percent: u32,
+    /// Chance, in thousandths, of a crash before each packet.
+    pub crash_per_mille: u32,
+    /// Upper bound on the virtual time between two packets.
+    pub max_packet_gap: Duration,
+    /// How often each key is flushed, in virtual time.
+    pub flush_interval: Duration,
+    pub line_rate: u64,
+    pub epoch_duration: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig{
+            epochs: 3,
+            packets_per_epoch: 200,
+            grace_packets: 20,
+            replay_percent: 30,
+            late_percent: 25,
+            crash_per_mille: 3,
+            max_packet_gap: Duration::from_millis(20),
+            flush_interval: Duration::from_secs(1),
+            line_rate: 1024 * 1024,
+            epoch_duration: 60,
+        }
+    }
+}
+
+/// Violation records a replayed packet that was accepted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub step: u64,
+    pub epoch: u64,
+    pub tag: [u8; SPHINX_REPLAY_TAG_SIZE],
+}
+
+/// SimReport summarizes a simulated schedule.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SimReport {
+    pub seed: u64,
+    pub packets: u64,
+    pub replays_sent: u64,
+    pub replays_rejected: u64,
+    pub flushes: u64,
+    pub crashes: u64,
+    /// Accepted tags forgotten by crashes.
+    pub lost_tags: u64,
+    /// Fresh tags wrongly reported as replays.
+    pub false_rejections: u64,
+    pub violations: Vec<Violation>,
+}
+
+/// VirtualDisk keeps a flushed copy of every epoch cache next to the
+/// live one, so that a crash can roll the live cache back to it.
+pub struct VirtualDisk {
+    live: PathBuf,
+    durable: PathBuf,
+}
+
+impl VirtualDisk {
+    pub fn new(dir: &Path) -> Result<VirtualDisk, MixKeyError> {
+        let disk = VirtualDisk{
+            live: dir.join("live"),
+            durable: dir.join("durable"),
+        };
+        fs::create_dir_all(&disk.live)?;
+        fs::create_dir_all(&disk.durable)?;
+        Ok(disk)
+    }
+
+    /// Returns the base directory holding the live caches.
+    pub fn base_dir(&self) -> &Path {
+        &self.live
+    }
+
+    /// Record the epoch's cache as it is now as having reached disk.
+    pub fn persist(&self, epoch: u64) -> Result<(), MixKeyError> {
+        let durable = fsutil::epoch_dir(&self.durable, epoch);
+        if durable.exists() {
+            fs::remove_dir_all(&durable)?;
+        }
+        copy_dir(&fsutil::epoch_dir(&self.live, epoch), &durable)?;
+        Ok(())
+    }
+
+    /// Lose everything written to the epoch's cache since it was last
+    /// persisted. The cache must not be open.
+    pub fn crash(&self, epoch: u64) -> Result<(), MixKeyError> {
+        let live = fsutil::epoch_dir(&self.live, epoch);
+        if live.exists() {
+            fs::remove_dir_all(&live)?;
+        }
+        let durable = fsutil::epoch_dir(&self.durable, epoch);
+        if durable.exists() {
+            copy_dir(&durable, &live)?;
+        }
+        Ok(())
+    }
+
+    /// Remove both copies of the epoch's cache.
+    pub fn remove(&self, epoch: u64) -> Result<(), MixKeyError> {
+        for dir in &[&self.live, &self.durable] {
+            let path = fsutil::epoch_dir(dir, epoch);
+            if path.exists() {
+                fs::remove_dir_all(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), MixKeyError> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// TrafficSource produces the packets of a schedule, remembering what
+/// it sent for each epoch so that it can replay it.
+pub struct TrafficSource {
+    rng: XorShiftRng,
+    sent: HashMap<u64, Vec<Tag>>,
+    replay_percent: u32,
+}
+
+impl TrafficSource {
+    pub fn new(seed: u64, replay_percent: u32) -> TrafficSource {
+        TrafficSource{
+            rng: seeded_rng(seed),
+            sent: HashMap::new(),
+            replay_percent: replay_percent,
+        }
+    }
+
+    /// Returns the next packet's tag for the epoch, and whether it is a
+    /// replay of an earlier packet.
+    pub fn next(&mut self, epoch: u64) -> (Tag, bool) {
+        let replay_roll = self.rng.gen_range(0, 100);
+        let sent = self.sent.entry(epoch).or_insert_with(Vec::new);
+        if !sent.is_empty() && replay_roll < self.replay_percent {
+            let i = self.rng.gen_range(0, sent.len());
+            return (sent[i].clone(), true)
+        }
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        self.rng.fill_bytes(&mut raw);
+        sent.push(Tag(raw));
+        (Tag(raw), false)
+    }
+
+    /// Forget the epoch's packets.
+    pub fn retire(&mut self, epoch: u64) {
+        self.sent.remove(&epoch);
+    }
+}
+
+pub(crate) fn seeded_rng(seed: u64) -> XorShiftRng {
+    XorShiftRng::from_seed([seed as u32, (seed >> 32) as u32, 0x9e37_79b9, 0x7f4a_7c15])
+}
+
+/// Simulation runs one schedule against real `MixKey`s.
+pub struct Simulation {
+    config: SimConfig,
+    rng: XorShiftRng,
+    clock: Arc<ManualMonotonicClock>,
+    disk: VirtualDisk,
+    provider: SeedKeyProvider,
+    traffic: TrafficSource,
+    keys: HashMap<u64, MixKey>,
+    // Tags each live key has accepted, and whether that has been
+    // persisted.
+    accepted: HashMap<u64, HashMap<Tag, bool>>,
+    report: SimReport,
+}
+
+impl Simulation {
+    /// Prepare a schedule whose caches live under `dir`, which should be
+    /// empty.
+    pub fn new(seed: u64, config: SimConfig, dir: &Path) -> Result<Simulation, MixKeyError> {
+        let mut rng = seeded_rng(seed);
+        let mut master_seed = [0u8; 32];
+        rng.fill_bytes(&mut master_seed);
+        let traffic_seed = rng.next_u64();
+        Ok(Simulation{
+            rng: rng,
+            clock: Arc::new(ManualMonotonicClock::new()),
+            disk: VirtualDisk::new(dir)?,
+            provider: SeedKeyProvider::new(&master_seed)?,
+            traffic: TrafficSource::new(traffic_seed, config.replay_percent),
+            keys: HashMap::new(),
+            accepted: HashMap::new(),
+            report: SimReport{
+                seed: seed,
+                ..SimReport::default()
+            },
+            config: config,
+        })
+    }
+
+    /// Run the schedule to completion.
+    pub fn run(mut self) -> Result<SimReport, MixKeyError> {
+        self.open_key(0)?;
+        self.open_key(1)?;
+        let mut step = 0;
+        for epoch in 0..self.config.epochs {
+            for i in 0..self.config.packets_per_epoch {
+                if i == self.config.grace_packets && epoch > 0 {
+                    self.close_key(epoch - 1)?;
+                }
+                if self.rng.gen_range(0, 1000) < self.config.crash_per_mille {
+                    self.crash()?;
+                }
+                let late = epoch > 0 && i < self.config.grace_packets && self.rng.gen_range(0, 100) < self.config.late_percent;
+                let target = if late { epoch - 1 } else { epoch };
+                self.send(step, target)?;
+                let gap = self.rng.gen_range(0, self.config.max_packet_gap.as_millis() as u64 + 1);
+                self.clock.advance(Duration::from_millis(gap));
+                self.flush_due()?;
+                step += 1;
+            }
+            self.open_key(epoch + 2)?;
+        }
+        Ok(self.report)
+    }
+
+    fn open_key(&mut self, epoch: u64) -> Result<(), MixKeyError> {
+        let base_dir = self.disk.base_dir().to_str().unwrap().to_string();
+        let mut key = MixKey::with_key_provider(&self.provider, self.config.line_rate, epoch, self.config.epoch_duration, &base_dir)?;
+        key.set_monotonic_clock(self.clock.clone());
+        if !self.accepted.contains_key(&epoch) {
+            // A freshly generated key is written out before it is used.
+            key.flush()?;
+            self.disk.persist(epoch)?;
+            self.accepted.insert(epoch, HashMap::new());
+        }
+        self.keys.insert(epoch, key);
+        Ok(())
+    }
+
+    fn close_key(&mut self, epoch: u64) -> Result<(), MixKeyError> {
+        self.keys.remove(&epoch);
+        self.accepted.remove(&epoch);
+        self.traffic.retire(epoch);
+        self.disk.remove(epoch)
+    }
+
+    fn send(&mut self, step: u64, epoch: u64) -> Result<(), MixKeyError> {
+        let (tag, replay) = self.traffic.next(epoch);
+        let rejected = self.keys.get(&epoch).unwrap().is_replay(&tag)?;
+        self.report.packets += 1;
+        if replay {
+            self.report.replays_sent += 1;
+            if rejected {
+                self.report.replays_rejected += 1;
+            }
+        } else if rejected {
+            self.report.false_rejections += 1;
+        }
+
+        let accepted = self.accepted.get_mut(&epoch).unwrap();
+        if rejected {
+            return Ok(())
+        }
+        if accepted.contains_key(&tag) {
+            self.report.violations.push(Violation{
+                step: step,
+                epoch: epoch,
+                tag: tag.0,
+            });
+        } else {
+            accepted.insert(tag, false);
+        }
+        Ok(())
+    }
+
+    fn flush_due(&mut self) -> Result<(), MixKeyError> {
+        let mut epochs: Vec<u64> = self.keys.keys().cloned().collect();
+        epochs.sort();
+        for epoch in epochs {
+            if self.keys.get_mut(&epoch).unwrap().flush_if_due(self.config.flush_interval)? {
+                self.disk.persist(epoch)?;
+                for durable in self.accepted.get_mut(&epoch).unwrap().values_mut() {
+                    *durable = true;
+                }
+                self.report.flushes += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn crash(&mut self) -> Result<(), MixKeyError> {
+        let mut epochs: Vec<u64> = self.keys.keys().cloned().collect();
+        epochs.sort();
+        self.keys.clear();
+        for epoch in epochs {
+            self.disk.crash(epoch)?;
+            let accepted = self.accepted.get_mut(&epoch).unwrap();
+            let before = accepted.len();
+            accepted.retain(|_, durable| *durable);
+            self.report.lost_tags += (before - accepted.len()) as u64;
+            self.open_key(epoch)?;
+        }
+        self.report.crashes += 1;
+        Ok(())
+    }
+}
+
+/// Run `count` schedules with consecutive seeds starting at
+/// `first_seed`, each in its own directory under `dir`.
+pub fn run_schedules(first_seed: u64, count: u64, config: &SimConfig, dir: &Path) -> Result<Vec<SimReport>, MixKeyError> {
+    let mut reports = Vec::new();
+    for seed in first_seed..first_seed + count {
+        let sim_dir = dir.join(format!("sim.{}", seed));
+        reports.push(Simulation::new(seed, config.clone(), &sim_dir)?.run()?);
+        fs::remove_dir_all(&sim_dir)?;
+    }
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn simulation_test() {
+        let dir = TempDir::new().unwrap();
+        let config = SimConfig::default();
+        let reports = run_schedules(1, 10, &config, dir.path()).unwrap();
+        for report in &reports {
+            assert_eq!(report.violations, vec![]);
+            assert_eq!(report.false_rejections, 0);
+            assert_eq!(report.packets, config.epochs * config.packets_per_epoch);
+        }
+        assert!(reports.iter().any(|x| x.crashes > 0 && x.lost_tags > 0));
+
+        let again = run_schedules(1, 1, &config, dir.path()).unwrap();
+        assert_eq!(again[0], reports[0]);
+    }
+
+    #[test]
+    #[ignore]
+    fn many_schedules_test() {
+        let dir = TempDir::new().unwrap();
+        for report in run_schedules(1000, 2000, &SimConfig::default(), dir.path()).unwrap() {
+            assert_eq!(report.violations, vec![], "seed {}", report.seed);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+// sledupgrade.rs - Upgrade of caches written by sled 0.16.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Caches written before the move to sled 0.34 are in the on-disk
+//! format of sled 0.16, which the current release can not read. Their
+//! `conf` file is binary, where sled 0.34 writes text beginning with
+//! `segment_size:`. `MixKey::with_config` upgrades such a cache in
+//! place before opening it, so that a mix upgraded mid-epoch keeps
+//! detecting the replays of tags it saw before the upgrade.
+//!
+//! Both formats keep their files under the same names, so the upgrade
+//! goes through directories within the cache, each renamed into place
+//! once the previous step is durable:
+//!
+//! 1. Every entry is copied into a new tree in `sled.migrating`, which
+//!    is flushed and renamed to `sled.migrated`.
+//! 2. The old tree's files are moved into `sled.legacy`, and
+//!    `sled.migrated` is renamed to `sled.swapping`.
+//! 3. The new tree's files are moved up out of `sled.swapping`, which is
+//!    then removed, and `sled.legacy` last.
+//!
+//! A crash at any point leaves enough for the next open to pick up
+//! where the upgrade stopped. The overflow tree is upgraded likewise.
+//!
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+use sled;
+use sled_legacy;
+
+use errors::{MixKeyError, Op, ResultExt};
+use fsutil;
+use store;
+use super::OVERFLOW_DIR_NAME;
+
+
+const CONF_FILE_NAME: &str = "conf";
+const CONF_PREFIX: &[u8] = b"segment_size:";
+const MIGRATING_DIR_NAME: &str = "sled.migrating";
+const MIGRATED_DIR_NAME: &str = "sled.migrated";
+const SWAPPING_DIR_NAME: &str = "sled.swapping";
+const LEGACY_DIR_NAME: &str = "sled.legacy";
+
+
+/// Returns true if the name is that of one of sled's own files.
+pub(crate) fn is_sled_file(name: &str) -> bool {
+    name == CONF_FILE_NAME || name == "db" || name == "blobs" || name.starts_with("snap.")
+}
+
+/// Returns true if the directory holds a tree written by sled 0.16.
+pub fn is_legacy(dir: &Path) -> Result<bool, MixKeyError> {
+    let mut file = match File::open(dir.join(CONF_FILE_NAME)) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(MixKeyError::IoError(e)),
+    };
+    let mut prefix = vec![0u8; CONF_PREFIX.len()];
+    let mut read = 0;
+    while read < prefix.len() {
+        match file.read(&mut prefix[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(&prefix[..read] != CONF_PREFIX)
+}
+
+/// Upgrade the epoch's cache at `path` and its overflow tree, finishing
+/// an upgrade a crash interrupted. Returns true if anything was
+/// upgraded. The new trees compress their values if `use_compression`
+/// is set, as the cache will be opened with.
+pub fn upgrade(epoch: u64, path: &Path, use_compression: bool) -> Result<bool, MixKeyError> {
+    let mut upgraded = upgrade_tree(path, use_compression).context(epoch, Op::UpgradeCache, path)?;
+    let overflow_path = path.join(OVERFLOW_DIR_NAME);
+    if overflow_path.is_dir() {
+        upgraded |= upgrade_tree(&overflow_path, use_compression).context(epoch, Op::UpgradeCache, &overflow_path)?;
+    }
+    if upgraded {
+        info!("upgraded the cache of epoch {} from sled 0.16", epoch);
+    }
+    Ok(upgraded)
+}
+
+fn upgrade_tree(dir: &Path, use_compression: bool) -> Result<bool, MixKeyError> {
+    let swapping = dir.join(SWAPPING_DIR_NAME);
+    let migrated = dir.join(MIGRATED_DIR_NAME);
+    let legacy = dir.join(LEGACY_DIR_NAME);
+    if swapping.exists() {
+        finish(dir)?;
+        return Ok(true)
+    }
+    if migrated.exists() {
+        set_aside(dir)?;
+        finish(dir)?;
+        return Ok(true)
+    }
+    if is_legacy(dir)? {
+        copy(dir, use_compression)?;
+        set_aside(dir)?;
+        finish(dir)?;
+        return Ok(true)
+    }
+    // The tree is current, but a crash may have left the old one behind.
+    if legacy.exists() {
+        fs::remove_dir_all(&legacy)?;
+    }
+    Ok(false)
+}
+
+/// Copy every entry of the old tree into a new one in `sled.migrating`,
+/// and rename it to `sled.migrated` once it is durable.
+fn copy(dir: &Path, use_compression: bool) -> Result<(), MixKeyError> {
+    let migrating = dir.join(MIGRATING_DIR_NAME);
+    if migrating.exists() {
+        fs::remove_dir_all(&migrating)?;
+    }
+    {
+        let old = sled_legacy::Tree::start(sled_legacy::ConfigBuilder::default().path(dir.to_path_buf()).build())?;
+        let new = store::open_tree(&sled::Config::new().path(&migrating).use_compression(use_compression))?;
+        for item in old.iter() {
+            let (key, value) = item?;
+            new.insert(key.to_vec(), value.to_vec())?;
+        }
+        new.flush()?;
+    }
+    fsutil::atomic_rename(&migrating, &dir.join(MIGRATED_DIR_NAME))?;
+    Ok(())
+}
+
+/// Move the old tree's files into `sled.legacy`, then rename
+/// `sled.migrated` to `sled.swapping`.
+fn set_aside(dir: &Path) -> Result<(), MixKeyError> {
+    let legacy = dir.join(LEGACY_DIR_NAME);
+    fs::create_dir_all(&legacy)?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_str().map_or(false, is_sled_file) {
+            fsutil::atomic_rename(&entry.path(), &legacy.join(&name))?;
+        }
+    }
+    fsutil::atomic_rename(&dir.join(MIGRATED_DIR_NAME), &dir.join(SWAPPING_DIR_NAME))?;
+    Ok(())
+}
+
+/// Move the new tree's files up out of `sled.swapping`, and remove what
+/// is left of the upgrade.
+fn finish(dir: &Path) -> Result<(), MixKeyError> {
+    let swapping = dir.join(SWAPPING_DIR_NAME);
+    for entry in fs::read_dir(&swapping)? {
+        let entry = entry?;
+        fsutil::atomic_rename(&entry.path(), &dir.join(entry.file_name()))?;
+    }
+    fs::remove_dir(&swapping)?;
+    fsutil::sync_dir(dir)?;
+    let legacy = dir.join(LEGACY_DIR_NAME);
+    if legacy.exists() {
+        fs::remove_dir_all(&legacy)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    fn write_legacy(dir: &Path, entries: &[(Vec<u8>, Vec<u8>)]) {
+        let tree = sled_legacy::Tree::start(sled_legacy::ConfigBuilder::default().path(dir.to_path_buf()).build()).unwrap();
+        for &(ref key, ref value) in entries {
+            tree.set(key.clone(), value.clone()).unwrap();
+        }
+        tree.flush().unwrap();
+    }
+
+    fn read_tree(dir: &Path) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let tree = store::open_tree(&sled::Config::new().path(dir)).unwrap();
+        tree.iter().map(|item| {
+            let (key, value) = item.unwrap();
+            (key.to_vec(), value.to_vec())
+        }).collect()
+    }
+
+    #[test]
+    fn upgrade_test() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("mix_key.3");
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..50u8).map(|i| (vec![i; 32], vec![])).chain(vec![(b"public_key".to_vec(), vec![1, 2, 3])]).collect();
+        write_legacy(&path, &entries);
+        write_legacy(&path.join(OVERFLOW_DIR_NAME), &entries[..10]);
+        fs::write(path.join("frozen.tmp"), b"not sled").unwrap();
+        assert!(is_legacy(&path).unwrap());
+
+        assert!(upgrade(3, &path, false).unwrap());
+        assert!(!is_legacy(&path).unwrap());
+        assert!(!path.join(LEGACY_DIR_NAME).exists());
+        assert!(!path.join(SWAPPING_DIR_NAME).exists());
+        assert!(path.join("frozen.tmp").exists());
+        let mut upgraded = read_tree(&path);
+        upgraded.sort();
+        let mut expected = entries.clone();
+        expected.sort();
+        assert_eq!(upgraded, expected);
+        assert_eq!(read_tree(&path.join(OVERFLOW_DIR_NAME)).len(), 10);
+        assert!(!upgrade(3, &path, false).unwrap());
+
+        // An upgrade interrupted after the copy is finished on the next open.
+        let path = dir.path().join("mix_key.4");
+        write_legacy(&path, &entries);
+        copy(&path, false).unwrap();
+        assert!(path.join(MIGRATED_DIR_NAME).exists());
+        assert!(upgrade(4, &path, false).unwrap());
+        assert_eq!(read_tree(&path).len(), entries.len());
+    }
+}
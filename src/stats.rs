@@ -0,0 +1,67 @@
+// stats.rs - Per-epoch statistics.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! `MixKey::stats` and `MixKeys::stats` report the numbers capacity
+//! planning needs: how many tags an epoch holds, how full its filter is
+//! and how often it will answer falsely, how much disk its cache takes,
+//! and how many replays it caught. Unlike the `metrics` feature they
+//! are always available, and are returned as plain values rather than
+//! rendered for a scraper.
+//!
+
+
+/// KeyStats describes one epoch's key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyStats {
+    pub epoch: u64,
+    /// Tags stored, including any in the overflow store.
+    pub tags: u64,
+    /// The estimated fraction of filter bits set, or None while the
+    /// key is shed.
+    pub fill_ratio: Option<f64>,
+    /// The estimated probability that a fresh tag is found in the
+    /// filter and has to be looked up in the store, or None while the
+    /// key is shed.
+    pub false_positive_rate: Option<f64>,
+    /// Disk space used by the key's sled cache, or zero for other
+    /// backends.
+    pub disk_bytes: u64,
+    /// Replays detected since the key was opened.
+    pub replays: u64,
+}
+
+/// MixKeysStats holds the statistics of every live key, ordered by
+/// epoch, and their totals.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MixKeysStats {
+    pub epochs: Vec<KeyStats>,
+    pub tags: u64,
+    pub disk_bytes: u64,
+    pub replays: u64,
+}
+
+impl MixKeysStats {
+    pub(crate) fn new(mut epochs: Vec<KeyStats>) -> MixKeysStats {
+        epochs.sort_by_key(|stats| stats.epoch);
+        MixKeysStats{
+            tags: epochs.iter().map(|stats| stats.tags).sum(),
+            disk_bytes: epochs.iter().map(|stats| stats.disk_bytes).sum(),
+            replays: epochs.iter().map(|stats| stats.replays).sum(),
+            epochs: epochs,
+        }
+    }
+}
@@ -0,0 +1,473 @@
+// store.rs - Replay tag storage backends.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Every `MixKey` keeps the authoritative set of its epoch's tags in a
+//! `ReplayStore`, with the bloom filter in front of it. Mix servers use
+//! a sled tree on disk, which survives restarts. Test harnesses and
+//! short lived nodes can instead keep the tags in memory, which never
+//! touches the disk and forgets everything when the key is dropped.
+//! Applications can also supply their own store, for instance one
+//! shared by several processes, or keep the tags in a sled tree they
+//! already run, under a namespace of their choosing, with
+//! `SledTreeStores`.
+//!
+//! A store also holds a few named metadata values, such as the
+//! identifier of the epoch's private key, so that every process sharing
+//! a store agrees on the key.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::iter;
+
+use sled::{self, Tree};
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use errors::MixKeyError;
+use constants::MIX_KEY_MIN_TAG_SIZE;
+use super::{Tag, TagFilter, TAG_SIZE_KEY};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+
+/// CacheBackend selects where a `MixKey` stores its tags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum CacheBackend {
+    /// A sled tree in the key's `mix_key.<epoch>` directory.
+    Sled,
+    /// An in memory set, with nothing written to disk.
+    Memory,
+    /// A store supplied by the application through `MixKey::with_store`
+    /// or `MixKeys::with_store_factory`.
+    Custom,
+}
+
+impl Default for CacheBackend {
+    fn default() -> Self {
+        CacheBackend::Sled
+    }
+}
+
+/// ReplayStore holds the tags of a single epoch. This is an extension
+/// point for keeping tags in another database.
+pub trait ReplayStore: Send {
+    /// Returns true if the tag is stored.
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError>;
+
+    /// Store the tag, returning true if it was already stored. Stores
+    /// shared between processes must check and insert atomically.
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError>;
+
+    /// Delete the tag, returning true if it was stored. Stores that
+    /// can not delete tags keep the default, which refuses.
+    fn remove(&mut self, _tag: &Tag) -> Result<bool, MixKeyError> {
+        Err(MixKeyError::RemovalUnsupported)
+    }
+
+    /// Returns another handle onto the same tags, which another thread
+    /// can use at the same time. Stores that can only be used through
+    /// one handle keep the default, and are locked as a whole.
+    fn handle(&self) -> Option<Box<dyn ReplayStore>> {
+        None
+    }
+
+    /// Returns a store for the tags of the namespace, kept apart from
+    /// this store's own tags and those of every other namespace. Stores
+    /// without namespaces keep the default.
+    fn namespace(&self, _namespace: u8) -> Option<Box<dyn ReplayStore>> {
+        None
+    }
+
+    /// Make every stored tag durable.
+    fn flush(&mut self) -> Result<(), MixKeyError>;
+
+    /// Returns an iterator over every stored tag.
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a>;
+
+    /// Returns the metadata value stored under the name.
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError>;
+
+    /// Store the metadata value unless one is already stored under the
+    /// name, returning whichever value is stored.
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError>;
+
+    /// Store the metadata value, replacing any stored under the name,
+    /// and return true. Stores whose metadata can only be initialised
+    /// keep the default, which stores nothing and returns false.
+    fn set_metadata(&mut self, _name: &str, _value: &[u8]) -> Result<bool, MixKeyError> {
+        Ok(false)
+    }
+}
+
+/// ReplayStoreFactory opens the store of each epoch for a `MixKeys`.
+/// This is an extension point, used with `MixKeys::with_store_factory`.
+pub trait ReplayStoreFactory: Send + Sync {
+    fn open(&self, epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError>;
+
+    /// Delete the store of an epoch whose key was pruned.
+    fn remove(&self, _epoch: u64) -> Result<(), MixKeyError> {
+        Ok(())
+    }
+}
+
+/// The longest key prefix whose tag keys are built on the stack.
+const STACK_PREFIX_SIZE: usize = 64;
+
+/// SledStore keeps the tags in a sled tree. Every key is prefixed with
+/// `prefix`, which is empty when the tree belongs to the store alone.
+/// Only the first `tag_size` bytes of each tag are stored. sled copies
+/// the keys it is given into its own buffers, so tag keys are built on
+/// the stack unless the prefix is longer than `STACK_PREFIX_SIZE`.
+pub(crate) struct SledStore {
+    pub(crate) tree: Tree,
+    prefix: Vec<u8>,
+    tag_size: usize,
+}
+
+impl SledStore {
+    pub(crate) fn new(tree: Tree) -> SledStore {
+        SledStore::with_prefix(tree, vec![])
+    }
+
+    pub(crate) fn with_prefix(tree: Tree, prefix: Vec<u8>) -> SledStore {
+        SledStore{
+            tree: tree,
+            prefix: prefix,
+            tag_size: SPHINX_REPLAY_TAG_SIZE,
+        }
+    }
+
+    /// Read the size of the stored tags from the store's metadata.
+    /// Caches that do not record it hold full size tags.
+    pub(crate) fn load_tag_size(&mut self) -> Result<(), MixKeyError> {
+        self.tag_size = match self.metadata(TAG_SIZE_KEY)? {
+            Some(ref raw) if raw.len() == 1 && is_tag_size(raw[0] as usize) => raw[0] as usize,
+            Some(_) => return Err(MixKeyError::LoadCacheFailed),
+            None => SPHINX_REPLAY_TAG_SIZE,
+        };
+        Ok(())
+    }
+
+    pub(crate) fn tag_size(&self) -> usize {
+        self.tag_size
+    }
+
+    fn key(&self, name: &[u8]) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.prefix.len() + name.len());
+        key.extend_from_slice(&self.prefix);
+        key.extend_from_slice(name);
+        key
+    }
+
+    /// Call `f` with the key of the tag, built on the stack.
+    fn with_tag_key<T, F: FnOnce(&[u8]) -> T>(&self, tag: &Tag, f: F) -> T {
+        let name = &tag.0[..self.tag_size];
+        if self.prefix.is_empty() {
+            return f(name)
+        }
+        if self.prefix.len() > STACK_PREFIX_SIZE {
+            return f(&self.key(name))
+        }
+        let mut key = [0u8; STACK_PREFIX_SIZE + SPHINX_REPLAY_TAG_SIZE];
+        let len = self.prefix.len() + name.len();
+        key[..self.prefix.len()].copy_from_slice(&self.prefix);
+        key[self.prefix.len()..len].copy_from_slice(name);
+        f(&key[..len])
+    }
+}
+
+impl ReplayStore for SledStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        match self.with_tag_key(tag, |key| self.tree.get(key)) {
+            Ok(x) => Ok(x.is_some()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        match self.with_tag_key(tag, |key| self.tree.insert(key, &[] as &[u8])) {
+            Ok(old) => Ok(old.is_some()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        match self.with_tag_key(tag, |key| self.tree.remove(key)) {
+            Ok(old) => Ok(old.is_some()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn handle(&self) -> Option<Box<dyn ReplayStore>> {
+        let mut store = SledStore::with_prefix(self.tree.clone(), self.prefix.clone());
+        store.tag_size = self.tag_size;
+        Some(Box::new(store))
+    }
+
+    fn namespace(&self, namespace: u8) -> Option<Box<dyn ReplayStore>> {
+        let mut prefix = self.prefix.clone();
+        prefix.push(namespace);
+        let mut store = SledStore::with_prefix(self.tree.clone(), prefix);
+        store.tag_size = self.tag_size;
+        Some(Box::new(store))
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        let prefix = &self.prefix;
+        let tag_size = self.tag_size;
+        Box::new(self.tree.scan_prefix(prefix).filter_map(move |item| {
+            match item {
+                Ok((ref key, _)) if key.len() != prefix.len() + tag_size => None,
+                Ok((key, _)) => {
+                    let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+                    raw[..tag_size].copy_from_slice(&key[prefix.len()..]);
+                    Some(Ok(raw))
+                },
+                Err(e) => Some(Err(e.into())),
+            }
+        }))
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        match self.tree.get(&self.key(name.as_bytes())) {
+            Ok(x) => Ok(x.map(|value| value.to_vec())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        if let Some(stored) = self.metadata(name)? {
+            return Ok(stored)
+        }
+        match self.tree.insert(self.key(name.as_bytes()), value.to_vec()) {
+            Ok(_) => Ok(value.to_vec()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        match self.tree.insert(self.key(name.as_bytes()), value.to_vec()) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Returns true if tags of this many bytes can be stored.
+pub(crate) fn is_tag_size(tag_size: usize) -> bool {
+    tag_size >= MIX_KEY_MIN_TAG_SIZE && tag_size <= SPHINX_REPLAY_TAG_SIZE
+}
+
+/// Open the configured sled database, returning its default tree.
+pub(crate) fn open_tree(config: &sled::Config) -> Result<Tree, MixKeyError> {
+    let db = config.open()?;
+    Ok(Tree::clone(&db))
+}
+
+/// SledTreeStores keeps the tags of every epoch in a sled tree the
+/// application already manages, under keys prefixed with
+/// `<namespace>/mix_key.<epoch>/`. The application remains responsible
+/// for the tree's configuration; flushing a store flushes the whole tree.
+pub struct SledTreeStores {
+    tree: Tree,
+    namespace: String,
+}
+
+impl SledTreeStores {
+    pub fn new(tree: Tree, namespace: &str) -> SledTreeStores {
+        SledTreeStores{
+            tree: tree,
+            namespace: namespace.to_string(),
+        }
+    }
+
+    fn prefix(&self, epoch: u64) -> Vec<u8> {
+        format!("{}/mix_key.{}/", self.namespace, epoch).into_bytes()
+    }
+}
+
+impl ReplayStoreFactory for SledTreeStores {
+    fn open(&self, epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        Ok(Box::new(SledStore::with_prefix(self.tree.clone(), self.prefix(epoch))))
+    }
+
+    fn remove(&self, epoch: u64) -> Result<(), MixKeyError> {
+        let prefix = self.prefix(epoch);
+        let mut keys = vec![];
+        for item in self.tree.scan_prefix(&prefix) {
+            match item {
+                Ok((key, _)) => keys.push(key),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for key in keys {
+            self.tree.remove(&key)?;
+        }
+        Ok(())
+    }
+}
+
+/// MemoryStore keeps the tags in memory only.
+#[derive(Default)]
+pub(crate) struct MemoryStore {
+    tags: HashSet<Tag>,
+    metadata: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+}
+
+impl ReplayStore for MemoryStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(self.tags.contains(tag))
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(!self.tags.insert(tag.clone()))
+    }
+
+    fn remove(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(self.tags.remove(tag))
+    }
+
+    fn namespace(&self, _namespace: u8) -> Option<Box<dyn ReplayStore>> {
+        Some(Box::new(MemoryStore::default()))
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        Ok(())
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        Box::new(self.tags.iter().map(|tag| Ok(tag.0)))
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        Ok(self.metadata.get(name).cloned())
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        Ok(self.metadata.entry(name.to_string()).or_insert_with(|| value.to_vec()).clone())
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        self.metadata.insert(name.to_string(), value.to_vec());
+        Ok(true)
+    }
+}
+
+/// FilterStore keeps the tags in a filter only, which answers lookups
+/// with the filter's false positives and can not list its tags.
+pub(crate) struct FilterStore {
+    filter: TagFilter,
+    metadata: HashMap<String, Vec<u8>>,
+}
+
+impl FilterStore {
+    pub fn new(false_positive_rate: f32, expected_num_items: u32) -> FilterStore {
+        FilterStore{
+            filter: TagFilter::new(false_positive_rate, expected_num_items, false),
+            metadata: HashMap::new(),
+        }
+    }
+}
+
+impl ReplayStore for FilterStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(self.filter.contains(tag))
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(!self.filter.insert(tag))
+    }
+
+    /// Nothing is ever removed, as a tag can not be told apart from
+    /// those it collides with.
+    fn remove(&mut self, _tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(false)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        Ok(())
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        Box::new(iter::empty())
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        Ok(self.metadata.get(name).cloned())
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        Ok(self.metadata.entry(name.to_string()).or_insert_with(|| value.to_vec()).clone())
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        self.metadata.insert(name.to_string(), value.to_vec());
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn sled_tree_stores_test() {
+        let dir = TempDir::new().unwrap();
+        let tree = open_tree(&sled::Config::new().path(dir.path().join("app.db"))).unwrap();
+        tree.insert(b"app/setting".to_vec(), b"on".to_vec()).unwrap();
+        let mix = SledTreeStores::new(tree.clone(), "mix");
+        let other = SledTreeStores::new(tree.clone(), "other");
+
+        let tag = Tag([5u8; SPHINX_REPLAY_TAG_SIZE]);
+        let mut store = mix.open(1).unwrap();
+        assert_eq!(store.insert(&tag).unwrap(), false);
+        assert_eq!(store.insert(&tag).unwrap(), true);
+        assert_eq!(store.remove(&tag).unwrap(), true);
+        assert_eq!(store.remove(&tag).unwrap(), false);
+        assert_eq!(store.insert(&tag).unwrap(), false);
+        assert_eq!(store.init_metadata("private_key", b"key").unwrap(), b"key".to_vec());
+        assert_eq!(store.set_metadata("state", &[1]).unwrap(), true);
+        assert_eq!(store.set_metadata("state", &[2]).unwrap(), true);
+        assert_eq!(store.metadata("state").unwrap(), Some(vec![2]));
+        assert_eq!(store.tags().collect::<Result<Vec<_>, _>>().unwrap(), vec![tag.0]);
+        assert_eq!(mix.open(2).unwrap().contains(&tag).unwrap(), false);
+        assert_eq!(other.open(1).unwrap().contains(&tag).unwrap(), false);
+        assert_eq!(other.open(1).unwrap().metadata("private_key").unwrap(), None);
+
+        mix.remove(1).unwrap();
+        assert_eq!(mix.open(1).unwrap().tags().count(), 0);
+        assert_eq!(mix.open(1).unwrap().metadata("private_key").unwrap(), None);
+        assert_eq!(tree.get(b"app/setting").unwrap().unwrap().to_vec(), b"on".to_vec());
+    }
+}
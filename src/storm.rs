@@ -0,0 +1,163 @@
+// storm.rs - Replay storm alarm.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Every mix sees a trickle of replays, from retransmissions and
+//! clients that resend after a timeout. An active replay attack looks
+//! different: many replays within a short time, often of few tags.
+//!
+//! With `MixKeysBuilder::replay_storm_alarm` set, every replay a key
+//! detects is counted in a sliding window. When the replays per second
+//! over the window exceed the threshold, a `ReplayStorm` summarizing
+//! the window is logged and sent to the receivers of
+//! `MixKeys::subscribe_storms`. It is raised once, and again only after
+//! the rate has dropped back to the threshold.
+//!
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use events::Subscribers;
+use super::Tag;
+
+
+/// ReplayStormConfig sets when a replay storm is raised.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplayStormConfig {
+    /// The length of the sliding window.
+    pub window: Duration,
+    /// The replays per second over the window above which a storm is
+    /// raised.
+    pub threshold: f64,
+}
+
+/// ReplayStorm summarizes the window in which the replay rate crossed
+/// the threshold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayStorm {
+    pub window: Duration,
+    /// Replays detected within the window.
+    pub replays: u64,
+    /// Replays per second over the window.
+    pub rate: f64,
+    /// Distinct tags among the replays. Few tags replayed many times
+    /// point at a targeted attack.
+    pub distinct_tags: u64,
+    /// The epochs of the keys that detected the replays, in order.
+    pub epochs: Vec<u64>,
+}
+
+/// StormDetector keeps the replays of the sliding window. Keys of a
+/// `MixKeys` share one.
+pub(crate) struct StormDetector {
+    config: ReplayStormConfig,
+    replays: VecDeque<(Duration, u64, Tag)>,
+    raised: bool,
+    subscribers: Subscribers<ReplayStorm>,
+}
+
+impl StormDetector {
+    pub(crate) fn new(config: ReplayStormConfig) -> StormDetector {
+        StormDetector{
+            config: config,
+            replays: VecDeque::new(),
+            raised: false,
+            subscribers: Subscribers::default(),
+        }
+    }
+
+    pub(crate) fn subscribe(&mut self) -> Receiver<ReplayStorm> {
+        self.subscribers.subscribe()
+    }
+
+    /// Count a replay of the epoch's key at monotonic time `now`,
+    /// returning the storm if this replay raised one.
+    pub(crate) fn record(&mut self, now: Duration, epoch: u64, tag: &Tag) -> Option<ReplayStorm> {
+        while let Some(&(at, _, _)) = self.replays.front() {
+            if now.checked_sub(at).map_or(false, |age| age >= self.config.window) {
+                self.replays.pop_front();
+            } else {
+                break
+            }
+        }
+        self.replays.push_back((now, epoch, tag.clone()));
+        let rate = self.replays.len() as f64 / self.config.window.as_secs_f64();
+        if rate <= self.config.threshold {
+            self.raised = false;
+            return None
+        }
+        if self.raised {
+            return None
+        }
+        self.raised = true;
+        let storm = self.summarize(rate);
+        warn!("replay storm: {} replays of {} distinct tags in {:?}", storm.replays, storm.distinct_tags, storm.window);
+        self.subscribers.publish(storm.clone());
+        Some(storm)
+    }
+
+    fn summarize(&self, rate: f64) -> ReplayStorm {
+        let tags: HashSet<&Tag> = self.replays.iter().map(|&(_, _, ref tag)| tag).collect();
+        let mut epochs: Vec<u64> = self.replays.iter().map(|&(_, epoch, _)| epoch).collect();
+        epochs.sort();
+        epochs.dedup();
+        ReplayStorm{
+            window: self.config.window,
+            replays: self.replays.len() as u64,
+            rate: rate,
+            distinct_tags: tags.len() as u64,
+            epochs: epochs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use super::*;
+
+
+    #[test]
+    fn storm_detector_test() {
+        let mut detector = StormDetector::new(ReplayStormConfig{
+            window: Duration::from_secs(10),
+            threshold: 0.5,
+        });
+        let storms = detector.subscribe();
+        let tag = Tag::new([1u8; SPHINX_REPLAY_TAG_SIZE]);
+        let secs = Duration::from_secs;
+        for i in 0..5 {
+            assert_eq!(detector.record(secs(i), 7, &tag), None);
+        }
+
+        let storm = detector.record(secs(5), 8, &Tag::new([2u8; SPHINX_REPLAY_TAG_SIZE])).unwrap();
+        assert_eq!(storm.replays, 6);
+        assert_eq!(storm.distinct_tags, 2);
+        assert_eq!(storm.epochs, vec![7, 8]);
+        assert!((storm.rate - 0.6).abs() < 1e-9);
+        assert_eq!(detector.record(secs(6), 8, &tag), None);
+        assert_eq!(storms.try_iter().collect::<Vec<_>>(), vec![storm]);
+
+        assert_eq!(detector.record(secs(40), 8, &tag), None);
+        for i in 0..5 {
+            detector.record(secs(41 + i), 8, &tag);
+        }
+        assert_eq!(storms.try_iter().count(), 1);
+    }
+}
@@ -0,0 +1,288 @@
+// tagimport.rs - Parallel, resumable import of tag dumps.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Importing a whole epoch's tags at line rate one at a time takes far
+//! longer than the grace period a failover has. `MixKey::import_tags_parallel`
+//! reads the dump's chunks on the calling thread and hands them to a
+//! pool of worker threads, which verify each chunk's checksum and insert
+//! its tags into the key's shards side by side.
+//!
+//! Chunks finish out of order, so progress is tracked as the position
+//! below which every chunk is done. Whenever it passes another
+//! `checkpoint_every` records, the cache is flushed and the position is
+//! reported. An interrupted import resumes by passing the last reported
+//! position as `resume_from`, which skips the chunks before it without
+//! verifying or inserting them again.
+//!
+//! Builds with the `minimal` feature import on the calling thread.
+//!
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel};
+use std::thread;
+
+use constants::{MIX_KEY_IMPORT_CHECKPOINT, MIX_KEY_IMPORT_THREADS};
+use dump::{DumpChunk, DumpReader};
+use errors::{MixKeyError, Op, ResultExt};
+use super::MixKey;
+
+
+/// ImportConfig tunes `MixKey::import_tags_parallel`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportConfig {
+    /// The number of threads verifying and inserting chunks. With one,
+    /// chunks are imported on the calling thread.
+    pub threads: usize,
+    /// Flush and report progress every time at least this many more
+    /// records are done.
+    pub checkpoint_every: u64,
+    /// Skip the records before this position, the last checkpoint
+    /// reported by an interrupted import of the same dump.
+    pub resume_from: u64,
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        ImportConfig{
+            threads: MIX_KEY_IMPORT_THREADS,
+            checkpoint_every: MIX_KEY_IMPORT_CHECKPOINT,
+            resume_from: 0,
+        }
+    }
+}
+
+/// ImportProgress is reported at every checkpoint of an import.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportProgress {
+    /// Every record before this position is verified, inserted and
+    /// flushed.
+    pub checkpoint: u64,
+    /// The number of tags inserted so far that were not already stored.
+    pub imported: u64,
+}
+
+/// Checkpoints tracks which chunks are done.
+struct Checkpoints {
+    every: u64,
+    done_below: u64,
+    done: BTreeMap<u64, u64>,
+    progress: ImportProgress,
+}
+
+impl Checkpoints {
+    fn new(every: u64) -> Checkpoints {
+        Checkpoints{
+            every: every.max(1),
+            done_below: 0,
+            done: BTreeMap::new(),
+            progress: ImportProgress::default(),
+        }
+    }
+
+    fn complete(&mut self, first: u64, end: u64, imported: u64) {
+        self.progress.imported += imported;
+        self.done.insert(first, end);
+        while let Some(end) = self.done.remove(&self.done_below) {
+            self.done_below = end;
+        }
+    }
+
+    fn is_due(&self) -> bool {
+        self.done_below >= self.progress.checkpoint + self.every
+    }
+
+    fn checkpoint<F: FnMut(&ImportProgress)>(&mut self, key: &mut MixKey, progress: &mut F) -> Result<(), MixKeyError> {
+        key.flush()?;
+        self.progress.checkpoint = self.done_below;
+        progress(&self.progress);
+        Ok(())
+    }
+}
+
+/// Import the dump read from `reader` into `key`, returning the final
+/// progress.
+pub(crate) fn import<R, F>(key: &mut MixKey, reader: R, config: &ImportConfig, mut progress: F) -> Result<ImportProgress, MixKeyError>
+    where R: Read, F: FnMut(&ImportProgress)
+{
+    let (epoch, path) = (key.epoch(), key.path().to_path_buf());
+    let mut dump = DumpReader::new(reader).context(epoch, Op::ImportTags, &path)?;
+    if dump.epoch() != epoch {
+        return Err(MixKeyError::InvalidDump.context(epoch, Op::ImportTags, &path))
+    }
+    let mut checkpoints = Checkpoints::new(config.checkpoint_every);
+    let result = if config.threads <= 1 || cfg!(feature = "minimal") {
+        import_sequential(key, &mut dump, config, &mut checkpoints, &mut progress)
+    } else {
+        import_parallel(key, &mut dump, config, &mut checkpoints, &mut progress)
+    };
+    result.context(epoch, Op::ImportTags, &path)?;
+    checkpoints.checkpoint(key, &mut progress).context(epoch, Op::ImportTags, &path)?;
+    Ok(checkpoints.progress)
+}
+
+/// Returns the next chunk not before `resume_from`, counting skipped
+/// chunks as done.
+fn next_chunk<R: Read>(dump: &mut DumpReader<R>, resume_from: u64, checkpoints: &mut Checkpoints) -> Result<Option<DumpChunk>, MixKeyError> {
+    while let Some(chunk) = dump.next_chunk()? {
+        if chunk.end() > resume_from {
+            return Ok(Some(chunk))
+        }
+        checkpoints.complete(chunk.first(), chunk.end(), 0);
+    }
+    Ok(None)
+}
+
+fn insert_chunk(key: &MixKey, chunk: DumpChunk) -> Result<u64, MixKeyError> {
+    let tags = chunk.verify()?;
+    key.insert_tags(tags.into_iter().map(Ok))
+}
+
+fn import_sequential<R, F>(key: &mut MixKey, dump: &mut DumpReader<R>, config: &ImportConfig, checkpoints: &mut Checkpoints, progress: &mut F) -> Result<(), MixKeyError>
+    where R: Read, F: FnMut(&ImportProgress)
+{
+    while let Some(chunk) = next_chunk(dump, config.resume_from, checkpoints)? {
+        let (first, end) = (chunk.first(), chunk.end());
+        let imported = insert_chunk(key, chunk)?;
+        checkpoints.complete(first, end, imported);
+        if checkpoints.is_due() {
+            checkpoints.checkpoint(key, progress)?;
+        }
+    }
+    Ok(())
+}
+
+fn import_parallel<R, F>(key: &mut MixKey, dump: &mut DumpReader<R>, config: &ImportConfig, checkpoints: &mut Checkpoints, progress: &mut F) -> Result<(), MixKeyError>
+    where R: Read, F: FnMut(&ImportProgress)
+{
+    let (work_tx, work_rx) = sync_channel::<DumpChunk>(config.threads * 2);
+    let work_rx = Arc::new(Mutex::new(work_rx));
+    let (done_tx, done_rx) = channel();
+    let workers: Vec<_> = (0..config.threads).map(|_| {
+        let key = key.clone();
+        let work_rx = work_rx.clone();
+        let done_tx = done_tx.clone();
+        thread::spawn(move || {
+            loop {
+                let chunk = match work_rx.lock().unwrap().recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return,
+                };
+                let span = (chunk.first(), chunk.end());
+                if done_tx.send((span, insert_chunk(&key, chunk))).is_err() {
+                    return
+                }
+            }
+        })
+    }).collect();
+    drop(done_tx);
+
+    let mut result = Ok(());
+    while result.is_ok() {
+        for ((first, end), done) in done_rx.try_iter() {
+            match done {
+                Ok(imported) => checkpoints.complete(first, end, imported),
+                Err(e) => result = Err(e),
+            }
+        }
+        if result.is_ok() && checkpoints.is_due() {
+            result = checkpoints.checkpoint(key, progress);
+        }
+        match next_chunk(dump, config.resume_from, checkpoints) {
+            Ok(Some(chunk)) => work_tx.send(chunk).unwrap(),
+            Ok(None) => break,
+            Err(e) => result = Err(e),
+        }
+    }
+    drop(work_tx);
+    for ((first, end), done) in done_rx.iter() {
+        match done {
+            Ok(imported) => checkpoints.complete(first, end, imported),
+            Err(e) => result = result.and(Err(e)),
+        }
+    }
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+
+    use byteorder::{ByteOrder, LittleEndian};
+    use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+    use dump::{write_dump, DUMP_CHUNK_TAGS};
+    use keyprovider::LocalKeyProvider;
+    use store::CacheBackend;
+    use super::*;
+    use super::super::Tag;
+
+
+    fn tags(n: u64) -> Vec<[u8; SPHINX_REPLAY_TAG_SIZE]> {
+        (0..n).map(|i| {
+            let mut tag = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            LittleEndian::write_u64(&mut tag[8..], i);
+            tag[0] = i as u8;
+            tag
+        }).collect()
+    }
+
+    fn memory_key() -> MixKey {
+        MixKey::with_backend(CacheBackend::Memory, &LocalKeyProvider, 1024 * 1024, 7, 1, &String::new()).unwrap()
+    }
+
+    #[test]
+    fn import_tags_parallel_test() {
+        let tags = tags(3 * DUMP_CHUNK_TAGS as u64 + 5);
+        let mut raw = vec![];
+        write_dump(&mut raw, 7, tags.iter().map(|tag| Ok(*tag))).unwrap();
+
+        let mut key = memory_key();
+        let config = ImportConfig{
+            threads: 3,
+            checkpoint_every: DUMP_CHUNK_TAGS as u64,
+            resume_from: 0,
+        };
+        let mut reported = vec![];
+        let done = key.import_tags_parallel(&raw[..], &config, |progress| reported.push(*progress)).unwrap();
+        assert_eq!(done, ImportProgress{ checkpoint: tags.len() as u64, imported: tags.len() as u64 });
+        assert_eq!(reported.last(), Some(&done));
+        assert!(reported.windows(2).all(|pair| pair[0].checkpoint <= pair[1].checkpoint));
+        assert_eq!(key.tag_count(), tags.len() as u64);
+        assert!(tags.iter().step_by(101).all(|tag| key.is_replay(&Tag(*tag)).unwrap()));
+
+        let resumed = ImportConfig{
+            resume_from: 2 * DUMP_CHUNK_TAGS as u64,
+            ..config.clone()
+        };
+        let mut other = memory_key();
+        let done = other.import_tags_parallel(&raw[..], &resumed, |_| {}).unwrap();
+        assert_eq!(done.imported, DUMP_CHUNK_TAGS as u64 + 5);
+        assert_eq!(other.is_replay(&Tag(tags[0])).unwrap(), false);
+
+        let mut corrupt = raw.clone();
+        let len = corrupt.len();
+        corrupt[len - 40] ^= 1;
+        let mut third = memory_key();
+        let mut checkpoint = 0;
+        assert!(third.import_tags_parallel(&corrupt[..], &config, |progress| checkpoint = progress.checkpoint).is_err());
+        assert!(checkpoint < tags.len() as u64);
+    }
+}
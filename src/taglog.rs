@@ -0,0 +1,292 @@
+// taglog.rs - Memory mapped append-only tag log.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Replay tags are written once and never updated, and within an epoch
+//! never deleted, so a key-value store is more than they need. A
+//! `TagLogStore` appends each epoch's tags to a memory mapped file of
+//! fixed size records, and answers lookups from an in memory index of
+//! them.
+//!
+//! The log of an epoch is `mix_key.<epoch>/tags.log`:
+//!
+//!    magic (8) || count (8, LE) || tag (32) || tag (32) || ...
+//!
+//! The file grows by `MIX_KEY_TAG_LOG_CHUNK` records at a time. `count`
+//! is only written by `flush`, after the records it covers were synced,
+//! so a crash never exposes a record that did not reach the disk;
+//! records past `count` are ignored and overwritten. The key's metadata
+//! is kept beside the log, in a `metafile::MetadataFile`.
+//!
+//! The log is locked while it is open, so two processes can not append
+//! to it at once. Tags can not be removed.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian};
+use fs2::FileExt;
+use memmap::MmapMut;
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use constants::MIX_KEY_TAG_LOG_CHUNK;
+use errors::MixKeyError;
+use fsutil;
+use metafile::MetadataFile;
+use store::{ReplayStore, ReplayStoreFactory};
+use super::Tag;
+
+
+const LOG_FILE_NAME: &str = "tags.log";
+const LOG_MAGIC: &[u8; 8] = b"SRCTAGLG";
+const HEADER_SIZE: usize = 16;
+
+
+/// TagLogStore appends the tags of one epoch to a memory mapped log.
+pub struct TagLogStore {
+    dir: PathBuf,
+    file: File,
+    metadata_file: MetadataFile,
+    map: MmapMut,
+    index: HashSet<Tag>,
+    metadata: HashMap<String, Vec<u8>>,
+    capacity: u64,
+    len: u64,
+    synced: u64,
+}
+
+impl TagLogStore {
+    /// Open the log in the directory, creating both if needed.
+    pub fn open(dir: &Path) -> Result<TagLogStore, MixKeyError> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new().read(true).write(true).create(true).open(dir.join(LOG_FILE_NAME))?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(MixKeyError::StoreError(format!("tag log in {} is locked by another process", dir.display())))
+        }
+        if file.metadata()?.len() == 0 {
+            file.set_len(TagLogStore::file_size(MIX_KEY_TAG_LOG_CHUNK))?;
+            let mut header = [0u8; HEADER_SIZE];
+            header[..8].copy_from_slice(LOG_MAGIC);
+            (&file).write_all(&header)?;
+            fsutil::sync_file(&file)?;
+            fsutil::sync_dir(dir)?;
+        }
+        let size = file.metadata()?.len();
+        if size < HEADER_SIZE as u64 || (size - HEADER_SIZE as u64) % SPHINX_REPLAY_TAG_SIZE as u64 != 0 {
+            return Err(MixKeyError::LoadCacheFailed)
+        }
+        let capacity = (size - HEADER_SIZE as u64) / SPHINX_REPLAY_TAG_SIZE as u64;
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        if &map[..8] != LOG_MAGIC {
+            return Err(MixKeyError::LoadCacheFailed)
+        }
+        let len = LittleEndian::read_u64(&map[8..HEADER_SIZE]);
+        if len > capacity {
+            return Err(MixKeyError::LoadCacheFailed)
+        }
+        let mut store = TagLogStore{
+            dir: dir.to_path_buf(),
+            file: file,
+            map: map,
+            index: HashSet::with_capacity(len as usize),
+            metadata_file: MetadataFile::new(dir),
+            metadata: MetadataFile::new(dir).load()?,
+            capacity: capacity,
+            len: len,
+            synced: len,
+        };
+        for i in 0..len {
+            let tag = Tag(store.record(i));
+            store.index.insert(tag);
+        }
+        Ok(store)
+    }
+
+    fn file_size(capacity: u64) -> u64 {
+        HEADER_SIZE as u64 + capacity * SPHINX_REPLAY_TAG_SIZE as u64
+    }
+
+    fn offset(i: u64) -> usize {
+        HEADER_SIZE + i as usize * SPHINX_REPLAY_TAG_SIZE
+    }
+
+    fn record(&self, i: u64) -> [u8; SPHINX_REPLAY_TAG_SIZE] {
+        let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+        raw.copy_from_slice(&self.map[TagLogStore::offset(i)..TagLogStore::offset(i + 1)]);
+        raw
+    }
+
+    /// Make room for another chunk of records and map the file again.
+    fn grow(&mut self) -> Result<(), MixKeyError> {
+        self.map.flush()?;
+        let capacity = self.capacity + MIX_KEY_TAG_LOG_CHUNK;
+        self.file.set_len(TagLogStore::file_size(capacity))?;
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+        self.capacity = capacity;
+        Ok(())
+    }
+}
+
+impl Drop for TagLogStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("failed to flush tag log in {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+impl ReplayStore for TagLogStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(self.index.contains(tag))
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.index.contains(tag) {
+            return Ok(true)
+        }
+        if self.len == self.capacity {
+            self.grow()?;
+        }
+        let offset = TagLogStore::offset(self.len);
+        self.map[offset..offset + SPHINX_REPLAY_TAG_SIZE].copy_from_slice(&tag.0);
+        self.len += 1;
+        self.index.insert(tag.clone());
+        Ok(false)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        if self.synced == self.len {
+            return Ok(())
+        }
+        let start = TagLogStore::offset(self.synced);
+        self.map.flush_range(start, TagLogStore::offset(self.len) - start)?;
+        LittleEndian::write_u64(&mut self.map[8..HEADER_SIZE], self.len);
+        self.map.flush_range(0, HEADER_SIZE)?;
+        self.synced = self.len;
+        Ok(())
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        let store = &*self;
+        Box::new((0..store.len).map(move |i| Ok(store.record(i))))
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        Ok(self.metadata.get(name).cloned())
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        if let Some(stored) = self.metadata.get(name) {
+            return Ok(stored.clone())
+        }
+        self.set_metadata(name, value)?;
+        Ok(value.to_vec())
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        self.metadata.insert(name.to_string(), value.to_vec());
+        self.metadata_file.store(&self.metadata)?;
+        Ok(true)
+    }
+}
+
+/// TagLogStores opens a `TagLogStore` for every epoch of a `MixKeys`,
+/// in the epoch's directory below a base directory.
+pub struct TagLogStores {
+    base_dir: PathBuf,
+}
+
+impl TagLogStores {
+    pub fn new(base_dir: &Path) -> TagLogStores {
+        TagLogStores{
+            base_dir: base_dir.to_path_buf(),
+        }
+    }
+}
+
+impl ReplayStoreFactory for TagLogStores {
+    fn open(&self, epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        Ok(Box::new(TagLogStore::open(&fsutil::epoch_dir(&self.base_dir, epoch))?))
+    }
+
+    fn remove(&self, epoch: u64) -> Result<(), MixKeyError> {
+        let dir = fsutil::epoch_dir(&self.base_dir, epoch);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use std::io::{Seek, SeekFrom};
+
+    use self::tempfile::TempDir;
+    use super::*;
+
+
+    #[test]
+    fn tag_log_store_test() {
+        let base_dir = TempDir::new().unwrap();
+        let factory = TagLogStores::new(base_dir.path());
+        let tags: Vec<Tag> = (0..MIX_KEY_TAG_LOG_CHUNK + 3).map(|i| {
+            let mut raw = [0u8; SPHINX_REPLAY_TAG_SIZE];
+            LittleEndian::write_u64(&mut raw, i + 1);
+            Tag(raw)
+        }).collect();
+
+        let mut store = factory.open(1).unwrap();
+        assert!(factory.open(1).is_err());
+        for tag in &tags {
+            assert_eq!(store.insert(tag).unwrap(), false);
+        }
+        assert_eq!(store.insert(&tags[7]).unwrap(), true);
+        assert_eq!(store.init_metadata("private_key", b"key").unwrap(), b"key".to_vec());
+        assert_eq!(store.init_metadata("private_key", b"other").unwrap(), b"key".to_vec());
+        assert_eq!(store.set_metadata("state", &[1]).unwrap(), true);
+        drop(store);
+
+        // A record past the count, as a crash before a flush leaves
+        // behind, is ignored.
+        let dir = fsutil::epoch_dir(base_dir.path(), 1);
+        let unflushed = Tag([0xffu8; SPHINX_REPLAY_TAG_SIZE]);
+        let mut file = OpenOptions::new().write(true).open(dir.join(LOG_FILE_NAME)).unwrap();
+        file.seek(SeekFrom::Start(TagLogStore::offset(tags.len() as u64) as u64)).unwrap();
+        file.write_all(&unflushed.0).unwrap();
+        drop(file);
+
+        let mut store = TagLogStore::open(&dir).unwrap();
+        assert_eq!(store.contains(&unflushed).unwrap(), false);
+        for tag in &tags {
+            assert_eq!(store.contains(tag).unwrap(), true);
+        }
+        assert_eq!(store.tags().count(), tags.len());
+        assert_eq!(store.metadata("private_key").unwrap(), Some(b"key".to_vec()));
+        assert_eq!(store.metadata("state").unwrap(), Some(vec![1]));
+        assert!(store.remove(&tags[0]).is_err());
+        drop(store);
+
+        factory.remove(1).unwrap();
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,382 @@
+// testing.rs - Deterministic clocks, keys and stores for tests.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Lets downstream crates test epoch rollover, grace periods and pruning
+//! reproducibly, without sleeping or touching the disk.
+//!
+//! A `TestClock` is both the epoch clock and the monotonic clock, and
+//! only moves when advanced. A `SeededKeyProvider` generates the same
+//! keys, in the same order, for the same seed. `builder` wires both
+//! into a `MixKeysBuilder` for keys kept in memory:
+//!
+//! ```ignore
+//! let clock = TestClock::at(10, 3600);
+//! let mut mix_keys = testing::builder(&clock, 7).grace_period(600).build()?;
+//! clock.advance_epochs(1);
+//! mix_keys.rollover(11)?;
+//! ```
+//!
+//! `FaultyStores` keeps the tags in memory too, but fails writes,
+//! corrupts reads and stalls flushes when its `FaultInjector` says so,
+//! so that a mix server's handling of each `MixKeyError` can be tested.
+//!
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use rand::{Rng, XorShiftRng};
+
+use ecdh_wrapper::{PrivateKey, KEY_SIZE};
+use epoch::Time;
+
+use builder::MixKeysBuilder;
+use errors::MixKeyError;
+use keyprovider::{EpochKey, KeyProvider};
+use sim::seeded_rng;
+use store::{CacheBackend, MemoryStore, ReplayStore, ReplayStoreFactory};
+use timesource::{ClockSource, MonotonicClock};
+use super::Tag;
+
+
+/// TestClock is an epoch clock of a fixed period that only moves when
+/// advanced. It counts time from the start of epoch zero and is also a
+/// `MonotonicClock`. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct TestClock {
+    period: u64,
+    now: Arc<Mutex<Duration>>,
+}
+
+impl TestClock {
+    /// Returns a clock standing at the start of the given epoch.
+    pub fn at(epoch: u64, period: u64) -> TestClock {
+        assert!(period > 0, "the epoch period must be positive");
+        TestClock{
+            period: period,
+            now: Arc::new(Mutex::new(Duration::from_secs(epoch * period))),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Advance by whole epochs, keeping the time elapsed within the
+    /// epoch.
+    pub fn advance_epochs(&self, epochs: u64) {
+        self.advance(Duration::from_secs(epochs * self.period));
+    }
+
+    /// Advance to the start of the next epoch.
+    pub fn next_epoch(&self) {
+        let till = ClockSource::now(self).till;
+        self.advance(Duration::from_secs(till));
+    }
+}
+
+impl ClockSource for TestClock {
+    fn now(&self) -> Time {
+        let secs = self.now.lock().unwrap().as_secs();
+        Time{
+            epoch: secs / self.period,
+            elapsed: secs % self.period,
+            till: self.period - secs % self.period,
+        }
+    }
+
+    fn period(&self) -> u64 {
+        self.period
+    }
+
+    /// Epoch 0 starts when the clock reads zero.
+    fn epoch_zero(&self) -> u64 {
+        0
+    }
+}
+
+impl MonotonicClock for TestClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// SeededKeyProvider generates keys in process from a seeded random
+/// number generator. It is for tests only: anyone who knows the seed
+/// knows every key.
+pub struct SeededKeyProvider {
+    rng: Mutex<XorShiftRng>,
+}
+
+impl SeededKeyProvider {
+    pub fn new(seed: u64) -> SeededKeyProvider {
+        SeededKeyProvider{
+            rng: Mutex::new(seeded_rng(seed)),
+        }
+    }
+}
+
+impl KeyProvider for SeededKeyProvider {
+    fn generate(&self, _epoch: u64) -> Result<Vec<u8>, MixKeyError> {
+        let mut raw_key = [0u8; KEY_SIZE];
+        self.rng.lock().unwrap().fill_bytes(&mut raw_key);
+        Ok(PrivateKey::from_bytes(&raw_key)?.to_vec())
+    }
+
+    fn open(&self, _epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+        let mut private_key = PrivateKey::default();
+        private_key.load_bytes(id)?;
+        Ok(Arc::new(private_key))
+    }
+}
+
+/// Returns a builder for keys kept in memory, timed by the clock and
+/// generated from the seed.
+pub fn builder(clock: &TestClock, seed: u64) -> MixKeysBuilder {
+    MixKeysBuilder::new(clock.clone())
+        .monotonic_clock(Arc::new(clock.clone()))
+        .key_provider(Arc::new(SeededKeyProvider::new(seed)))
+        .backend(CacheBackend::Memory)
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    writes: u64,
+    fail_write: Option<u64>,
+    corrupt_reads: bool,
+    flush_stall: Option<Duration>,
+    fail_flushes: bool,
+}
+
+/// FaultInjector arms the faults of the stores of a `FaultyStores`.
+/// Clones arm the same faults, which take effect at once, even in
+/// stores opened before.
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjector {
+    faults: Arc<Mutex<Faults>>,
+}
+
+impl FaultInjector {
+    /// Fail the nth tag insert from now on, counting from one, with
+    /// `MixKeyError::StoreError`.
+    pub fn fail_write(&self, nth: u64) {
+        let mut faults = self.faults.lock().unwrap();
+        faults.fail_write = Some(faults.writes + nth);
+    }
+
+    /// While set, tag lookups and listings fail with
+    /// `MixKeyError::StoreError`, and metadata is read back with every
+    /// bit flipped.
+    pub fn corrupt_reads(&self, corrupt: bool) {
+        self.faults.lock().unwrap().corrupt_reads = corrupt;
+    }
+
+    /// Make every flush advance the test clock by `stall`, or stop
+    /// stalling with `None`.
+    pub fn stall_flushes(&self, stall: Option<Duration>) {
+        self.faults.lock().unwrap().flush_stall = stall;
+    }
+
+    /// While set, flushes fail with `MixKeyError::StoreError`.
+    pub fn fail_flushes(&self, fail: bool) {
+        self.faults.lock().unwrap().fail_flushes = fail;
+    }
+
+    /// Returns the number of tag inserts so far.
+    pub fn writes(&self) -> u64 {
+        self.faults.lock().unwrap().writes
+    }
+}
+
+fn injected(what: &str) -> MixKeyError {
+    MixKeyError::StoreError(format!("injected {}", what))
+}
+
+/// FaultyStore keeps the tags of one epoch in memory and fails as its
+/// injector says.
+struct FaultyStore {
+    store: MemoryStore,
+    faults: FaultInjector,
+    clock: TestClock,
+}
+
+impl FaultyStore {
+    fn corrupt_reads(&self) -> bool {
+        self.faults.faults.lock().unwrap().corrupt_reads
+    }
+}
+
+impl ReplayStore for FaultyStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.corrupt_reads() {
+            return Err(injected("corrupt read"))
+        }
+        self.store.contains(tag)
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        {
+            let mut faults = self.faults.faults.lock().unwrap();
+            faults.writes += 1;
+            if faults.fail_write == Some(faults.writes) {
+                return Err(injected("write failure"))
+            }
+        }
+        self.store.insert(tag)
+    }
+
+    fn remove(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        self.store.remove(tag)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        let (stall, fail) = {
+            let faults = self.faults.faults.lock().unwrap();
+            (faults.flush_stall, faults.fail_flushes)
+        };
+        if let Some(stall) = stall {
+            self.clock.advance(stall);
+        }
+        if fail {
+            return Err(injected("flush failure"))
+        }
+        self.store.flush()
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        if self.corrupt_reads() {
+            return Box::new(Some(Err(injected("corrupt read"))).into_iter())
+        }
+        self.store.tags()
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        let corrupt = self.corrupt_reads();
+        Ok(self.store.metadata(name)?.map(|value| {
+            if corrupt {
+                value.iter().map(|b| !b).collect()
+            } else {
+                value
+            }
+        }))
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        self.store.init_metadata(name, value)
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        self.store.set_metadata(name, value)
+    }
+}
+
+/// FaultyStores opens in memory stores whose faults are armed through
+/// its `FaultInjector`, and whose flush stalls advance the test clock.
+pub struct FaultyStores {
+    faults: FaultInjector,
+    clock: TestClock,
+}
+
+impl FaultyStores {
+    pub fn new(clock: &TestClock) -> FaultyStores {
+        FaultyStores{
+            faults: FaultInjector::default(),
+            clock: clock.clone(),
+        }
+    }
+
+    pub fn injector(&self) -> FaultInjector {
+        self.faults.clone()
+    }
+}
+
+impl ReplayStoreFactory for FaultyStores {
+    fn open(&self, _epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        Ok(Box::new(FaultyStore{
+            store: MemoryStore::default(),
+            faults: self.faults.clone(),
+            clock: self.clock.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use errors::Op;
+    use super::*;
+
+
+    #[test]
+    fn deterministic_rollover_test() {
+        let clock = TestClock::at(10, 600);
+        let mut mix_keys = builder(&clock, 7).num_mix_keys(2).grace_period(60).build().unwrap();
+        let other = builder(&TestClock::at(10, 600), 7).num_mix_keys(2).build().unwrap();
+        assert_eq!(mix_keys.epochs(), vec![10, 11]);
+        assert_eq!(mix_keys.public_key(11), other.public_key(11));
+        assert_eq!(mix_keys.key_info()[0].expiry, 11 * 600);
+
+        let tag = Tag::new([1u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(mix_keys.is_replay(10, &tag).unwrap(), false);
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(ClockSource::now(&clock).elapsed, 100);
+        clock.next_epoch();
+        mix_keys.rollover(11).unwrap();
+        assert_eq!(mix_keys.epochs(), vec![10, 11, 12]);
+        assert_eq!(mix_keys.is_replay(10, &tag).unwrap(), true);
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(mix_keys.prune(), vec![10]);
+        match mix_keys.is_replay(10, &tag) {
+            Err(MixKeyError::EpochTooOld{epoch: 10, current: 11}) => {},
+            x => panic!("checked a tag against a pruned epoch: {:?}", x.map_err(|e| e.to_string())),
+        }
+        assert_eq!(MonotonicClock::now(&clock), Duration::from_secs(11 * 600 + 60));
+    }
+
+    #[test]
+    fn faulty_stores_test() {
+        let clock = TestClock::at(10, 600);
+        let stores = FaultyStores::new(&clock);
+        let faults = stores.injector();
+        let mut mix_keys = builder(&clock, 7).num_mix_keys(1).store_factory(Arc::new(stores)).build().unwrap();
+        let tag = |b| Tag::new([b; SPHINX_REPLAY_TAG_SIZE]);
+
+        let writes = faults.writes();
+        faults.fail_write(2);
+        assert_eq!(mix_keys.is_replay(10, &tag(1)).unwrap(), false);
+        assert!(mix_keys.is_replay(10, &tag(2)).is_err());
+        assert_eq!(mix_keys.is_replay(10, &tag(3)).unwrap(), false);
+        assert_eq!(faults.writes() - writes, 3);
+
+        faults.corrupt_reads(true);
+        match mix_keys.is_replay(10, &tag(1)) {
+            Err(MixKeyError::Context{op: Op::LookupTag, ..}) => {},
+            x => panic!("a corrupt read was not reported: {:?}", x.map_err(|e| e.to_string())),
+        }
+        faults.corrupt_reads(false);
+        assert_eq!(mix_keys.is_replay(10, &tag(1)).unwrap(), true);
+
+        faults.stall_flushes(Some(Duration::from_secs(15)));
+        clock.advance(mix_keys.flush_interval());
+        assert_eq!(mix_keys.flush_due().unwrap(), vec![10]);
+        let adaptations = mix_keys.flush_adaptations();
+        assert!(adaptations[0].stalled);
+        assert_eq!(adaptations[0].flush_duration, Duration::from_secs(15));
+    }
+}
@@ -0,0 +1,249 @@
+// tiered.rs - Two-tier replay stores.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! A high rate epoch can hold more tags than an operator's fast storage
+//! fits. A `TieredStore` keeps the most recently stored tags in a hot
+//! store, such as a sled tree on NVMe, and once it holds more than
+//! `TieringPolicy::hot_tags` moves the oldest `TieringPolicy::batch` of
+//! them to a cold store, such as a sled tree on a disk array. Lookups
+//! consult both tiers.
+//!
+//! Each batch is inserted and flushed in the cold store before it is
+//! deleted from the hot one, so a crash part way through a migration
+//! leaves tags in both tiers rather than in neither. The hot tier's
+//! insertion order is only known for tags stored since the store was
+//! opened; tags already in it are migrated first, in the store's order.
+//!
+//! `TieredStores` opens a tiered store of two sled trees for every
+//! epoch, for `MixKeys::with_store_factory`.
+//!
+
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use sled;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use constants::{MIX_KEY_TIER_BATCH, MIX_KEY_TIER_HOT_TAGS};
+use errors::MixKeyError;
+use fsutil;
+use store::{self, ReplayStore, ReplayStoreFactory, SledStore};
+use super::Tag;
+
+
+/// TieringPolicy decides when tags move from the hot tier to the cold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TieringPolicy {
+    /// The most tags the hot tier holds after a migration.
+    pub hot_tags: u64,
+    /// The number of tags moved to the cold tier at once.
+    pub batch: u64,
+}
+
+impl Default for TieringPolicy {
+    fn default() -> Self {
+        TieringPolicy{
+            hot_tags: MIX_KEY_TIER_HOT_TAGS,
+            batch: MIX_KEY_TIER_BATCH,
+        }
+    }
+}
+
+/// TieredStore keeps an epoch's recent tags in a hot store and older
+/// ones in a cold store. Metadata is kept in the hot store.
+pub struct TieredStore {
+    hot: Box<dyn ReplayStore>,
+    cold: Box<dyn ReplayStore>,
+    policy: TieringPolicy,
+    order: VecDeque<Tag>,
+}
+
+impl TieredStore {
+    pub fn new(mut hot: Box<dyn ReplayStore>, cold: Box<dyn ReplayStore>, policy: TieringPolicy) -> Result<TieredStore, MixKeyError> {
+        let mut order = VecDeque::new();
+        for raw in hot.tags() {
+            order.push_back(Tag(raw?));
+        }
+        Ok(TieredStore{
+            hot: hot,
+            cold: cold,
+            policy: policy,
+            order: order,
+        })
+    }
+
+    /// Returns the number of tags in the hot tier.
+    pub fn hot_len(&self) -> u64 {
+        self.order.len() as u64
+    }
+
+    /// Move batches of the oldest hot tags to the cold tier until the
+    /// hot tier is within its limit.
+    fn migrate(&mut self) -> Result<(), MixKeyError> {
+        while self.hot_len() > self.policy.hot_tags {
+            let n = (self.policy.batch.max(1) as usize).min(self.order.len());
+            let batch: Vec<Tag> = self.order.drain(..n).collect();
+            for tag in &batch {
+                self.cold.insert(tag)?;
+            }
+            self.cold.flush()?;
+            for tag in &batch {
+                self.hot.remove(tag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ReplayStore for TieredStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(self.hot.contains(tag)? || self.cold.contains(tag)?)
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.cold.contains(tag)? || self.hot.insert(tag)? {
+            return Ok(true)
+        }
+        self.order.push_back(tag.clone());
+        self.migrate()?;
+        Ok(false)
+    }
+
+    fn remove(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let hot = self.hot.remove(tag)?;
+        if hot {
+            self.order.retain(|stored| stored != tag);
+        }
+        Ok(self.cold.remove(tag)? || hot)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        self.hot.flush()?;
+        self.cold.flush()
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        Box::new(self.hot.tags().chain(self.cold.tags()))
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        self.hot.metadata(name)
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        self.hot.init_metadata(name, value)
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        self.hot.set_metadata(name, value)
+    }
+}
+
+/// TieredStores opens, for every epoch, a tiered store of a sled tree in
+/// the `mix_key.<epoch>` directory of each of the hot and cold
+/// directories.
+pub struct TieredStores {
+    hot_dir: PathBuf,
+    cold_dir: PathBuf,
+    policy: TieringPolicy,
+}
+
+impl TieredStores {
+    pub fn new(hot_dir: PathBuf, cold_dir: PathBuf, policy: TieringPolicy) -> TieredStores {
+        TieredStores{
+            hot_dir: hot_dir,
+            cold_dir: cold_dir,
+            policy: policy,
+        }
+    }
+
+    fn open_tree(path: PathBuf) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        let tree = store::open_tree(&sled::Config::new().path(path))?;
+        Ok(Box::new(SledStore::new(tree)))
+    }
+}
+
+impl ReplayStoreFactory for TieredStores {
+    fn open(&self, epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        let hot = TieredStores::open_tree(fsutil::epoch_dir(&self.hot_dir, epoch))?;
+        let cold = TieredStores::open_tree(fsutil::epoch_dir(&self.cold_dir, epoch))?;
+        Ok(Box::new(TieredStore::new(hot, cold, self.policy)?))
+    }
+
+    fn remove(&self, epoch: u64) -> Result<(), MixKeyError> {
+        for dir in &[&self.hot_dir, &self.cold_dir] {
+            let path = fsutil::epoch_dir(dir, epoch);
+            if path.exists() {
+                fsutil::wipe_dir(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use store::MemoryStore;
+    use super::*;
+
+
+    #[test]
+    fn tiered_store_test() {
+        let policy = TieringPolicy{
+            hot_tags: 4,
+            batch: 3,
+        };
+        let mut store = TieredStore::new(Box::new(MemoryStore::default()), Box::new(MemoryStore::default()), policy).unwrap();
+        let tags: Vec<Tag> = (0..10u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        for tag in &tags {
+            assert_eq!(store.insert(tag).unwrap(), false);
+        }
+        assert_eq!(store.hot_len(), 4);
+        assert_eq!(store.cold.contains(&tags[0]).unwrap(), true);
+        assert_eq!(store.hot.contains(&tags[0]).unwrap(), false);
+        assert_eq!(store.hot.contains(&tags[9]).unwrap(), true);
+        for tag in &tags {
+            assert_eq!(store.insert(tag).unwrap(), true);
+        }
+        assert_eq!(store.tags().count(), 10);
+        assert_eq!(store.remove(&tags[0]).unwrap(), true);
+        assert_eq!(store.contains(&tags[0]).unwrap(), false);
+
+        let hot = TempDir::new().unwrap();
+        let cold = TempDir::new().unwrap();
+        let stores = TieredStores::new(hot.path().to_path_buf(), cold.path().to_path_buf(), policy);
+        {
+            let mut store = stores.open(7).unwrap();
+            for tag in &tags {
+                store.insert(tag).unwrap();
+            }
+            assert_eq!(store.init_metadata("private_key", b"key").unwrap(), b"key".to_vec());
+            store.flush().unwrap();
+        }
+        let mut store = stores.open(7).unwrap();
+        assert!(tags.iter().all(|tag| store.contains(tag).unwrap()));
+        assert_eq!(store.metadata("private_key").unwrap(), Some(b"key".to_vec()));
+        drop(store);
+        stores.remove(7).unwrap();
+        assert!(!fsutil::epoch_dir(cold.path(), 7).exists());
+    }
+}
@@ -0,0 +1,126 @@
+// timesource.rs - Monotonic time sources.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! The epoch clock follows wall clock time and is only used to decide
+//! which epoch we are in. Flush intervals, idle tracking and rate
+//! measurements instead use a `MonotonicClock`, which never jumps when
+//! the wall clock is adjusted.
+//!
+//! The epoch clock is a `ClockSource`. `epoch::Clock` serves mixnets
+//! whose epochs are a fixed period from a fixed genesis time, such as
+//! Katzenpost's; others implement `ClockSource` and pass it to
+//! `MixKeys::builder`.
+//!
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use epoch::{Clock, Time};
+
+
+/// ClockSource tells which epoch it is. This is an extension point for
+/// mixnets whose epochs do not follow `epoch::Clock`.
+pub trait ClockSource: Send + Sync {
+    /// Returns the current epoch, with the seconds elapsed since it
+    /// started and left until it ends.
+    fn now(&self) -> Time;
+
+    /// Returns the length of an epoch in seconds.
+    fn period(&self) -> u64;
+
+    /// Returns the UNIX time, in seconds, at which epoch 0 starts. The
+    /// default reads the wall clock, which is right for sources that
+    /// follow it; simulated sources must return their own origin.
+    fn epoch_zero(&self) -> u64 {
+        let now = ClockSource::now(self);
+        let unix = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        unix.saturating_sub(now.epoch * self.period() + now.elapsed)
+    }
+
+    /// Returns the UNIX time, in seconds, as the clock source tells it.
+    fn unix_time(&self) -> u64 {
+        let now = ClockSource::now(self);
+        self.epoch_zero() + now.epoch * self.period() + now.elapsed
+    }
+}
+
+impl ClockSource for Clock {
+    fn now(&self) -> Time {
+        Clock::now(self)
+    }
+
+    fn period(&self) -> u64 {
+        Clock::period(self)
+    }
+}
+
+
+/// MonotonicClock is a source of monotonically increasing time. This
+/// is an extension point for simulations and tests.
+pub trait MonotonicClock: Send + Sync {
+    /// Returns the time elapsed since an arbitrary fixed origin.
+    fn now(&self) -> Duration;
+}
+
+/// SystemMonotonicClock reads the operating system's monotonic clock.
+#[derive(Clone, Debug)]
+pub struct SystemMonotonicClock {
+    origin: Instant,
+}
+
+impl SystemMonotonicClock {
+    pub fn new() -> Self {
+        SystemMonotonicClock{
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemMonotonicClock {
+    fn default() -> Self {
+        SystemMonotonicClock::new()
+    }
+}
+
+impl MonotonicClock for SystemMonotonicClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// ManualMonotonicClock only moves when advanced explicitly, which
+/// makes time dependent behavior testable without sleeping.
+#[derive(Clone, Debug, Default)]
+pub struct ManualMonotonicClock {
+    now: Arc<Mutex<Duration>>,
+}
+
+impl ManualMonotonicClock {
+    pub fn new() -> Self {
+        ManualMonotonicClock::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl MonotonicClock for ManualMonotonicClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
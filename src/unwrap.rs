@@ -0,0 +1,134 @@
+// unwrap.rs - Batched unwrap scheduling around epoch boundaries.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! During the grace period after an epoch boundary a packet may have
+//! been made for either the previous or the current epoch's key, and
+//! the server cannot tell which until it has tried both. Trying the two
+//! keys alternately for each packet serializes every DH operation.
+//!
+//! An `UnwrapBatch` instead collects the packets arriving around the
+//! boundary and computes the shared secrets of the whole batch against
+//! each candidate key, with one worker thread per candidate, so the
+//! candidates' DH operations run side by side. The caller then tries
+//! the candidates of each packet in turn, newest epoch first.
+//!
+//! Builds with the `minimal` feature spawn no threads, and compute the
+//! candidates one key after another on the caller's thread.
+//!
+
+#[cfg(not(feature = "minimal"))]
+use std::sync::Arc;
+#[cfg(not(feature = "minimal"))]
+use std::thread;
+
+use ecdh_wrapper::{PublicKey, KEY_SIZE};
+
+use errors::MixKeyError;
+use super::MixKey;
+
+
+/// UnwrapCandidate is a packet's shared secret under one epoch's key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnwrapCandidate {
+    pub epoch: u64,
+    pub shared_secret: [u8; KEY_SIZE],
+}
+
+/// UnwrapBatch computes the shared secrets of a batch of packets
+/// against every candidate key.
+pub struct UnwrapBatch<T> {
+    keys: Vec<MixKey>,
+    ids: Vec<T>,
+    group_elements: Vec<PublicKey>,
+}
+
+impl<T> UnwrapBatch<T> {
+    /// Returns a batch for the given candidate keys.
+    pub fn new(mut keys: Vec<MixKey>) -> UnwrapBatch<T> {
+        keys.sort_by(|a, b| b.epoch().cmp(&a.epoch()));
+        UnwrapBatch{
+            keys: keys,
+            ids: vec![],
+            group_elements: vec![],
+        }
+    }
+
+    /// Returns the epochs of the candidate keys, newest first.
+    pub fn epochs(&self) -> Vec<u64> {
+        self.keys.iter().map(|key| key.epoch()).collect()
+    }
+
+    /// Add a packet, identified by `id`, with the given group element.
+    pub fn push(&mut self, id: T, group_element: PublicKey) {
+        self.ids.push(id);
+        self.group_elements.push(group_element);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Compute the shared secret of every packet under every candidate
+    /// key. Returns each packet's id and candidates, newest epoch first,
+    /// in the order the packets were pushed.
+    pub fn run(self) -> Result<Vec<(T, Vec<UnwrapCandidate>)>, MixKeyError> {
+        let results = exp_each(self.keys, self.group_elements).into_iter().collect::<Result<Vec<_>, _>>()?;
+
+        Ok(self.ids.into_iter().enumerate().map(|(i, id)| {
+            (id, results.iter().map(|candidates| candidates[i]).collect())
+        }).collect())
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+fn exp_each(keys: Vec<MixKey>, group_elements: Vec<PublicKey>) -> Vec<Result<Vec<UnwrapCandidate>, MixKeyError>> {
+    let group_elements = Arc::new(group_elements);
+    let mut keys = keys.into_iter();
+    let first = keys.next();
+    let workers: Vec<_> = keys.map(|key| {
+        let group_elements = group_elements.clone();
+        thread::spawn(move || exp_all(&key, &group_elements))
+    }).collect();
+
+    let mut results = vec![];
+    if let Some(key) = first {
+        results.push(exp_all(&key, &group_elements));
+    }
+    for worker in workers {
+        results.push(worker.join().expect("unwrap worker panicked"));
+    }
+    results
+}
+
+#[cfg(feature = "minimal")]
+fn exp_each(keys: Vec<MixKey>, group_elements: Vec<PublicKey>) -> Vec<Result<Vec<UnwrapCandidate>, MixKeyError>> {
+    keys.iter().map(|key| exp_all(key, &group_elements)).collect()
+}
+
+fn exp_all(key: &MixKey, group_elements: &[PublicKey]) -> Result<Vec<UnwrapCandidate>, MixKeyError> {
+    let epoch = key.epoch();
+    group_elements.iter().map(|group_element| {
+        Ok(UnwrapCandidate{
+            epoch: epoch,
+            shared_secret: key.exp(group_element)?,
+        })
+    }).collect()
+}
@@ -0,0 +1,113 @@
+// version.rs - Version and compatibility information.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Reports which version of the crate is running and which versions
+//! of each on-disk format it reads and writes, so that tooling can
+//! explain why a cache, dump or archive fails to load.
+//!
+//! Every cache records the format version it was written in and the
+//! version of the crate that created it. A cache in a newer format
+//! than this build supports fails to load with
+//! `MixKeyError::IncompatibleCache` rather than a generic load failure.
+//! Caches created before the format version was recorded are format 0.
+//! Format 1 caches record the size of the tags they store, which may be
+//! smaller than `SPHINX_REPLAY_TAG_SIZE`; format 0 caches hold full size
+//! tags. A cache in an older format is migrated forward when it is
+//! opened; see the `migration` module.
+//!
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use dump::DUMP_VERSION;
+use identity::BUNDLE_VERSION;
+use store::CacheBackend;
+#[cfg(feature = "archive")]
+use archive::ARCHIVE_VERSION;
+#[cfg(feature = "katzenpost-compat")]
+use katzenpost::KEY_VERSION;
+
+
+/// The version of this crate.
+pub const CRATE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The version of the epoch cache layout this build writes.
+pub const CACHE_FORMAT_VERSION: u8 = 1;
+
+
+/// FormatVersions lists the version of each on-disk format this build
+/// reads and writes. Formats behind a disabled feature are None.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatVersions {
+    pub cache: u8,
+    pub dump: u8,
+    pub identity_bundle: u8,
+    pub archive: Option<u8>,
+    pub katzenpost_key: Option<u8>,
+}
+
+/// VersionInfo describes this build of the crate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub crate_version: &'static str,
+    pub formats: FormatVersions,
+    pub tag_size: usize,
+    pub backends: Vec<CacheBackend>,
+    /// The optional features this build was compiled with.
+    pub features: Vec<&'static str>,
+}
+
+/// Returns the version and compatibility information of this build.
+pub fn version_info() -> VersionInfo {
+    let mut features = vec![];
+    if cfg!(feature = "archive") {
+        features.push("archive");
+    }
+    if cfg!(feature = "async") {
+        features.push("async");
+    }
+    if cfg!(feature = "katzenpost-compat") {
+        features.push("katzenpost-compat");
+    }
+    if cfg!(feature = "metrics") {
+        features.push("metrics");
+    }
+    if cfg!(feature = "redis") {
+        features.push("redis");
+    }
+    if cfg!(feature = "server") {
+        features.push("server");
+    }
+    VersionInfo{
+        crate_version: CRATE_VERSION,
+        formats: FormatVersions{
+            cache: CACHE_FORMAT_VERSION,
+            dump: DUMP_VERSION,
+            identity_bundle: BUNDLE_VERSION,
+            #[cfg(feature = "archive")]
+            archive: Some(ARCHIVE_VERSION),
+            #[cfg(not(feature = "archive"))]
+            archive: None,
+            #[cfg(feature = "katzenpost-compat")]
+            katzenpost_key: Some(KEY_VERSION),
+            #[cfg(not(feature = "katzenpost-compat"))]
+            katzenpost_key: None,
+        },
+        tag_size: SPHINX_REPLAY_TAG_SIZE,
+        backends: vec![CacheBackend::Sled, CacheBackend::Memory, CacheBackend::Custom],
+        features: features,
+    }
+}
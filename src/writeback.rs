@@ -0,0 +1,323 @@
+// writeback.rs - Coalesced tag writes from a writer thread.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Inserting every fresh tag into sled from the packet processing
+//! thread puts a tree insert on the hot path of every packet. With
+//! `CacheConfig::write_batch` set, a sled backed key instead keeps
+//! fresh tags in an in memory write buffer, and a writer thread per key
+//! commits them to sled in batches: as soon as `WriteBatch::max_tags`
+//! are waiting, or `WriteBatch::max_delay` after the first of them
+//! arrived. Lookups consult the buffer before sled, so a buffered tag is
+//! a replay like any other.
+//!
+//! Flushing the store first waits for the writer to commit everything
+//! buffered, so the durability policy applies unchanged. A tag that is
+//! not committed yet is lost by a crash just as an unflushed one is.
+//!
+//! If a commit fails the writer stops, keeping its tags in the buffer,
+//! and every later insert and flush fails with the error.
+//!
+
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use sphinxcrypto::constants::SPHINX_REPLAY_TAG_SIZE;
+
+use constants::{MIX_KEY_WRITE_BATCH_DELAY, MIX_KEY_WRITE_BATCH_TAGS};
+use errors::MixKeyError;
+use store::ReplayStore;
+use super::Tag;
+
+
+/// WriteBatch bounds how long fresh tags wait in the write buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// Commit once this many tags are waiting.
+    pub max_tags: usize,
+    /// Commit at most this long after the first waiting tag arrived.
+    pub max_delay: Duration,
+}
+
+impl Default for WriteBatch {
+    fn default() -> Self {
+        WriteBatch{
+            max_tags: MIX_KEY_WRITE_BATCH_TAGS,
+            max_delay: Duration::from_millis(MIX_KEY_WRITE_BATCH_DELAY),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Buffer {
+    pending: HashSet<Tag>,
+    flushing: usize,
+    shutdown: bool,
+    error: Option<String>,
+}
+
+struct Shared {
+    buffer: Mutex<Buffer>,
+    changed: Condvar,
+    /// Counts the batches committed, so an insert can tell whether its
+    /// tag may have left the buffer since it looked in the store.
+    commits: AtomicU64,
+}
+
+/// Writer stops the writer thread, once it committed every buffered
+/// tag, when the last handle onto the store is dropped.
+struct Writer {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.shared.buffer.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// WriteBackStore buffers the fresh tags inserted into a store, and
+/// commits them from a writer thread. Every handle onto it shares the
+/// buffer. Metadata is read and written directly.
+pub struct WriteBackStore {
+    store: Box<dyn ReplayStore>,
+    shared: Arc<Shared>,
+    batch: WriteBatch,
+    _writer: Arc<Writer>,
+}
+
+impl WriteBackStore {
+    /// Start a writer thread for the store, which needs to hand out a
+    /// second handle onto its tags for the thread.
+    pub fn new(store: Box<dyn ReplayStore>, batch: WriteBatch) -> Result<WriteBackStore, MixKeyError> {
+        let writer_store = match store.handle() {
+            Some(x) => x,
+            None => return Err(MixKeyError::InvalidConfig("write batching needs a store with several handles".to_string())),
+        };
+        let shared = Arc::new(Shared{
+            buffer: Mutex::new(Buffer::default()),
+            changed: Condvar::new(),
+            commits: AtomicU64::new(0),
+        });
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || commit(&thread_shared, writer_store, batch));
+        Ok(WriteBackStore{
+            store: store,
+            shared: shared.clone(),
+            batch: batch,
+            _writer: Arc::new(Writer{
+                shared: shared,
+                thread: Some(thread),
+            }),
+        })
+    }
+
+    fn failed(buffer: &Buffer) -> Result<(), MixKeyError> {
+        match buffer.error {
+            Some(ref e) => Err(MixKeyError::StoreError(e.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Wait until the writer committed every buffered tag.
+    fn drain(&self) -> Result<(), MixKeyError> {
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        buffer.flushing += 1;
+        self.shared.changed.notify_all();
+        while !buffer.pending.is_empty() && buffer.error.is_none() {
+            buffer = self.shared.changed.wait(buffer).unwrap();
+        }
+        buffer.flushing -= 1;
+        WriteBackStore::failed(&buffer)
+    }
+}
+
+/// Commit the buffered tags in batches until the store is dropped.
+fn commit(shared: &Shared, mut store: Box<dyn ReplayStore>, batch: WriteBatch) {
+    let mut buffer = shared.buffer.lock().unwrap();
+    loop {
+        if buffer.pending.is_empty() {
+            if buffer.shutdown {
+                return
+            }
+            buffer = shared.changed.wait(buffer).unwrap();
+            continue
+        }
+        if buffer.pending.len() < batch.max_tags && buffer.flushing == 0 && !buffer.shutdown {
+            buffer = shared.changed.wait_timeout(buffer, batch.max_delay).unwrap().0;
+        }
+        let tags: Vec<Tag> = buffer.pending.iter().cloned().collect();
+        drop(buffer);
+        let result = tags.iter().map(|tag| store.insert(tag)).collect::<Result<Vec<_>, _>>();
+        buffer = shared.buffer.lock().unwrap();
+        match result {
+            Ok(_) => {
+                for tag in &tags {
+                    buffer.pending.remove(tag);
+                }
+                shared.commits.fetch_add(1, Ordering::Release);
+            },
+            Err(e) => {
+                warn!("write buffer commit failed: {}", e);
+                buffer.error = Some(e.to_string());
+                shared.changed.notify_all();
+                return
+            },
+        }
+        shared.changed.notify_all();
+    }
+}
+
+impl ReplayStore for WriteBackStore {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.shared.buffer.lock().unwrap().pending.contains(tag) {
+            return Ok(true)
+        }
+        self.store.contains(tag)
+    }
+
+    /// The store is looked up before the buffer is locked, and the
+    /// buffer is checked and filled under one lock, so that of two
+    /// handles inserting the same fresh tag only one finds it fresh. If
+    /// a batch was committed in between, the store is looked up again.
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        let commits = self.shared.commits.load(Ordering::Acquire);
+        if self.store.contains(tag)? {
+            return Ok(true)
+        }
+        let mut buffer = self.shared.buffer.lock().unwrap();
+        WriteBackStore::failed(&buffer)?;
+        if self.shared.commits.load(Ordering::Acquire) != commits && self.store.contains(tag)? {
+            return Ok(true)
+        }
+        if !buffer.pending.insert(tag.clone()) {
+            return Ok(true)
+        }
+        if buffer.pending.len() == 1 || buffer.pending.len() >= self.batch.max_tags {
+            self.shared.changed.notify_all();
+        }
+        Ok(false)
+    }
+
+    fn remove(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        self.drain()?;
+        self.store.remove(tag)
+    }
+
+    fn handle(&self) -> Option<Box<dyn ReplayStore>> {
+        self.store.handle().map(|store| Box::new(WriteBackStore{
+            store: store,
+            shared: self.shared.clone(),
+            batch: self.batch,
+            _writer: self._writer.clone(),
+        }) as Box<dyn ReplayStore>)
+    }
+
+    /// Namespaced tags are written straight to the underlying store.
+    fn namespace(&self, namespace: u8) -> Option<Box<dyn ReplayStore>> {
+        self.store.namespace(namespace)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        self.drain()?;
+        self.store.flush()
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; SPHINX_REPLAY_TAG_SIZE], MixKeyError>> + 'a> {
+        if let Err(e) = self.drain() {
+            return Box::new(Some(Err(e)).into_iter())
+        }
+        self.store.tags()
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        self.store.metadata(name)
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        self.store.init_metadata(name, value)
+    }
+
+    fn set_metadata(&mut self, name: &str, value: &[u8]) -> Result<bool, MixKeyError> {
+        self.store.set_metadata(name, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate tempfile;
+
+    use self::tempfile::TempDir;
+    use sled;
+
+    use store::{SledStore, open_tree};
+    use super::*;
+
+
+    #[test]
+    fn write_back_store_test() {
+        let dir = TempDir::new().unwrap();
+        let tree = open_tree(&sled::Config::new().path(dir.path().join("cache.db"))).unwrap();
+        let batch = WriteBatch{
+            max_tags: 1000,
+            max_delay: Duration::from_secs(60),
+        };
+        let mut store = WriteBackStore::new(Box::new(SledStore::new(tree.clone())), batch).unwrap();
+        let mut other = store.handle().unwrap();
+
+        let tags: Vec<Tag> = (0..3u8).map(|i| Tag([i; SPHINX_REPLAY_TAG_SIZE])).collect();
+        for tag in &tags {
+            assert_eq!(store.insert(tag).unwrap(), false);
+        }
+        assert_eq!(other.insert(&tags[1]).unwrap(), true);
+        assert_eq!(other.contains(&tags[2]).unwrap(), true);
+        assert!(tree.get(&tags[0].0).unwrap().is_none());
+
+        store.flush().unwrap();
+        for tag in &tags {
+            assert!(tree.get(&tag.0).unwrap().is_some());
+        }
+        assert_eq!(store.tags().count(), 3);
+
+        let tag = Tag([9u8; SPHINX_REPLAY_TAG_SIZE]);
+        assert_eq!(other.insert(&tag).unwrap(), false);
+        drop(store);
+        drop(other);
+        assert!(tree.get(&tag.0).unwrap().is_some());
+
+        // Handles racing to insert the same tags find each fresh once.
+        let batch = WriteBatch{
+            max_tags: 8,
+            max_delay: Duration::from_millis(1),
+        };
+        let store = WriteBackStore::new(Box::new(SledStore::new(tree.clone())), batch).unwrap();
+        let threads: Vec<_> = (0..4).map(|_| {
+            let mut handle = store.handle().unwrap();
+            thread::spawn(move || (0..200u8).filter(|i| !handle.insert(&Tag([*i; SPHINX_REPLAY_TAG_SIZE])).unwrap()).count())
+        }).collect();
+        let fresh: usize = threads.into_iter().map(|thread| thread.join().unwrap()).sum();
+        assert_eq!(fresh, 200 - 4);
+    }
+}
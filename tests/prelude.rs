@@ -0,0 +1,189 @@
+// prelude.rs - Semver guard for the stable public API.
+// Copyright (C) 2018  David Anthony Stainton.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//!
+//! Uses the prelude the way a downstream mix server would. If a change
+//! to the crate breaks this file, it breaks downstream crates too and
+//! needs a new major version.
+//!
+
+#![cfg(feature = "std")]
+
+extern crate sphinx_replay_cache;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use sphinx_replay_cache::prelude::*;
+
+
+const TAG_SIZE: usize = 32;
+
+// The signatures downstream code calls.
+#[allow(dead_code)]
+fn signatures() {
+    let _: fn(Clock, u8, String, u64) -> Result<MixKeys, MixKeyError> = MixKeys::new;
+    let _: fn(Clock) -> MixKeysBuilder = MixKeys::builder;
+    let _: fn(MixKeysBuilder) -> Result<MixKeys, MixKeyError> = MixKeysBuilder::build;
+    let _: fn(MixKeysBuilder, CacheConfig) -> MixKeysBuilder = MixKeysBuilder::cache_config;
+    let _: fn(Clock, u8, String, u64, &[u8]) -> Result<MixKeys, MixKeyError> = MixKeys::new_with_seed;
+    let _: fn(Clock, u8, u64) -> Result<MixKeys, MixKeyError> = MixKeys::in_memory;
+    let _: fn(Clock, u8, String, u64, Arc<dyn KeyProvider>) -> Result<MixKeys, MixKeyError> = MixKeys::with_key_provider;
+    let _: fn(Clock, u8, String, u64, Arc<dyn KeyProvider>, CacheBackend) -> Result<MixKeys, MixKeyError> = MixKeys::with_backend;
+    let _: fn(Clock, u8, u64, Arc<dyn KeyProvider>, Arc<dyn ReplayStoreFactory>) -> Result<MixKeys, MixKeyError> = MixKeys::with_store_factory;
+    let _: fn(&MixKeys, u64) -> Option<MixKey> = MixKeys::key;
+    let _: fn(&MixKeys, u64) -> Result<MixKey, MixKeyError> = MixKeys::key_for_packet;
+    let _: fn(&MixKeys, u64) -> Option<PublicKey> = MixKeys::public_key;
+    let _: fn(&mut MixKeys, u64) -> Result<bool, MixKeyError> = MixKeys::generate;
+    let _: fn(&mut MixKeys) -> Vec<u64> = MixKeys::prune;
+    let _: fn(&MixKeys) -> std::sync::mpsc::Receiver<KeyEvent> = MixKeys::subscribe;
+    let _: fn(&MixKeys) -> MixKeysHandle = MixKeys::handle;
+    let _: fn(&MixKeysHandle, u64, &Tag) -> Result<bool, MixKeyError> = MixKeysHandle::is_replay;
+    let _: fn(&MixKeysHandle, u64) -> Result<MixKey, MixKeyError> = MixKeysHandle::key;
+    let _: fn(&MixKeysHandle, u64) -> Option<PublicKey> = MixKeysHandle::public_key;
+    let _: fn(&mut MixKeys) -> Result<Vec<u64>, MixKeyError> = MixKeys::flush_due;
+    let _: fn(&MixKeys) -> DurabilityPolicy = MixKeys::durability_policy;
+    let _: fn(&MixKeys) -> Option<ReplayWindow> = MixKeys::worst_case_replay_window;
+    let _: fn(&mut MixKeys, FlushBounds) = MixKeys::set_flush_bounds;
+    let _: fn(&MixKeys) -> Vec<FlushAdaptation> = MixKeys::flush_adaptations;
+    let _: fn(MixKeys) -> (MixKeyScheduler, std::sync::mpsc::Receiver<RotationEvent>) = MixKeyScheduler::new;
+
+    let _: fn(&MixKey, &Tag) -> Result<bool, MixKeyError> = MixKey::is_replay;
+    let _: fn(&MixKey, &Tag) -> Result<bool, MixKeyError> = MixKey::contains;
+    let _: fn(&str) -> Result<Tag, MixKeyError> = Tag::from_hex;
+    let _: fn(&MixKeys) -> Vec<EpochKeyInfo> = MixKeys::key_info;
+    let _: fn(&MixKeys, Option<&dyn DescriptorSigner>) -> Result<DescriptorBundle, MixKeyError> = MixKeys::descriptor_bundle;
+    let _: fn(&MixKeys) -> &[u64] = MixKeys::future_caches;
+    let _: fn(&MixKeys) -> HealthReport = MixKeys::health;
+    let _: fn(&DedupSet<32>, &[u8; 32]) -> Result<bool, MixKeyError> = DedupSet::is_duplicate;
+    let _: fn(&MixKeys) -> Result<Vec<CapacityAlarm>, MixKeyError> = MixKeys::check_capacity;
+    let _: fn(&mut MixKeys, Arc<dyn AlertSink>) = MixKeys::set_alert_sink;
+    let _: fn(&MixKeys) -> u64 = MixKeys::highest_epoch;
+    let _: fn(&MixKeys) -> Result<Vec<u64>, MixKeyError> = MixKeys::warm_up;
+    let _: fn(&MixKeys) -> bool = MixKeys::is_ready;
+    let _: fn(&MixKey) -> Result<KeyStats, MixKeyError> = MixKey::stats;
+    let _: fn(&MixKeys) -> Result<MixKeysStats, MixKeyError> = MixKeys::stats;
+    let _: fn(&MixKeysConfig, Clock) -> MixKeysBuilder = MixKeysConfig::builder;
+    let _: fn(&mut MixKey) -> Result<(), MixKeyError> = MixKey::flush;
+    let _: fn(&MixKey) -> PublicKey = MixKey::public_key;
+    let _: fn(&MixKey) -> Kem = MixKey::kem;
+    let _: fn(&MixKey, &[u8]) -> Result<Vec<u8>, MixKeyError> = MixKey::decapsulate;
+    let _: fn(&MixKey, &PublicKey) -> Result<[u8; 32], MixKeyError> = MixKey::exp;
+    let _: fn(&MixKey) -> u64 = MixKey::epoch;
+    let _: fn(&MixKey) -> Result<FilterReplica, MixKeyError> = MixKey::replica;
+    let _: fn(&mut FilterReplica, &Tag) -> Result<bool, MixKeyError> = FilterReplica::is_replay;
+    let _: fn([u8; TAG_SIZE]) -> Tag = Tag::new;
+    let _: fn(&Tag) -> &[u8; TAG_SIZE] = Tag::as_bytes;
+}
+
+// The extension points, implemented with exactly their current methods.
+struct Key(PrivateKey);
+
+impl EpochKey for Key {
+    fn public_key(&self) -> PublicKey {
+        self.0.public_key()
+    }
+
+    fn exp(&self, public_key: &PublicKey) -> Result<[u8; 32], MixKeyError> {
+        Ok(self.0.exp(public_key))
+    }
+
+    fn export(&self) -> Result<PrivateKey, MixKeyError> {
+        Err(MixKeyError::KeyNotExportable)
+    }
+}
+
+struct Provider;
+
+impl KeyProvider for Provider {
+    fn generate(&self, epoch: u64) -> Result<Vec<u8>, MixKeyError> {
+        LocalKeyProvider.generate(epoch)
+    }
+
+    fn open(&self, epoch: u64, id: &[u8]) -> Result<Arc<dyn EpochKey>, MixKeyError> {
+        let key = LocalKeyProvider.open(epoch, id)?;
+        Ok(Arc::new(Key(key.export()?)))
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    tags: Vec<[u8; TAG_SIZE]>,
+    metadata: Vec<(String, Vec<u8>)>,
+}
+
+impl ReplayStore for Store {
+    fn contains(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        Ok(self.tags.iter().any(|stored| Tag::new(*stored) == *tag))
+    }
+
+    fn insert(&mut self, tag: &Tag) -> Result<bool, MixKeyError> {
+        if self.contains(tag)? {
+            return Ok(true)
+        }
+        self.tags.push(*tag.as_bytes());
+        Ok(false)
+    }
+
+    fn flush(&mut self) -> Result<(), MixKeyError> {
+        Ok(())
+    }
+
+    fn tags<'a>(&'a mut self) -> Box<dyn Iterator<Item = Result<[u8; TAG_SIZE], MixKeyError>> + 'a> {
+        Box::new(self.tags.iter().map(|tag| Ok(*tag)))
+    }
+
+    fn metadata(&mut self, name: &str) -> Result<Option<Vec<u8>>, MixKeyError> {
+        Ok(self.metadata.iter().find(|entry| entry.0 == name).map(|entry| entry.1.clone()))
+    }
+
+    fn init_metadata(&mut self, name: &str, value: &[u8]) -> Result<Vec<u8>, MixKeyError> {
+        if let Some(stored) = self.metadata(name)? {
+            return Ok(stored)
+        }
+        self.metadata.push((name.to_string(), value.to_vec()));
+        Ok(value.to_vec())
+    }
+}
+
+struct Stores;
+
+impl ReplayStoreFactory for Stores {
+    fn open(&self, _epoch: u64) -> Result<Box<dyn ReplayStore>, MixKeyError> {
+        Ok(Box::new(Store::default()))
+    }
+}
+
+struct Timer;
+
+impl MonotonicClock for Timer {
+    fn now(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+}
+
+#[test]
+fn prelude_test() {
+    let clock = Clock::new_katzenpost();
+    let epoch = clock.now().epoch;
+    let mut mix_keys = MixKeys::with_store_factory(clock, 2, 1024 * 1024, Arc::new(Provider), Arc::new(Stores)).unwrap();
+    mix_keys.set_monotonic_clock(Arc::new(Timer));
+    let key = mix_keys.key(epoch).unwrap();
+    assert_eq!(key.backend(), CacheBackend::Custom);
+    let tag = Tag::new([1u8; TAG_SIZE]);
+    assert_eq!(key.is_replay(&tag).unwrap(), false);
+    assert_eq!(key.is_replay(&tag).unwrap(), true);
+}